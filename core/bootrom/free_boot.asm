@@ -0,0 +1,187 @@
+; Free replacement bootrom for the DMG, written for CorrodedBoy.
+; Released into the public domain (CC0 1.0), it contains no Nintendo code.
+;
+; Clears VRAM, decompresses the logo from the cartridge header,
+; scrolls it down the screen and plays the boot sound. The cartridge
+; header is not verified. The final register values are read from
+; RegisterTable, which the emulator fills in for the emulated model.
+;
+; Assemble with RGBDS:
+;   rgbasm -o free_boot.o free_boot.asm
+;   rgblink -x -o free_boot.bin free_boot.o
+
+DEF rNR11 EQU $FF11
+DEF rNR13 EQU $FF13
+DEF rNR14 EQU $FF14
+DEF rNR52 EQU $FF26
+DEF rLCDC EQU $FF40
+DEF rSCY  EQU $FF42
+DEF rLY   EQU $FF44
+DEF rBGP  EQU $FF47
+DEF rBOOT EQU $FF50
+DEF rBCPS EQU $FF68
+DEF rBCPD EQU $FF69
+
+SECTION "Boot", ROM0[$0000]
+    ld sp, $FFFE
+    ; Clear VRAM
+    xor a
+    ld hl, $9FFF
+
+ClearVram:
+    ld [hl-], a
+    bit 7, h
+    jr nz, ClearVram
+    ; Turn on the sound, channel 1 plays the boot sound
+    ld hl, rNR52
+    ld c, LOW(rNR11)
+    ld a, $80
+    ld [hl-], a
+    ldh [c], a
+    inc c
+    ld a, $F3
+    ldh [c], a
+    ld [hl-], a
+    ld a, $77
+    ld [hl], a
+    ; Color 3 of BG palette 0 is black on the CGB. Ignored on other models
+    ld a, $86
+    ldh [rBCPS], a
+    xor a
+    ldh [rBCPD], a
+    ldh [rBCPD], a
+    ; Decompress the logo from the cartridge header into tiles 1-24.
+    ; Every nibble is a row of 4 pixels, which is doubled in both directions
+    ld de, $0104
+    ld hl, $8010
+
+LogoLoop:
+    ld a, [de]
+    call WriteNibble
+    ld a, [de]
+    swap a
+    call WriteNibble
+    inc de
+    ld a, e
+    cp $34
+    jr nz, LogoLoop
+    ; The (R) symbol is tile 25
+    ld de, RegisteredSymbol
+    ld b, 8
+
+CopySymbol:
+    ld a, [de]
+    inc de
+    ld [hl+], a
+    inc hl
+    dec b
+    jr nz, CopySymbol
+    ; Tilemap, tiles 1-12 at $9904, 13-24 at $9924 and the (R) symbol at $9910
+    ld a, 25
+    ld [$9910], a
+    ld hl, $992F
+
+TilemapRow:
+    ld c, 12
+
+TilemapColumn:
+    dec a
+    jr z, ScrollLogo
+    ld [hl-], a
+    dec c
+    jr nz, TilemapColumn
+    ld l, $0F
+    jr TilemapRow
+
+; Scroll the logo down from the top of the screen, one line every two frames
+ScrollLogo:
+    ld a, $FC
+    ldh [rBGP], a
+    ld a, $64
+    ldh [rSCY], a
+    ld d, a
+    ld a, $91
+    ldh [rLCDC], a
+
+ScrollLoop:
+    ld b, 2
+    call WaitFrames
+    dec d
+    ld a, d
+    ldh [rSCY], a
+    jr nz, ScrollLoop
+    ; Play the two note boot sound and show the logo for a second
+    ld a, $83
+    call PlayNote
+    ld b, 6
+    call WaitFrames
+    ld a, $C1
+    call PlayNote
+    ld b, 60
+    call WaitFrames
+    ; Restore the white CGB palette
+    ld a, $86
+    ldh [rBCPS], a
+    ld a, $FF
+    ldh [rBCPD], a
+    ldh [rBCPD], a
+    ; Load the final register values of the emulated model. The emulator
+    ; fills in the table, the defaults are the values of the DMG
+    ld sp, RegisterTable
+    pop af
+    pop bc
+    pop de
+    pop hl
+    ld sp, $FFFE
+    jr BootEnd
+
+; Write a row of the nibble in the upper half of A to [HL] and [HL+2]
+WriteNibble:
+    ld b, 4
+
+DoublePixel:
+    sla a
+    push af
+    rl c
+    pop af
+    rl c
+    dec b
+    jr nz, DoublePixel
+    ld a, c
+    ld [hl+], a
+    inc hl
+    ld [hl+], a
+    inc hl
+    ret
+
+; Play a note on channel 1, A is the low byte of the frequency
+PlayNote:
+    ldh [rNR13], a
+    ld a, $87
+    ldh [rNR14], a
+    ret
+
+; Wait for B frames
+WaitFrames:
+    ldh a, [rLY]
+    cp 144
+    jr z, WaitFrames
+
+WaitVBlank:
+    ldh a, [rLY]
+    cp 144
+    jr nz, WaitVBlank
+    dec b
+    jr nz, WaitFrames
+    ret
+
+RegisteredSymbol:
+    db $3C, $42, $B9, $A5, $B9, $A5, $42, $3C
+
+RegisterTable:
+    db $B0, $01, $13, $00, $D8, $00, $4D, $01 ; F, A, C, B, E, D, L, H
+    ds $FE - @, $00
+
+; Unmap the bootrom, execution continues at $0100 in the cartridge
+BootEnd:
+    ldh [rBOOT], a
@@ -10,11 +10,28 @@ mod gpu;
 mod screen;
 mod joypad;
 mod audio;
+mod hdma;
+mod oam_dma;
+mod sgb;
+mod hardware_model;
+mod free_bootrom;
+mod serial;
+mod link_cable;
+mod printer;
+
+pub use rom::CartridgeHeader;
+pub use hardware_model::HardwareModel;
+pub use gpu::compatibility_palettes::PaletteCombo;
+pub use gpu::PpuRenderer;
+pub use serial::SerialDevice;
+pub use link_cable::LinkedEmulators;
+pub use printer::{Printer, PrintedImage};
 
 use serde::{Serialize, Deserialize};
 use flate2::write::ZlibEncoder;
 use flate2::write::ZlibDecoder;
 use std::io::Write;
+use std::fmt;
 
 /// Represents a frontend KeyPress event.
 #[derive(Copy, Clone, PartialEq, Debug)]
@@ -36,6 +53,45 @@ pub enum FrontendEvent {
     QueueSound
 }
 
+/// Represents an error which occured while loading
+/// a ROM, bootrom or savestate into the emulator.
+#[derive(Debug)]
+pub enum LoadError {
+    /// The file could not be read
+    Io(std::io::Error),
+    /// The ROM is too small to contain a cartridge header
+    RomTooSmall(usize),
+    /// The cartridge type in the ROM header is not supported
+    UnsupportedCartridgeType(u8),
+    /// The bootrom is not the expected 256 bytes
+    InvalidBootromSize(usize),
+    /// The bootrom was enabled before one was loaded
+    NoBootrom,
+    /// The savestate could not be decompressed or deserialized
+    InvalidSavestate,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(error) => { write!(f, "Unable to read file: {}", error) }
+            LoadError::RomTooSmall(size) => { write!(f, "ROM is too small to contain a header ({} bytes)", size) }
+            LoadError::UnsupportedCartridgeType(t) => { write!(f, "Unsupported cartridge type 0x{:02X}", t) }
+            LoadError::InvalidBootromSize(size) => { write!(f, "Bootrom was {} bytes instead of the expected 256 bytes", size) }
+            LoadError::NoBootrom => { write!(f, "No bootrom has been loaded") }
+            LoadError::InvalidSavestate => { write!(f, "Savestate is corrupted or from an incompatible version") }
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<std::io::Error> for LoadError {
+    fn from(error: std::io::Error) -> LoadError {
+        return LoadError::Io(error);
+    }
+}
+
 /// Represents a Gameboy (DMG) Emulator.
 #[derive(Serialize, Deserialize)]
 pub struct Emulator
@@ -46,6 +102,8 @@ pub struct Emulator
     pub frame_counter: usize,
     pub paused: bool,
     pub prev_sound_frame_cycles: u64,
+    requested_hardware_model: Option<HardwareModel>, // None selects the model from the cartridge header
+    hardware_model: HardwareModel,
 }
 
 impl Emulator
@@ -60,6 +118,8 @@ impl Emulator
             frame_counter: 0,
             paused: false,
             prev_sound_frame_cycles: 0,
+            requested_hardware_model: None,
+            hardware_model: HardwareModel::Dmg,
         }
     }
  
@@ -72,8 +132,7 @@ impl Emulator
 
     /// Step the emulator one cycle.
     pub fn step(&mut self) {
-        let machine_cycles = self.cpu.cycle(&mut self.memory);
-        self.memory.cycle_devices(machine_cycles as usize);
+        self.cpu.cycle(&mut self.memory);
     }
 
     /// Step the emulator until a frontend event occurs.  
@@ -84,29 +143,42 @@ impl Emulator
             return FrontendEvent::Render;
         }
         loop {
-            self.step();
+            if let Some(event) = self.step_frontend() {
+                return event;
+            }
+        }
+    }
 
-            // Check for GPU updates. Probably move this into the step devices code?
-            if self.memory.gpu.should_draw_scanline() {
-                if self.memory.gpu.state_modified_last_frame || self.memory.gpu.state_modified {
-                     // No point in drawing if nothing has changed
-                    self.screen.draw_line(&self.memory.gpu); 
-                }
-                self.memory.gpu.scanline_draw_requested = false;
+    /// Step the emulator one cycle and draw the screen when needed.
+    /// Returns the frontend event requested during the step, if any
+    pub fn step_frontend(&mut self) -> Option<FrontendEvent> {
+        self.step();
+
+        // Check for GPU updates. Probably move this into the step devices code?
+        if self.memory.gpu.should_draw_scanline() {
+            if self.memory.gpu.state_modified_last_frame || self.memory.gpu.state_modified {
+                 // No point in drawing if nothing has changed
+                self.screen.draw_line(&self.memory.gpu); 
             }
-            if self.memory.gpu.screen_draw_requested {
-                self.memory.gpu.state_modified_last_frame = self.memory.gpu.state_modified;
-                self.memory.gpu.state_modified = false;
-                self.memory.gpu.screen_draw_requested = false;
-                return FrontendEvent::Render;
+            self.memory.gpu.scanline_draw_requested = false;
+        }
+        if self.memory.gpu.screen_draw_requested {
+            if self.memory.sgb.enabled {
+                self.memory.sgb.perform_vram_transfer(&self.memory.gpu);
+                self.screen.draw_sgb_frame(&self.memory.sgb);
             }
+            self.memory.gpu.state_modified_last_frame = self.memory.gpu.state_modified;
+            self.memory.gpu.state_modified = false;
+            self.memory.gpu.screen_draw_requested = false;
+            return Some(FrontendEvent::Render);
+        }
 
-            // Check for audio updates
-            if self.memory.audio_device.sound_queue_push_requested {
-                self.memory.audio_device.sound_queue_push_requested = false;
-                return FrontendEvent::QueueSound;
-            }
+        // Check for audio updates
+        if self.memory.audio_device.sound_queue_push_requested {
+            self.memory.audio_device.sound_queue_push_requested = false;
+            return Some(FrontendEvent::QueueSound);
         }
+        return None;
     }
 
     /// Register a key being pressed from the UI
@@ -119,23 +191,177 @@ impl Emulator
         self.memory.joypad.clear_key(key);
     }
 
-    /// Use the bootrom data.  
-    /// 
-    /// Note: This will cause issues if the bootrom data
-    /// has not been loaded through `load_bootrom_from_data`
-    pub fn enable_bootrom(&mut self) {
+    /// Connect a device to the link port, like another Gameboy or a printer.
+    /// Without a device, the sent bytes are captured for debugging
+    pub fn connect_serial_device(&mut self, device: Box<dyn SerialDevice>) {
+        self.memory.serial.connect_device(device);
+    }
+
+    /// Disconnect the device from the link port, returning it
+    pub fn disconnect_serial_device(&mut self) -> Option<Box<dyn SerialDevice>> {
+        return self.memory.serial.disconnect_device();
+    }
+
+    /// Use the bootrom data. Fails if no bootrom has been loaded
+    /// through `load_bootrom_from_data` or `load_free_bootrom`
+    pub fn enable_bootrom(&mut self) -> Result<(), LoadError> {
+        if !self.memory.rom.is_bootrom_loaded() {
+            return Err(LoadError::NoBootrom);
+        }
         self.cpu.regs.pc = 0;
         self.memory.rom.using_boot_rom = true;
+        // The bootrom writes VRAM before enabling the LCD
+        self.memory.gpu.set_power_on_state();
+        return Ok(());
+    }
+
+    /// Load the free bootrom bundled with the emulator. It shows the
+    /// scrolling logo, and leaves the registers of the selected hardware model
+    pub fn load_free_bootrom(&mut self) {
+        let regs = if self.memory.rom.get_header_bytes().is_empty() {
+            // Updated for the selected model when the ROM is loaded
+            cpu::registers::Registers::new()
+        } else {
+            let rom_header = self.memory.rom.get_header_bytes();
+            self.hardware_model.get_post_boot_registers(rom_header, self.is_cgb_mode())
+        };
+        self.memory.rom.load_bootrom_from_data(&free_bootrom::get_free_bootrom(&regs)).unwrap();
+        self.memory.rom.free_boot_rom_loaded = true;
+    }
+
+    /// Load a ROM from data.  
+    /// 
+    /// An invalid header checksum does not prevent loading,
+    /// check `is_rom_header_checksum_valid` to warn about it
+    pub fn load_rom_from_data(&mut self, vec: &Vec<u8>) -> Result<(), LoadError> {
+        self.memory.rom.load_from_data(vec)?;
+        self.init_hardware_mode();
+        return Ok(());
+    }
+
+    /// Load a ROM from a file. See `load_rom_from_data`
+    pub fn load_rom_from_file(&mut self, filename: &str) -> Result<(), LoadError> {
+        self.memory.rom.load_from_file(filename)?;
+        self.init_hardware_mode();
+        return Ok(());
+    }
+
+    /// Select the Gameboy model to emulate. `None` selects the model from the
+    /// cartridge header: CGB for CGB games, SGB for SGB games, otherwise DMG.
+    /// 
+    /// This should be done before loading the ROM, or right after it,
+    /// as the hardware is reset to the state after the bootrom
+    pub fn set_hardware_model(&mut self, model: Option<HardwareModel>) {
+        self.requested_hardware_model = model;
+        if !self.memory.rom.get_header_bytes().is_empty() {
+            self.init_hardware_mode();
+        }
+    }
+
+    /// Returns the Gameboy model being emulated
+    pub fn get_hardware_model(&self) -> HardwareModel {
+        return self.hardware_model;
+    }
+
+    /// Select the renderer. The pixel FIFO renderer shows mid-scanline
+    /// raster effects, while the default scanline renderer is faster
+    pub fn set_renderer(&mut self, renderer: PpuRenderer) {
+        self.memory.gpu.renderer = renderer;
+    }
+
+    /// Set up the selected hardware model, and the state its bootrom leaves behind
+    fn init_hardware_mode(&mut self) {
+        let header = &self.memory.rom.header;
+        // Games with both CGB and SGB support are run on the CGB
+        let model = self.requested_hardware_model.unwrap_or(
+            HardwareModel::select_for_cartridge(header.supports_cgb(), header.supports_sgb()));
+        // The CGB runs DMG games in a compatibility mode
+        let cgb_mode = model.is_cgb() && header.supports_cgb();
+        self.hardware_model = model;
+        self.memory.set_cgb_mode(cgb_mode);
+        self.memory.sgb.enabled = model.is_sgb();
+        if self.memory.rom.free_boot_rom_loaded {
+            self.load_free_bootrom();
+        }
+        if self.memory.rom.using_boot_rom {
+            // The bootrom sets up the hardware itself
+            return;
+        }
+        let rom_header = self.memory.rom.get_header_bytes().to_vec();
+        self.cpu.regs = model.get_post_boot_registers(&rom_header, cgb_mode);
+        self.memory.set_post_boot_state(model, &rom_header);
+        if model.is_cgb() && !cgb_mode {
+            // The CGB bootrom colorizes DMG games
+            self.set_compatibility_palette(None);
+        }
+        else {
+            self.clear_compatibility_palette();
+        }
+    }
+
+    /// Returns true if the emulator is running in Gameboy Color mode
+    pub fn is_cgb_mode(&self) -> bool {
+        return self.memory.cgb_mode;
+    }
+
+    /// Returns true if the emulator is running in Super Gameboy mode.
+    /// The SGB screen with the border is then available in `screen.sgb_bitmap`
+    pub fn is_sgb_mode(&self) -> bool {
+        return self.memory.sgb.enabled;
+    }
+
+    /// Returns the RGB bitmap which should be displayed.
+    /// This is the 256x224 SGB screen in SGB mode, otherwise the 160x144 screen
+    pub fn get_screen_bitmap(&self) -> &[u8] {
+        if self.is_sgb_mode() {
+            return &self.screen.sgb_bitmap;
+        }
+        return &self.screen.bitmap;
+    }
+
+    /// Returns the width and height of `get_screen_bitmap`
+    pub fn get_screen_size(&self) -> (usize, usize) {
+        if self.is_sgb_mode() {
+            return (sgb::SGB_SCREEN_WIDTH, sgb::SGB_SCREEN_HEIGHT);
+        }
+        return (160, 144);
+    }
+
+    /// Colorize a DMG game like the Gameboy Color does.  
+    /// 
+    /// `None` selects the palette from the cartridge title, like the CGB bootrom.
+    /// Otherwise the palette of a button combination held during the boot animation is used.
+    /// Has no effect on CGB games
+    pub fn set_compatibility_palette(&mut self, combo: Option<PaletteCombo>) {
+        if self.is_cgb_mode() || self.memory.rom.get_header_bytes().is_empty() {
+            return;
+        }
+        let palette = match combo {
+            Some(combo) => combo.get_palette(),
+            None => gpu::compatibility_palettes::select_title_palette(self.memory.rom.get_header_bytes()),
+        };
+        self.memory.gpu.set_compatibility_palette(Some(palette));
     }
 
-    /// Load a ROM from data
-    pub fn load_rom_from_data(&mut self, vec: &Vec<u8>) {
-        self.memory.rom.load_from_data(vec);
+    /// Restore the monochrome DMG colors
+    pub fn clear_compatibility_palette(&mut self) {
+        self.memory.gpu.set_compatibility_palette(None);
     }
 
     /// Load a bootrom from data
-    pub fn load_bootrom_from_data(&mut self, vec: &Vec<u8>) {
-        self.memory.rom.load_bootrom_from_data(vec);
+    pub fn load_bootrom_from_data(&mut self, vec: &Vec<u8>) -> Result<(), LoadError> {
+        return self.memory.rom.load_bootrom_from_data(vec);
+    }
+
+    /// Returns the header of the loaded cartridge
+    pub fn get_cartridge_header(&self) -> &CartridgeHeader {
+        return &self.memory.rom.header;
+    }
+
+    /// Returns false if the header checksum of the loaded ROM is invalid. 
+    /// Real hardware refuses to boot these, but they usually work fine
+    pub fn is_rom_header_checksum_valid(&self) -> bool {
+        return self.memory.rom.is_header_checksum_valid();
     }
 
     /// Get the emulator sound queue. This should be
@@ -165,9 +391,54 @@ impl Emulator
         self.memory.rom.romname = romname.to_owned();
     }
 
+    /// Store the wall clock time in the cartridge RTC.
+    /// This should be done whenever the emulator state is saved, so that
+    /// the RTC can catch up on the time passed when it is loaded again
+    pub fn set_rtc_unix_time(&mut self, unix_time: i64) {
+        self.memory.rom.set_rtc_unix_time(unix_time);
+    }
+
+    /// Advance the cartridge RTC by the wall clock time passed since
+    /// the time was last stored through `set_rtc_unix_time`
+    pub fn catch_up_rtc_to_unix_time(&mut self, unix_time: i64) {
+        self.memory.rom.catch_up_rtc_to_unix_time(unix_time);
+    }
+
+    /// Returns true if the loaded cartridge has battery backed RAM,
+    /// which should be persisted through `export_battery_save`
+    pub fn has_battery_save(&self) -> bool {
+        return self.memory.rom.has_battery;
+    }
+
+    /// Export the battery backed cartridge RAM as a .sav file,
+    /// compatible with other emulators. The MBC3 RTC is stored in a footer
+    pub fn export_battery_save(&mut self) -> Vec<u8> {
+        #[cfg(not(target_arch = "wasm32"))]
+        self.set_rtc_unix_time(chrono::Utc::now().timestamp());
+        return self.memory.rom.export_battery_ram();
+    }
+
+    /// Returns true if the battery backed RAM or the RTC registers were written
+    /// since the last `export_battery_save`. The RTC ticking alone does not count,
+    /// as the time stamp in the footer lets it catch up when loaded
+    pub fn is_battery_save_modified(&self) -> bool {
+        return self.memory.rom.is_battery_ram_modified();
+    }
+
+    /// Import the battery backed cartridge RAM from a .sav file.
+    /// This should be done after the ROM has been loaded
+    pub fn import_battery_save(&mut self, data: &[u8]) {
+        self.memory.rom.import_battery_ram(data);
+        #[cfg(not(target_arch = "wasm32"))]
+        self.catch_up_rtc_to_unix_time(chrono::Utc::now().timestamp());
+    }
+
     /// Serialize the entire emulator into bytes.
     /// These are compressed. DrawHelper and BlipBuf state is not saved
     pub fn serialize(&mut self) -> Vec<u8> {
+        // Wall clock time is not available in WASM, the frontend has to set it instead
+        #[cfg(not(target_arch = "wasm32"))]
+        self.set_rtc_unix_time(chrono::Utc::now().timestamp());
         // Serialize using serde bincode format
         let serialized_bytes = bincode::serialize(&self).unwrap();
         // Compress using flate2
@@ -178,15 +449,28 @@ impl Emulator
     }
 
     /// Deserialize a serialized emulator.
-    pub fn deserialize(bytes: &Vec<u8>) -> Emulator {
+    pub fn deserialize(bytes: &Vec<u8>) -> Result<Emulator, LoadError> {
         // Decompress
         let mut decoder = ZlibDecoder::new(Vec::<u8>::new());
-        decoder.write_all(&bytes).unwrap();
-        let bincode_bytes = decoder.finish().unwrap();
+        decoder.write_all(&bytes).map_err(|_| LoadError::InvalidSavestate)?;
+        let bincode_bytes = decoder.finish().map_err(|_| LoadError::InvalidSavestate)?;
         // Deserialize
-        let mut em : Emulator = bincode::deserialize(&bincode_bytes).unwrap();
+        let mut em : Emulator = bincode::deserialize(&bincode_bytes).map_err(|_| LoadError::InvalidSavestate)?;
         em.memory.gpu.init_draw_helper();
-        return em;
+        #[cfg(not(target_arch = "wasm32"))]
+        em.catch_up_rtc_to_unix_time(chrono::Utc::now().timestamp());
+        return Ok(em);
+    }
+
+    /// Load a serialized emulator state into this emulator.
+    /// The device connected to the link port is not part of the state, it stays connected
+    pub fn load_state(&mut self, bytes: &Vec<u8>) -> Result<(), LoadError> {
+        let mut em = Emulator::deserialize(bytes)?;
+        if let Some(device) = self.disconnect_serial_device() {
+            em.connect_serial_device(device);
+        }
+        *self = em;
+        return Ok(());
     }
 }
     
@@ -195,13 +479,53 @@ mod test
 {
     // Test serialization and deserialization using serde
     use super::Emulator;
+
+    /// Run a Mooneye test ROM and check that it passed.
+    /// The Mooneye test suite is not bundled, the built ROMs have to be placed in roms/mooneye.
+    /// Passing tests send the Fibonacci numbers 3, 5, 8, 13, 21, 34 through serial
+    pub fn run_mooneye_test(filename: &str) {
+        const PASS_SEQUENCE : [u8; 6] = [3, 5, 8, 13, 21, 34];
+        let mut em = Emulator::new();
+        em.memory.serial.capture.output_to_stdout = false;
+        em.load_rom_from_file(&format!("../roms/mooneye/{}", filename)).unwrap();
+        // Tests finish within a few seconds
+        for _ in 0..60*20 {
+            em.run_until_frontend_event();
+            if em.memory.serial.capture.buffer.len() >= PASS_SEQUENCE.len() {
+                break;
+            }
+        }
+        assert_eq!(em.memory.serial.capture.buffer, PASS_SEQUENCE, "Mooneye test {} failed", filename);
+    }
+
+    /// Run a Mealybug Tearoom test ROM on the DMG with the pixel FIFO renderer,
+    /// and compare the screen shades with the expected screenshot.
+    /// The tests are not bundled, the built ROMs have to be placed in roms/mealybug
+    /// next to their DMG expected screenshots converted to .bmp
+    pub fn run_mealybug_test(name: &str) {
+        use super::{HardwareModel, PpuRenderer};
+        let mut em = Emulator::new();
+        em.set_hardware_model(Some(HardwareModel::Dmg));
+        em.set_renderer(PpuRenderer::PixelFifo);
+        em.load_rom_from_file(&format!("../roms/mealybug/{}.gb", name)).unwrap();
+        // The tests draw their result within a few frames
+        for _ in 0..60 {
+            em.run_until_frontend_event();
+        }
+        let expected = bmp::open(format!("../roms/mealybug/{}.bmp", name)).unwrap();
+        for (x, y) in expected.coordinates() {
+            let expected_shade = 3 - expected.get_pixel(x, y).r / 85;
+            let shade = em.screen.shades[(y*160 + x) as usize];
+            assert_eq!(shade, expected_shade, "Mealybug test {} failed at ({}, {})", name, x, y);
+        }
+    }
     
     #[test]
     fn serialization()
     {
         let mut em1 = Emulator::new();
-        em1.memory.output_serial_to_stdout = false;
-        em1.memory.rom.load_from_file("../roms/blargg/cpu_instrs.gb");
+        em1.memory.serial.capture.output_to_stdout = false;
+        em1.memory.rom.load_from_file("../roms/blargg/cpu_instrs.gb").unwrap();
 
         // Run emulator for a while
         for _ in 0..300 {
@@ -211,7 +535,7 @@ mod test
         // Serialize emulator
         let serialized_bytes = em1.serialize();
         // Deserialize into second emulator
-        let mut em2 = Emulator::deserialize(&serialized_bytes);
+        let mut em2 = Emulator::deserialize(&serialized_bytes).unwrap();
 
         // Run both for a few frames
         for _ in 0..20 {
@@ -232,5 +556,127 @@ mod test
                 assert!(false, "Deserialized emulator bitmap mismatch");
             }
         }
+
+        // Corrupted savestates should return an error instead of panicking
+        let mut corrupted_bytes = serialized_bytes.clone();
+        corrupted_bytes.truncate(serialized_bytes.len() / 2);
+        assert!(Emulator::deserialize(&corrupted_bytes).is_err());
+    }
+
+    #[test]
+    fn cgb_detection()
+    {
+        // DMG only ROM
+        let mut em = Emulator::new();
+        em.load_rom_from_file("../roms/acid2/dmg-acid2.gb").unwrap();
+        assert_eq!(em.is_cgb_mode(), false);
+        assert_eq!(em.cpu.regs.a, 0x01);
+
+        // ROM with CGB support
+        let mut em = Emulator::new();
+        em.load_rom_from_file("../roms/blargg/cpu_instrs.gb").unwrap();
+        assert_eq!(em.is_cgb_mode(), true);
+        assert_eq!(em.cpu.regs.a, 0x11);
+    }
+
+    #[test]
+    fn hardware_models()
+    {
+        use super::HardwareModel;
+        // Gameboy Pocket, detected through A=0xFF
+        let mut em = Emulator::new();
+        em.set_hardware_model(Some(HardwareModel::Mgb));
+        em.load_rom_from_file("../roms/acid2/dmg-acid2.gb").unwrap();
+        assert_eq!(em.get_hardware_model(), HardwareModel::Mgb);
+        assert_eq!(em.cpu.regs.a, 0xFF);
+        assert_eq!(em.memory.read_byte(0xFF04), 0xAB);
+        // The logo is left in VRAM
+        assert_eq!(em.memory.read_byte(0x9904), 0x01);
+        assert_eq!(em.memory.read_byte(0x9910), 0x19);
+
+        // DMG game on the CGB runs in compatibility mode
+        em.set_hardware_model(Some(HardwareModel::Cgb));
+        assert_eq!(em.is_cgb_mode(), false);
+        assert_eq!(em.cpu.regs.a, 0x11);
+
+        // CGB game on the GBA, detected through B=0x01
+        let mut em = Emulator::new();
+        em.set_hardware_model(Some(HardwareModel::Agb));
+        em.load_rom_from_file("../roms/blargg/cpu_instrs.gb").unwrap();
+        assert_eq!(em.is_cgb_mode(), true);
+        assert_eq!((em.cpu.regs.a, em.cpu.regs.b), (0x11, 0x01));
+
+        // CGB game on the DMG
+        em.set_hardware_model(Some(HardwareModel::Dmg));
+        assert_eq!(em.is_cgb_mode(), false);
+        assert_eq!(em.cpu.regs.a, 0x01);
+    }
+
+    #[test]
+    fn free_bootrom()
+    {
+        use super::{HardwareModel, LoadError};
+        let mut em = Emulator::new();
+        assert!(matches!(em.enable_bootrom(), Err(LoadError::NoBootrom)));
+
+        em.set_hardware_model(Some(HardwareModel::Mgb));
+        em.load_free_bootrom();
+        em.enable_bootrom().unwrap();
+        em.load_rom_from_file("../roms/acid2/dmg-acid2.gb").unwrap();
+        assert_eq!(em.cpu.regs.pc, 0);
+        // The logo scroll takes a few seconds
+        for _ in 0..10_000_000 {
+            if !em.memory.rom.using_boot_rom {
+                break;
+            }
+            em.step();
+        }
+        assert_eq!(em.memory.rom.using_boot_rom, false);
+        assert_eq!(em.cpu.regs.pc, 0x100);
+        // The registers of the selected model are left behind
+        let rom_header = em.memory.rom.get_header_bytes().to_vec();
+        let regs = HardwareModel::Mgb.get_post_boot_registers(&rom_header, false);
+        assert_eq!((em.cpu.regs.a, em.cpu.regs.f, em.cpu.regs.get_bc(), em.cpu.regs.get_de(), em.cpu.regs.get_hl()),
+            (regs.a, regs.f, regs.get_bc(), regs.get_de(), regs.get_hl()));
+        assert_eq!(em.cpu.regs.sp, 0xFFFE);
+        // The logo is in VRAM, scrolled into place
+        let logo_tiles = super::hardware_model::generate_logo_tiles(&rom_header);
+        for (i, val) in logo_tiles.iter().enumerate() {
+            assert_eq!(em.memory.gpu.read_byte(0x8000 + i), *val, "Logo tile data mismatch at {}", i);
+        }
+        for (address, tile_id) in super::hardware_model::get_logo_tilemap() {
+            assert_eq!(em.memory.read_byte(address), tile_id);
+        }
+        assert_eq!(em.memory.read_byte(0xFF42), 0);
+        assert_eq!(em.memory.read_byte(0xFF40), 0x91);
+    }
+
+    #[test]
+    fn compatibility_palette()
+    {
+        let mut em = Emulator::new();
+        em.memory.serial.capture.output_to_stdout = false;
+        em.load_rom_from_file("../roms/acid2/dmg-acid2.gb").unwrap();
+        for _ in 0..30 {
+            em.run_until_frontend_event();
+        }
+        let gray_checksum = em.screen.calculate_simple_checksum();
+        em.set_compatibility_palette(Some(super::PaletteCombo::RightB));
+        for _ in 0..2 {
+            em.run_until_frontend_event();
+        }
+        // Inverted palette, the top left pixel is white on DMG
+        assert_eq!(&em.screen.bitmap[0..3], &[0, 0, 0]);
+        em.clear_compatibility_palette();
+        for _ in 0..2 {
+            em.run_until_frontend_event();
+        }
+        assert_eq!(em.screen.calculate_simple_checksum(), gray_checksum);
+        // The CGB colorizes DMG games from their title when booting without a bootrom
+        em.set_hardware_model(Some(super::HardwareModel::Cgb));
+        let title_palette = super::gpu::compatibility_palettes::select_title_palette(em.memory.rom.get_header_bytes());
+        assert_eq!(em.memory.gpu.compatibility_palette, Some(title_palette));
+        em.set_hardware_model(Some(super::HardwareModel::Dmg));
+        assert_eq!(em.memory.gpu.compatibility_palette, None);
     }
 }
\ No newline at end of file
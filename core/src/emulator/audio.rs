@@ -75,9 +75,7 @@ pub struct AudioDevice {
     noise_channel : noise_channel::NoiseChannel,
 
     clock_cycles: usize,
-    length_step_counter: usize,
-    vol_step_counter: usize,
-    sweep_step_counter: usize,
+    frame_sequencer_step: usize,
 
     gen_rate: usize,
     pub sound_queue_push_requested: bool,
@@ -96,9 +94,7 @@ impl AudioDevice {
             wave_channel: wave_channel::WaveChannel::new(),
             noise_channel: noise_channel::NoiseChannel::new(),
             clock_cycles: 0,
-            length_step_counter: 0,
-            vol_step_counter: 0,
-            sweep_step_counter: 0,
+            frame_sequencer_step: 0,
 
             gen_rate: 0,
             sound_queue_push_requested: false,
@@ -112,8 +108,13 @@ impl AudioDevice {
 
     pub fn read_byte(&self, address : usize) -> u8 {
         return match address {
+            // The channel statuses are read live, unused bits read as 1
             0xFF26 => {
-                self.options.bytes[2]
+                (self.options.power_status() as u8) << 7 | 0b0111_0000
+                    | (self.noise_channel.enabled as u8) << 3
+                    | (self.wave_channel.enabled as u8) << 2
+                    | (self.square_channel2.enabled as u8) << 1
+                    | self.square_channel1.enabled as u8
             }
             _ => self.memory[address - 0xFF10]
         }
@@ -138,7 +139,13 @@ impl AudioDevice {
             0xFF16 ..= 0xFF19 => { self.square_channel2.update_options(val, address-0xFF15) },
             0xFF1A ..= 0xFF1E => { self.wave_channel.update_options(val, address-0xFF1A) },
             0xFF20 ..= 0xFF23 => { self.noise_channel.update_options(val, address-0xFF1F) },
-            0xFF24 ..= 0xFF26 => { self.update_options() },
+            0xFF24 ..= 0xFF26 => { 
+                // Powering on resets the frame sequencer
+                if address == 0xFF26 && !self.options.power_status() {
+                    self.frame_sequencer_step = 0;
+                }
+                self.update_options() 
+            },
             0xFF30 ..= 0xFF3F => { self.wave_channel.write_wave_ram(address, val) }
             _ => {}
         }
@@ -153,37 +160,37 @@ impl AudioDevice {
             return;
         }
         self.clock_cycles += cycles;
-        self.length_step_counter += cycles;
-        self.vol_step_counter += cycles;
-        self.sweep_step_counter += cycles;
-        // Step the channel lengths, 256 hz
-        if self.length_step_counter >= (CLOCK_RATE / 256) {
+        // Generate 1024 samples for output every GEN_RATE cycles
+        if self.clock_cycles > self.gen_rate {
+            self.generate_samples(self.gen_rate);
+            self.clock_cycles -= self.gen_rate;
+            self.mix_samples();
+            self.sound_queue_push_requested = true;
+        }
+    }
+
+    /// Step the frame sequencer, clocked at 512 hz by the falling edge of a DIV bit.
+    /// The lengths are stepped at 256 hz, the sweep at 128 hz and the volume envelopes at 64 hz
+    pub fn step_frame_sequencer(&mut self) {
+        if !self.options.power_status() {
+            return;
+        }
+        if self.frame_sequencer_step % 2 == 0 {
             self.square_channel1.step_length();
             self.square_channel2.step_length();
             self.wave_channel.step_length();
             self.noise_channel.step_length();
             self.update_channel_enables();
-            self.length_step_counter -= CLOCK_RATE / 256;
         }
-        // Steep sweep at 256 hz
-        if self.sweep_step_counter >= (CLOCK_RATE / 128) {
+        if self.frame_sequencer_step == 2 || self.frame_sequencer_step == 6 {
             self.square_channel1.step_sweep();
-            self.sweep_step_counter -= CLOCK_RATE / 128;
         }
-        // Step the volume envelopes, 64 hz
-        if self.vol_step_counter >= (CLOCK_RATE / 64) {
+        if self.frame_sequencer_step == 7 {
             self.square_channel1.step_volume();
             self.square_channel2.step_volume();
             self.noise_channel.step_volume();
-            self.vol_step_counter -= CLOCK_RATE / 64;
-        }
-        // Generate 1024 samples for output every GEN_RATE cycles
-        if self.clock_cycles > self.gen_rate {
-            self.generate_samples(self.gen_rate);
-            self.clock_cycles -= self.gen_rate;
-            self.mix_samples();
-            self.sound_queue_push_requested = true;
         }
+        self.frame_sequencer_step = (self.frame_sequencer_step + 1) % 8;
     }
 
     pub fn generate_samples(&mut self, sample_count: usize) {
@@ -290,4 +297,23 @@ impl AudioDevice {
     }
 }
 
+#[cfg(test)]
+mod test
+{
+    use super::AudioDevice;
 
+    #[test]
+    fn nr52_channel_status()
+    {
+        let mut audio = AudioDevice::new();
+        audio.write_byte(0xFF26, 0x80);
+        // Unused bits read as 1, no channel is playing
+        assert_eq!(audio.read_byte(0xFF26), 0xF0);
+        // Triggering the second square channel enables it
+        audio.write_byte(0xFF19, 0x80);
+        assert_eq!(audio.read_byte(0xFF26), 0xF2);
+        // The status bits are read-only
+        audio.write_byte(0xFF26, 0x8D);
+        assert_eq!(audio.read_byte(0xFF26), 0xF2);
+    }
+}
@@ -1,4 +1,4 @@
-mod registers;
+pub mod registers;
 mod cycle_timings;
 use super::memory;
 use super::interrupts::InterruptTypes;
@@ -10,14 +10,17 @@ use serde::{Serialize, Deserialize};
 /// The Gameboy CPU is a SharpLR35902, which is a 8080/Z80 derivative.  
 /// The CPU runs at 4194304 hz, but every instruction takes cycles
 /// divisible by 4, so this program generally treats everything
-/// in machine cycles (cycles / 4)
-/// Todo:
-/// Memory cycle increment inside longer instructions
+/// in machine cycles (cycles / 4).
+/// 
+/// Every memory access takes a machine cycle, during which the other
+/// devices (timer, PPU, APU) are stepped, so that they see the accesses
+/// at the right point inside an instruction
 #[derive(Serialize, Deserialize)]
 pub struct CPU
 {
     pub regs : registers::Registers,
     pub halted: bool,
+    pub stopped: bool,
     pub machine_cycles: u64,
     pub machine_cycles_delta: u8,
     halt_bug_active: bool,
@@ -28,18 +31,26 @@ impl CPU {
     {
         CPU { 
             regs : registers::Registers::new(), 
-            halted: false, machine_cycles: 0, 
+            halted: false, stopped: false, machine_cycles: 0, 
             machine_cycles_delta: 0,
             halt_bug_active: false,
         }
     }
     
     /// Cycle/step the Gameboy CPU. Runs for a single instruction.
+    /// The devices in memory are stepped along with the CPU.
     /// 
-    /// Returns the total cycles taken (not machine cycles)
+    /// Returns the machine cycles taken
     pub fn cycle(&mut self, memory: &mut memory::Memory) -> u8 {
+        self.machine_cycles_delta = 0;
+        if self.stopped {
+            // STOP mode is left when a key in a selected column is pressed.
+            // The devices keep running, so that the frontend keeps receiving events
+            self.stopped = !memory.joypad.is_selected_key_pressed();
+            self.tick(memory);
+        }
         // Handle interrupts
-        if !self.handle_interrupts(memory) {
+        else if !self.handle_interrupts(memory) {
             if !self.halted {
                 // Fetch the instruction
                 let opcode = self.fetchbyte(memory);
@@ -52,7 +63,7 @@ impl CPU {
                 self.execute(opcode, memory);
             }
             else {
-                self.machine_cycles_delta = 1;
+                self.tick(memory);
             }
         }
 
@@ -67,12 +78,12 @@ impl CPU {
             self.halted = false;
         }
         if memory.interrupt_handler.is_interrupt_pending() {
-            // Push program counter to stack
+            // Two wait cycles, then push program counter to stack
+            self.tick(memory);
             self.push_stack(memory, self.regs.pc);
             // Disable interrupts
             memory.interrupt_handler.interrupt_master_enable = false;
             self.halted = false;
-            self.machine_cycles_delta = 4;
 
             let interrupt_type = memory.interrupt_handler.get_highest_priority_interrupt();
             // Jump to interrupt routine location
@@ -86,6 +97,8 @@ impl CPU {
             }
             memory.interrupt_handler.clear_interrupt(interrupt_type);
             memory.interrupt_handler.update_ime();
+            // Setting the program counter takes the last cycle
+            self.tick_until(memory, 5);
             return true;
         }  
         // Toggle IME a cycle after previous toggle instruction
@@ -106,7 +119,7 @@ impl CPU {
         let mut branch = false;
         match opcode {
             0x0 => {  } // NOP (No op)
-            0x10 => { } // STOP
+            0x10 => { self.op_stop(memory); } // STOP, switches CPU speed on CGB if requested through KEY1
             0x76 => { self.op_halt(memory); } // HALT
             0xCB => { let wide_op = self.fetchbyte(memory); self.execute_cb(wide_op, memory); return; } // Wide instructions prefix
 
@@ -122,35 +135,31 @@ impl CPU {
             0x31 => { self.regs.sp = self.fetchword(memory)} // LD SP d16
 
             // LD (Wide) A
-            0x02 => { memory.write_byte(self.regs.get_bc(), self.regs.a)} // LD (BC) A
-            0x12 => { memory.write_byte(self.regs.get_de(), self.regs.a);} // LD (DE) A
-            0x22 => { memory.write_byte(self.regs.get_hl(), self.regs.a); 
-                let v = self.regs.get_hl(); memory.write_byte(v, self.regs.a); self.regs.set_hl(v.wrapping_add(1));} // LD (HL+) A
-            0x32 => { memory.write_byte(self.regs.get_hl(), self.regs.a);
-                let v = self.regs.get_hl(); memory.write_byte(v, self.regs.a); self.regs.set_hl(v.wrapping_sub(1));} // LD (HL-) A
+            0x02 => { self.write_byte(memory, self.regs.get_bc(), self.regs.a)} // LD (BC) A
+            0x12 => { self.write_byte(memory, self.regs.get_de(), self.regs.a);} // LD (DE) A
+            0x22 => { let v = self.regs.get_hl(); self.write_byte(memory, v, self.regs.a); self.regs.set_hl(v.wrapping_add(1));} // LD (HL+) A
+            0x32 => { let v = self.regs.get_hl(); self.write_byte(memory, v, self.regs.a); self.regs.set_hl(v.wrapping_sub(1));} // LD (HL-) A
 
             // LD A (Wide)
-            0x0A => { self.regs.a = memory.read_byte(self.regs.get_bc())} // LD A (BC)
-            0x1A => { self.regs.a = memory.read_byte(self.regs.get_de())} // LD A (DE)
-            0x2A => { self.regs.a = memory.read_byte(self.regs.get_hl());
-                let v = self.regs.get_hl(); self.regs.a = memory.read_byte(v); self.regs.set_hl(v.wrapping_add(1))} // LD A (HL+)
-            0x3A => { self.regs.a = memory.read_byte(self.regs.get_hl());
-                let v = self.regs.get_hl(); self.regs.a = memory.read_byte(v); self.regs.set_hl(v.wrapping_sub(1))} // LD A (HL-)
+            0x0A => { self.regs.a = self.read_byte(memory, self.regs.get_bc())} // LD A (BC)
+            0x1A => { self.regs.a = self.read_byte(memory, self.regs.get_de())} // LD A (DE)
+            0x2A => { let v = self.regs.get_hl(); self.regs.a = self.read_byte(memory, v); self.regs.set_hl(v.wrapping_add(1))} // LD A (HL+)
+            0x3A => { let v = self.regs.get_hl(); self.regs.a = self.read_byte(memory, v); self.regs.set_hl(v.wrapping_sub(1))} // LD A (HL-)
 
             // LD (HL) B,C,D,E,H,L
-            0x70 => {let addr = self.regs.get_hl(); memory.write_byte(addr, self.regs.b)} // LD (HL) B
-            0x71 => {let addr = self.regs.get_hl(); memory.write_byte(addr, self.regs.c)} // LD (HL) C
-            0x72 => {let addr = self.regs.get_hl(); memory.write_byte(addr, self.regs.d)} // LD (HL) D
-            0x73 => {let addr = self.regs.get_hl(); memory.write_byte(addr, self.regs.e)} // LD (HL) E
-            0x74 => {let addr = self.regs.get_hl(); memory.write_byte(addr, self.regs.h)} // LD (HL) H
-            0x75 => {let addr = self.regs.get_hl(); memory.write_byte(addr, self.regs.l)} // LD (HL) L
-            0x77 => {let addr = self.regs.get_hl(); memory.write_byte(addr, self.regs.a)} // LD (HL) A
+            0x70 => {let addr = self.regs.get_hl(); self.write_byte(memory, addr, self.regs.b)} // LD (HL) B
+            0x71 => {let addr = self.regs.get_hl(); self.write_byte(memory, addr, self.regs.c)} // LD (HL) C
+            0x72 => {let addr = self.regs.get_hl(); self.write_byte(memory, addr, self.regs.d)} // LD (HL) D
+            0x73 => {let addr = self.regs.get_hl(); self.write_byte(memory, addr, self.regs.e)} // LD (HL) E
+            0x74 => {let addr = self.regs.get_hl(); self.write_byte(memory, addr, self.regs.h)} // LD (HL) H
+            0x75 => {let addr = self.regs.get_hl(); self.write_byte(memory, addr, self.regs.l)} // LD (HL) L
+            0x77 => {let addr = self.regs.get_hl(); self.write_byte(memory, addr, self.regs.a)} // LD (HL) A
 
             // LD B,D,H,(HL) d8
             0x06 => { self.regs.b = self.fetchbyte(memory)} // LD B d8
             0x16 => { self.regs.d = self.fetchbyte(memory)} // LD D d8
             0x26 => { self.regs.h = self.fetchbyte(memory)} // LD H d8
-            0x36 => { memory.write_byte(self.regs.get_hl(), self.fetchbyte(memory))} // LD (HL) d8
+            0x36 => { let v = self.fetchbyte(memory); self.write_byte(memory, self.regs.get_hl(), v)} // LD (HL) d8
 
             // LD C, E, L, A  d8
             0x0E => { self.regs.c = self.fetchbyte(memory)} // LD C d8
@@ -180,15 +189,15 @@ impl CPU {
             0x04 => {self.regs.b = self.op_inc(self.regs.b)} // INC B
             0x14 => {self.regs.d = self.op_inc(self.regs.d)} // INC D
             0x24 => {self.regs.h = self.op_inc(self.regs.h)} // INC H
-            0x34 => { let addr = self.regs.get_hl();
-                memory.write_byte(addr, self.op_inc(memory.read_byte(addr)))} // INC (HL)
+            0x34 => { let addr = self.regs.get_hl(); let v = self.read_byte(memory, addr);
+                let v = self.op_inc(v); self.write_byte(memory, addr, v)} // INC (HL)
 
             // Decrement B, D, H
             0x05 => { self.regs.b = self.op_dec(self.regs.b)} // DEC B
             0x15 => { self.regs.d = self.op_dec(self.regs.d)} // DEC D
             0x25 => { self.regs.h = self.op_dec(self.regs.h)} // DEC H
-            0x35 => { let addr = self.regs.get_hl(); 
-                memory.write_byte(addr, self.op_dec(memory.read_byte(addr)))} // DEC (HL)
+            0x35 => { let addr = self.regs.get_hl(); let v = self.read_byte(memory, addr);
+                let v = self.op_dec(v); self.write_byte(memory, addr, v)} // DEC (HL)
 
             // Set carry flag CF
             0x37 => {self.regs.set_carry_flag(true); self.regs.set_halfcarry_flag(false); self.regs.set_subtract_flag(false);} // SCF
@@ -200,7 +209,7 @@ impl CPU {
             0x1F => { self.regs.a = self.op_rr(self.regs.a); self.regs.set_zero_flag(false); } // RRA
 
             // Store stack pointer at address
-            0x08 => {memory.write_word(self.fetchword(memory), self.regs.sp)} // LD (a16), SP
+            0x08 => { let addr = self.fetchword(memory); self.write_word(memory, addr, self.regs.sp)} // LD (a16), SP
 
             // Increment C, E, L, A
             0x0C => { self.regs.c = self.op_inc(self.regs.c)} // INC C
@@ -230,7 +239,7 @@ impl CPU {
             0x43 => { self.regs.b = self.regs.e} // LD B E
             0x44 => { self.regs.b = self.regs.h} // LD B H
             0x45 => { self.regs.b = self.regs.l} // LD B L
-            0x46 => { self.regs.b = memory.read_byte(self.regs.get_hl())} // LD B (HL)
+            0x46 => { self.regs.b = self.read_byte(memory, self.regs.get_hl())} // LD B (HL)
             0x47 => { self.regs.b = self.regs.a} // LD B A
 
             // Load into C
@@ -240,7 +249,7 @@ impl CPU {
             0x4B => { self.regs.c = self.regs.e} // LD C E
             0x4C => { self.regs.c = self.regs.h} // LD C H
             0x4D => { self.regs.c = self.regs.l} // LD C L
-            0x4E => { self.regs.c = memory.read_byte(self.regs.get_hl())} // LD C (HL)
+            0x4E => { self.regs.c = self.read_byte(memory, self.regs.get_hl())} // LD C (HL)
             0x4F => { self.regs.c = self.regs.a} // LD C A
 
             // Load into D
@@ -250,7 +259,7 @@ impl CPU {
             0x53 => { self.regs.d = self.regs.e} // LD D E
             0x54 => { self.regs.d = self.regs.h} // LD D H
             0x55 => { self.regs.d = self.regs.l} // LD D L
-            0x56 => { self.regs.d = memory.read_byte(self.regs.get_hl())} // LD D (HL)
+            0x56 => { self.regs.d = self.read_byte(memory, self.regs.get_hl())} // LD D (HL)
             0x57 => { self.regs.d = self.regs.a} // LD D A
 
             // Load into E
@@ -260,7 +269,7 @@ impl CPU {
             0x5B => { } // LD E E (NOP)
             0x5C => { self.regs.e = self.regs.h} // LD E H
             0x5D => { self.regs.e = self.regs.l} // LD E L
-            0x5E => {self.regs.e = memory.read_byte(self.regs.get_hl())} // LD E (HL)
+            0x5E => {self.regs.e = self.read_byte(memory, self.regs.get_hl())} // LD E (HL)
             0x5F => { self.regs.e = self.regs.a} // LD E A
 
             // Load into H
@@ -270,7 +279,7 @@ impl CPU {
             0x63 => { self.regs.h = self.regs.e} // LD H E
             0x64 => { } // LD H H (NOP)
             0x65 => { self.regs.h = self.regs.l} // LD H L
-            0x66 => { self.regs.h = memory.read_byte(self.regs.get_hl())} // LD H (HL)
+            0x66 => { self.regs.h = self.read_byte(memory, self.regs.get_hl())} // LD H (HL)
             0x67 => { self.regs.h = self.regs.a} // LD H A
 
             // Load into L
@@ -280,7 +289,7 @@ impl CPU {
             0x6B => { self.regs.l = self.regs.e} // LD L E
             0x6C => { self.regs.l = self.regs.h} // LD L H
             0x6D => { } // LD L L (NOP)
-            0x6E => { self.regs.l = memory.read_byte(self.regs.get_hl())} // LD L (HL)
+            0x6E => { self.regs.l = self.read_byte(memory, self.regs.get_hl())} // LD L (HL)
             0x6F => { self.regs.l = self.regs.a} // LD L A
 
             // Load into A
@@ -290,7 +299,7 @@ impl CPU {
             0x7B => { self.regs.a = self.regs.e} // LD A E
             0x7C => { self.regs.a = self.regs.h} // LD A H
             0x7D => { self.regs.a = self.regs.l} // LD A L
-            0x7E => { self.regs.a = memory.read_byte(self.regs.get_hl())} // LD A (HL)
+            0x7E => { self.regs.a = self.read_byte(memory, self.regs.get_hl())} // LD A (HL)
             0x7F => { } // LD A A (NOOP)
 
             // Add instruction
@@ -300,7 +309,7 @@ impl CPU {
             0x83 => { self.op_add(self.regs.e, false); } // ADD E
             0x84 => { self.op_add(self.regs.h, false); } // ADD H
             0x85 => { self.op_add(self.regs.l, false); } // ADD L
-            0x86 => { let v = memory.read_byte(self.regs.get_hl()); self.op_add(v, false); } // ADD (HL)
+            0x86 => { let v = self.read_byte(memory, self.regs.get_hl()); self.op_add(v, false); } // ADD (HL)
             0x87 => { self.op_add(self.regs.a, false); } // ADD A
             0xC6 => { let v = self.fetchbyte(memory);  self.op_add(v, false)} // ADD A d8
 
@@ -311,7 +320,7 @@ impl CPU {
             0x8B => { self.op_add(self.regs.e, true); } // ADC E
             0x8C => { self.op_add(self.regs.h, true); } // ADC H
             0x8D => { self.op_add(self.regs.l, true); } // ADC L
-            0x8E => { let v = memory.read_byte(self.regs.get_hl()); self.op_add(v, true); } // ADC (HL)
+            0x8E => { let v = self.read_byte(memory, self.regs.get_hl()); self.op_add(v, true); } // ADC (HL)
             0x8F => { self.op_add(self.regs.a, true); } // ADC A
             0xCE => { let v = self.fetchbyte(memory); self.op_add(v, true)} // ADC A d8
 
@@ -322,7 +331,7 @@ impl CPU {
             0x93 => { self.op_sub(self.regs.e, false); } // SUB E
             0x94 => { self.op_sub(self.regs.h, false); } // SUB H
             0x95 => { self.op_sub(self.regs.l, false); } // SUB L
-            0x96 => { let v = memory.read_byte(self.regs.get_hl()); self.op_sub(v, false); } // SUB (HL)
+            0x96 => { let v = self.read_byte(memory, self.regs.get_hl()); self.op_sub(v, false); } // SUB (HL)
             0x97 => { self.op_sub(self.regs.a, false); } // SUB A
             0xD6 => { let v = self.fetchbyte(memory); self.op_sub(v, false) } // SUB d8
 
@@ -333,7 +342,7 @@ impl CPU {
             0x9B => { self.op_sub(self.regs.e, true); } // SBC E
             0x9C => { self.op_sub(self.regs.h, true); } // SBC H
             0x9D => { self.op_sub(self.regs.l, true); } // SBC L
-            0x9E => { let v = memory.read_byte(self.regs.get_hl()); self.op_sub(v, true); } // SUB (HL)
+            0x9E => { let v = self.read_byte(memory, self.regs.get_hl()); self.op_sub(v, true); } // SUB (HL)
             0x9F => { self.op_sub(self.regs.a, true); } // SBC A
             0xDE => { let v = self.fetchbyte(memory); self.op_sub(v, true) } // SBC d8
 
@@ -344,7 +353,7 @@ impl CPU {
             0xA3 => { self.op_and(self.regs.e); } // AND E
             0xA4 => { self.op_and(self.regs.h); } // AND H
             0xA5 => { self.op_and(self.regs.l); } // AND L
-            0xA6 => { let v = memory.read_byte(self.regs.get_hl()); self.op_and(v); } // AND (HL)
+            0xA6 => { let v = self.read_byte(memory, self.regs.get_hl()); self.op_and(v); } // AND (HL)
             0xA7 => { self.op_and(self.regs.a);} // AND A
             0xE6 => { let v = self.fetchbyte(memory); self.op_and(v)} // AND d8
 
@@ -355,7 +364,7 @@ impl CPU {
             0xAB => { self.op_xor(self.regs.e); } // XOR E
             0xAC => { self.op_xor(self.regs.h); } // XOR H
             0xAD => { self.op_xor(self.regs.l); } // XOR L
-            0xAE => { let v = memory.read_byte(self.regs.get_hl()); self.op_xor(v); } // XOR (HL)
+            0xAE => { let v = self.read_byte(memory, self.regs.get_hl()); self.op_xor(v); } // XOR (HL)
             0xAF => { self.op_xor(self.regs.a) } // XOR A
             0xEE => { let v = self.fetchbyte(memory); self.op_xor(v)} // XOR d8
 
@@ -366,7 +375,7 @@ impl CPU {
             0xB3 => { self.op_or(self.regs.e); } // OR E
             0xB4 => { self.op_or(self.regs.h); } // OR H
             0xB5 => { self.op_or(self.regs.l); } // OR L
-            0xB6 => { let v = memory.read_byte(self.regs.get_hl()); self.op_or(v); } // OR (HL)
+            0xB6 => { let v = self.read_byte(memory, self.regs.get_hl()); self.op_or(v); } // OR (HL)
             0xB7 => { self.op_or(self.regs.a) } // OR A
             0xF6 => { let v = self.fetchbyte(memory); self.op_or(v)} // OR d8
 
@@ -377,26 +386,26 @@ impl CPU {
             0xBB => { self.op_cp(self.regs.e); } // CP E
             0xBC => { self.op_cp(self.regs.h); } // CP H
             0xBD => { self.op_cp(self.regs.l); } // CP L
-            0xBE => { let v = memory.read_byte(self.regs.get_hl()); self.op_cp(v); } // CP (HL)
+            0xBE => { let v = self.read_byte(memory, self.regs.get_hl()); self.op_cp(v); } // CP (HL)
             0xBF => { self.op_cp(self.regs.a); } // CP A, (A == A)
             0xFE => { let v = self.fetchbyte(memory); 
                 self.op_cp(v); } // CP A d8
 
             // LD (8 bit, high ram)
-            0xE0 => { memory.write_byte(0xFF00 | self.fetchbyte(memory) as u16, self.regs.a) } // LD (a8) A
+            0xE0 => { let addr = 0xFF00 | self.fetchbyte(memory) as u16; self.write_byte(memory, addr, self.regs.a) } // LD (a8) A
             0xF0 => { 
                 let addr = 0xFF00 | self.fetchbyte(memory) as u16;
-                self.regs.a = memory.read_byte(addr) } // LD A (a8)
+                self.regs.a = self.read_byte(memory, addr) } // LD A (a8)
 
             // LD (C, high ram)
-            0xE2 => { memory.write_byte(0xFF00 | self.regs.c as u16, self.regs.a) } // LD (C) A
-            0xF2 => { self.regs.a = memory.read_byte(0xFF00 | self.regs.c as u16) } // LD A (C)            
+            0xE2 => { self.write_byte(memory, 0xFF00 | self.regs.c as u16, self.regs.a) } // LD (C) A
+            0xF2 => { self.regs.a = self.read_byte(memory, 0xFF00 | self.regs.c as u16) } // LD A (C)            
 
             // LD A 
             0xEA => { 
                 let addr = self.fetchword(memory); 
-                memory.write_byte(addr, self.regs.a)} // LD (a16) A
-            0xFA => { self.regs.a = memory.read_byte(self.fetchword(memory))} // LD A (a16)
+                self.write_byte(memory, addr, self.regs.a)} // LD (a16) A
+            0xFA => { let addr = self.fetchword(memory); self.regs.a = self.read_byte(memory, addr)} // LD A (a16)
 
             // Other instructions
             0xF9 => { self.regs.sp = self.regs.get_hl()} // LD HL SP
@@ -429,10 +438,10 @@ impl CPU {
             0xCD => { self.op_call(memory); } // CALL a16
 
             // Ret
-            0xC0 => { if !self.regs.get_zero_flag() { self.op_ret(memory); branch = true; }} // RET NZ
-            0xD0 => { if !self.regs.get_carry_flag() { self.op_ret(memory); branch = true; }} // RET NC
-            0xC8 => { if self.regs.get_zero_flag() { self.op_ret(memory); branch = true; }} // RET Z
-            0xD8 => { if self.regs.get_carry_flag() { self.op_ret(memory); branch = true; }} // RET C
+            0xC0 => { self.tick(memory); if !self.regs.get_zero_flag() { self.op_ret(memory); branch = true; }} // RET NZ
+            0xD0 => { self.tick(memory); if !self.regs.get_carry_flag() { self.op_ret(memory); branch = true; }} // RET NC
+            0xC8 => { self.tick(memory); if self.regs.get_zero_flag() { self.op_ret(memory); branch = true; }} // RET Z
+            0xD8 => { self.tick(memory); if self.regs.get_carry_flag() { self.op_ret(memory); branch = true; }} // RET C
             0xC9 => { self.op_ret(memory);} // RET
             0xD9 => { self.op_ret(memory); memory.interrupt_handler.interrupt_master_enable = true;} // RETI
 
@@ -463,7 +472,8 @@ impl CPU {
 
             other => panic!("Instruction {:2X} is not implemented", other)
           }
-          self.machine_cycles_delta = cycle_timings::get_machine_cycles_for_op(opcode, branch, false);
+          // Internal cycles at the end of the instruction
+          self.tick_until(memory, cycle_timings::get_machine_cycles_for_op(opcode, branch, false));
     }
 
     /// Execute the extra 256 instructions, which are preceeded by the CB instruction
@@ -477,8 +487,8 @@ impl CPU {
             0x04 => { self.regs.h = self.op_rlc(self.regs.h)} // RLC H
             0x05 => { self.regs.l = self.op_rlc(self.regs.l)} // RLC L
             0x06 => { // RLC (HL)
-                let val = self.op_rlc(memory.read_byte(self.regs.get_hl())); 
-                memory.write_byte(self.regs.get_hl(), val)} 
+                let val = self.read_byte(memory, self.regs.get_hl()); let val = self.op_rlc(val); 
+                self.write_byte(memory, self.regs.get_hl(), val)} 
             0x07 => { self.regs.a = self.op_rlc(self.regs.a)} // RLC A
 
             // RRC
@@ -489,8 +499,8 @@ impl CPU {
             0x0C => { self.regs.h = self.op_rrc(self.regs.h)} // RRC H
             0x0D => { self.regs.l = self.op_rrc(self.regs.l)} // RRC L
             0x0E => { // RRC (HL)
-                let val = self.op_rrc(memory.read_byte(self.regs.get_hl())); 
-                memory.write_byte(self.regs.get_hl(), val)} 
+                let val = self.read_byte(memory, self.regs.get_hl()); let val = self.op_rrc(val); 
+                self.write_byte(memory, self.regs.get_hl(), val)} 
             0x0F => { self.regs.a = self.op_rrc(self.regs.a)} // RRC A
 
             // RL
@@ -501,8 +511,8 @@ impl CPU {
             0x14 => { self.regs.h = self.op_rl(self.regs.h)} // RL H
             0x15 => { self.regs.l = self.op_rl(self.regs.l)} // RL L
             0x16 => { // RL (HL)
-                let val = self.op_rl(memory.read_byte(self.regs.get_hl())); 
-                memory.write_byte(self.regs.get_hl(), val)} 
+                let val = self.read_byte(memory, self.regs.get_hl()); let val = self.op_rl(val); 
+                self.write_byte(memory, self.regs.get_hl(), val)} 
             0x17 => { self.regs.a = self.op_rl(self.regs.a)} // RL A
 
             // RR
@@ -513,8 +523,8 @@ impl CPU {
             0x1C => { self.regs.h = self.op_rr(self.regs.h)} // RR H
             0x1D => { self.regs.l = self.op_rr(self.regs.l)} // RR L
             0x1E => { // RR (HL)
-                let val = self.op_rr(memory.read_byte(self.regs.get_hl())); 
-                memory.write_byte(self.regs.get_hl(), val)} 
+                let val = self.read_byte(memory, self.regs.get_hl()); let val = self.op_rr(val); 
+                self.write_byte(memory, self.regs.get_hl(), val)} 
             0x1F => { self.regs.a = self.op_rr(self.regs.a)} // RR A
 
             // SLA
@@ -525,8 +535,8 @@ impl CPU {
             0x24 => { self.regs.h = self.op_sla(self.regs.h)} // SLA H
             0x25 => { self.regs.l = self.op_sla(self.regs.l)} // SLA L
             0x26 => { // SLA (HL)
-                let val = self.op_sla(memory.read_byte(self.regs.get_hl())); 
-                memory.write_byte(self.regs.get_hl(), val)} 
+                let val = self.read_byte(memory, self.regs.get_hl()); let val = self.op_sla(val); 
+                self.write_byte(memory, self.regs.get_hl(), val)} 
             0x27 => { self.regs.a = self.op_sla(self.regs.a)} // SLA A
 
             // SRA
@@ -537,8 +547,8 @@ impl CPU {
             0x2C => { self.regs.h = self.op_sra(self.regs.h)} // SRA H
             0x2D => { self.regs.l = self.op_sra(self.regs.l)} // SRA L
             0x2E => { // SRA (HL)
-                let val = self.op_sra(memory.read_byte(self.regs.get_hl())); 
-                memory.write_byte(self.regs.get_hl(), val)} 
+                let val = self.read_byte(memory, self.regs.get_hl()); let val = self.op_sra(val); 
+                self.write_byte(memory, self.regs.get_hl(), val)} 
             0x2F => { self.regs.a = self.op_sra(self.regs.a)} // SRA A
             
 
@@ -550,8 +560,8 @@ impl CPU {
             0x34 => { self.regs.h = self.op_swap(self.regs.h); } // SWAP H
             0x35 => { self.regs.l = self.op_swap(self.regs.l); } // SWAP L
             0x36 => { // SWAP (HL)
-                let val = memory.read_byte(self.regs.get_hl()); 
-                memory.write_byte(self.regs.get_hl(), self.op_swap(val));
+                let val = self.read_byte(memory, self.regs.get_hl()); 
+                let val = self.op_swap(val); self.write_byte(memory, self.regs.get_hl(), val);
             }
             0x37 => { self.regs.a = self.op_swap(self.regs.a);} // SWAP A
 
@@ -563,8 +573,8 @@ impl CPU {
             0x3C => { self.regs.h = self.op_srl(self.regs.h)} // SRL H
             0x3D => { self.regs.l = self.op_srl(self.regs.l)} // SRL L
             0x3E => { // SRL (HL)
-                let val = self.op_srl(memory.read_byte(self.regs.get_hl())); 
-                memory.write_byte(self.regs.get_hl(), val)} 
+                let val = self.read_byte(memory, self.regs.get_hl()); let val = self.op_srl(val); 
+                self.write_byte(memory, self.regs.get_hl(), val)} 
             0x3F => { self.regs.a = self.op_srl(self.regs.a)} // SRL A
 
             // BIT 0
@@ -575,7 +585,7 @@ impl CPU {
             0x44 => { self.op_read_bit(self.regs.h, 0b0000_0001); } // BIT 0 H
             0x45 => { self.op_read_bit(self.regs.l, 0b0000_0001); } // BIT 0 L
             0x46 => { // BIT 0 (HL)
-                let val = memory.read_byte(self.regs.get_hl());
+                let val = self.read_byte(memory, self.regs.get_hl());
                 self.op_read_bit(val, 0b0000_0001); }
             0x47 => { self.op_read_bit(self.regs.a, 0b0000_0001); } // BIT 0 A
 
@@ -587,7 +597,7 @@ impl CPU {
             0x4C => { self.op_read_bit(self.regs.h, 0b0000_0010); } // BIT 1 H
             0x4D => { self.op_read_bit(self.regs.l, 0b0000_0010); } // BIT 1 L
             0x4E => { // BIT 1 (HL)
-                let val = memory.read_byte(self.regs.get_hl());
+                let val = self.read_byte(memory, self.regs.get_hl());
                 self.op_read_bit(val, 0b0000_0010); }
             0x4F => { self.op_read_bit(self.regs.a, 0b0000_0010); } // BIT 1 A
 
@@ -599,7 +609,7 @@ impl CPU {
             0x54 => { self.op_read_bit(self.regs.h, 0b0000_0100); } // BIT 2 H
             0x55 => { self.op_read_bit(self.regs.l, 0b0000_0100); } // BIT 2 L
             0x56 => { // BIT 2 (HL)
-                let val = memory.read_byte(self.regs.get_hl());
+                let val = self.read_byte(memory, self.regs.get_hl());
                 self.op_read_bit(val, 0b0000_0100); }
             0x57 => { self.op_read_bit(self.regs.a, 0b0000_0100); } // BIT 2 A
 
@@ -611,7 +621,7 @@ impl CPU {
             0x5C => { self.op_read_bit(self.regs.h, 0b0000_1000); } // BIT 3 H
             0x5D => { self.op_read_bit(self.regs.l, 0b0000_1000); } // BIT 3 L
             0x5E => { // BIT 3 (HL)
-                let val = memory.read_byte(self.regs.get_hl());
+                let val = self.read_byte(memory, self.regs.get_hl());
                 self.op_read_bit(val, 0b0000_1000); }
             0x5F => { self.op_read_bit(self.regs.a, 0b0000_1000); } // BIT 3 A
 
@@ -623,7 +633,7 @@ impl CPU {
             0x64 => { self.op_read_bit(self.regs.h, 0b0001_0000); } // BIT 4 H
             0x65 => { self.op_read_bit(self.regs.l, 0b0001_0000); } // BIT 4 L
             0x66 => { // BIT 4 (HL)
-                let val = memory.read_byte(self.regs.get_hl());
+                let val = self.read_byte(memory, self.regs.get_hl());
                 self.op_read_bit(val, 0b0001_0000); }
             0x67 => { self.op_read_bit(self.regs.a, 0b0001_0000); } // BIT 4 A
 
@@ -635,7 +645,7 @@ impl CPU {
             0x6C => { self.op_read_bit(self.regs.h, 0b0010_0000); } // BIT 5 H
             0x6D => { self.op_read_bit(self.regs.l, 0b0010_0000); } // BIT 5 L
             0x6E => { // BIT 5 (HL)
-                let val = memory.read_byte(self.regs.get_hl());
+                let val = self.read_byte(memory, self.regs.get_hl());
                 self.op_read_bit(val, 0b0010_0000); }
             0x6F => { self.op_read_bit(self.regs.a, 0b0010_0000); } // BIT 5 A
 
@@ -648,7 +658,7 @@ impl CPU {
             0x74 => { self.op_read_bit(self.regs.h, 0b0100_0000); } // BIT 6 H
             0x75 => { self.op_read_bit(self.regs.l, 0b0100_0000); } // BIT 6 L
             0x76 => { // BIT 6 (HL)
-                let val = memory.read_byte(self.regs.get_hl());
+                let val = self.read_byte(memory, self.regs.get_hl());
                 self.op_read_bit(val, 0b0100_0000); }
             0x77 => { self.op_read_bit(self.regs.a, 0b0100_0000); } // BIT 6 A
 
@@ -660,7 +670,7 @@ impl CPU {
             0x7C => { self.op_read_bit(self.regs.h, 0b1000_0000); } // BIT 7 H
             0x7D => { self.op_read_bit(self.regs.l, 0b1000_0000); } // BIT 7 L
             0x7E => { // BIT 7 (HL)
-                let val = memory.read_byte(self.regs.get_hl());
+                let val = self.read_byte(memory, self.regs.get_hl());
                 self.op_read_bit(val, 0b1000_0000); }
             0x7F => { self.op_read_bit(self.regs.a, 0b1000_0000); } // BIT 7 A
 
@@ -673,8 +683,8 @@ impl CPU {
             0x84 => { self.regs.h = self.op_reset_bit(self.regs.h, 0b0000_0001); } // RES 0 H
             0x85 => { self.regs.l = self.op_reset_bit(self.regs.l, 0b0000_0001); } // RES 0 L
             0x86 => { // RES 0 (HL)
-                let val = memory.read_byte(self.regs.get_hl());
-                memory.write_byte(self.regs.get_hl(), self.op_reset_bit(val, 0b0000_0001)); }
+                let val = self.read_byte(memory, self.regs.get_hl());
+                let val = self.op_reset_bit(val, 0b0000_0001); self.write_byte(memory, self.regs.get_hl(), val); }
             0x87 => { self.regs.a = self.op_reset_bit(self.regs.a, 0b0000_0001); } // RES 0 A
 
             // RES 1
@@ -685,8 +695,8 @@ impl CPU {
             0x8C => { self.regs.h = self.op_reset_bit(self.regs.h, 0b0000_0010); } // RES 1 H
             0x8D => { self.regs.l = self.op_reset_bit(self.regs.l, 0b0000_0010); } // RES 1 L
             0x8E => { // RES 1 (HL)
-                let val = memory.read_byte(self.regs.get_hl());
-                memory.write_byte(self.regs.get_hl(), self.op_reset_bit(val, 0b0000_0010)); }
+                let val = self.read_byte(memory, self.regs.get_hl());
+                let val = self.op_reset_bit(val, 0b0000_0010); self.write_byte(memory, self.regs.get_hl(), val); }
             0x8F => { self.regs.a = self.op_reset_bit(self.regs.a, 0b0000_0010); } // RES 1 A
 
             // ERS 2
@@ -697,8 +707,8 @@ impl CPU {
             0x94 => { self.regs.h = self.op_reset_bit(self.regs.h, 0b0000_0100); } // RES 2 H
             0x95 => { self.regs.l = self.op_reset_bit(self.regs.l, 0b0000_0100); } // RES 2 L
             0x96 => { // RES 2 (HL)
-                let val = memory.read_byte(self.regs.get_hl());
-                memory.write_byte(self.regs.get_hl(), self.op_reset_bit(val, 0b0000_0100)); }
+                let val = self.read_byte(memory, self.regs.get_hl());
+                let val = self.op_reset_bit(val, 0b0000_0100); self.write_byte(memory, self.regs.get_hl(), val); }
             0x97 => { self.regs.a = self.op_reset_bit(self.regs.a, 0b0000_0100); } // RES 2 A
 
             // RES 3
@@ -709,8 +719,8 @@ impl CPU {
             0x9C => { self.regs.h = self.op_reset_bit(self.regs.h, 0b0000_1000); } // RES 3 H
             0x9D => { self.regs.l = self.op_reset_bit(self.regs.l, 0b0000_1000); } // RES 3 L
             0x9E => { // RES 3 (HL)
-                let val = memory.read_byte(self.regs.get_hl());
-                memory.write_byte(self.regs.get_hl(), self.op_reset_bit(val, 0b0000_1000)); }
+                let val = self.read_byte(memory, self.regs.get_hl());
+                let val = self.op_reset_bit(val, 0b0000_1000); self.write_byte(memory, self.regs.get_hl(), val); }
             0x9F => { self.regs.a = self.op_reset_bit(self.regs.a, 0b0000_1000); } // RES 3 A
 
             // RES 4
@@ -721,8 +731,8 @@ impl CPU {
             0xA4 => { self.regs.h = self.op_reset_bit(self.regs.h, 0b0001_0000); } // RES 4 H
             0xA5 => { self.regs.l = self.op_reset_bit(self.regs.l, 0b0001_0000); } // RES 4 L
             0xA6 => { // RES 4 (HL)
-                let val = memory.read_byte(self.regs.get_hl());
-                memory.write_byte(self.regs.get_hl(), self.op_reset_bit(val, 0b0001_0000)); }
+                let val = self.read_byte(memory, self.regs.get_hl());
+                let val = self.op_reset_bit(val, 0b0001_0000); self.write_byte(memory, self.regs.get_hl(), val); }
             0xA7 => { self.regs.a = self.op_reset_bit(self.regs.a, 0b0001_0000); } // RES 4 A
 
             // RES 5
@@ -733,8 +743,8 @@ impl CPU {
             0xAC => { self.regs.h = self.op_reset_bit(self.regs.h, 0b0010_0000); } // RES 5 H
             0xAD => { self.regs.l = self.op_reset_bit(self.regs.l, 0b0010_0000); } // RES 5 L
             0xAE => { // RES 5 (HL)
-                let val = memory.read_byte(self.regs.get_hl());
-                memory.write_byte(self.regs.get_hl(), self.op_reset_bit(val, 0b0010_0000)); }
+                let val = self.read_byte(memory, self.regs.get_hl());
+                let val = self.op_reset_bit(val, 0b0010_0000); self.write_byte(memory, self.regs.get_hl(), val); }
             0xAF => { self.regs.a = self.op_reset_bit(self.regs.a, 0b0010_0000); } // RES 5 A
 
             // RES 6
@@ -745,8 +755,8 @@ impl CPU {
             0xB4 => { self.regs.h = self.op_reset_bit(self.regs.h, 0b0100_0000); } // RES 6 H
             0xB5 => { self.regs.l = self.op_reset_bit(self.regs.l, 0b0100_0000); } // RES 6 L
             0xB6 => { // RES 6 (HL)
-                let val = memory.read_byte(self.regs.get_hl());
-                memory.write_byte(self.regs.get_hl(), self.op_reset_bit(val, 0b0100_0000)); }
+                let val = self.read_byte(memory, self.regs.get_hl());
+                let val = self.op_reset_bit(val, 0b0100_0000); self.write_byte(memory, self.regs.get_hl(), val); }
             0xB7 => { self.regs.a = self.op_reset_bit(self.regs.a, 0b0100_0000); } // RES 6 A
 
             // RES 7
@@ -757,8 +767,8 @@ impl CPU {
             0xBC => { self.regs.h = self.op_reset_bit(self.regs.h, 0b1000_0000); } // RES 7 H
             0xBD => { self.regs.l = self.op_reset_bit(self.regs.l, 0b1000_0000); } // RES 7 L
             0xBE => { // RES 7 (HL)
-                let val = memory.read_byte(self.regs.get_hl());
-                memory.write_byte(self.regs.get_hl(), self.op_reset_bit(val, 0b1000_0000)); }
+                let val = self.read_byte(memory, self.regs.get_hl());
+                let val = self.op_reset_bit(val, 0b1000_0000); self.write_byte(memory, self.regs.get_hl(), val); }
             0xBF => { self.regs.a = self.op_reset_bit(self.regs.a, 0b1000_0000); } // RES 7 A
 
             // Set bit N to 1
@@ -770,8 +780,8 @@ impl CPU {
             0xC4 => { self.regs.h = self.op_set_bit(self.regs.h, 0b0000_0001); } // SET 0 H
             0xC5 => { self.regs.l = self.op_set_bit(self.regs.l, 0b0000_0001); } // SET 0 L
             0xC6 => { // SET 0 (HL)
-                let val = memory.read_byte(self.regs.get_hl());
-                memory.write_byte(self.regs.get_hl(), self.op_set_bit(val, 0b0000_0001)); }
+                let val = self.read_byte(memory, self.regs.get_hl());
+                let val = self.op_set_bit(val, 0b0000_0001); self.write_byte(memory, self.regs.get_hl(), val); }
             0xC7 => { self.regs.a = self.op_set_bit(self.regs.a, 0b0000_0001); } // SET 0 A
 
             // SET 1
@@ -782,8 +792,8 @@ impl CPU {
             0xCC => { self.regs.h = self.op_set_bit(self.regs.h, 0b0000_0010); } // SET 1 H
             0xCD => { self.regs.l = self.op_set_bit(self.regs.l, 0b0000_0010); } // SET 1 L
             0xCE => { // SET 1 (HL)
-                let val = memory.read_byte(self.regs.get_hl());
-                memory.write_byte(self.regs.get_hl(), self.op_set_bit(val, 0b0000_0010)); }
+                let val = self.read_byte(memory, self.regs.get_hl());
+                let val = self.op_set_bit(val, 0b0000_0010); self.write_byte(memory, self.regs.get_hl(), val); }
             0xCF => { self.regs.a = self.op_set_bit(self.regs.a, 0b0000_0010); } // SET 1 A
 
             // SET 2
@@ -794,8 +804,8 @@ impl CPU {
             0xD4 => { self.regs.h = self.op_set_bit(self.regs.h, 0b0000_0100); } // SET 2 H
             0xD5 => { self.regs.l = self.op_set_bit(self.regs.l, 0b0000_0100); } // SET 2 L
             0xD6 => { // SET 2 (HL)
-                let val = memory.read_byte(self.regs.get_hl());
-                memory.write_byte(self.regs.get_hl(), self.op_set_bit(val, 0b0000_0100)); }
+                let val = self.read_byte(memory, self.regs.get_hl());
+                let val = self.op_set_bit(val, 0b0000_0100); self.write_byte(memory, self.regs.get_hl(), val); }
             0xD7 => { self.regs.a = self.op_set_bit(self.regs.a, 0b0000_0100); } // SET 2 A
 
             // SET 3
@@ -806,8 +816,8 @@ impl CPU {
             0xDC => { self.regs.h = self.op_set_bit(self.regs.h, 0b0000_1000); } // SET 3 H
             0xDD => { self.regs.l = self.op_set_bit(self.regs.l, 0b0000_1000); } // SET 3 L
             0xDE => { // SET 3 (HL)
-                let val = memory.read_byte(self.regs.get_hl());
-                memory.write_byte(self.regs.get_hl(), self.op_set_bit(val, 0b0000_1000)); }
+                let val = self.read_byte(memory, self.regs.get_hl());
+                let val = self.op_set_bit(val, 0b0000_1000); self.write_byte(memory, self.regs.get_hl(), val); }
             0xDF => { self.regs.a = self.op_set_bit(self.regs.a, 0b0000_1000); } // SET 3 A
 
             // SET 4
@@ -818,8 +828,8 @@ impl CPU {
             0xE4 => { self.regs.h = self.op_set_bit(self.regs.h, 0b0001_0000); } // SET 4 H
             0xE5 => { self.regs.l = self.op_set_bit(self.regs.l, 0b0001_0000); } // SET 4 L
             0xE6 => { // SET 4 (HL)
-                let val = memory.read_byte(self.regs.get_hl());
-                memory.write_byte(self.regs.get_hl(), self.op_set_bit(val, 0b0001_0000)); }
+                let val = self.read_byte(memory, self.regs.get_hl());
+                let val = self.op_set_bit(val, 0b0001_0000); self.write_byte(memory, self.regs.get_hl(), val); }
             0xE7 => { self.regs.a = self.op_set_bit(self.regs.a, 0b0001_0000); } // SET 4 A
 
             // SET 5
@@ -830,8 +840,8 @@ impl CPU {
             0xEC => { self.regs.h = self.op_set_bit(self.regs.h, 0b0010_0000); } // SET 5 H
             0xED => { self.regs.l = self.op_set_bit(self.regs.l, 0b0010_0000); } // SET 5 L
             0xEE => { // SET 5 (HL)
-                let val = memory.read_byte(self.regs.get_hl());
-                memory.write_byte(self.regs.get_hl(), self.op_set_bit(val, 0b0010_0000)); }
+                let val = self.read_byte(memory, self.regs.get_hl());
+                let val = self.op_set_bit(val, 0b0010_0000); self.write_byte(memory, self.regs.get_hl(), val); }
             0xEF => { self.regs.a = self.op_set_bit(self.regs.a, 0b0010_0000); } // SET 5 A
 
             // SET 6
@@ -842,8 +852,8 @@ impl CPU {
             0xF4 => { self.regs.h = self.op_set_bit(self.regs.h, 0b0100_0000); } // SET 6 H
             0xF5 => { self.regs.l = self.op_set_bit(self.regs.l, 0b0100_0000); } // SET 6 L
             0xF6 => { // SET 6 (HL)
-                let val = memory.read_byte(self.regs.get_hl());
-                memory.write_byte(self.regs.get_hl(), self.op_set_bit(val, 0b0100_0000)); }
+                let val = self.read_byte(memory, self.regs.get_hl());
+                let val = self.op_set_bit(val, 0b0100_0000); self.write_byte(memory, self.regs.get_hl(), val); }
             0xF7 => { self.regs.a = self.op_set_bit(self.regs.a, 0b0100_0000); } // SET 6 A
 
             // SET 7
@@ -854,63 +864,103 @@ impl CPU {
             0xFC => { self.regs.h = self.op_set_bit(self.regs.h, 0b1000_0000); } // SET 7 H
             0xFD => { self.regs.l = self.op_set_bit(self.regs.l, 0b1000_0000); } // SET 7 L
             0xFE => { // SET 7 (HL)
-                let val = memory.read_byte(self.regs.get_hl());
-                memory.write_byte(self.regs.get_hl(), self.op_set_bit(val, 0b1000_0000)); }
+                let val = self.read_byte(memory, self.regs.get_hl());
+                let val = self.op_set_bit(val, 0b1000_0000); self.write_byte(memory, self.regs.get_hl(), val); }
             0xFF => { self.regs.a = self.op_set_bit(self.regs.a, 0b1000_0000); } // SET 7 A
         }
-        self.machine_cycles_delta = cycle_timings::get_machine_cycles_for_op(opcode, false, true);
+        self.tick_until(memory, cycle_timings::get_machine_cycles_for_op(opcode, false, true));
+    }
+
+    /// Step the devices in memory by one machine cycle
+    fn tick(&mut self, memory: &mut memory::Memory) {
+        memory.cycle_devices(1);
+        self.machine_cycles_delta += 1;
+    }
+
+    /// Step the devices until the current instruction has taken `machine_cycles`.
+    /// Used for the internal cycles which do not access memory
+    fn tick_until(&mut self, memory: &mut memory::Memory, machine_cycles: u8) {
+        while self.machine_cycles_delta < machine_cycles {
+            self.tick(memory);
+        }
+    }
+
+    /// Read a byte from memory, taking one machine cycle
+    fn read_byte(&mut self, memory: &mut memory::Memory, address: u16) -> u8 {
+        self.tick(memory);
+        return memory.read_byte(address);
+    }
+
+    /// Write a byte to memory, taking one machine cycle
+    fn write_byte(&mut self, memory: &mut memory::Memory, address: u16, value: u8) {
+        self.tick(memory);
+        memory.write_byte(address, value);
+    }
+
+    /// Write a word to memory, low byte first. Takes two machine cycles
+    fn write_word(&mut self, memory: &mut memory::Memory, address: u16, value: u16) {
+        self.write_byte(memory, address, value as u8);
+        self.write_byte(memory, address.wrapping_add(1), (value >> 8) as u8);
     }
 
     /// Fetch the byte from memory specified by the pc, and increment pc
     /// Generally used to get the next instruction to execute
-    pub fn fetchbyte(&mut self, memory: &memory::Memory) -> u8 
+    pub fn fetchbyte(&mut self, memory: &mut memory::Memory) -> u8 
     {
-        let byte = memory.read_byte(self.regs.pc);
-        self.regs.pc += 1;
+        let byte = self.read_byte(memory, self.regs.pc);
+        self.regs.pc = self.regs.pc.wrapping_add(1);
         return byte;
     }
 
     /// Fetch the word from memory specified by the pc, and increment pc
-    fn fetchword(&mut self, memory: &memory::Memory) -> u16
+    fn fetchword(&mut self, memory: &mut memory::Memory) -> u16
     {
-        let word = memory.read_word(self.regs.pc);
-        self.regs.pc += 2;
-        return word;
+        let low = self.fetchbyte(memory) as u16;
+        let high = self.fetchbyte(memory) as u16;
+        return high << 8 | low;
     }
 
     // Instruction/opcode helpers below
 
     /// JR Instruction helper, make a relative (signed) jump from imm s8
-    fn op_jump_relative(&mut self, memory: &memory::Memory) {
+    fn op_jump_relative(&mut self, memory: &mut memory::Memory) {
         let offset = (self.fetchbyte(memory) as i8) as i32;
         self.regs.pc = ((self.regs.pc as u32) as i32 + offset) as u16;
     }
 
     /// JP Instruction helper, make an absolute jump from imm d16
-    fn op_jump(&mut self, memory: &memory::Memory) {
+    fn op_jump(&mut self, memory: &mut memory::Memory) {
         self.regs.pc = self.fetchword(memory);
     }
 
-    /// Push value to stack, and adjust stack pointer
+    /// Push value to stack, and adjust stack pointer.
+    /// Takes an internal cycle followed by a write of the high and the low byte
     fn push_stack(&mut self, memory: &mut memory::Memory, reg : u16) {
-        self.regs.sp -= 2;
-        memory.write_word(self.regs.sp, reg);
+        self.tick(memory);
+        self.regs.sp = self.regs.sp.wrapping_sub(1);
+        self.write_byte(memory, self.regs.sp, (reg >> 8) as u8);
+        self.regs.sp = self.regs.sp.wrapping_sub(1);
+        self.write_byte(memory, self.regs.sp, reg as u8);
     } 
 
     /// Pop value from stack, and adjust stack pointer
-    fn pop_stack(&mut self, memory: &memory::Memory) -> u16 {
-        let v = memory.read_word(self.regs.sp);
-        self.regs.sp += 2;
-        return v;
+    fn pop_stack(&mut self, memory: &mut memory::Memory) -> u16 {
+        let low = self.read_byte(memory, self.regs.sp) as u16;
+        self.regs.sp = self.regs.sp.wrapping_add(1);
+        let high = self.read_byte(memory, self.regs.sp) as u16;
+        self.regs.sp = self.regs.sp.wrapping_add(1);
+        return high << 8 | low;
     }
 
     /// Push the program counter and move the program counter to the specified imm d16
     fn op_call(&mut self, memory: &mut memory::Memory) {
-        self.push_stack(memory, self.regs.pc + 2); self.regs.pc = self.fetchword(memory);
+        let addr = self.fetchword(memory);
+        self.push_stack(memory, self.regs.pc); 
+        self.regs.pc = addr;
     }
 
     /// Pop the program counter and return there
-    fn op_ret(&mut self, memory : &memory::Memory) {
+    fn op_ret(&mut self, memory : &mut memory::Memory) {
         self.regs.pc = self.pop_stack(memory);
     }
 
@@ -1154,6 +1204,16 @@ impl CPU {
         return value | bitmask;
     }
 
+    /// STOP, switches CPU speed on CGB if requested through KEY1.
+    /// Otherwise the CPU sleeps until a key is pressed
+    pub fn op_stop(&mut self, memory: &mut memory::Memory) {
+        if !memory.try_speed_switch() {
+            self.stopped = true;
+            // STOP resets DIV
+            memory.timer.write_byte(0xFF04, 0);
+        }
+    }
+
     pub fn op_halt(&mut self, memory: &mut memory::Memory) {
         /*if memory.interrupt_handler.interrupt_master_enable {
             self.halted = true;
@@ -1192,15 +1252,14 @@ mod test
         const EXPECTED_OUTPUT : &str = "cpu_instrs\n\n01:ok  02:ok  03:ok  04:ok  05:ok  06:ok  07:ok  08:ok  09:ok  10:ok  11:ok  \n\nPassed all tests\n";
         let mut memory = Memory::new();
         let mut cpu = CPU::new();
-        memory.rom.load_from_file("../roms/blargg/cpu_instrs.gb");
-        memory.output_serial_to_stdout = true;
+        memory.rom.load_from_file("../roms/blargg/cpu_instrs.gb").unwrap();
+        memory.serial.capture.output_to_stdout = true;
 
         for _i in 0..30000000 {
-            let machine_cycles = cpu.cycle(&mut memory);
-            memory.cycle_devices(machine_cycles as usize);
+            cpu.cycle(&mut memory);
         }
 
-        let s = String::from_utf8_lossy(memory.serial_buffer.as_slice());
+        let s = String::from_utf8_lossy(memory.serial.capture.buffer.as_slice());
         assert_eq!(s.as_ref(), EXPECTED_OUTPUT);
         println!("{:?}", s);
     }
@@ -1210,16 +1269,56 @@ mod test
         const EXPECTED_OUTPUT : &str = "instr_timing\n\n\nPassed\n";
         let mut memory = Memory::new();
         let mut cpu = CPU::new();
-        memory.rom.load_from_file("../roms/blargg/instr_timing.gb");
-        memory.output_serial_to_stdout = false;
+        memory.rom.load_from_file("../roms/blargg/instr_timing.gb").unwrap();
+        memory.serial.capture.output_to_stdout = false;
 
         for _i in 0..1000000 {
-            let machine_cycles = cpu.cycle(&mut memory);
-            memory.cycle_devices(machine_cycles as usize);
+            cpu.cycle(&mut memory);
+        }
+
+        let s = String::from_utf8_lossy(memory.serial.capture.buffer.as_slice());
+        assert_eq!(s.as_ref(), EXPECTED_OUTPUT);
+        println!("{:?}", s);
+    }
+
+    #[test]
+    fn blargg_mem_timing() {
+        const EXPECTED_OUTPUT : &str = "mem_timing\n\n01:ok  02:ok  03:ok  \n\nPassed all tests\n";
+        let mut memory = Memory::new();
+        let mut cpu = CPU::new();
+        memory.rom.load_from_file("../roms/blargg/mem_timing.gb").unwrap();
+        memory.serial.capture.output_to_stdout = false;
+
+        for _i in 0..3000000 {
+            cpu.cycle(&mut memory);
         }
 
-        let s = String::from_utf8_lossy(memory.serial_buffer.as_slice());
+        let s = String::from_utf8_lossy(memory.serial.capture.buffer.as_slice());
         assert_eq!(s.as_ref(), EXPECTED_OUTPUT);
         println!("{:?}", s);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn blargg_interrupt_time() {
+        // This test only prints its result to the screen
+        const EXPECTED_OUTPUT : &str = "interrupt time\n\n00 00 00\n00 08 0D\n01 00 00\n01 08 0D\n\nPassed";
+        let mut memory = Memory::new();
+        let mut cpu = CPU::new();
+        memory.rom.load_from_file("../roms/blargg/interrupt_time.gb").unwrap();
+        // The test uses the double speed mode of the Gameboy Color
+        memory.set_cgb_mode(true);
+        cpu.regs.a = 0x11;
+
+        for _i in 0..3000000 {
+            cpu.cycle(&mut memory);
+        }
+
+        // The font tiles are numbered by their ASCII codes
+        let s = (0..8).map(|row| {
+            let line: String = (0..20).map(|col| memory.gpu.video_ram[0x1800 + row*32 + col] as char).collect();
+            line.trim_end().to_owned()
+        }).collect::<Vec<String>>().join("\n");
+        assert_eq!(s, EXPECTED_OUTPUT);
+        println!("{:?}", s);
+    }
+}
@@ -22,7 +22,7 @@ impl Registers {
     {
         Registers {a: 0x01, b: 0x00, c: 0x13, d: 0x00, e:0xD8, h: 0x01, l: 0x4D, f : 0xB0, pc: 0x100, sp: 0xFFFE}
     }
-    
+
     // Setters and getters for the 16 bit combined registers af, bc, de and hl
     pub fn get_af(&self) -> u16
     {
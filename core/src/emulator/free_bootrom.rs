@@ -0,0 +1,33 @@
+/// Contains the free replacement bootrom bundled with the emulator.
+/// The source is in core/bootrom/free_boot.asm. It shows the scrolling logo
+/// and plays the boot sound like the DMG bootrom, but does not verify the header.
+
+use super::cpu::registers::Registers;
+
+const FREE_BOOTROM: &[u8; 256] = include_bytes!("../../bootrom/free_boot.bin");
+
+/// Offset of the table the final register values are popped from,
+/// in the order F, A, C, B, E, D, L, H
+const REGISTER_TABLE_OFFSET: usize = 0xD1;
+
+/// Returns the free bootrom, which leaves the CPU with the registers `regs`
+pub fn get_free_bootrom(regs: &Registers) -> Vec<u8> {
+    let mut data = FREE_BOOTROM.to_vec();
+    let table = [regs.f, regs.a, regs.c, regs.b, regs.e, regs.d, regs.l, regs.h];
+    data[REGISTER_TABLE_OFFSET..REGISTER_TABLE_OFFSET + 8].copy_from_slice(&table);
+    return data;
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+
+    #[test]
+    fn register_table()
+    {
+        // The bundled table holds the DMG registers
+        let regs = Registers::new();
+        assert_eq!(get_free_bootrom(&regs), FREE_BOOTROM.to_vec());
+    }
+}
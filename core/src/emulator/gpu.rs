@@ -5,6 +5,8 @@ use serde::{Serialize, Deserialize};
 use serde_big_array::BigArray;
 use modular_bitfield::prelude::*;
 pub mod draw_helper;
+pub mod compatibility_palettes;
+pub mod pixel_fifo;
 
 #[derive(Copy, Clone, PartialEq, Debug)]
 enum LCDMode {
@@ -14,6 +16,26 @@ enum LCDMode {
     UsingVRAMPeriod,
 }
 
+/// Selects how the screen is drawn. The scanline renderer draws a whole line at the end of mode 3,
+/// which is fast. The pixel FIFO renderer draws a dot at a time, which makes mid-scanline
+/// register writes visible and mode 3 vary in length like on hardware
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Debug)]
+pub enum PpuRenderer {
+    Scanline,
+    PixelFifo,
+}
+
+impl PpuRenderer {
+    /// Parse a renderer name, "scanline" or "fifo"
+    pub fn from_name(name: &str) -> Option<PpuRenderer> {
+        return match name {
+            "scanline" => Some(PpuRenderer::Scanline),
+            "fifo" => Some(PpuRenderer::PixelFifo),
+            _ => None,
+        }
+    }
+}
+
 #[bitfield]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct LCDOptions {
@@ -40,7 +62,7 @@ pub struct LCDOptions {
 #[derive(Serialize, Deserialize)]
 pub struct GPU {
     #[serde(with = "BigArray")]
-    pub video_ram: [u8; 8192], // 8kb, 0x8000 - 0x9FFF
+    pub video_ram: [u8; 16384], // 2*8kb, 0x8000 - 0x9FFF. Bank 1 is only used by CGB
     #[serde(with = "BigArray")]
     pub oam_ram: [u8; 160], // 160 bytes, 0xFE00 - 0xFE9F
     pub options: LCDOptions,
@@ -56,12 +78,21 @@ pub struct GPU {
     pub window_y: u8, // 0xFF4A Window Y (Window upper left pos)
     pub window_x: u8, // 0xFF4B Window X (Window upper left pos)
 
-    pub oam_dma_transfer: u8, //0xFF46
-
     pub background_palette: u8, // 0xFF47 BGP
     pub sprite_palette_1: u8, // 0xFF48
     pub sprite_palette_2: u8, // 0xFF49
 
+    // Gameboy Color registers
+    pub cgb_mode: bool,
+    pub vram_bank: usize, // 0xFF4F VBK
+    pub bg_palette_index: u8, // 0xFF68 BCPS/BGPI
+    pub sprite_palette_index: u8, // 0xFF6A OCPS/OBPI
+    #[serde(with = "BigArray")]
+    pub bg_palette_ram: [u8; 64], // Accessed through 0xFF69 BCPD/BGPD
+    #[serde(with = "BigArray")]
+    pub sprite_palette_ram: [u8; 64], // Accessed through 0xFF6B OCPD/OBPD
+    // Colors used for DMG games on the Gameboy Color
+    pub compatibility_palette: Option<compatibility_palettes::CompatibilityPalette>,
 
     // Needed for window hardware quirk
     // Window needs to remember position incase disabled/enabled on same frame
@@ -72,12 +103,17 @@ pub struct GPU {
 
     disabled_cycles: usize,
     clock_cycles: usize,
+    hblank_length: usize, // The rest of the 456 cycle line after mode 3
+    lcd_enabled_line: bool, // The first line after the LCD is enabled, which has no OAM scan
+    pub renderer: PpuRenderer,
     pub scanline_draw_requested: bool,
     pub screen_draw_requested: bool,
+    pub hblank_dma_requested: bool, // CGB H-Blank VRAM DMA
 
     // Interrupt related
     pub vblank_interrupt_requested: bool,
     pub stat_interrupt_requested: bool,
+    stat_line: bool, // The STAT interrupt sources OR'ed together
 
     // Drawing helpers
     pub state_modified: bool,
@@ -86,12 +122,13 @@ pub struct GPU {
     #[serde(skip)]
     #[serde(default="serde_drawhelper_default")]
     pub draw_helper : draw_helper::DrawHelper,
+    pub pixel_fifo: pixel_fifo::PixelFifo,
 }
 
 impl GPU {
     pub fn new() -> GPU {
         GPU { 
-            video_ram: [0; 8192], 
+            video_ram: [0; 16384], 
             oam_ram: [0; 160], 
             options: LCDOptions::new(),
 
@@ -103,46 +140,69 @@ impl GPU {
             lyc: 0,
             window_y: 0,
             window_x: 0, 
-            oam_dma_transfer: 0, 
             background_palette: 0,
             sprite_palette_1: 0, 
             sprite_palette_2: 0, 
 
+            cgb_mode: false,
+            vram_bank: 0,
+            bg_palette_index: 0,
+            sprite_palette_index: 0,
+            // The CGB bootrom initializes the BG palettes to white
+            bg_palette_ram: [0xFF; 64],
+            sprite_palette_ram: [0; 64],
+            compatibility_palette: None,
+
             wy_equalled_ly: false,
             wx_triggered: false,
             internal_window_ly: 0,
 
-            gpu_disabled: false,
+            gpu_disabled: true,
             disabled_cycles: 0,
 
             clock_cycles: 0, 
+            hblank_length: 204,
+            lcd_enabled_line: false,
+            renderer: PpuRenderer::Scanline,
             scanline_draw_requested: false, 
             screen_draw_requested: false, 
+            hblank_dma_requested: false,
             vblank_interrupt_requested: false, 
             stat_interrupt_requested: false,
+            stat_line: false,
             state_modified: false,
             state_modified_last_frame: false,
-            draw_helper : draw_helper::DrawHelper::new()
+            draw_helper : draw_helper::DrawHelper::new(),
+            pixel_fifo: pixel_fifo::PixelFifo::new(),
         }
     }
 
     pub fn read_byte(&self, address: usize) -> u8 {
         match address {
-            0x8000 ..= 0x9FFF => { return self.video_ram[address - 0x8000] }
+            // VRAM and OAM are locked while the PPU reads them
+            0x8000 ..= 0x9FFF if !self.is_vram_accessible() => { return 0xFF; }
+            0xFE00 ..= 0xFE9F if !self.is_oam_accessible() => { return 0xFF; }
+            0x8000 ..= 0x9FFF => { return self.video_ram[self.vram_bank*0x2000 + address - 0x8000] }
             0xFE00 ..= 0xFE9F => { return self.oam_ram[address - 0xFE00] }
             // Device control addresses
             0xFF40 => { return self.lcd_control; }
-            0xFF41 => { return self.lcd_stat; }
+            0xFF41 => { return 0x80 | self.lcd_stat; }
             0xFF42 => { return self.scroll_y; }
             0xFF43 => { return self.scroll_x; }
             0xFF44 => { return self.ly; }
             0xFF45 => { return self.lyc; }
-            0xFF46 => { return 0; } // Write only
             0xFF47 => { return self.background_palette; }
             0xFF48 => { return self.sprite_palette_1; }
             0xFF49 => { return self.sprite_palette_2; }
             0xFF4A => { return self.window_y; }
             0xFF4B => { return self.window_x; }
+            // CGB registers, unused in DMG mode
+            0xFF4F if self.cgb_mode => { return 0b1111_1110 | self.vram_bank as u8; }
+            0xFF68 if self.cgb_mode => { return 0b0100_0000 | self.bg_palette_index; }
+            0xFF69 if self.cgb_mode => { return self.bg_palette_ram[(self.bg_palette_index & 0x3F) as usize]; }
+            0xFF6A if self.cgb_mode => { return 0b0100_0000 | self.sprite_palette_index; }
+            0xFF6B if self.cgb_mode => { return self.sprite_palette_ram[(self.sprite_palette_index & 0x3F) as usize]; }
+            0xFF4F | 0xFF68 ..= 0xFF6B => { return 0xFF; }
             _ => { panic!("Illegal memory access at addr {} in GPU", address)}
         }
     }
@@ -150,28 +210,49 @@ impl GPU {
     pub fn write_byte(&mut self, address: usize, value: u8) {
         self.state_modified = true;
         match address {
+            0x8000 ..= 0x9FFF if !self.is_vram_accessible() => { }
+            0xFE00 ..= 0xFE9F if !self.is_oam_accessible() => { }
             0x8000 ..= 0x9FFF => { 
-                self.video_ram[address - 0x8000] = value; 
-                self.draw_helper.update_by_vram_address(address, &self.video_ram, &self.oam_ram); 
+                self.video_ram[self.vram_bank*0x2000 + address - 0x8000] = value; 
+                self.draw_helper.update_by_vram_address(address, self.vram_bank, &self.video_ram, &self.oam_ram); 
             }
             0xFE00 ..= 0xFE9F => { 
                 self.oam_ram[address - 0xFE00] = value; 
-                self.draw_helper.update_by_vram_address(address, &self.video_ram, &self.oam_ram)
+                self.draw_helper.update_by_vram_address(address, 0, &self.video_ram, &self.oam_ram)
             }
 
             // Device control addresses
             0xFF40 => { self.lcd_control = value; self.update_lcd_options(); }
-            0xFF41 => { self.lcd_stat = value; self.update_lcd_options(); }
+            0xFF41 => { 
+                // The mode and the LY=LYC flag are read only
+                self.lcd_stat = value & 0b0111_1000 | self.lcd_stat & 0b0000_0111;
+                self.update_lcd_options();
+                self.update_stat_line();
+            }
             0xFF42 => { self.scroll_y = value; }
             0xFF43 => { self.scroll_x = value; }
-            0xFF44 => { self.ly = value; }
-            0xFF45 => { self.lyc = value; }
-            0xFF46 => { self.oam_dma_transfer = value; }
+            0xFF44 => { } // LY is read only
+            0xFF45 => { self.lyc = value; self.update_stat_line(); }
             0xFF47 => { self.background_palette = value; self.update_palettes(); }
             0xFF48 => { self.sprite_palette_1 = value; self.update_palettes(); }
             0xFF49 => { self.sprite_palette_2 = value; self.update_palettes(); }
             0xFF4A => { self.window_y = value; }
             0xFF4B => { self.window_x = value; }
+            // CGB registers, unused in DMG mode
+            0xFF4F if self.cgb_mode => { self.vram_bank = (value & 0x01) as usize; }
+            0xFF68 if self.cgb_mode => { self.bg_palette_index = value & 0b1011_1111; }
+            0xFF69 if self.cgb_mode => { 
+                self.bg_palette_ram[(self.bg_palette_index & 0x3F) as usize] = value;
+                self.bg_palette_index = GPU::increment_palette_index(self.bg_palette_index);
+                self.update_cgb_palettes();
+            }
+            0xFF6A if self.cgb_mode => { self.sprite_palette_index = value & 0b1011_1111; }
+            0xFF6B if self.cgb_mode => { 
+                self.sprite_palette_ram[(self.sprite_palette_index & 0x3F) as usize] = value;
+                self.sprite_palette_index = GPU::increment_palette_index(self.sprite_palette_index);
+                self.update_cgb_palettes();
+            }
+            0xFF4F | 0xFF68 ..= 0xFF6B => { }
             _ => { panic!("Illegal memory write at addr {} in GPU", address) }
         }
     }
@@ -190,43 +271,58 @@ impl GPU {
 
         match self.get_lcd_mode_flag() {
 
-            // Horizontal blank period, Scanline not active, 204 cycles
+            // The first line after the LCD is enabled starts in mode 0 instead of the OAM scan,
+            // and is 4 cycles shorter
+            LCDMode::HBlankPeriod if self.lcd_enabled_line => {
+                if self.ly == self.window_y {
+                    self.wy_equalled_ly = true;
+                }
+                if self.clock_cycles >= 76 {
+                    self.clock_cycles -= 76;
+                    self.lcd_enabled_line = false;
+                    self.start_vram_period();
+                }
+            }
+
+            // Horizontal blank period, Scanline not active, 204 cycles after a 172 cycle mode 3
             LCDMode::HBlankPeriod => {
-                if self.clock_cycles >= 204 {
-                    self.clock_cycles -= 204;
-                    self.set_lcd_mode_flag(LCDMode::UsingVRAMPeriod);
+                if self.clock_cycles >= self.hblank_length {
+                    self.clock_cycles -= self.hblank_length;
                     self.increment_interal_window_ly();
                     self.ly += 1;
-                    self.check_for_lyc_interrupt();
 
                     if self.ly > 143 {
                         // Enter vblank
                         self.set_lcd_mode_flag(LCDMode::VBlankPeriod);
-                        self.check_for_stat_interrupt();
                         // Render entire frame
                         self.screen_draw_requested = true;
                         self.vblank_interrupt_requested = true;
+                        // Entering VBlank also triggers the OAM STAT interrupt source
+                        let stat_line = self.get_stat_line() || self.options.stat_oam_inter_enable();
+                        self.set_stat_line(stat_line);
                     }
                     else {
                         self.set_lcd_mode_flag(LCDMode::UsingOAMPeriod);
-                        self.check_for_stat_interrupt();
                     }
                 }
             }
 
             // Vertical blank period, Scanline not active, 10 lines * 456 cycles
             LCDMode::VBlankPeriod => {
+                // LY reads 0 after the first machine cycle of line 153
+                if self.ly == 153 && self.clock_cycles >= 4 {
+                    self.ly = 0;
+                }
                 if self.clock_cycles >= 456 {
                     self.clock_cycles -= 456;
-                    self.ly += 1;
-                    if self.ly == 154 { // After 10 lines of VBlank, start drawing again
-                        self.ly = 0;
+                    if self.ly == 0 { // After 10 lines of VBlank, start drawing again
                         self.internal_window_ly = 0;
                         self.wy_equalled_ly = self.ly == self.window_y;
                         self.set_lcd_mode_flag(LCDMode::UsingOAMPeriod);
-                        self.check_for_stat_interrupt();
                     }
-                    self.check_for_lyc_interrupt();
+                    else {
+                        self.ly += 1;
+                    }
                 }
             }
 
@@ -237,23 +333,62 @@ impl GPU {
                 }
                 if self.clock_cycles >= 80 {
                     self.clock_cycles = self.clock_cycles - 80;
-                    self.set_lcd_mode_flag(LCDMode::UsingVRAMPeriod);
+                    self.start_vram_period();
                 }
             }
 
-            // Read from VRAM, Scanline Active, 172 cycles
+            // Read from VRAM, Scanline Active, 172 cycles for the scanline renderer.
+            // The pixel FIFO renderer takes 172 to 289 cycles
             LCDMode::UsingVRAMPeriod => {
-                if self.clock_cycles >= 172 {
-                    self.clock_cycles -= 172;
+                let mode_done = match self.renderer {
+                    PpuRenderer::Scanline => {
+                        let done = self.clock_cycles >= 172;
+                        if done {
+                            self.clock_cycles -= 172;
+                        }
+                        done
+                    }
+                    PpuRenderer::PixelFifo => {
+                        while self.clock_cycles > 0 && !self.pixel_fifo.line_done {
+                            self.clock_cycles -= 1;
+                            self.step_pixel_fifo();
+                        }
+                        self.pixel_fifo.line_done
+                    }
+                };
+                if mode_done {
+                    self.hblank_length = match self.renderer {
+                        PpuRenderer::Scanline => { 204 }
+                        PpuRenderer::PixelFifo => { 376usize.saturating_sub(self.pixel_fifo.dots) }
+                    };
                     self.set_lcd_mode_flag(LCDMode::HBlankPeriod);
-                    self.check_for_stat_interrupt();
                     self.scanline_draw_requested = true;
+                    self.hblank_dma_requested = self.cgb_mode;
                 }
             }
         }
+        self.update_stat_line();
+    }
 
+    fn start_vram_period(&mut self) {
+        self.set_lcd_mode_flag(LCDMode::UsingVRAMPeriod);
+        if self.renderer == PpuRenderer::PixelFifo {
+            self.start_pixel_fifo_line();
+        }
     }
 
+    /// VRAM can not be accessed by the CPU during mode 3
+    pub fn is_vram_accessible(&self) -> bool {
+        return !self.options.lcd_enable() || self.get_lcd_mode_flag() != LCDMode::UsingVRAMPeriod;
+    }
+
+    /// OAM can not be accessed by the CPU during modes 2 and 3
+    pub fn is_oam_accessible(&self) -> bool {
+        return !self.options.lcd_enable() || match self.get_lcd_mode_flag() {
+            LCDMode::UsingOAMPeriod | LCDMode::UsingVRAMPeriod => false,
+            _ => true,
+        };
+    }
 
     fn get_lcd_mode_flag(&self) -> LCDMode {
         return match self.options.lcd_mode() {
@@ -271,22 +406,52 @@ impl GPU {
             if self.get_lcd_mode_flag() != LCDMode::VBlankPeriod {
                 println!("WARNING: LCD disabled outside VBlank period, this can damage Gameboy hardware!");
             }
-            self.gpu_disabled = true;
-            self.clock_cycles = 0;
-            self.disabled_cycles = 0;
-            self.ly = 0;
-            self.wy_equalled_ly = false;
-            self.internal_window_ly = 0;
+            self.reset_disabled_lcd();
         }
         else if self.gpu_disabled { // LCD was just enabled
             //self.options.set_window_enable(true);
             self.gpu_disabled = false;
-            self.check_for_lyc_interrupt();
+            self.lcd_enabled_line = true;
             self.scanline_draw_requested = true;
             self.set_lcd_mode_flag(LCDMode::HBlankPeriod);
+            self.update_stat_line();
         }
     }
 
+    /// Set the LCD to its power on state, disabled with LCDC cleared. The bootrom enables it
+    pub fn set_power_on_state(&mut self) {
+        self.lcd_control = 0;
+        self.options = LCDOptions::from_bytes([self.lcd_control, self.lcd_stat]);
+        self.reset_disabled_lcd();
+    }
+
+    /// Set the LCD to the state the bootrom leaves it in, running with the current LCDC.
+    /// The bootrom hands over during line 153 after LY switched to 0, STAT reads 0x85.
+    /// The first frame is a full one, without the shortened first line of an LCD enable
+    pub fn set_post_boot_state(&mut self) {
+        self.options = LCDOptions::from_bytes([self.lcd_control, self.lcd_stat]);
+        self.reset_disabled_lcd();
+        self.gpu_disabled = !self.options.lcd_enable();
+        if self.options.lcd_enable() {
+            self.set_lcd_mode_flag(LCDMode::VBlankPeriod);
+            self.clock_cycles = 8;
+            self.update_stat_line();
+        }
+    }
+
+    fn reset_disabled_lcd(&mut self) {
+        self.gpu_disabled = true;
+        self.clock_cycles = 0;
+        self.disabled_cycles = 0;
+        self.ly = 0;
+        self.lcd_enabled_line = false;
+        self.stat_line = false;
+        // STAT reads mode 0 while the LCD is disabled
+        self.set_lcd_mode_flag(LCDMode::HBlankPeriod);
+        self.wy_equalled_ly = false;
+        self.internal_window_ly = 0;
+    }
+
     fn set_lcd_mode_flag(&mut self, mode : LCDMode) {
         let f = match mode {
             LCDMode::HBlankPeriod => 0,
@@ -298,21 +463,75 @@ impl GPU {
         self.options = LCDOptions::from_bytes([self.lcd_control, self.lcd_stat]);
     }
 
-    fn check_for_stat_interrupt(&mut self) {
-        if !self.stat_interrupt_requested { // Don't clear if already set from lyc=ly
-            self.stat_interrupt_requested = match self.get_lcd_mode_flag() {
-                LCDMode::UsingOAMPeriod | LCDMode::VBlankPeriod if self.options.stat_oam_inter_enable() => true,
-                LCDMode::VBlankPeriod if self.options.stat_vblank_inter_enable() => true,
-                LCDMode::HBlankPeriod if self.options.stat_hblank_inter_enable() => true,
-                _ => false
-            }
-        }
+    /// LY as seen by the LY=LYC comparison, which is only done a machine cycle after LY changes.
+    /// Returns None until then
+    fn get_ly_compare(&self) -> Option<u8> {
+        let comparing = match self.get_lcd_mode_flag() {
+            // Line 0 keeps LY from line 153
+            LCDMode::UsingOAMPeriod => { self.ly == 0 || self.clock_cycles >= 4 }
+            // LY changes to 0 a machine cycle into line 153
+            LCDMode::VBlankPeriod if self.ly == 0 => { self.clock_cycles >= 8 }
+            LCDMode::VBlankPeriod => { self.clock_cycles >= 4 }
+            _ => { true }
+        };
+        return if comparing { Some(self.ly) } else { None };
     }
 
-    fn check_for_lyc_interrupt(&mut self) {
-        if self.options.stat_lyc_inter_enable() && self.lyc == self.ly {
+    /// Returns the STAT interrupt sources OR'ed together
+    fn get_stat_line(&self) -> bool {
+        let lyc_source = self.options.stat_lyc_inter_enable() && self.get_ly_compare() == Some(self.lyc);
+        let mode_source = match self.get_lcd_mode_flag() {
+            // The mode 0 of the first line after the LCD is enabled does not request interrupts
+            LCDMode::HBlankPeriod => { self.options.stat_hblank_inter_enable() && !self.lcd_enabled_line }
+            LCDMode::VBlankPeriod => { self.options.stat_vblank_inter_enable() }
+            LCDMode::UsingOAMPeriod => { self.options.stat_oam_inter_enable() }
+            LCDMode::UsingVRAMPeriod => { false }
+        };
+        return lyc_source || mode_source;
+    }
+
+    /// The STAT interrupt is only requested when the line goes high. While a source keeps
+    /// the line high, the other sources are blocked
+    fn set_stat_line(&mut self, line: bool) {
+        if line && !self.stat_line {
             self.stat_interrupt_requested = true;
         }
+        self.stat_line = line;
+    }
+
+    /// Update the LY=LYC flag in STAT and the STAT interrupt line
+    fn update_stat_line(&mut self) {
+        if !self.options.lcd_enable() {
+            return;
+        }
+        let coincidence = self.get_ly_compare() == Some(self.lyc);
+        self.lcd_stat = self.lcd_stat & 0b1111_1011 | (coincidence as u8) << 2;
+        self.options = LCDOptions::from_bytes([self.lcd_control, self.lcd_stat]);
+        let line = self.get_stat_line();
+        self.set_stat_line(line);
+    }
+
+    /// Colorize the DMG palettes, or restore the gray shades with None
+    pub fn set_compatibility_palette(&mut self, palette: Option<compatibility_palettes::CompatibilityPalette>) {
+        self.compatibility_palette = palette;
+        self.update_compatibility_palette();
+        self.update_palettes();
+        self.state_modified = true;
+    }
+
+    fn update_compatibility_palette(&mut self) {
+        match self.compatibility_palette {
+            Some(palette) => {
+                self.draw_helper.background_palette.set_color_map(palette.get_bg_colors());
+                self.draw_helper.sprite_palette_1.set_color_map(palette.get_obj0_colors());
+                self.draw_helper.sprite_palette_2.set_color_map(palette.get_obj1_colors());
+            }
+            None => {
+                self.draw_helper.background_palette.reset_color_map();
+                self.draw_helper.sprite_palette_1.reset_color_map();
+                self.draw_helper.sprite_palette_2.reset_color_map();
+            }
+        }
     }
 
     fn update_palettes(&mut self) {
@@ -321,13 +540,29 @@ impl GPU {
         self.draw_helper.sprite_palette_2.update_sprite(self.sprite_palette_2);
     }
 
+    fn update_cgb_palettes(&mut self) {
+        self.draw_helper.update_cgb_palettes(&self.bg_palette_ram, &self.sprite_palette_ram);
+    }
+
+    /// Bit 7 of the palette index enables auto increment after a data write
+    fn increment_palette_index(index: u8) -> u8 {
+        if index & 0x80 == 0x80 {
+            return 0x80 | ((index + 1) & 0x3F);
+        }
+        return index;
+    }
+
     /// This function handles a hardware quirk in the Gameboy
     /// If the window layer is enabled, the window starts drawing
     /// from the ly line. If the window layer is then turned off,
     /// and then on again later, the window starts from the previous ly
     /// from the last window line drawn, not the current one
     fn increment_interal_window_ly(&mut self) {
-        if self.wy_equalled_ly && self.options.window_enable() && self.ly >= self.window_y && self.window_x <= 144 {
+        let window_drawn = match self.renderer {
+            PpuRenderer::Scanline => { self.wy_equalled_ly && self.options.window_enable() && self.ly >= self.window_y && self.window_x <= 144 }
+            PpuRenderer::PixelFifo => { self.pixel_fifo.window_drawn }
+        };
+        if window_drawn {
             self.internal_window_ly += 1;
         }
     }
@@ -359,6 +594,19 @@ impl GPU {
         }
     }
 
+    /// Returns the CGB attributes of a tilemap entry, stored in VRAM bank 1
+    pub fn get_tilemap_attributes(&self, x: usize, y: usize, tilemap_select: bool) -> draw_helper::TileAttributes {
+        if !self.cgb_mode {
+            return draw_helper::TileAttributes::new();
+        }
+        if !tilemap_select {
+            return draw_helper::TileAttributes::from_byte(self.video_ram[0x2000 + (0x9800 - 0x8000) + y*32 + x]);
+        }
+        else {
+            return draw_helper::TileAttributes::from_byte(self.video_ram[0x2000 + (0x9C00 - 0x8000) + y*32 + x]);
+        }
+    }
+
     // Getters for LCDC options
     pub fn get_bg_enable(&self) -> bool {
         return self.options.bg_enable();
@@ -384,7 +632,9 @@ impl GPU {
     /// Mainly used to restore DrawHelper after serialization, as it is not serialized
     pub fn init_draw_helper(&mut self) {
         self.draw_helper.generate_all_from_mem(&self.video_ram, &self.oam_ram);
+        self.update_compatibility_palette();
         self.update_palettes();
+        self.update_cgb_palettes();
     }
 }
 
@@ -396,14 +646,43 @@ fn serde_drawhelper_default() -> draw_helper::DrawHelper {
 mod test
 {
     use super::super::memory;
-    use super::LCDMode;
+    use super::{GPU, LCDMode, PpuRenderer};
+
+    /// Cycle the GPU a dot at a time until it enters `mode`. Returns the number of dots
+    fn cycle_until_mode(gpu: &mut GPU, mode: LCDMode) -> usize {
+        let mut dots = 0;
+        while gpu.get_lcd_mode_flag() != mode {
+            gpu.cycle(1);
+            dots += 1;
+        }
+        return dots;
+    }
+
+    /// Cycle the GPU a dot at a time until LY is `ly`
+    fn cycle_until_ly(gpu: &mut GPU, ly: u8) {
+        while gpu.ly != ly {
+            gpu.cycle(1);
+        }
+    }
+
+    /// Returns the length of mode 3 on the next line, drawn by the pixel FIFO renderer
+    fn mode3_length(mem: &mut memory::Memory) -> usize {
+        cycle_until_mode(&mut mem.gpu, LCDMode::UsingVRAMPeriod);
+        return cycle_until_mode(&mut mem.gpu, LCDMode::HBlankPeriod);
+    }
 
     #[test]
     fn gpu_mode_switching()
     {
         let mut mem = memory::Memory::new();
+        mem.write_byte(0xFF40, 0);
         mem.write_byte(0xFF40, 0b1000_0000); // Enable LCD, bit 7
+        // The first line starts in mode 0 instead of the OAM scan
         assert_eq!(mem.gpu.get_lcd_mode_flag(), LCDMode::HBlankPeriod); 
+        mem.gpu.cycle(76);
+        assert_eq!(mem.gpu.get_lcd_mode_flag(), LCDMode::UsingVRAMPeriod);
+        mem.gpu.cycle(172);
+        assert_eq!(mem.gpu.get_lcd_mode_flag(), LCDMode::HBlankPeriod);
         mem.gpu.cycle(204);
         assert_eq!(mem.gpu.get_lcd_mode_flag(), LCDMode::UsingOAMPeriod);
         assert_eq!(mem.gpu.ly, 1);
@@ -417,4 +696,340 @@ mod test
         assert_eq!(mem.gpu.get_lcd_mode_flag(), LCDMode::VBlankPeriod);
         assert_eq!(mem.gpu.ly, 144);
     }
+
+    #[test]
+    fn post_boot_first_frame()
+    {
+        // Without a bootrom, the LCD is left running with LCDC=0x91 on line 153, where LY reads 0
+        let mut mem = memory::Memory::new();
+        assert_eq!(mem.read_byte(0xFF40), 0x91);
+        assert_eq!(mem.read_byte(0xFF44), 0);
+        assert_eq!(mem.read_byte(0xFF41), 0x85);
+        assert_eq!(cycle_until_mode(&mut mem.gpu, LCDMode::UsingOAMPeriod), 456 - 8);
+        // The first frame has full length lines, starting with the OAM scan
+        assert_eq!(mem.gpu.ly, 0);
+        assert_eq!(cycle_until_mode(&mut mem.gpu, LCDMode::UsingVRAMPeriod), 80);
+        assert_eq!(cycle_until_mode(&mut mem.gpu, LCDMode::HBlankPeriod), 172);
+        assert_eq!(cycle_until_mode(&mut mem.gpu, LCDMode::UsingOAMPeriod), 204);
+        assert_eq!(mem.gpu.ly, 1);
+        assert_eq!(cycle_until_mode(&mut mem.gpu, LCDMode::VBlankPeriod), 143*456);
+        assert_eq!(mem.gpu.ly, 144);
+    }
+
+    #[test]
+    fn cgb_registers()
+    {
+        let mut mem = memory::Memory::new();
+        // CGB registers are disabled in DMG mode
+        mem.write_byte(0xFF4F, 1);
+        assert_eq!(mem.read_byte(0xFF4F), 0xFF);
+        mem.write_byte(0xD000, 0x11);
+        mem.write_byte(0xFF70, 2);
+        assert_eq!(mem.read_byte(0xD000), 0x11);
+
+        mem.set_cgb_mode(true);
+        // VRAM banks
+        mem.write_byte(0x8000, 0xAA);
+        mem.write_byte(0xFF4F, 1);
+        assert_eq!(mem.read_byte(0xFF4F), 0xFF);
+        assert_eq!(mem.read_byte(0x8000), 0);
+        mem.write_byte(0x8000, 0xBB);
+        mem.write_byte(0xFF4F, 0);
+        assert_eq!(mem.read_byte(0x8000), 0xAA);
+        assert_eq!(mem.gpu.video_ram[0x2000], 0xBB);
+
+        // WRAM banks, bank 0 selects bank 1
+        mem.write_byte(0xFF70, 2);
+        assert_eq!(mem.read_byte(0xFF70), 0xFA);
+        mem.write_byte(0xD000, 0x22);
+        assert_eq!(mem.read_byte(0xF000), 0x22); // Echo ram
+        mem.write_byte(0xFF70, 0);
+        assert_eq!(mem.read_byte(0xD000), 0x11);
+
+        // Palette RAM with auto increment
+        mem.write_byte(0xFF68, 0x80 | 0x08); // BG palette 1, color 0
+        mem.write_byte(0xFF69, 0b0001_1111); // Magenta, 0x7C1F low byte
+        mem.write_byte(0xFF69, 0b0111_1100); // Magenta, 0x7C1F high byte
+        assert_eq!(mem.read_byte(0xFF68), 0xC0 | 0x0A);
+        mem.write_byte(0xFF68, 0x08);
+        assert_eq!(mem.read_byte(0xFF69), 0b0001_1111);
+        let color = mem.gpu.draw_helper.cgb_background_palettes[1].get_color(0);
+        assert_eq!((color.r, color.g, color.b), (255, 0, 255));
+
+        // Tile attributes are stored in VRAM bank 1
+        mem.write_byte(0xFF4F, 1);
+        mem.write_byte(0x9C01, 0b1010_1011);
+        let attributes = mem.gpu.get_tilemap_attributes(1, 0, true);
+        assert_eq!(attributes.palette, 3);
+        assert_eq!(attributes.vram_bank, 1);
+        assert_eq!(attributes.flip_x, true);
+        assert_eq!(attributes.flip_y, false);
+        assert_eq!(attributes.bg_priority, true);
+    }
+
+    #[test]
+    fn pixel_fifo_mode3_length()
+    {
+        let mut mem = memory::Memory::new();
+        mem.gpu.renderer = PpuRenderer::PixelFifo;
+        mem.write_byte(0xFF40, 0b1000_0011); // LCD, sprites and background
+        assert_eq!(mode3_length(&mut mem), 172);
+        // Scrolled pixels are discarded
+        mem.write_byte(0xFF43, 3);
+        assert_eq!(mode3_length(&mut mem), 175);
+        mem.write_byte(0xFF43, 0);
+
+        // A sprite at the left edge waits for the whole background fetch
+        mem.write_byte(0xFE00, 16); // Lines 0 to 7
+        mem.write_byte(0xFE01, 8);
+        assert_eq!(mode3_length(&mut mem), 172 + 11);
+        // A sprite after the tile data is fetched only takes 6 dots
+        mem.write_byte(0xFE01, 8 + 5);
+        assert_eq!(mode3_length(&mut mem), 172 + 6);
+        // The HBlank is shortened, the line still takes 456 dots
+        let hblank_dots = cycle_until_mode(&mut mem.gpu, LCDMode::UsingOAMPeriod);
+        assert_eq!(80 + 172 + 6 + hblank_dots, 456);
+    }
+
+    #[test]
+    fn pixel_fifo_mid_scanline_write()
+    {
+        let mut mem = memory::Memory::new();
+        mem.gpu.renderer = PpuRenderer::PixelFifo;
+        // Tile 0 is color 3, the whole background uses it
+        for i in 0..16 {
+            mem.write_byte(0x8000 + i, 0xFF);
+        }
+        mem.write_byte(0xFF47, 0xE4);
+        mem.write_byte(0xFF40, 0b1001_0001);
+        cycle_until_mode(&mut mem.gpu, LCDMode::UsingVRAMPeriod);
+        // The palette changes in the middle of the line
+        for _ in 0..92 {
+            mem.gpu.cycle(1);
+        }
+        mem.write_byte(0xFF47, 0x00);
+        cycle_until_mode(&mut mem.gpu, LCDMode::HBlankPeriod);
+        let line = mem.gpu.pixel_fifo.get_line();
+        assert_eq!(line[0], 0);
+        assert_eq!(line[159*3], 255);
+    }
+
+    #[test]
+    fn pixel_fifo_mid_scanline_scx()
+    {
+        let mut mem = memory::Memory::new();
+        mem.gpu.renderer = PpuRenderer::PixelFifo;
+        // Alternating columns of white tile 0 and black tile 1
+        for i in 0..16 {
+            mem.write_byte(0x8010 + i, 0xFF);
+        }
+        for x in 0..32 {
+            mem.write_byte(0x9800 + x, (x % 2) as u8);
+        }
+        mem.write_byte(0xFF47, 0xE4);
+        mem.write_byte(0xFF40, 0b1001_0001);
+        let expected_shade = |x: usize, scroll_x: usize| { (((x + scroll_x) / 8) % 2) as u8 * 3 };
+
+        // The low 3 bits of SCX are only read at the start of the line
+        cycle_until_mode(&mut mem.gpu, LCDMode::UsingVRAMPeriod);
+        mem.gpu.cycle(92);
+        mem.write_byte(0xFF43, 3);
+        cycle_until_mode(&mut mem.gpu, LCDMode::HBlankPeriod);
+        let shades = mem.gpu.pixel_fifo.get_line_shades();
+        for x in 0..160 {
+            assert_eq!(shades[x], expected_shade(x, 0), "Line 0, x {}", x);
+        }
+
+        // The upper bits are read by every tile fetch
+        cycle_until_mode(&mut mem.gpu, LCDMode::UsingVRAMPeriod);
+        mem.gpu.cycle(92);
+        mem.write_byte(0xFF43, 3 + 8);
+        cycle_until_mode(&mut mem.gpu, LCDMode::HBlankPeriod);
+        let shades = mem.gpu.pixel_fifo.get_line_shades();
+        for x in 0..64 {
+            assert_eq!(shades[x], expected_shade(x, 3), "Line 1, x {}", x);
+        }
+        for x in 120..160 {
+            assert_eq!(shades[x], expected_shade(x, 3 + 8), "Line 1, x {}", x);
+        }
+    }
+
+    #[test]
+    fn pixel_fifo_savestate()
+    {
+        let mut mem = memory::Memory::new();
+        mem.gpu.renderer = PpuRenderer::PixelFifo;
+        // Alternating columns of white tile 0 and black tile 1, and a sprite using tile 1
+        for i in 0..16 {
+            mem.write_byte(0x8010 + i, 0xFF);
+        }
+        for x in 0..32 {
+            mem.write_byte(0x9800 + x, (x % 2) as u8);
+        }
+        mem.write_byte(0xFE00, 16);
+        mem.write_byte(0xFE01, 8 + 124);
+        mem.write_byte(0xFE02, 1);
+        mem.write_byte(0xFF47, 0xE4);
+        mem.write_byte(0xFF48, 0x00);
+        mem.write_byte(0xFF40, 0b1001_0011);
+
+        // A state saved in the middle of mode 3 finishes the line like the original
+        cycle_until_mode(&mut mem.gpu, LCDMode::UsingVRAMPeriod);
+        mem.gpu.cycle(92);
+        let mut loaded : GPU = bincode::deserialize(&bincode::serialize(&mem.gpu).unwrap()).unwrap();
+        loaded.init_draw_helper();
+        let dots = cycle_until_mode(&mut mem.gpu, LCDMode::HBlankPeriod);
+        assert_eq!(cycle_until_mode(&mut loaded, LCDMode::HBlankPeriod), dots);
+        assert_eq!(loaded.pixel_fifo.get_line(), mem.gpu.pixel_fifo.get_line());
+        assert_eq!(loaded.pixel_fifo.get_line_shades(), mem.gpu.pixel_fifo.get_line_shades());
+        // The sprite is drawn in white over the black tile
+        assert_eq!(loaded.pixel_fifo.get_line_shades()[124], 0);
+    }
+
+    #[test]
+    #[ignore = "needs roms/mooneye"]
+    fn mooneye_ppu()
+    {
+        use super::super::test::run_mooneye_test;
+        const TESTS : [&str; 12] = [
+            "hblank_ly_scx_timing-GS.gb", "intr_1_2_timing-GS.gb", "intr_2_0_timing.gb", "intr_2_mode0_timing.gb",
+            "intr_2_mode0_timing_sprites.gb", "intr_2_mode3_timing.gb", "intr_2_oam_ok_timing.gb", "lcdon_timing-GS.gb",
+            "lcdon_write_timing-GS.gb", "stat_irq_blocking.gb", "stat_lyc_onoff.gb", "vblank_stat_intr-GS.gb",
+        ];
+        for test in TESTS.iter() {
+            run_mooneye_test(&format!("acceptance/ppu/{}", test));
+        }
+    }
+
+    #[test]
+    #[ignore = "needs roms/mealybug"]
+    fn mealybug_ppu()
+    {
+        use super::super::test::run_mealybug_test;
+        run_mealybug_test("m3_bgp_change");
+        run_mealybug_test("m3_scx_low_3_bits");
+    }
+
+    #[test]
+    fn stat_register()
+    {
+        let mut mem = memory::Memory::new();
+        mem.write_byte(0xFF40, 0b1000_0000);
+        cycle_until_mode(&mut mem.gpu, LCDMode::UsingVRAMPeriod);
+        // The mode and LY=LYC flag can not be written, bit 7 is unused
+        mem.write_byte(0xFF41, 0b0111_1000);
+        assert_eq!(mem.read_byte(0xFF41), 0b1111_1111);
+        mem.write_byte(0xFF45, 1);
+        assert_eq!(mem.read_byte(0xFF41), 0b1111_1011);
+        // LY is read only
+        mem.write_byte(0xFF44, 10);
+        assert_eq!(mem.read_byte(0xFF44), 0);
+        // Mode 0 while the LCD is disabled
+        mem.write_byte(0xFF40, 0);
+        assert_eq!(mem.read_byte(0xFF41) & 0b11, 0);
+    }
+
+    #[test]
+    fn stat_irq_blocking()
+    {
+        let mut mem = memory::Memory::new();
+        mem.write_byte(0xFF45, 2);
+        mem.write_byte(0xFF41, 0b0100_1000); // LY=LYC and mode 0 sources
+        mem.write_byte(0xFF40, 0b1000_0000);
+        // The mode 0 at the start of the first line does not request an interrupt
+        cycle_until_mode(&mut mem.gpu, LCDMode::UsingVRAMPeriod);
+        assert_eq!(mem.gpu.stat_interrupt_requested, false);
+        cycle_until_mode(&mut mem.gpu, LCDMode::HBlankPeriod);
+        assert_eq!(mem.gpu.stat_interrupt_requested, true);
+
+        // LY=LYC is compared a machine cycle after LY changes
+        cycle_until_ly(&mut mem.gpu, 2);
+        mem.gpu.stat_interrupt_requested = false;
+        mem.gpu.cycle(3);
+        assert_eq!(mem.gpu.stat_interrupt_requested, false);
+        mem.gpu.cycle(1);
+        assert_eq!(mem.gpu.stat_interrupt_requested, true);
+        mem.gpu.stat_interrupt_requested = false;
+        // The line is still high from LY=LYC, mode 0 is blocked
+        cycle_until_mode(&mut mem.gpu, LCDMode::HBlankPeriod);
+        assert_eq!(mem.gpu.stat_interrupt_requested, false);
+        cycle_until_ly(&mut mem.gpu, 3);
+        cycle_until_mode(&mut mem.gpu, LCDMode::HBlankPeriod);
+        assert_eq!(mem.gpu.stat_interrupt_requested, true);
+
+        // Enabling a source which is already active raises the line
+        mem.gpu.stat_interrupt_requested = false;
+        mem.write_byte(0xFF41, 0);
+        mem.write_byte(0xFF41, 0b0000_1000);
+        assert_eq!(mem.gpu.stat_interrupt_requested, true);
+    }
+
+    #[test]
+    fn stat_vblank_oam_source()
+    {
+        let mut mem = memory::Memory::new();
+        mem.write_byte(0xFF41, 0b0010_0000); // Mode 2 source
+        mem.write_byte(0xFF40, 0b1000_0000);
+        // No OAM scan on the first line
+        cycle_until_ly(&mut mem.gpu, 1);
+        mem.gpu.cycle(1);
+        assert_eq!(mem.gpu.stat_interrupt_requested, true);
+        // The first line is 4 cycles shorter
+        mem.write_byte(0xFF40, 0);
+        mem.write_byte(0xFF40, 0b1000_0000);
+        for _ in 0..452 {
+            assert_eq!(mem.gpu.ly, 0);
+            mem.gpu.cycle(1);
+        }
+        assert_eq!(mem.gpu.ly, 1);
+
+        // Entering VBlank also triggers the mode 2 source
+        cycle_until_ly(&mut mem.gpu, 143);
+        mem.gpu.stat_interrupt_requested = false;
+        cycle_until_mode(&mut mem.gpu, LCDMode::VBlankPeriod);
+        assert_eq!(mem.gpu.stat_interrupt_requested, true);
+    }
+
+    #[test]
+    fn ly_lyc_153()
+    {
+        let mut mem = memory::Memory::new();
+        mem.write_byte(0xFF45, 0);
+        mem.write_byte(0xFF41, 0b0100_0000); // LY=LYC source
+        mem.write_byte(0xFF40, 0b1000_0000);
+        cycle_until_ly(&mut mem.gpu, 153);
+        mem.gpu.stat_interrupt_requested = false;
+        // LY reads 0 after a machine cycle, and is compared a machine cycle later
+        mem.gpu.cycle(4);
+        assert_eq!(mem.read_byte(0xFF44), 0);
+        assert_eq!(mem.read_byte(0xFF41) & 0b111, 0b001);
+        assert_eq!(mem.gpu.stat_interrupt_requested, false);
+        mem.gpu.cycle(4);
+        assert_eq!(mem.read_byte(0xFF41) & 0b111, 0b101);
+        assert_eq!(mem.gpu.stat_interrupt_requested, true);
+        // Line 0 keeps the line high, no new interrupt is requested
+        mem.gpu.stat_interrupt_requested = false;
+        cycle_until_mode(&mut mem.gpu, LCDMode::UsingOAMPeriod);
+        assert_eq!(mem.read_byte(0xFF41) & 0b111, 0b110);
+        assert_eq!(mem.gpu.stat_interrupt_requested, false);
+    }
+
+    #[test]
+    fn vram_oam_locking()
+    {
+        let mut mem = memory::Memory::new();
+        mem.write_byte(0x8000, 0x11);
+        mem.write_byte(0xFE00, 0x22);
+        // OAM is locked during mode 2
+        cycle_until_ly(&mut mem.gpu, 1);
+        assert_eq!(mem.gpu.get_lcd_mode_flag(), LCDMode::UsingOAMPeriod);
+        assert_eq!((mem.read_byte(0x8000), mem.read_byte(0xFE00)), (0x11, 0xFF));
+        mem.write_byte(0xFE00, 0x33);
+        // VRAM and OAM are locked during mode 3
+        cycle_until_mode(&mut mem.gpu, LCDMode::UsingVRAMPeriod);
+        assert_eq!((mem.read_byte(0x8000), mem.read_byte(0xFE00)), (0xFF, 0xFF));
+        mem.write_byte(0x8000, 0x44);
+        cycle_until_mode(&mut mem.gpu, LCDMode::HBlankPeriod);
+        assert_eq!((mem.read_byte(0x8000), mem.read_byte(0xFE00)), (0x11, 0x22));
+    }
 }
\ No newline at end of file
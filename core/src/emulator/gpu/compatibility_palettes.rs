@@ -0,0 +1,296 @@
+/// Contains the palettes a Gameboy Color uses to colorize monochrome (DMG) games.
+/// The CGB bootrom picks a palette based on a checksum of the cartridge title,
+/// but only for games published by Nintendo. The player can override this
+/// by holding a button combination while the Gameboy logo is shown.
+
+use serde::{Serialize, Deserialize};
+
+use super::draw_helper::Color;
+
+/// Colors for the background and the two object palettes,
+/// from lightest to darkest, as RGB555
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Debug)]
+pub struct CompatibilityPalette {
+    pub bg: [u16; 4],
+    pub obj0: [u16; 4],
+    pub obj1: [u16; 4],
+}
+
+impl CompatibilityPalette {
+    /// Build the palette of an entry in the bootrom combination table
+    fn from_combination(index: usize) -> CompatibilityPalette {
+        let (obj0, obj1, bg) = COMBINATIONS[index];
+        return CompatibilityPalette {
+            bg: PALETTES[bg as usize],
+            obj0: PALETTES[obj0 as usize],
+            obj1: PALETTES[obj1 as usize],
+        };
+    }
+
+    pub fn get_bg_colors(&self) -> [Color; 4] {
+        return to_colors(&self.bg);
+    }
+
+    pub fn get_obj0_colors(&self) -> [Color; 4] {
+        return to_colors(&self.obj0);
+    }
+
+    pub fn get_obj1_colors(&self) -> [Color; 4] {
+        return to_colors(&self.obj1);
+    }
+}
+
+fn to_colors(palette: &[u16; 4]) -> [Color; 4] {
+    let mut colors = [Color { r: 0, g: 0, b: 0, a: 255 }; 4];
+    for (i, val) in palette.iter().enumerate() {
+        colors[i] = Color::from_rgb555(*val);
+    }
+    return colors;
+}
+
+/// The palettes in the CGB bootrom, as RGB555 from lightest to darkest
+const PALETTES: [[u16; 4]; 30] = [
+    [0x7FFF, 0x32BF, 0x00D0, 0x0000], // 0, brown
+    [0x639F, 0x4279, 0x15B0, 0x04CB], // 1, dark brown
+    [0x7FFF, 0x6E31, 0x454A, 0x0000], // 2, dark blue
+    [0x7FFF, 0x1BEF, 0x0200, 0x0000], // 3, light green
+    [0x7FFF, 0x421F, 0x1CF2, 0x0000], // 4, red
+    [0x7FFF, 0x5294, 0x294A, 0x0000], // 5, grayscale
+    [0x7FFF, 0x03FF, 0x012F, 0x0000], // 6, yellow
+    [0x7FFF, 0x03EF, 0x01D6, 0x0000],
+    [0x7FFF, 0x42B5, 0x3DC8, 0x0000],
+    [0x7E74, 0x03FF, 0x0180, 0x0000],
+    [0x67FF, 0x77AC, 0x1A13, 0x2D6B],
+    [0x7ED6, 0x4BFF, 0x2175, 0x0000],
+    [0x53FF, 0x4A5F, 0x7E52, 0x0000], // 12, pastel
+    [0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0],
+    [0x03ED, 0x7FFF, 0x255F, 0x0000],
+    [0x036A, 0x021F, 0x03FF, 0x7FFF],
+    [0x7FFF, 0x01DF, 0x0112, 0x0000],
+    [0x231F, 0x035F, 0x00F2, 0x0009],
+    [0x7FFF, 0x03EA, 0x011F, 0x0000], // 18, dark green
+    [0x299F, 0x001A, 0x000C, 0x0000],
+    [0x7FFF, 0x027F, 0x001F, 0x0000],
+    [0x7FFF, 0x03E0, 0x0206, 0x0120],
+    [0x7FFF, 0x7EEB, 0x001F, 0x7C00],
+    [0x7FFF, 0x3FFF, 0x7E00, 0x001F],
+    [0x7FFF, 0x03FF, 0x001F, 0x0000], // 24, orange
+    [0x03FF, 0x001F, 0x000C, 0x0000],
+    [0x7FFF, 0x033F, 0x0193, 0x0000],
+    [0x0000, 0x4200, 0x037F, 0x7FFF], // 27, inverted
+    [0x7FFF, 0x7E8C, 0x7C00, 0x0000], // 28, light blue
+    [0x7FFF, 0x1BEF, 0x6180, 0x0000], // 29, green
+];
+
+/// The palette combinations in the CGB bootrom, as (OBJ0, OBJ1, BG) indices into `PALETTES`
+const COMBINATIONS: [(u8, u8, u8); 51] = [
+    ( 4,  4, 29), (18, 18, 18), (20, 20, 20), (24, 24, 24), ( 9,  9,  9), ( 0,  0,  0),
+    (27, 27, 27), ( 5,  5,  5), (12, 12, 12), (26, 26, 26), (16,  8,  8), ( 4, 28, 28),
+    ( 4,  2,  2), ( 3,  4,  4), ( 4, 29, 29), (28,  4, 28), ( 2, 17,  2), (16, 16,  8),
+    ( 4,  4,  7), ( 4,  4, 18), ( 4,  4, 20), (19, 19,  9), ( 4,  4, 24), (17, 17,  2),
+    ( 4,  4,  2), ( 4,  4,  3), (28, 28,  0), ( 3,  3,  0), ( 0,  0,  1), (18, 22, 18),
+    (20, 22, 20), (24, 22, 24), (16, 22,  8), (17,  4, 13), (28,  0, 14), (28,  4, 15),
+    (19, 22,  9), (16, 28, 10), ( 4, 23, 28), (17, 22,  2), ( 4,  0,  2), ( 4, 28,  3),
+    (28,  3,  0), ( 3, 28,  4), (21, 28,  4), ( 3, 28,  0), (25,  3, 28), ( 0, 28,  8),
+    ( 4,  3, 28), (28,  3,  6), ( 4, 28, 29),
+];
+
+/// Title checksums known by the CGB bootrom. The checksums from index
+/// `FIRST_AMBIGUOUS_CHECKSUM` on are shared by several titles, and also
+/// have to match the fourth title letter in `TITLE_FOURTH_LETTERS`
+const TITLE_CHECKSUMS: [u8; 94] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58,
+    0xC9, 0x3E, 0x70, 0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95,
+    0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97, 0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6,
+    0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE, 0x0C, 0x29, 0xE8, 0xB7,
+    0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F, 0x6B,
+    0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D,
+    0xF4, 0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF,
+    0x0D, 0xF4, 0xB3,
+];
+
+const FIRST_AMBIGUOUS_CHECKSUM: usize = 65;
+const TITLE_FOURTH_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+/// The entry in `COMBINATIONS` for every title checksum
+const CHECKSUM_COMBINATIONS: [u8; 94] = [
+     0,  4,  5, 35, 34,  3, 31, 15, 10,  5, 19, 36,  7, 37, 30, 44,
+    21, 32, 31, 20,  5, 33, 13, 14,  5, 29,  5, 18,  9,  3,  2, 26,
+    25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34,
+     5, 42,  6,  5, 33, 25, 42, 42, 40,  2, 16, 25, 42, 42,  5,  0,
+    39, 36, 22, 25,  6, 32, 12, 36, 11, 39, 18, 39, 24, 31, 50, 17,
+    46,  6, 27,  0, 47, 41, 41,  0,  0, 19, 34, 23, 18, 29,
+];
+
+/// The combination used when the title is not recognized, same as the Right combination
+const DEFAULT_COMBINATION: usize = 0;
+
+/// The button combinations which can be held during the CGB boot animation
+/// to select a palette for a DMG game
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Debug)]
+pub enum PaletteCombo {
+    Up,
+    UpA,
+    UpB,
+    Left,
+    LeftA,
+    LeftB,
+    Down,
+    DownA,
+    DownB,
+    Right,
+    RightA,
+    RightB,
+}
+
+impl PaletteCombo {
+    /// Parse a combination name, e.g. "up", "left+a" or "right+b"
+    pub fn from_name(name: &str) -> Option<PaletteCombo> {
+        return match name.to_lowercase().as_str() {
+            "up" => Some(PaletteCombo::Up),
+            "up+a" => Some(PaletteCombo::UpA),
+            "up+b" => Some(PaletteCombo::UpB),
+            "left" => Some(PaletteCombo::Left),
+            "left+a" => Some(PaletteCombo::LeftA),
+            "left+b" => Some(PaletteCombo::LeftB),
+            "down" => Some(PaletteCombo::Down),
+            "down+a" => Some(PaletteCombo::DownA),
+            "down+b" => Some(PaletteCombo::DownB),
+            "right" => Some(PaletteCombo::Right),
+            "right+a" => Some(PaletteCombo::RightA),
+            "right+b" => Some(PaletteCombo::RightB),
+            _ => None,
+        }
+    }
+
+    /// Returns the index of the combination in the bootrom combination table
+    fn get_combination(&self) -> usize {
+        return match self {
+            PaletteCombo::Up => 5,
+            PaletteCombo::UpA => 43,
+            PaletteCombo::UpB => 28,
+            PaletteCombo::Left => 48,
+            PaletteCombo::LeftA => 40,
+            PaletteCombo::LeftB => 7,
+            PaletteCombo::Down => 8,
+            PaletteCombo::DownA => 3,
+            PaletteCombo::DownB => 49,
+            PaletteCombo::Right => DEFAULT_COMBINATION,
+            PaletteCombo::RightA => 1,
+            PaletteCombo::RightB => 6,
+        }
+    }
+
+    pub fn get_palette(&self) -> CompatibilityPalette {
+        return CompatibilityPalette::from_combination(self.get_combination());
+    }
+}
+
+/// Sum of the 16 title bytes at 0x0134 - 0x0143
+pub fn calculate_title_checksum(rom_header: &[u8]) -> u8 {
+    return rom_header[0x0134..0x0144].iter().fold(0u8, |sum, c| sum.wrapping_add(*c));
+}
+
+/// Returns true if the old or new licensee code in the header is Nintendo
+pub fn is_nintendo_licensee(rom_header: &[u8]) -> bool {
+    let old_licensee = rom_header[0x014B];
+    return old_licensee == 0x01 || (old_licensee == 0x33 && &rom_header[0x0144..0x0146] == b"01");
+}
+
+/// Find the entry of the title in `TITLE_CHECKSUMS`, like the CGB bootrom
+fn find_title_index(rom_header: &[u8]) -> Option<usize> {
+    // Only Nintendo games get a title specific palette
+    if !is_nintendo_licensee(rom_header) {
+        return None;
+    }
+    let checksum = calculate_title_checksum(rom_header);
+    let fourth_letter = rom_header[0x0137];
+    for (i, title_checksum) in TITLE_CHECKSUMS.iter().enumerate() {
+        if *title_checksum != checksum {
+            continue;
+        }
+        if i < FIRST_AMBIGUOUS_CHECKSUM || TITLE_FOURTH_LETTERS[i - FIRST_AMBIGUOUS_CHECKSUM] == fourth_letter {
+            return Some(i);
+        }
+    }
+    return None;
+}
+
+/// Select a palette from the cartridge title, like the CGB bootrom.
+/// `rom_header` is the first 0x150 bytes of the ROM
+pub fn select_title_palette(rom_header: &[u8]) -> CompatibilityPalette {
+    let combination = match find_title_index(rom_header) {
+        Some(i) => CHECKSUM_COMBINATIONS[i] as usize,
+        None => DEFAULT_COMBINATION,
+    };
+    return CompatibilityPalette::from_combination(combination);
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+
+    fn create_header(title: &[u8]) -> Vec<u8> {
+        let mut header = vec![0; 0x150];
+        header[0x0134..0x0134 + title.len()].copy_from_slice(title);
+        header[0x014B] = 0x01;
+        return header;
+    }
+
+    #[test]
+    fn title_palette_selection()
+    {
+        let mut header = create_header(b"POKEMON RED");
+        assert_eq!(calculate_title_checksum(&header), 0x14);
+        let palette = select_title_palette(&header);
+        assert_eq!(palette.bg, [0x7FFF, 0x421F, 0x1CF2, 0x0000]);
+        assert_eq!(palette.obj0, [0x7FFF, 0x1BEF, 0x0200, 0x0000]);
+        assert_eq!(palette.obj1, palette.bg);
+
+        // Only Nintendo titles are colorized
+        header[0x014B] = 0x33;
+        header[0x0144..0x0146].copy_from_slice(b"08");
+        assert_eq!(select_title_palette(&header), PaletteCombo::Right.get_palette());
+        header[0x0144..0x0146].copy_from_slice(b"01");
+        assert_eq!(select_title_palette(&header), palette);
+
+        // Unknown titles use the default palette
+        header[0x0134] = b'X';
+        assert_eq!(select_title_palette(&header), PaletteCombo::Right.get_palette());
+
+        assert_eq!(select_title_palette(&create_header(b"TETRIS")), PaletteCombo::DownA.get_palette());
+        let palette = select_title_palette(&create_header(b"POKEMON GREEN"));
+        assert_eq!(palette.bg, PaletteCombo::Right.get_palette().bg);
+        assert_eq!(palette.obj1, palette.bg);
+
+        assert_eq!(PaletteCombo::from_name("Left+B"), Some(PaletteCombo::LeftB));
+        assert_eq!(PaletteCombo::from_name("middle"), None);
+    }
+
+    #[test]
+    fn ambiguous_title_checksums()
+    {
+        // The checksum of POKEMON BLUE is shared, the fourth letter tells the titles apart
+        let header = create_header(b"POKEMON BLUE");
+        assert_eq!(calculate_title_checksum(&header), 0x61);
+        assert_eq!(find_title_index(&header), Some(72));
+        let palette = select_title_palette(&header);
+        assert_eq!(palette.bg, [0x7FFF, 0x7E8C, 0x7C00, 0x0000]);
+        assert_eq!(palette.obj0, [0x7FFF, 0x421F, 0x1CF2, 0x0000]);
+        assert_eq!(palette.obj1, palette.bg);
+
+        // Same checksum, but a different fourth letter
+        let header = create_header(b"POKXMON BLUE\xED");
+        assert_eq!(calculate_title_checksum(&header), 0x61);
+        assert_eq!(find_title_index(&header), None);
+        assert_eq!(select_title_palette(&header), PaletteCombo::Right.get_palette());
+
+        // Titles in the ambiguous part of the table can share a palette with unique ones
+        let header = create_header(b"DONKEYKONGLAND");
+        assert_eq!(find_title_index(&header), Some(73));
+        assert_eq!(select_title_palette(&header), select_title_palette(&create_header(b"DONKEYKONGLAND 3")));
+        assert_eq!(find_title_index(&create_header(b"KID ICARUS")), Some(76));
+        assert_eq!(find_title_index(&create_header(b"SUPER MARIOLAND")), Some(66));
+    }
+}
@@ -4,7 +4,8 @@
 /// 
 /// The TileData class contains the Tile data located in 0x8000 - 0x97FF,
 /// in a nice RGB format. All of the tiles are continually updated here,
-/// even if the tiledata select only currently renders one of them
+/// even if the tiledata select only currently renders one of them.
+/// The Gameboy Color has a second VRAM bank with another 384 tiles
 /// 
 /// The TileMap combines the data from the TileData class and the
 /// tilemap representation in the GPU to generate a 32x32 Tile Atlas.
@@ -15,6 +16,8 @@
 /// The SpriteMap uses the sprite 
 /// The entire system is designed so that drawing a line can be done entirely with memcpys.
 
+use serde::{Serialize, Deserialize};
+
 /// Represents a 4 byte RGBA color
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub struct Color {
@@ -24,6 +27,20 @@ pub struct Color {
     pub a: u8,
 }
 
+impl Color {
+    /// Convert a little-endian RGB555 color, used by the CGB and SGB
+    pub fn from_rgb555(color: u16) -> Color {
+        // Expand the 5 bit channels to 8 bits
+        let expand = |c: u16| -> u8 { ((c << 3) | (c >> 2)) as u8 };
+        return Color { 
+            r: expand(color & 0x1F), 
+            g: expand((color >> 5) & 0x1F), 
+            b: expand((color >> 10) & 0x1F), 
+            a: 255 
+        };
+    }
+}
+
 const COLOR_TRANSPARENT : Color = Color {r:255, g:255, b:255, a: 0};
 const COLOR_WHITE: Color = Color {r:255, g:255, b:255, a: 255};
 const COLOR_LIGHTGRAY: Color = Color {r:170, g:170, b:170, a: 255};
@@ -52,7 +69,7 @@ impl Tile {
     /// Generate the tile from the Tile representation in GPU VRAM.
     /// The lower and upper bits for the color are in separate bytes,
     /// which makes the parsing somewhat convoluted.
-    fn generate(&mut self, addr: usize, gpu_vram : &[u8; 8192]) {
+    fn generate(&mut self, addr: usize, gpu_vram : &[u8]) {
         let data : &[u8] = &gpu_vram[addr..addr+16];
         let mut y = 0;
         let mut a : u8;
@@ -75,36 +92,62 @@ impl Tile {
     }
 }
 
-/// Represents the 384 tiles in GPU memory
+/// Represents the 2*384 tiles in GPU memory
 pub struct TileData {
-    // Represents the tile data stored between 0x8000-0x97FF
+    // Represents the tile data stored between 0x8000-0x97FF, in both VRAM banks
     pub tiles: Vec<Tile>
 }
 
 impl TileData {
     pub fn new() -> TileData {
-        TileData {tiles : vec![Tile::new(); 384]}
+        TileData {tiles : vec![Tile::new(); 384*2]}
     }
 
     /// Return a tile based on tile id, depending on the tiledata select
-    pub fn get_tile(&self, tile_id : u8, tile_data_select: bool) -> &Tile {
+    pub fn get_tile(&self, tile_id : u8, tile_data_select: bool, vram_bank: usize) -> &Tile {
         if tile_data_select { // Return tiles representing 0x8000-0x8FFF
-            return &self.tiles[tile_id as usize];
+            return &self.tiles[vram_bank*384 + tile_id as usize];
         }
         else { // Return tiles representing 0x8800-0x97FF. Treat id as signed integer
-            return &self.tiles[(256+(tile_id as i8 as i16)) as usize];
+            return &self.tiles[vram_bank*384 + (256+(tile_id as i8 as i16)) as usize];
         }
     }
 
     /// Update the tile object which represents this memory address
-    pub fn generate_tile(&mut self, data_address : usize, gpu_vram : &[u8; 8192]) {
+    pub fn generate_tile(&mut self, data_address : usize, vram_bank: usize, gpu_vram : &[u8; 16384]) {
         let id = (data_address - 0x8000) / 16;
-        self.tiles[id].generate(id*16, gpu_vram);
+        self.tiles[vram_bank*384 + id].generate(vram_bank*0x2000 + id*16, gpu_vram);
+    }
+}
+
+/// Represents the CGB background map attributes, stored in VRAM bank 1
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct TileAttributes {
+    pub palette: usize,
+    pub vram_bank: usize,
+    pub flip_x: bool,
+    pub flip_y: bool,
+    pub bg_priority: bool, // BG colors 1-3 are drawn over sprites
+}
+
+impl TileAttributes {
+    pub fn new() -> TileAttributes {
+        TileAttributes { palette: 0, vram_bank: 0, flip_x: false, flip_y: false, bg_priority: false }
+    }
+
+    pub fn from_byte(val: u8) -> TileAttributes {
+        TileAttributes { 
+            palette: (val & 0b0000_0111) as usize,
+            vram_bank: ((val & 0b0000_1000) >> 3) as usize,
+            flip_x: val & 0b0010_0000 == 0b0010_0000,
+            flip_y: val & 0b0100_0000 == 0b0100_0000,
+            bg_priority: val & 0b1000_0000 == 0b1000_0000,
+        }
     }
 }
 
 /// Represents a Sprite, which is located in OAM memory
-#[derive(Copy, Clone, PartialEq, Debug)]
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub struct Sprite {
     pub x : usize,
     pub y : usize,
@@ -113,12 +156,15 @@ pub struct Sprite {
     pub flip_y : bool,
     pub flip_x : bool,
     pub palette_select : bool,
+    pub cgb_palette : usize,
+    pub vram_bank : usize,
 }
 
 impl Sprite {
     pub fn new() -> Sprite {
         Sprite { x: 0, y: 0, tile_id: 0, below_background : false, 
-                flip_y: false, flip_x: false, palette_select: false}
+                flip_y: false, flip_x: false, palette_select: false,
+                cgb_palette: 0, vram_bank: 0}
     }
 
     pub fn generate_by_id(&mut self, id: usize, oam_ram : &[u8; 160]) {
@@ -134,6 +180,9 @@ impl Sprite {
         self.flip_y = options & 0b0100_0000 == 0b0100_0000;
         self.flip_x = options & 0b0010_0000 == 0b0010_0000;
         self.palette_select = options & 0b0001_0000 == 0b0001_0000;
+        // CGB only
+        self.vram_bank = ((options & 0b0000_1000) >> 3) as usize;
+        self.cgb_palette = (options & 0b0000_0111) as usize;
     }
 }
 
@@ -168,6 +217,10 @@ pub struct DrawHelper {
     pub background_palette : Palette,
     pub sprite_palette_1: Palette,
     pub sprite_palette_2: Palette,
+
+    // CGB color palettes, generated from the palette RAM
+    pub cgb_background_palettes: [Palette; 8],
+    pub cgb_sprite_palettes: [Palette; 8],
 }
 
 impl DrawHelper {
@@ -178,19 +231,23 @@ impl DrawHelper {
             background_palette : Palette::new_bg(), 
             sprite_palette_1 : Palette::new_sprite(),
             sprite_palette_2 : Palette::new_sprite(),
+            cgb_background_palettes: [Palette::new_bg(); 8],
+            cgb_sprite_palettes: [Palette::new_sprite(); 8],
         }
     }
 
-    pub fn generate_all_from_mem(&mut self, gpu_vram: &[u8; 8192], oam_ram : &[u8; 160]) {
+    pub fn generate_all_from_mem(&mut self, gpu_vram: &[u8; 16384], oam_ram : &[u8; 160]) {
         for addr in 0x8000..0xFE9F {
-            self.update_by_vram_address(addr, gpu_vram, oam_ram);
+            self.update_by_vram_address(addr, 0, gpu_vram, oam_ram);
+            self.update_by_vram_address(addr, 1, gpu_vram, oam_ram);
         }
     }
 
     /// Generate the tiles from the active tiledata in VRAM
-    pub fn generate_tiles(&mut self, gpu_vram: &[u8; 8192]) {
+    pub fn generate_tiles(&mut self, gpu_vram: &[u8; 16384]) {
         for id in 0..384 {
-            self.tile_data.generate_tile(0x8000+id*16, gpu_vram);
+            self.tile_data.generate_tile(0x8000+id*16, 0, gpu_vram);
+            self.tile_data.generate_tile(0x8000+id*16, 1, gpu_vram);
         }
     }
 
@@ -201,11 +258,11 @@ impl DrawHelper {
     }
 
     /// Update the drawing acceleration structures based on a VRAM write
-    pub fn update_by_vram_address(&mut self, address : usize, gpu_vram: &[u8; 8192], oam_ram : &[u8; 160]) {
+    pub fn update_by_vram_address(&mut self, address : usize, vram_bank: usize, gpu_vram: &[u8; 16384], oam_ram : &[u8; 160]) {
         match address {
-            0x8000 ..= 0x87FF => { self.update_tiledata_by_address(address, gpu_vram) } // Tile set #1: tiles 0-127
-            0x8800 ..= 0x8FFF => { self.update_tiledata_by_address(address, gpu_vram) } // Tile set #1: tiles 128-255, Tile set #0: tiles -1 to -128
-            0x9000 ..= 0x97FF => { self.update_tiledata_by_address(address, gpu_vram) } // Tile set #0: tiles 0-127
+            0x8000 ..= 0x87FF => { self.update_tiledata_by_address(address, vram_bank, gpu_vram) } // Tile set #1: tiles 0-127
+            0x8800 ..= 0x8FFF => { self.update_tiledata_by_address(address, vram_bank, gpu_vram) } // Tile set #1: tiles 128-255, Tile set #0: tiles -1 to -128
+            0x9000 ..= 0x97FF => { self.update_tiledata_by_address(address, vram_bank, gpu_vram) } // Tile set #0: tiles 0-127
             0x9800 ..= 0x9BFF => { } // Tile map #0
             0x9C00 ..= 0x9FFF => { } // Tile map #1
             0xFE00 ..= 0xFE9F => { self.update_sprites_by_address(address, oam_ram) } // OAM
@@ -218,37 +275,90 @@ impl DrawHelper {
     }
 
     /// Update the tilemap based on a VRAM write. 0x9800-0x9FFF
-    pub fn update_tiledata_by_address(&mut self, address : usize, gpu_vram: &[u8; 8192])
+    pub fn update_tiledata_by_address(&mut self, address : usize, vram_bank: usize, gpu_vram: &[u8; 16384])
     {
-        self.tile_data.generate_tile(address, gpu_vram);
+        self.tile_data.generate_tile(address, vram_bank, gpu_vram);
+    }
+
+    /// Regenerate the CGB palettes from the 64 byte palette RAMs
+    pub fn update_cgb_palettes(&mut self, bg_palette_ram: &[u8; 64], sprite_palette_ram: &[u8; 64]) {
+        for i in 0..8 {
+            self.cgb_background_palettes[i].update_cgb(&bg_palette_ram[i*8..i*8+8]);
+            self.cgb_sprite_palettes[i].update_cgb(&sprite_palette_ram[i*8..i*8+8]);
+        }
     }
 
     pub fn get_bg_tile_pixel(&self, tile_id: u8, x: usize, y: usize, tile_data_select: bool) -> Color {
-        let tile = self.tile_data.get_tile(tile_id, tile_data_select);
+        let tile = self.tile_data.get_tile(tile_id, tile_data_select, 0);
         return tile.get_pixel(x, y, &self.background_palette);
     }
 
-    pub fn get_sprite_tile_pixel(&self, tile_id: u8, mut x: usize, mut y: usize, tile_data_select: bool, sprite: &Sprite, flip_y_ignore: bool) -> Color {
-        let tile = self.tile_data.get_tile(tile_id, tile_data_select);
+    /// Returns the color id (0-3) of a background tile pixel, with the CGB attributes applied
+    pub fn get_bg_tile_color_id(&self, tile_id: u8, mut x: usize, mut y: usize, tile_data_select: bool, attributes: &TileAttributes) -> u8 {
+        let tile = self.tile_data.get_tile(tile_id, tile_data_select, attributes.vram_bank);
+        if attributes.flip_x {
+            x = 7 - x;
+        }
+        if attributes.flip_y {
+            y = 7 - y;
+        }
+        return tile.pixels[y*8+x];
+    }
+
+    /// Returns the color of a background color id. CGB mode uses the palette from the tile attributes
+    pub fn get_bg_color(&self, color_id: u8, attributes: &TileAttributes, cgb_mode: bool) -> Color {
+        if cgb_mode {
+            return self.cgb_background_palettes[attributes.palette].get_color(color_id);
+        }
+        return self.background_palette.get_color(color_id);
+    }
+
+    /// Returns the color id (0-3) of a sprite tile pixel. 0 is transparent
+    pub fn get_sprite_tile_color_id(&self, tile_id: u8, mut x: usize, mut y: usize, sprite: &Sprite, flip_y_ignore: bool, cgb_mode: bool) -> u8 {
+        let vram_bank = if cgb_mode { sprite.vram_bank } else { 0 };
+        let tile = self.tile_data.get_tile(tile_id, true, vram_bank);
         if sprite.flip_x {
             x = 7 - x;
         }
         if sprite.flip_y && !flip_y_ignore {
             y = 7 - y;
         }
+        return tile.pixels[y*8+x];
+    }
+
+    /// Returns the DMG shade (0-3) the background palette selects for a color id
+    pub fn get_bg_shade(&self, color_id: u8) -> u8 {
+        return self.background_palette.get_shade_index(color_id);
+    }
+
+    /// Returns the DMG shade (0-3) the sprite palette selects for a color id
+    pub fn get_sprite_shade(&self, color_id: u8, sprite: &Sprite) -> u8 {
+        if !sprite.palette_select {
+            return self.sprite_palette_1.get_shade_index(color_id);
+        }
+        return self.sprite_palette_2.get_shade_index(color_id);
+    }
+
+    /// Returns the color of a sprite color id, based on the sprite palette
+    pub fn get_sprite_color(&self, color_id: u8, sprite: &Sprite, cgb_mode: bool) -> Color {
+        if cgb_mode {
+            return self.cgb_sprite_palettes[sprite.cgb_palette].get_color(color_id);
+        }
         if !sprite.palette_select {
-            return tile.get_pixel(x, y, &self.sprite_palette_1);
+            return self.sprite_palette_1.get_color(color_id);
         }
         else {
-            return tile.get_pixel(x, y, &self.sprite_palette_2);
+            return self.sprite_palette_2.get_color(color_id);
         }
     }
 }
 
 
+#[derive(Copy, Clone)]
 pub struct Palette {
     palette: [Color; 4],
-    map: [Color; 4]
+    map: [Color; 4],
+    shades: [u8; 4], // The shade (0-3) the DMG palette register selects for each color id
 }
 
 /// Represents a 4 color palette
@@ -257,6 +367,7 @@ impl Palette {
         return Palette { 
             palette: [COLOR_WHITE, COLOR_LIGHTGRAY, COLOR_DARKGRAY, COLOR_BLACK],
             map: [COLOR_WHITE, COLOR_LIGHTGRAY, COLOR_DARKGRAY, COLOR_BLACK],
+            shades: [0, 1, 2, 3],
         }
     }
 
@@ -264,6 +375,7 @@ impl Palette {
         return Palette { 
             palette: [COLOR_TRANSPARENT, COLOR_LIGHTGRAY, COLOR_DARKGRAY, COLOR_BLACK],
             map: [COLOR_WHITE, COLOR_LIGHTGRAY, COLOR_DARKGRAY, COLOR_BLACK],
+            shades: [0, 1, 2, 3],
         }
     }
 
@@ -271,7 +383,15 @@ impl Palette {
         return self.palette[val as usize];
     }
 
+    /// Returns the DMG shade (0-3) of a color id, independent of the colors of the shades
+    pub fn get_shade_index(&self, val: u8) -> u8 {
+        return self.shades[val as usize];
+    }
+
     pub fn update_bg(&mut self, palette_flag: u8) {
+        for i in 0..4 {
+            self.shades[i] = (palette_flag >> (i*2)) & 0b11;
+        }
         self.palette[0] = self.get_color_from_bits(palette_flag & 0b0000_0011);
         self.palette[1] = self.get_color_from_bits((palette_flag & 0b0000_1100) >> 2);
         self.palette[2] = self.get_color_from_bits((palette_flag & 0b0011_0000) >> 4);
@@ -279,6 +399,9 @@ impl Palette {
     }
 
     pub fn update_sprite(&mut self, palette_flag: u8) {
+        for i in 1..4 {
+            self.shades[i] = (palette_flag >> (i*2)) & 0b11;
+        }
         self.palette[1] = self.get_color_from_bits((palette_flag & 0b0000_1100) >> 2);
         self.palette[2] = self.get_color_from_bits((palette_flag & 0b0011_0000) >> 4);
         self.palette[3] = self.get_color_from_bits((palette_flag & 0b1100_0000) >> 6);
@@ -288,4 +411,28 @@ impl Palette {
         return self.map[color_val as usize];
     }
 
+    /// Returns one of the 4 shades, regardless of the palette register
+    pub fn get_shade(&self, shade: u8) -> Color {
+        return self.map[shade as usize];
+    }
+
+    /// Set the 4 shades the DMG palette register selects from.
+    /// Used to colorize DMG games like the Gameboy Color does
+    pub fn set_color_map(&mut self, map: [Color; 4]) {
+        self.map = map;
+    }
+
+    /// Restore the default gray shades
+    pub fn reset_color_map(&mut self) {
+        self.map = [COLOR_WHITE, COLOR_LIGHTGRAY, COLOR_DARKGRAY, COLOR_BLACK];
+    }
+
+    /// Update the palette from 4 little-endian RGB555 CGB colors
+    pub fn update_cgb(&mut self, data: &[u8]) {
+        for i in 0..4 {
+            let color = data[i*2] as u16 | (data[i*2+1] as u16) << 8;
+            self.palette[i] = Color::from_rgb555(color);
+        }
+    }
+
 }
\ No newline at end of file
@@ -0,0 +1,329 @@
+/// Dot based pixel FIFO renderer, an alternative to drawing whole scanlines.
+///
+/// Mode 3 is stepped a dot at a time. The background fetcher reads a tile row in 6 dots
+/// and pushes the 8 pixels to the background FIFO once it is empty, while one pixel is shifted
+/// out to the LCD every dot. The registers are read when they are used, so mid-scanline
+/// writes to SCX, SCY, LCDC, WX and the palettes are visible like on hardware.
+///
+/// The length of mode 3 varies like on hardware:
+/// * The first tile fetch of a line is done twice, which takes 6 dots
+/// * SCX % 8 pixels are discarded at the start of the line
+/// * When the window starts, the FIFO is cleared and the fetcher restarts from the window tilemap
+/// * A sprite waits for the background fetch in progress and then takes 6 dots to fetch,
+///   6 to 11 dots in total
+///
+/// Sprites are fetched from left to right, and a sprite pixel only replaces a transparent pixel
+/// in the sprite FIFO. This gives the DMG priority of the lowest X coordinate. On CGB, the lowest OAM index wins.
+
+use std::collections::VecDeque;
+
+use serde::{Serialize, Deserialize};
+
+use super::GPU;
+use super::draw_helper::{Color, Sprite, TileAttributes};
+
+const SCREEN_WIDTH: usize = 160;
+/// Dots taken by a tile fetch, before the pixels can be pushed
+const FETCH_DOTS: u8 = 6;
+/// Dots taken to fetch a sprite, once the background fetch is done
+const SPRITE_FETCH_DOTS: u8 = 6;
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
+struct BgPixel {
+    color_id: u8,
+    attributes: TileAttributes,
+}
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
+struct SpritePixel {
+    color_id: u8,
+    oam_index: usize,
+    sprite: Sprite,
+}
+
+/// The state of the renderer during mode 3
+#[derive(Serialize, Deserialize)]
+pub struct PixelFifo {
+    bg_fifo: VecDeque<BgPixel>,
+    sprite_fifo: VecDeque<SpritePixel>,
+    fetcher_step: u8,
+    fetcher_x: usize,
+    tile_id: u8,
+    attributes: TileAttributes,
+    tile_row: [BgPixel; 8],
+    startup_dots: u8,
+    discard: usize,
+    fetching_window: bool,
+    // Sprites on this line which have not been fetched yet, with their OAM index
+    sprites: Vec<(usize, Sprite)>,
+    fetched_sprite: Option<(usize, Sprite)>,
+    sprite_fetch_dots: u8,
+    lx: usize,
+    pub dots: usize,
+    pub line_done: bool,
+    pub window_drawn: bool,
+    line: Vec<u8>, // 160 pixels, 3 channels
+    line_shades: Vec<u8>, // The DMG shades (0-3) of the line
+}
+
+impl PixelFifo {
+    pub fn new() -> PixelFifo {
+        let blank_pixel = BgPixel { color_id: 0, attributes: TileAttributes::new() };
+        PixelFifo {
+            bg_fifo: VecDeque::with_capacity(16),
+            sprite_fifo: VecDeque::with_capacity(16),
+            fetcher_step: 0,
+            fetcher_x: 0,
+            tile_id: 0,
+            attributes: TileAttributes::new(),
+            tile_row: [blank_pixel; 8],
+            startup_dots: 0,
+            discard: 0,
+            fetching_window: false,
+            sprites: Vec::with_capacity(10),
+            fetched_sprite: None,
+            sprite_fetch_dots: 0,
+            lx: 0,
+            dots: 0,
+            // Nothing is drawn until the next line is started
+            line_done: true,
+            window_drawn: false,
+            line: vec![255; SCREEN_WIDTH*3],
+            line_shades: vec![0; SCREEN_WIDTH],
+        }
+    }
+
+    /// Returns the last drawn line, 160 pixels with 3 channels
+    pub fn get_line(&self) -> &[u8] {
+        return &self.line;
+    }
+
+    /// Returns the DMG shades of the last drawn line, used by the Super Gameboy
+    pub fn get_line_shades(&self) -> &[u8] {
+        return &self.line_shades;
+    }
+
+    fn set_pixel(&mut self, color: Color, shade: u8) {
+        self.line[self.lx*3+0] = color.r;
+        self.line[self.lx*3+1] = color.g;
+        self.line[self.lx*3+2] = color.b;
+        self.line_shades[self.lx] = shade;
+    }
+}
+
+impl GPU {
+    /// Start drawing a line. Selects the first 10 sprites on the line, in OAM order
+    pub(super) fn start_pixel_fifo_line(&mut self) {
+        let height = if self.options.sprite_tile_size() { 16 } else { 8 };
+        let line = self.ly as usize + 16;
+        let fifo = &mut self.pixel_fifo;
+        fifo.sprites.clear();
+        for (i, sprite) in self.draw_helper.sprite_data.sprites.iter().enumerate() {
+            if fifo.sprites.len() < 10 && line >= sprite.y && line < sprite.y + height {
+                fifo.sprites.push((i, *sprite));
+            }
+        }
+        fifo.bg_fifo.clear();
+        fifo.sprite_fifo.clear();
+        fifo.fetcher_step = 0;
+        fifo.fetcher_x = 0;
+        fifo.startup_dots = FETCH_DOTS;
+        fifo.discard = (self.scroll_x % 8) as usize;
+        fifo.fetching_window = false;
+        fifo.fetched_sprite = None;
+        fifo.sprite_fetch_dots = 0;
+        fifo.lx = 0;
+        fifo.dots = 0;
+        fifo.line_done = false;
+        fifo.window_drawn = false;
+    }
+
+    /// Step the renderer by a dot
+    pub(super) fn step_pixel_fifo(&mut self) {
+        self.pixel_fifo.dots += 1;
+        // The first tile fetch of the line is thrown away
+        if self.pixel_fifo.startup_dots > 0 {
+            self.pixel_fifo.startup_dots -= 1;
+            return;
+        }
+        // Shifting pixels is paused during a sprite fetch
+        if self.pixel_fifo.sprite_fetch_dots > 0 {
+            self.pixel_fifo.sprite_fetch_dots -= 1;
+            if self.pixel_fifo.sprite_fetch_dots == 0 {
+                self.merge_fetched_sprite();
+            }
+            return;
+        }
+        // The window restarts the fetcher when it is reached
+        let window_visible = self.should_draw_window() && (self.options.bg_enable() || self.cgb_mode);
+        if window_visible && !self.pixel_fifo.fetching_window && self.pixel_fifo.discard == 0 && self.pixel_fifo.lx + 7 >= self.window_x as usize {
+            let fifo = &mut self.pixel_fifo;
+            fifo.bg_fifo.clear();
+            fifo.fetcher_step = 0;
+            fifo.fetcher_x = 0;
+            fifo.fetching_window = true;
+            fifo.window_drawn = true;
+            // With WX below 7, the left part of the window is outside the screen
+            fifo.discard = 7usize.saturating_sub(self.window_x as usize);
+        }
+        // Is a sprite starting at the current pixel?
+        if self.options.sprite_enable() {
+            let lx = self.pixel_fifo.lx;
+            let next_sprite = self.pixel_fifo.sprites.iter().enumerate()
+                .filter(|(_, (_, sprite))| sprite.x <= lx + 8)
+                .min_by_key(|(_, (_, sprite))| sprite.x)
+                .map(|(i, _)| i);
+            if let Some(i) = next_sprite {
+                // The sprite waits until the background fetcher reads the tile data high byte,
+                // which is done during the first dot of the sprite fetch
+                if self.pixel_fifo.bg_fifo.is_empty() || self.pixel_fifo.fetcher_step < FETCH_DOTS - 1 {
+                    self.step_bg_fetcher();
+                }
+                else {
+                    if self.pixel_fifo.fetcher_step < FETCH_DOTS {
+                        self.step_bg_fetcher();
+                    }
+                    let sprite = self.pixel_fifo.sprites.remove(i);
+                    self.pixel_fifo.fetched_sprite = Some(sprite);
+                    self.pixel_fifo.sprite_fetch_dots = SPRITE_FETCH_DOTS - 1;
+                }
+                return;
+            }
+        }
+        self.shift_pixel();
+        self.step_bg_fetcher();
+    }
+
+    fn step_bg_fetcher(&mut self) {
+        let step = self.pixel_fifo.fetcher_step;
+        if step < FETCH_DOTS {
+            match step {
+                1 => { self.fetch_tile_id(); }
+                5 => { self.fetch_tile_row(); }
+                _ => { }
+            }
+            self.pixel_fifo.fetcher_step += 1;
+        }
+        if self.pixel_fifo.fetcher_step == FETCH_DOTS && self.pixel_fifo.bg_fifo.is_empty() {
+            let fifo = &mut self.pixel_fifo;
+            fifo.bg_fifo.extend(fifo.tile_row.iter());
+            fifo.fetcher_x += 1;
+            fifo.fetcher_step = 0;
+        }
+    }
+
+    fn fetch_tile_id(&mut self) {
+        let (x, y, tilemap_select) = if self.pixel_fifo.fetching_window {
+            (self.pixel_fifo.fetcher_x, self.internal_window_ly, self.get_window_tile_map())
+        }
+        else {
+            let x = (self.scroll_x as usize / 8 + self.pixel_fifo.fetcher_x) % 32;
+            let y = (self.ly as usize + self.scroll_y as usize) % 256;
+            (x, y, self.get_bg_tile_map())
+        };
+        self.pixel_fifo.tile_id = self.get_tilemap_id(x % 32, (y / 8) % 32, tilemap_select);
+        self.pixel_fifo.attributes = self.get_tilemap_attributes(x % 32, (y / 8) % 32, tilemap_select);
+    }
+
+    fn fetch_tile_row(&mut self) {
+        let attributes = self.pixel_fifo.attributes;
+        let mut row = if self.pixel_fifo.fetching_window {
+            self.internal_window_ly % 8
+        }
+        else {
+            (self.ly as usize + self.scroll_y as usize) % 8
+        };
+        if attributes.flip_y {
+            row = 7 - row;
+        }
+        let tile_id = self.pixel_fifo.tile_id;
+        let tile_offset = if self.get_tile_data() { tile_id as usize * 16 } else { (0x1000 + (tile_id as i8 as isize)*16) as usize };
+        let address = attributes.vram_bank*0x2000 + tile_offset + row*2;
+        let (low, high) = (self.video_ram[address], self.video_ram[address + 1]);
+        for x in 0..8 {
+            let bit = if attributes.flip_x { x } else { 7 - x };
+            let color_id = ((low >> bit) & 1) | (((high >> bit) & 1) << 1);
+            self.pixel_fifo.tile_row[x] = BgPixel { color_id: color_id, attributes: attributes };
+        }
+    }
+
+    /// Mix the fetched sprite into the sprite FIFO. Only transparent pixels are replaced,
+    /// or on CGB the pixels of sprites with a higher OAM index
+    fn merge_fetched_sprite(&mut self) {
+        let (oam_index, sprite) = match self.pixel_fifo.fetched_sprite.take() {
+            Some(sprite) => { sprite }
+            None => { return; }
+        };
+        let height = if self.options.sprite_tile_size() { 16 } else { 8 };
+        let mut row = self.ly as usize + 16 - sprite.y;
+        if sprite.flip_y {
+            row = height - 1 - row;
+        }
+        let tile_id = if height == 16 { (sprite.tile_id & 0xFE) as usize + row / 8 } else { sprite.tile_id as usize };
+        let vram_bank = if self.cgb_mode { sprite.vram_bank } else { 0 };
+        let address = vram_bank*0x2000 + tile_id*16 + (row % 8)*2;
+        let (low, high) = (self.video_ram[address], self.video_ram[address + 1]);
+
+        let transparent = SpritePixel { color_id: 0, oam_index: usize::MAX, sprite: sprite };
+        // The sprite starts left of the current pixel when it is partly outside the screen
+        let start = sprite.x as isize - 8 - self.pixel_fifo.lx as isize;
+        for x in 0..8 {
+            let position = start + x as isize;
+            if position < 0 {
+                continue;
+            }
+            let position = position as usize;
+            while self.pixel_fifo.sprite_fifo.len() <= position {
+                self.pixel_fifo.sprite_fifo.push_back(transparent);
+            }
+            let bit = if sprite.flip_x { x } else { 7 - x };
+            let color_id = ((low >> bit) & 1) | (((high >> bit) & 1) << 1);
+            let existing = self.pixel_fifo.sprite_fifo[position];
+            let replace = existing.color_id == 0 || (self.cgb_mode && oam_index < existing.oam_index);
+            if color_id != 0 && replace {
+                self.pixel_fifo.sprite_fifo[position] = SpritePixel { color_id: color_id, oam_index: oam_index, sprite: sprite };
+            }
+        }
+    }
+
+    /// Shift a pixel out to the LCD, mixing the background and sprite FIFOs
+    fn shift_pixel(&mut self) {
+        let bg_pixel = match self.pixel_fifo.bg_fifo.pop_front() {
+            Some(pixel) => { pixel }
+            None => { return; }
+        };
+        // Fine scroll and the window left of the screen
+        if self.pixel_fifo.discard > 0 {
+            self.pixel_fifo.discard -= 1;
+            return;
+        }
+        let sprite_pixel = self.pixel_fifo.sprite_fifo.pop_front();
+        let (color, shade) = self.mix_pixel(bg_pixel, sprite_pixel);
+        self.pixel_fifo.set_pixel(color, shade);
+        self.pixel_fifo.lx += 1;
+        if self.pixel_fifo.lx == SCREEN_WIDTH {
+            self.pixel_fifo.line_done = true;
+        }
+    }
+
+    /// Returns the color of the pixel, and its DMG shade
+    fn mix_pixel(&self, bg_pixel: BgPixel, sprite_pixel: Option<SpritePixel>) -> (Color, u8) {
+        // On DMG, LCDC bit 0 blanks the background. On CGB it only removes the background priority
+        let bg_enable = self.options.bg_enable();
+        let bg_color_id = if bg_enable || self.cgb_mode { bg_pixel.color_id } else { 0 };
+        if let Some(sprite_pixel) = sprite_pixel {
+            if sprite_pixel.color_id != 0 && self.options.sprite_enable() {
+                let bg_priority = bg_enable && (sprite_pixel.sprite.below_background || bg_pixel.attributes.bg_priority);
+                if bg_color_id == 0 || !bg_priority {
+                    let color = self.draw_helper.get_sprite_color(sprite_pixel.color_id, &sprite_pixel.sprite, self.cgb_mode);
+                    return (color, self.draw_helper.get_sprite_shade(sprite_pixel.color_id, &sprite_pixel.sprite));
+                }
+            }
+        }
+        if !bg_enable && !self.cgb_mode {
+            return (self.draw_helper.background_palette.get_shade(0), 0);
+        }
+        let color = self.draw_helper.get_bg_color(bg_color_id, &bg_pixel.attributes, self.cgb_mode);
+        return (color, self.draw_helper.get_bg_shade(bg_color_id));
+    }
+}
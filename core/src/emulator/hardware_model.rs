@@ -0,0 +1,222 @@
+/// Contains the state each Gameboy model is left in after its bootrom has run.
+/// When no bootrom is used, this state is set up directly so that games
+/// which detect the hardware through the CPU registers behave correctly.
+
+use serde::{Serialize, Deserialize};
+
+use super::cpu::registers::Registers;
+use super::gpu::compatibility_palettes;
+
+/// The Gameboy models which can be emulated
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Debug)]
+pub enum HardwareModel {
+    /// Early original Gameboy
+    Dmg0,
+    /// Original Gameboy
+    Dmg,
+    /// Gameboy Pocket and Gameboy Light
+    Mgb,
+    /// Super Gameboy
+    Sgb,
+    /// Super Gameboy 2
+    Sgb2,
+    /// Gameboy Color
+    Cgb,
+    /// Gameboy Advance running Gameboy Color games
+    Agb,
+}
+
+impl HardwareModel {
+    /// Parse a model name, e.g. "dmg", "sgb2" or "cgb"
+    pub fn from_name(name: &str) -> Option<HardwareModel> {
+        return match name.to_lowercase().as_str() {
+            "dmg0" => Some(HardwareModel::Dmg0),
+            "dmg" => Some(HardwareModel::Dmg),
+            "mgb" => Some(HardwareModel::Mgb),
+            "sgb" => Some(HardwareModel::Sgb),
+            "sgb2" => Some(HardwareModel::Sgb2),
+            "cgb" => Some(HardwareModel::Cgb),
+            "agb" => Some(HardwareModel::Agb),
+            _ => None,
+        }
+    }
+
+    /// Select the model a game is best played on from the cartridge header.
+    /// CGB games run on the CGB, SGB games on the SGB and the rest on the DMG
+    pub fn select_for_cartridge(supports_cgb: bool, supports_sgb: bool) -> HardwareModel {
+        if supports_cgb {
+            return HardwareModel::Cgb;
+        }
+        if supports_sgb {
+            return HardwareModel::Sgb;
+        }
+        return HardwareModel::Dmg;
+    }
+
+    /// Returns true for models with Gameboy Color hardware
+    pub fn is_cgb(&self) -> bool {
+        return matches!(self, HardwareModel::Cgb | HardwareModel::Agb);
+    }
+
+    /// Returns true for the Super Gameboy models
+    pub fn is_sgb(&self) -> bool {
+        return matches!(self, HardwareModel::Sgb | HardwareModel::Sgb2);
+    }
+
+    /// Returns true if the bootrom leaves the Nintendo logo in VRAM
+    pub fn leaves_logo_in_vram(&self) -> bool {
+        return matches!(self, HardwareModel::Dmg0 | HardwareModel::Dmg | HardwareModel::Mgb);
+    }
+
+    /// CPU registers after the bootrom.
+    /// `rom_header` is the first 0x150 bytes of the ROM, `cgb_mode` is false
+    /// when a CGB runs a DMG game
+    pub fn get_post_boot_registers(&self, rom_header: &[u8], cgb_mode: bool) -> Registers {
+        // The DMG bootrom ends by comparing the header checksum, which sets the H and C flags
+        let header_checksum = rom_header[0x014D];
+        let dmg_flags = if header_checksum == 0 { 0x80 } else { 0xB0 };
+        let mut regs = match self {
+            HardwareModel::Dmg0 => {
+                Registers {a: 0x01, b: 0xFF, c: 0x13, d: 0x00, e: 0xC1, h: 0x84, l: 0x03, f: 0x00, pc: 0x100, sp: 0xFFFE}
+            }
+            HardwareModel::Dmg => {
+                Registers {a: 0x01, b: 0x00, c: 0x13, d: 0x00, e: 0xD8, h: 0x01, l: 0x4D, f: dmg_flags, pc: 0x100, sp: 0xFFFE}
+            }
+            // A=0xFF is used by games to detect the Gameboy Pocket
+            HardwareModel::Mgb => {
+                Registers {a: 0xFF, b: 0x00, c: 0x13, d: 0x00, e: 0xD8, h: 0x01, l: 0x4D, f: dmg_flags, pc: 0x100, sp: 0xFFFE}
+            }
+            HardwareModel::Sgb => {
+                Registers {a: 0x01, b: 0x00, c: 0x14, d: 0x00, e: 0x00, h: 0xC0, l: 0x60, f: 0x00, pc: 0x100, sp: 0xFFFE}
+            }
+            HardwareModel::Sgb2 => {
+                Registers {a: 0xFF, b: 0x00, c: 0x14, d: 0x00, e: 0x00, h: 0xC0, l: 0x60, f: 0x00, pc: 0x100, sp: 0xFFFE}
+            }
+            // A=0x11 is used by games to detect the Gameboy Color
+            HardwareModel::Cgb | HardwareModel::Agb if cgb_mode => {
+                Registers {a: 0x11, b: 0x00, c: 0x00, d: 0xFF, e: 0x56, h: 0x00, l: 0x0D, f: 0x80, pc: 0x100, sp: 0xFFFE}
+            }
+            // DMG games on the CGB. B and HL are left over from the palette selection
+            HardwareModel::Cgb | HardwareModel::Agb => {
+                let (b, hl) = if compatibility_palettes::is_nintendo_licensee(rom_header) {
+                    (compatibility_palettes::calculate_title_checksum(rom_header), 0x991A)
+                } else {
+                    (0x00, 0x007C)
+                };
+                Registers {a: 0x11, b: b, c: 0x00, d: 0x00, e: 0x08, h: (hl >> 8) as u8, l: hl as u8, f: 0x80, pc: 0x100, sp: 0xFFFE}
+            }
+        };
+        if *self == HardwareModel::Agb {
+            // The GBA bootrom runs an extra INC B, used by games to detect the GBA
+            regs.b = regs.b.wrapping_add(1);
+            let zero = if regs.b == 0 { 0x80 } else { 0x00 };
+            let half_carry = if regs.b & 0x0F == 0 { 0x20 } else { 0x00 };
+            regs.f = zero | half_carry | (regs.f & 0x10);
+        }
+        return regs;
+    }
+
+    /// The internal 16 bit divider counter after the bootrom. DIV is the upper byte.
+    /// The SGB and CGB bootroms take a variable amount of time, these are typical values
+    pub fn get_post_boot_divider(&self) -> u16 {
+        return match self {
+            HardwareModel::Dmg0 => 0x1830,
+            HardwareModel::Dmg | HardwareModel::Mgb => 0xABCC,
+            HardwareModel::Sgb | HardwareModel::Sgb2 => 0xD85C,
+            HardwareModel::Cgb | HardwareModel::Agb => 0x267C,
+        }
+    }
+
+    /// NR52 after the bootrom. The SGB bootrom does not play the boot sound
+    pub fn get_post_boot_nr52(&self) -> u8 {
+        return if self.is_sgb() { 0xF0 } else { 0xF1 };
+    }
+}
+
+/// The ® symbol drawn next to the logo by the DMG bootrom
+const REGISTERED_SYMBOL: [u8; 8] = [0x3C, 0x42, 0xB9, 0xA5, 0xB9, 0xA5, 0x42, 0x3C];
+
+/// Generate the tile data the DMG bootrom leaves at 0x8000 - 0x81AF.
+/// Tile 0 is empty, tiles 1-24 are the Nintendo logo from the cartridge
+/// header scaled up 2x, and tile 25 is the ® symbol
+pub fn generate_logo_tiles(rom_header: &[u8]) -> Vec<u8> {
+    let mut tiles = vec![0; 26*16];
+    // Every logo byte is two rows of four pixels, one row per nibble.
+    // Each pixel is doubled horizontally and each row vertically
+    for (i, byte) in rom_header[0x0104..0x0134].iter().enumerate() {
+        for (j, nibble) in [byte >> 4, byte & 0x0F].iter().enumerate() {
+            let mut row = 0u8;
+            for bit in 0..4 {
+                if nibble & (1 << bit) != 0 {
+                    row |= 0b11 << (bit * 2);
+                }
+            }
+            let address = 16 + i*8 + j*4;
+            tiles[address] = row;
+            tiles[address + 2] = row;
+        }
+    }
+    for (i, row) in REGISTERED_SYMBOL.iter().enumerate() {
+        tiles[25*16 + i*2] = *row;
+    }
+    return tiles;
+}
+
+/// Tilemap entries for the logo, as (address, tile id)
+pub fn get_logo_tilemap() -> Vec<(u16, u8)> {
+    let mut entries = Vec::new();
+    for i in 0..12 {
+        entries.push((0x9904 + i as u16, 1 + i));
+        entries.push((0x9924 + i as u16, 13 + i));
+    }
+    entries.push((0x9910, 25));
+    return entries;
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+
+    #[test]
+    fn post_boot_registers()
+    {
+        let mut header = vec![0; 0x150];
+        header[0x014D] = 0x42;
+        let dmg = HardwareModel::Dmg.get_post_boot_registers(&header, false);
+        assert_eq!((dmg.a, dmg.f), (0x01, 0xB0));
+        assert_eq!(HardwareModel::Mgb.get_post_boot_registers(&header, false).a, 0xFF);
+        assert_eq!(HardwareModel::Sgb2.get_post_boot_registers(&header, false).a, 0xFF);
+        let cgb = HardwareModel::Cgb.get_post_boot_registers(&header, true);
+        assert_eq!((cgb.a, cgb.b, cgb.f), (0x11, 0x00, 0x80));
+        let agb = HardwareModel::Agb.get_post_boot_registers(&header, true);
+        assert_eq!((agb.a, agb.b, agb.f), (0x11, 0x01, 0x00));
+
+        // A zero header checksum clears the H and C flags on the DMG
+        header[0x014D] = 0x00;
+        assert_eq!(HardwareModel::Dmg.get_post_boot_registers(&header, false).f, 0x80);
+
+        // DMG games on the CGB
+        header[0x0134..0x013F].copy_from_slice(b"POKEMON RED");
+        header[0x014B] = 0x01;
+        let cgb = HardwareModel::Cgb.get_post_boot_registers(&header, false);
+        assert_eq!((cgb.b, cgb.h, cgb.l), (0x14, 0x99, 0x1A));
+        header[0x014B] = 0x00;
+        let cgb = HardwareModel::Cgb.get_post_boot_registers(&header, false);
+        assert_eq!((cgb.b, cgb.h, cgb.l), (0x00, 0x00, 0x7C));
+    }
+
+    #[test]
+    fn logo_tiles()
+    {
+        let mut header = vec![0; 0x150];
+        header[0x0104] = 0xCE;
+        header[0x0105] = 0xED;
+        let tiles = generate_logo_tiles(&header);
+        // 0xC = 1100, the leftmost pixel is bit 3 and becomes bits 7-6
+        assert_eq!(&tiles[16..24], &[0xF0, 0, 0xF0, 0, 0xFC, 0, 0xFC, 0]);
+        assert_eq!(tiles[24], 0xFC);
+        assert_eq!(tiles[25*16], 0x3C);
+        assert_eq!(get_logo_tilemap().len(), 25);
+    }
+}
@@ -0,0 +1,160 @@
+// Important memory locations (CGB only):
+// HDMA1, HDMA2 : 0xFF51 - 0xFF52. Source address, high and low byte. Lower 4 bits are ignored
+// HDMA3, HDMA4 : 0xFF53 - 0xFF54. VRAM destination address, high and low byte. Only bits 12-4 are used
+// HDMA5 : 0xFF55. Transfer length/mode/start. Length is (val & 0x7F + 1) * 16 bytes
+// Bit 7 = 0: General purpose DMA, the entire transfer happens at once
+// Bit 7 = 1: H-Blank DMA, 16 bytes are transferred at the start of every H-Blank
+// Writing bit 7 = 0 during an H-Blank DMA cancels it
+
+use serde::{Serialize, Deserialize};
+
+/// Represents the CGB VRAM DMA registers. The transfers themselves are
+/// done by Memory, as they need access to the entire memory map
+#[derive(Serialize, Deserialize)]
+pub struct Hdma {
+    source: u16,
+    destination: u16, // Offset into VRAM, 0x0000 - 0x1FF0
+    remaining_blocks: u8, // 16 byte blocks left to transfer
+    pub hblank_transfer_active: bool,
+    pub general_transfer_requested: bool,
+}
+
+impl Hdma {
+    pub fn new() -> Hdma {
+        Hdma { source: 0, destination: 0, remaining_blocks: 0,
+            hblank_transfer_active: false, general_transfer_requested: false }
+    }
+
+    pub fn read_byte(&self, address : usize) -> u8 {
+        match address {
+            0xFF51 ..= 0xFF54 => { return 0xFF; } // Write only
+            // Bit 7 is 0 while an H-Blank transfer is active. Reads 0xFF when done
+            0xFF55 => {
+                let length = self.remaining_blocks.wrapping_sub(1) & 0x7F;
+                return if self.hblank_transfer_active { length } else { 0x80 | length };
+            }
+            _ => panic!("Invalid memory address encountered")
+        }
+    }
+
+    pub fn write_byte(&mut self, address : usize, val: u8) {
+        match address {
+            0xFF51 => { self.source = (val as u16) << 8 | (self.source & 0x00FF); }
+            0xFF52 => { self.source = (self.source & 0xFF00) | (val & 0xF0) as u16; }
+            0xFF53 => { self.destination = ((val & 0x1F) as u16) << 8 | (self.destination & 0x00FF); }
+            0xFF54 => { self.destination = (self.destination & 0xFF00) | (val & 0xF0) as u16; }
+            0xFF55 => {
+                if self.hblank_transfer_active && val & 0x80 == 0 { // Cancel H-Blank transfer
+                    self.hblank_transfer_active = false;
+                    return;
+                }
+                self.remaining_blocks = (val & 0x7F) + 1;
+                if val & 0x80 == 0x80 {
+                    self.hblank_transfer_active = true;
+                }
+                else {
+                    self.general_transfer_requested = true;
+                }
+            }
+            _ => panic!("Invalid memory address encountered")
+        }
+    }
+
+    pub fn get_remaining_blocks(&self) -> u8 {
+        return self.remaining_blocks;
+    }
+
+    /// Advance to the next 16 byte block.
+    /// Returns the source address and the VRAM address of the current block
+    pub fn next_block(&mut self) -> (u16, u16) {
+        let addresses = (self.source, 0x8000 | self.destination);
+        self.source = self.source.wrapping_add(16);
+        self.destination = (self.destination + 16) & 0x1FF0;
+        self.remaining_blocks = self.remaining_blocks.saturating_sub(1);
+        if self.remaining_blocks == 0 {
+            self.hblank_transfer_active = false;
+        }
+        return addresses;
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::super::memory;
+
+    /// Run the devices until the start of the next H-Blank.
+    /// Returns the machine cycles taken
+    fn run_until_hblank(mem: &mut memory::Memory) -> usize {
+        let mut cycles = 0;
+        while mem.read_byte(0xFF41) & 0b11 == 0 {
+            mem.cycle_devices(1);
+            cycles += 1;
+        }
+        while mem.read_byte(0xFF41) & 0b11 != 0 {
+            mem.cycle_devices(1);
+            cycles += 1;
+        }
+        return cycles;
+    }
+
+    #[test]
+    fn hdma_transfers()
+    {
+        let mut mem = memory::Memory::new();
+        mem.set_cgb_mode(true);
+        for i in 0..0x40 {
+            mem.write_byte(0xC000 + i, i as u8);
+        }
+        // General purpose DMA of 32 bytes from 0xC000 to 0x8100
+        mem.write_byte(0xFF51, 0xC0);
+        mem.write_byte(0xFF52, 0x00);
+        mem.write_byte(0xFF53, 0x81);
+        mem.write_byte(0xFF54, 0x00);
+        mem.write_byte(0xFF55, 0x01);
+        assert_eq!(mem.read_byte(0x8100), 0x00);
+        assert_eq!(mem.read_byte(0x811F), 0x1F);
+        assert_eq!(mem.read_byte(0xFF55), 0xFF);
+
+        // H-Blank DMA of 48 bytes from 0xC010 to 0x9000, 16 bytes per line
+        mem.write_byte(0xFF51, 0xC0);
+        mem.write_byte(0xFF52, 0x10);
+        mem.write_byte(0xFF53, 0x10);
+        mem.write_byte(0xFF54, 0x00);
+        mem.write_byte(0xFF55, 0x82);
+        assert_eq!(mem.read_byte(0xFF55), 0x02);
+        run_until_hblank(&mut mem);
+        assert_eq!(mem.read_byte(0x9000), 0x10);
+        assert_eq!(mem.read_byte(0x9010), 0x00);
+        assert_eq!(mem.read_byte(0xFF55), 0x01);
+        // The CPU is halted for 8 machine cycles per block
+        assert_eq!(run_until_hblank(&mut mem), 114 - 8);
+        assert_eq!(mem.read_byte(0x9010), 0x20);
+        // Cancel the transfer
+        mem.write_byte(0xFF55, 0x00);
+        assert_eq!(mem.read_byte(0xFF55), 0x80);
+        run_until_hblank(&mut mem);
+        assert_eq!(mem.read_byte(0x9020), 0x00);
+    }
+
+    #[test]
+    fn speed_switch()
+    {
+        let mut mem = memory::Memory::new();
+        assert_eq!(mem.try_speed_switch(), false);
+        mem.set_cgb_mode(true);
+        mem.write_byte(0xFF4D, 0x01);
+        assert_eq!(mem.read_byte(0xFF4D), 0x7F);
+        assert_eq!(mem.try_speed_switch(), true);
+        assert_eq!(mem.read_byte(0xFF4D), 0xFE);
+
+        // A scanline takes twice as many machine cycles in double speed mode
+        run_until_hblank(&mut mem);
+        assert_eq!(run_until_hblank(&mut mem), 228);
+        mem.write_byte(0xFF4D, 0x01);
+        mem.try_speed_switch();
+        assert_eq!(mem.read_byte(0xFF4D), 0x7E);
+        run_until_hblank(&mut mem);
+        assert_eq!(run_until_hblank(&mut mem), 114);
+    }
+}
@@ -12,7 +12,16 @@
 /// A depressed key has value 0 for the bit
 /// 
 /// The systems asks for a keypress to be read by writing either 
-/// 0x10 (bit 4) or 0x20 (bit 5) to JOYPAD
+/// 0x10 (bit 4) or 0x20 (bit 5) to JOYPAD. A cleared bit selects the column,
+/// when both are selected the columns are combined and when neither is
+/// selected no key reads as pressed. Bit 7 and 6 always read 1.
+/// 
+/// A JOYPAD interrupt is requested when a selected line goes from high to low,
+/// either from a key press or from selecting a column with a held key
+/// 
+/// The Super Gameboy supports up to 4 joypads. When several are enabled,
+/// the current joypad id can be read with neither column selected,
+/// and the next joypad is selected when bit 5 goes high
 
 use super::super::emulator::KeyPress;
 
@@ -24,29 +33,66 @@ pub struct Joypad {
     key_column_select: u8, // Bit 4/5
     // 0: Right, left, up, down, 1: A, b, select, start
     key_columns: [u8; 2],
+    // SGB multiplayer. Only the first joypad is connected
+    player_count: u8,
+    current_player: u8,
+    pub request_interrupt: bool,
 }
 
 impl Joypad {
     pub fn new() -> Joypad {
-        Joypad { key_column_select: 0, key_columns: [0xF, 0xF]}
+        Joypad { key_column_select: 0, key_columns: [0xF, 0xF], player_count: 1, current_player: 0, request_interrupt: false }
     }
 
     pub fn write_byte(&mut self, joyp: u8) {
+        let previous_lines = self.get_selected_lines();
+        let previous_select = self.key_column_select;
         self.key_column_select = joyp & 0x30;
+        if self.player_count > 1 && previous_select & 0x20 == 0 && self.key_column_select & 0x20 == 0x20 {
+            self.current_player = (self.current_player + 1) % self.player_count;
+        }
+        self.check_interrupt(previous_lines);
     }
      
     pub fn read_byte(&self) -> u8 {
         //println!("r1: {0:#010b}, r2: {1:#010b}", self.key_columns[0], self.key_columns[1]);
-        return match self.key_column_select {
-            0x10 => self.key_columns[0],
-            0x20 => self.key_columns[1],
-            _ => 0xFF,
+        if self.player_count > 1 && self.key_column_select == 0x30 {
+            return 0xF0 | (0x0F - self.current_player);
+        }
+        return 0xC0 | self.key_column_select | self.get_selected_lines();
+    }
+
+    /// Returns true if a key in a selected column is pressed, which wakes the CPU from STOP
+    pub fn is_selected_key_pressed(&self) -> bool {
+        return self.get_selected_lines() != 0x0F;
+    }
+
+    /// The lower nibble of JOYP, the selected columns ANDed together
+    fn get_selected_lines(&self) -> u8 {
+        if self.player_count > 1 && self.current_player != 0 {
+            return 0x0F; // Disconnected joypad
+        }
+        let mut lines = 0x0F;
+        if self.key_column_select & 0x10 == 0 {
+            lines &= self.key_columns[1];
+        }
+        if self.key_column_select & 0x20 == 0 {
+            lines &= self.key_columns[0];
+        }
+        return lines;
+    }
+
+    /// Request an interrupt if any selected line went from high to low
+    fn check_interrupt(&mut self, previous_lines: u8) {
+        if previous_lines & !self.get_selected_lines() != 0 {
+            self.request_interrupt = true;
         }
     }
 
     /// Set key bit to pressed (0)
     pub fn press_key(&mut self, key: KeyPress) {
         //println!("key-press: {:?}", key);
+        let previous_lines = self.get_selected_lines();
         match key {
             KeyPress::Right =>    { self.key_columns[1] &= !(1 << 0) } // Bit 0
             KeyPress::Left =>     { self.key_columns[1] &= !(1 << 1) } // Bit 1
@@ -57,6 +103,7 @@ impl Joypad {
             KeyPress::Select =>   { self.key_columns[0] &= !(1 << 2) } // Bit 2
             KeyPress::Start =>    { self.key_columns[0] &= !(1 << 3) } // Bit 3
         }
+        self.check_interrupt(previous_lines);
     }
 
     /// Set key bit to unpressed (1)
@@ -73,6 +120,12 @@ impl Joypad {
         }
     }
 
+    /// Set the number of SGB joypads (1, 2 or 4), requested through MLT_REQ
+    pub fn set_player_count(&mut self, player_count: u8) {
+        self.player_count = player_count;
+        self.current_player = 0;
+    }
+
     pub fn clear_all_keys(&mut self) {
         self.key_columns[0] = 0x0F;
         self.key_columns[1] = 0x0F;
@@ -83,7 +136,7 @@ impl Joypad {
 mod test
 {
     use super::Joypad;
-    use super::super::KeyPress;
+    use super::super::{Emulator, KeyPress};
 
     #[test]
     fn joypad_test()
@@ -92,29 +145,97 @@ mod test
         joypad.write_byte(0x10);
         // Test START, A, B, Select
         joypad.press_key(KeyPress::Start);
-        assert_eq!(joypad.read_byte(), 0b0000_0111);
+        assert_eq!(joypad.read_byte(), 0b1101_0111);
         joypad.press_key(KeyPress::A);
         joypad.press_key(KeyPress::B);
         joypad.press_key(KeyPress::Select);
-        assert_eq!(joypad.read_byte(), 0b0000_0000);
+        assert_eq!(joypad.read_byte(), 0b1101_0000);
         // Test switching
         joypad.write_byte(0x20);
-        assert_eq!(joypad.read_byte(), 0b0000_1111);
+        assert_eq!(joypad.read_byte(), 0b1110_1111);
         // Test Down, Up, Left, Right
         joypad.press_key(KeyPress::Down);
-        assert_eq!(joypad.read_byte(), 0b0000_0111);
+        assert_eq!(joypad.read_byte(), 0b1110_0111);
         joypad.press_key(KeyPress::Up);
         joypad.press_key(KeyPress::Left);
         joypad.press_key(KeyPress::Right);
-        assert_eq!(joypad.read_byte(), 0b0000_0000);
+        assert_eq!(joypad.read_byte(), 0b1110_0000);
         // Test clearing
         joypad.clear_all_keys();
-        assert_eq!(joypad.read_byte(), 0b0000_1111);
+        assert_eq!(joypad.read_byte(), 0b1110_1111);
         joypad.write_byte(0x10);
-        assert_eq!(joypad.read_byte(), 0b0000_1111);
+        assert_eq!(joypad.read_byte(), 0b1101_1111);
         // Test edge case, no column selected
         // This should return 0xFF
+        joypad.write_byte(0x30);
+        assert_eq!(joypad.read_byte(), 0xFF);
+    }
+
+    #[test]
+    fn both_columns_selected()
+    {
+        let mut joypad = Joypad::new();
         joypad.write_byte(0x00);
+        assert_eq!(joypad.read_byte(), 0b1100_1111);
+        // The columns are ANDed together
+        joypad.press_key(KeyPress::A);
+        joypad.press_key(KeyPress::Down);
+        assert_eq!(joypad.read_byte(), 0b1100_0110);
+        // Nothing reads as pressed with neither column selected
+        joypad.write_byte(0x30);
         assert_eq!(joypad.read_byte(), 0xFF);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn joypad_interrupt()
+    {
+        let mut joypad = Joypad::new();
+        joypad.write_byte(0x20);
+        // Keys in the unselected column do not request an interrupt
+        joypad.press_key(KeyPress::A);
+        assert_eq!(joypad.request_interrupt, false);
+        joypad.press_key(KeyPress::Left);
+        assert_eq!(joypad.request_interrupt, true);
+        // Releasing a key is a low to high transition
+        joypad.request_interrupt = false;
+        joypad.clear_key(KeyPress::Left);
+        assert_eq!(joypad.request_interrupt, false);
+        // Selecting a column with a held key pulls its line low
+        joypad.write_byte(0x10);
+        assert_eq!(joypad.request_interrupt, true);
+        // A line which is already low does not request another interrupt
+        joypad.request_interrupt = false;
+        joypad.write_byte(0x00);
+        joypad.press_key(KeyPress::Right);
+        joypad.press_key(KeyPress::Right);
+        assert_eq!(joypad.request_interrupt, false);
+    }
+
+    #[test]
+    fn stop_wakes_on_input()
+    {
+        let mut data = vec![0; 0x8000];
+        let code = [
+            0x3E, 0x20, // LD A 0x20
+            0xE0, 0x00, // LD (JOYP) A, select the arrow keys
+            0x10, 0x00, // STOP
+            0x3E, 0x42, // LD A 0x42
+            0xE0, 0x80, // LD (0xFF80) A
+            0x18, 0xFE, // JR -2
+        ];
+        data[0x100..0x100 + code.len()].copy_from_slice(&code);
+        let mut emulator = Emulator::new();
+        emulator.load_rom_from_data(&data).unwrap();
+        emulator.run(1000);
+        assert_eq!(emulator.memory.read_byte(0xFF80), 0x00);
+        // Keys in the unselected column do not wake the CPU
+        emulator.press_key(KeyPress::Start);
+        emulator.run(1000);
+        assert_eq!(emulator.memory.read_byte(0xFF80), 0x00);
+        emulator.press_key(KeyPress::Up);
+        emulator.run(1000);
+        assert_eq!(emulator.memory.read_byte(0xFF80), 0x42);
+        // The joypad interrupt is requested as well
+        assert_eq!(emulator.memory.interrupt_handler.interrupt_flag & 0x10, 0x10);
+    }
+}
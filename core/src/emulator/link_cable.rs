@@ -0,0 +1,157 @@
+/// Connects two emulators in the same process through a link cable.
+/// The emulators are run in lockstep, so the serial transfers
+/// happen at the same point in time for both of them.
+
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use super::{Emulator, FrontendEvent};
+use super::serial::SerialDevice;
+
+/// The state shared by both ends of the cable
+struct LinkState {
+    // The byte in SB of each side, while it waits for an external clock
+    waiting_byte: [Option<u8>; 2],
+    // The byte clocked in from the other side, received at the next poll
+    incoming_byte: [Option<u8>; 2],
+}
+
+/// One end of the link cable, plugged into an emulator
+struct LinkPort {
+    state: Rc<RefCell<LinkState>>,
+    side: usize,
+}
+
+impl SerialDevice for LinkPort {
+    fn exchange_byte(&mut self, sent: u8) -> u8 {
+        let mut state = self.state.borrow_mut();
+        let other = 1 - self.side;
+        // The other side only receives the byte if it is waiting for the clock
+        match state.waiting_byte[other].take() {
+            Some(received) => {
+                state.incoming_byte[other] = Some(sent);
+                return received;
+            }
+            None => { return 0xFF; }
+        }
+    }
+
+    fn poll_external_clock(&mut self, sent: u8) -> Option<u8> {
+        let mut state = self.state.borrow_mut();
+        let received = state.incoming_byte[self.side].take();
+        if received.is_none() {
+            state.waiting_byte[self.side] = Some(sent);
+        }
+        return received;
+    }
+}
+
+/// Two emulators connected through a link cable
+pub struct LinkedEmulators {
+    pub emulators: [Emulator; 2],
+    state: Rc<RefCell<LinkState>>,
+    clock_cycles: [u64; 2], // Elapsed time of each emulator, in normal speed clock cycles
+}
+
+impl LinkedEmulators {
+    /// Connect two emulators. Any previously connected serial devices are replaced
+    pub fn new(mut first: Emulator, mut second: Emulator) -> LinkedEmulators {
+        let state = Rc::new(RefCell::new(LinkState { waiting_byte: [None; 2], incoming_byte: [None; 2] }));
+        first.connect_serial_device(Box::new(LinkPort { state: state.clone(), side: 0 }));
+        second.connect_serial_device(Box::new(LinkPort { state: state.clone(), side: 1 }));
+        return LinkedEmulators { emulators: [first, second], state: state, clock_cycles: [0; 2] };
+    }
+
+    /// Step the emulator which is behind in time by one instruction.
+    /// Returns the frontend event it requested, along with its index
+    pub fn step(&mut self) -> (usize, Option<FrontendEvent>) {
+        let i = if self.clock_cycles[0] <= self.clock_cycles[1] { 0 } else { 1 };
+        // A waiting side polls every cycle, so it is only marked as waiting while it is being stepped
+        self.state.borrow_mut().waiting_byte[i] = None;
+        let emulator = &mut self.emulators[i];
+        let machine_cycles = emulator.cpu.machine_cycles;
+        let event = emulator.step_frontend();
+        let clock_cycles_per_machine_cycle = if emulator.memory.double_speed { 2 } else { 4 };
+        self.clock_cycles[i] += (emulator.cpu.machine_cycles - machine_cycles) * clock_cycles_per_machine_cycle;
+        return (i, event);
+    }
+
+    /// Run both emulators until one of them requests a frontend event.
+    /// Returns the index of the emulator and the event
+    pub fn run_until_frontend_event(&mut self) -> (usize, FrontendEvent) {
+        if self.emulators[0].paused || self.emulators[1].paused {
+            return (0, FrontendEvent::Render);
+        }
+        loop {
+            if let (i, Some(event)) = self.step() {
+                return (i, event);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::LinkedEmulators;
+    use super::super::Emulator;
+
+    /// Create a ROM which sends `byte` through serial using the clock in `sc`,
+    /// then stores the received byte in 0xFF80
+    fn create_transfer_rom(byte: u8, sc: u8) -> Vec<u8> {
+        let mut data = vec![0; 0x8000];
+        let code = [
+            0x3E, byte, // LD A byte
+            0xE0, 0x01, // LD (SB) A
+            0x3E, sc,   // LD A sc
+            0xE0, 0x02, // LD (SC) A
+            0xF0, 0x02, // LD A (SC)
+            0xCB, 0x7F, // BIT 7 A
+            0x20, 0xFA, // JR NZ -6
+            0xF0, 0x01, // LD A (SB)
+            0xE0, 0x80, // LD (0xFF80) A
+            0x18, 0xFE, // JR -2
+        ];
+        data[0x100..0x100 + code.len()].copy_from_slice(&code);
+        return data;
+    }
+
+    #[test]
+    fn linked_transfer()
+    {
+        let mut master = Emulator::new();
+        master.load_rom_from_data(&create_transfer_rom(0x42, 0x81)).unwrap();
+        let mut slave = Emulator::new();
+        slave.load_rom_from_data(&create_transfer_rom(0x99, 0x80)).unwrap();
+        let mut linked = LinkedEmulators::new(master, slave);
+
+        // A transfer takes 4096 clock cycles
+        for _ in 0..2000 {
+            linked.step();
+        }
+        assert_eq!(linked.emulators[0].memory.read_byte(0xFF80), 0x99);
+        assert_eq!(linked.emulators[1].memory.read_byte(0xFF80), 0x42);
+        // Both sides receive the serial interrupt
+        assert_eq!(linked.emulators[0].memory.interrupt_handler.interrupt_flag & 0x08, 0x08);
+        assert_eq!(linked.emulators[1].memory.interrupt_handler.interrupt_flag & 0x08, 0x08);
+    }
+
+    #[test]
+    fn linked_transfer_after_load_state()
+    {
+        let mut master = Emulator::new();
+        master.load_rom_from_data(&create_transfer_rom(0x42, 0x81)).unwrap();
+        let mut slave = Emulator::new();
+        slave.load_rom_from_data(&create_transfer_rom(0x99, 0x80)).unwrap();
+        let mut linked = LinkedEmulators::new(master, slave);
+
+        // The link cable stays plugged in when a state is loaded
+        let state = linked.emulators[0].serialize();
+        linked.emulators[0].load_state(&state).unwrap();
+        for _ in 0..2000 {
+            linked.step();
+        }
+        assert_eq!(linked.emulators[0].memory.read_byte(0xFF80), 0x99);
+        assert_eq!(linked.emulators[1].memory.read_byte(0xFF80), 0x42);
+    }
+}
@@ -4,8 +4,13 @@ use super::joypad;
 use super::interrupts;
 use super::timer;
 use super::audio;
+use super::hdma;
+use super::sgb;
+use super::serial;
+use super::oam_dma::{self, Bus};
+use super::hardware_model::{self, HardwareModel};
 
-use std::io::{self, Write};
+use std::cmp;
 
 use serde::{Serialize, Deserialize};
 use serde_big_array::BigArray;
@@ -19,9 +24,19 @@ pub struct Memory
     pub rom: rom::Rom, // ROM, can be switched, 8kb ROM 0x0 - 0x7FFF, External RAM 0xA000 - BFFF
     pub gpu: gpu::GPU, // GPU/PPU. VRAM 0x8000 - 0x9FFF, OAM 0xFE00 - 0xFE9F
     pub joypad: joypad::Joypad,
+    pub sgb: sgb::Sgb, // Super Gameboy, receives commands through the joypad register
     pub audio_device: audio::AudioDevice,
     #[serde(with = "BigArray")]
-    working_ram: [u8; 8*KB], // 8kb, 0xC000 - 0xDFFFF
+    working_ram: [u8; 32*KB], // 8*4kb, 0xC000 - 0xDFFFF. Bank 1-7 are switchable on CGB
+    wram_bank: usize, // 0xFF70 SVBK, bank mapped to 0xD000 - 0xDFFF
+    pub cgb_mode: bool,
+    // CGB speed switching, 0xFF4D KEY1
+    pub double_speed: bool,
+    speed_switch_requested: bool,
+    // CGB VRAM DMA
+    pub hdma: hdma::Hdma,
+    dma_stall_cycles: usize, // Machine cycles where the CPU is halted by a VRAM DMA
+    pub oam_dma: oam_dma::OamDma,
     #[serde(with = "BigArray")]
     high_ram: [u8; 127], // 127 bytes, 0xFF80 - 0xFFFE
     #[serde(with = "BigArray")]
@@ -29,10 +44,7 @@ pub struct Memory
     // Interrupt related
     pub interrupt_handler : interrupts::InterruptHandler,
     pub timer: timer::Timer,
-    // Serial callback object.
-    // stdout implements the trait io::write, but also vector, which makes it useful for debugging
-    pub serial_buffer: Vec<u8>,
-    pub output_serial_to_stdout: bool,
+    pub serial: serial::Serial,
 }
 
 impl Memory {
@@ -42,14 +54,21 @@ impl Memory {
             rom: rom::Rom::new(),
             gpu: gpu::GPU::new(),
             joypad: joypad::Joypad::new(),
+            sgb: sgb::Sgb::new(),
             audio_device: audio::AudioDevice::new(),
-            working_ram: [1; 8*KB],
+            working_ram: [1; 32*KB],
+            wram_bank: 1,
+            cgb_mode: false,
+            double_speed: false,
+            speed_switch_requested: false,
+            hdma: hdma::Hdma::new(),
+            dma_stall_cycles: 0,
+            oam_dma: oam_dma::OamDma::new(),
             high_ram: [0; 127],
             device_ram: [0; 128],
             interrupt_handler : interrupts::InterruptHandler::new(),
             timer: timer::Timer::new(),
-            serial_buffer: Vec::new(),
-            output_serial_to_stdout: true,
+            serial: serial::Serial::new(),
         };
         mem.set_initial_values();
         return mem;
@@ -58,13 +77,22 @@ impl Memory {
     pub fn read_byte(&self, address: u16) -> u8
     {
         let address = address as usize;
+        if let Some(bus) = self.get_oam_dma_busy_bus(address) {
+            // OAM is disconnected, the other bus carries the transferred byte
+            return if bus == Bus::Oam { 0xFF } else { self.oam_dma.transferred_byte };
+        }
+        return self.read_byte_unlocked(address);
+    }
+
+    /// Read a byte without the restrictions of an OAM DMA transfer
+    fn read_byte_unlocked(&self, address: usize) -> u8 {
         match address {
             0x0000 ..= 0x7FFF | 
             0xA000 ..= 0xBFFF => { return self.rom.read_byte(address)} // ROM and External RAM in rom
             0x8000 ..= 0x9FFF |
             0xFE00 ..= 0xFE9F => { return self.gpu.read_byte(address)} // VRAM and OAM in GPU
-            0xC000 ..= 0xDFFF => { return self.working_ram[address - 0xC000]}
-            0xE000 ..= 0xFDFF => { return self.working_ram[address - 0xE000]} // Echo ram
+            0xC000 ..= 0xDFFF => { return self.working_ram[self.get_wram_index(address - 0xC000)]}
+            0xE000 ..= 0xFDFF => { return self.working_ram[self.get_wram_index(address - 0xE000)]} // Echo ram
             0xFEA0 ..= 0xFEFF => {} // Unused RAM
             0xFF0F => { return self.interrupt_handler.interrupt_flag }
             0xFF00 ..= 0xFF7F => { return self.read_byte_devices(address)}
@@ -75,6 +103,35 @@ impl Memory {
         return 0xFF;
     }
 
+    /// Returns the bus of the address if it is busy with an OAM DMA transfer
+    fn get_oam_dma_busy_bus(&self, address: usize) -> Option<Bus> {
+        if !self.oam_dma.active {
+            return None;
+        }
+        let bus = Bus::from_address(address);
+        if bus == Bus::Oam || bus == self.oam_dma.get_source_bus() {
+            return Some(bus);
+        }
+        return None;
+    }
+
+    /// Map an offset into 0xC000 - 0xDFFF to the working ram, using the selected bank
+    fn get_wram_index(&self, offset: usize) -> usize {
+        if offset < 0x1000 {
+            return offset;
+        }
+        return self.wram_bank*0x1000 + (offset - 0x1000);
+    }
+
+    /// Enable Gameboy Color hardware features, like banked VRAM/WRAM and color palettes
+    pub fn set_cgb_mode(&mut self, cgb_mode: bool) {
+        self.cgb_mode = cgb_mode;
+        self.gpu.cgb_mode = cgb_mode;
+        self.serial.cgb_mode = cgb_mode;
+        self.wram_bank = 1;
+        self.gpu.vram_bank = 0;
+    }
+
     pub fn read_word(&self, address: u16) -> u16 
     {
         (self.read_byte(address) as u16) | ((self.read_byte(address+1) as u16) << 8)
@@ -83,15 +140,17 @@ impl Memory {
     pub fn write_byte(&mut self, address: u16, value : u8)
     {
         let address = address as usize;
+        if self.get_oam_dma_busy_bus(address).is_some() {
+            return;
+        }
         match address {
             0x0000 ..= 0x7FFF | 
             0xA000 ..= 0xBFFF => { self.rom.write_byte(address, value)} // ROM and External RAM in rom
             0x8000 ..= 0x9FFF |
             0xFE00 ..= 0xFE9F => { self.gpu.write_byte(address, value)} // VRAM and OAM in GPU
-            0xC000 ..= 0xDFFF => { self.working_ram[address - 0xC000] = value}
-            0xE000 ..= 0xFDFF => { self.working_ram[address - 0xE000] = value} // Echo ram
+            0xC000 ..= 0xDFFF => { let i = self.get_wram_index(address - 0xC000); self.working_ram[i] = value}
+            0xE000 ..= 0xFDFF => { let i = self.get_wram_index(address - 0xE000); self.working_ram[i] = value} // Echo ram
             0xFEA0 ..= 0xFEFF => {} // Unused RAM
-            0xFF02 if value == 0x81 => { self.link_cable_serial(self.read_byte(0xFF01)); }
             0xFF0F => { self.interrupt_handler.interrupt_flag = value}
             0xFF00 ..= 0xFF7F => { self.write_byte_devices(address, value);}
             0xFF80 ..= 0xFFFE => { self.high_ram[address - 0xFF80] = value}
@@ -108,13 +167,25 @@ impl Memory {
     pub fn read_byte_devices(&self, address : usize) -> u8 {
         match address {
             // Joypad
-            0xFF01 => { return self.device_ram[1] }
             0xFF00 => { return self.joypad.read_byte() }
+            // Serial
+            0xFF01 ..= 0xFF02 => { return self.serial.read_byte(address) }
             // Timer 
             0xFF04 ..= 0xFF07 => { return self.timer.read_byte(address) }
 
             // PPU/GPU
+            0xFF46 => { return self.oam_dma.read_byte() }
             0xFF40 ..= 0xFF4B => { return self.gpu.read_byte(address) }
+            0xFF4F | 0xFF68 ..= 0xFF6B => { return self.gpu.read_byte(address) }
+
+            // CGB speed switch
+            0xFF4D if self.cgb_mode => { return (self.double_speed as u8) << 7 | 0b0111_1110 | self.speed_switch_requested as u8 }
+
+            // CGB VRAM DMA
+            0xFF51 ..= 0xFF55 if self.cgb_mode => { return self.hdma.read_byte(address) }
+
+            // CGB WRAM bank
+            0xFF70 if self.cgb_mode => { return 0b1111_1000 | self.wram_bank as u8 }
 
             // Audio Device
             0xFF10 ..= 0xFF3F => { return self.audio_device.read_byte(address)}
@@ -128,13 +199,38 @@ impl Memory {
 
     pub fn write_byte_devices(&mut self, address : usize, val: u8) {
         match address {
-            0xFF00 => { self.joypad.write_byte(val); }
+            0xFF00 => { 
+                self.joypad.write_byte(val); 
+                if self.sgb.enabled {
+                    self.sgb.write_joypad(val, &mut self.joypad);
+                }
+            }
+            // Serial
+            0xFF01 ..= 0xFF02 => { self.serial.write_byte(address, val); }
             // Timer
             0xFF04 ..= 0xFF07 => { self.timer.write_byte(address, val); }
             
             // PPU/GPU
-            0xFF46 => { self.gpu.write_byte(address, val); self.oam_dma_transfer(); }
+            0xFF46 => { self.oam_dma.write_byte(val); }
             0xFF40 ..= 0xFF4B => { self.gpu.write_byte(address, val); }
+            0xFF4F | 0xFF68 ..= 0xFF6B => { self.gpu.write_byte(address, val); }
+
+            // CGB speed switch, performed by the STOP instruction
+            0xFF4D if self.cgb_mode => { self.speed_switch_requested = val & 0x01 == 0x01; }
+
+            // CGB VRAM DMA
+            0xFF51 ..= 0xFF55 if self.cgb_mode => { 
+                self.hdma.write_byte(address, val); 
+                if self.hdma.general_transfer_requested {
+                    self.hdma.general_transfer_requested = false;
+                    while self.hdma.get_remaining_blocks() > 0 {
+                        self.hdma_transfer_block();
+                    }
+                }
+            }
+
+            // CGB WRAM bank, bank 0 selects bank 1
+            0xFF70 if self.cgb_mode => { self.wram_bank = cmp::max((val & 0x07) as usize, 1); }
 
             // Audio Device
             0xFF10 ..= 0xFF3F => { self.audio_device.write_byte(address, val); }
@@ -147,20 +243,15 @@ impl Memory {
         }
     }
 
-    // /Write to link cable, used as debug output
-    pub fn link_cable_serial(&mut self, c: u8) {
-        self.serial_buffer.push(c);
-        if self.output_serial_to_stdout {
-            print!("{}", c as char);
-            io::stdout().flush().expect("Unable to flush stdout");
-        }
-    }
-
     pub fn propagate_interrupt_requests(&mut self) {
         if self.timer.request_interrupt {
             self.interrupt_handler.trigger_interrupt(interrupts::InterruptTypes::Timer);
             self.timer.request_interrupt = false;
         }
+        if self.serial.request_interrupt {
+            self.interrupt_handler.trigger_interrupt(interrupts::InterruptTypes::Serial);
+            self.serial.request_interrupt = false;
+        }
         if self.gpu.vblank_interrupt_requested  {
             self.interrupt_handler.trigger_interrupt(interrupts::InterruptTypes::VBlank);
             self.gpu.vblank_interrupt_requested = false;
@@ -169,37 +260,99 @@ impl Memory {
             self.interrupt_handler.trigger_interrupt(interrupts::InterruptTypes::Stat);
             self.gpu.stat_interrupt_requested = false;
         }
+        if self.joypad.request_interrupt {
+            self.interrupt_handler.trigger_interrupt(interrupts::InterruptTypes::Joypad);
+            self.joypad.request_interrupt = false;
+        }
     }
 
     pub fn cycle_devices(&mut self, machine_cycles: usize) {
+        self.step_devices(machine_cycles);
+        // VRAM DMA halts the CPU while the other devices keep running
+        while self.dma_stall_cycles > 0 {
+            let stall_cycles = self.dma_stall_cycles;
+            self.dma_stall_cycles = 0;
+            self.step_devices(stall_cycles);
+        }
+    }
+
+    fn step_devices(&mut self, machine_cycles: usize) {
+        for _ in 0..machine_cycles {
+            self.oam_dma_cycle();
+        }
+        // The timer follows the CPU clock, the other devices run at normal speed
+        let clock_cycles = if self.double_speed { machine_cycles*2 } else { machine_cycles*4 };
         self.timer.increment_by_cycles((machine_cycles*4) as u16);
-        self.gpu.cycle(machine_cycles*4);
-        self.audio_device.cycle(machine_cycles*4);
+        self.serial.cycle(machine_cycles*4);
+        self.gpu.cycle(clock_cycles);
+        if self.gpu.hblank_dma_requested {
+            self.gpu.hblank_dma_requested = false;
+            if self.hdma.hblank_transfer_active {
+                self.hdma_transfer_block();
+            }
+        }
+        self.audio_device.cycle(clock_cycles);
+        if self.timer.frame_sequencer_step_requested {
+            self.timer.frame_sequencer_step_requested = false;
+            self.audio_device.step_frame_sequencer();
+        }
+        self.rom.cycle(clock_cycles);
         self.propagate_interrupt_requests();
     }
 
-    /// Return a slice of memory, used for DMA transfers
-    pub fn read_mem_slice(&self, start_addr : usize, end_addr : usize) -> &[u8] {
-        match start_addr {
-            0xC000 ..= 0xDFFF | 
-            0xE000 ..= 0xFDFF => &self.working_ram[start_addr - 0xC000..end_addr - 0xC000],
-            0x0000 ..= 0x7FFF | 
-            0xA000 ..= 0xBFFF => self.rom.read_mem_slice(start_addr, end_addr),
-            _ => panic!("DMA OAM Transfer tried to use invalid address range")
+    /// Perform a CGB speed switch if it has been requested through KEY1.
+    /// Called by the STOP instruction. Returns true if the speed was switched
+    pub fn try_speed_switch(&mut self) -> bool {
+        if !self.cgb_mode || !self.speed_switch_requested {
+            return false;
         }
+        self.double_speed = !self.double_speed;
+        self.timer.double_speed = self.double_speed;
+        self.speed_switch_requested = false;
+        // STOP resets DIV
+        self.timer.write_byte(0xFF04, 0);
+        return true;
     }
 
-    // The memory is normally locked for 160 cycles (except for HRAM), but should work without locking
-    pub fn oam_dma_transfer(&mut self) {
-        let start_addr : usize = ((self.gpu.oam_dma_transfer as u16) << 8) as usize;
+    /// Copy a 16 byte block to VRAM. This takes 8 microseconds,
+    /// during which the CPU is halted
+    fn hdma_transfer_block(&mut self) {
+        let (source, destination) = self.hdma.next_block();
+        for i in 0..16 {
+            let val = self.read_byte(source.wrapping_add(i));
+            self.gpu.write_byte((destination + i) as usize, val);
+        }
+        self.dma_stall_cycles += if self.double_speed { 16 } else { 8 };
+    }
 
-        // copy slice to get around borrow checker
-        let mut mem : [u8; 160] = [0; 160];
-        mem.copy_from_slice(self.read_mem_slice(start_addr, start_addr + 160));
+    /// Copy a byte to OAM if an OAM DMA transfer is active. One byte is copied every machine cycle
+    fn oam_dma_cycle(&mut self) {
+        if let Some((source, index)) = self.oam_dma.cycle() {
+            // The transfer reads VRAM and writes OAM regardless of the PPU mode
+            let val = match source {
+                0x8000 ..= 0x9FFF => { self.gpu.video_ram[self.gpu.vram_bank*0x2000 + source - 0x8000] }
+                _ => { self.read_byte_unlocked(source) }
+            };
+            self.oam_dma.transferred_byte = val;
+            self.gpu.oam_ram[index] = val;
+            self.gpu.draw_helper.update_by_vram_address(0xFE00 + index, 0, &self.gpu.video_ram, &self.gpu.oam_ram);
+        }
+    }
 
-        // Memcpy into OAM
-        self.gpu.oam_ram[0..160].copy_from_slice(&mem);
-        self.gpu.draw_helper.generate_sprites(&self.gpu.oam_ram);
+    /// Set the IO registers, divider and VRAM to the state
+    /// the bootrom of `model` leaves behind
+    pub fn set_post_boot_state(&mut self, model: HardwareModel, rom_header: &[u8]) {
+        self.set_initial_values();
+        self.write_byte(0xFF26, model.get_post_boot_nr52());
+        self.timer.set_divider(model.get_post_boot_divider());
+        if model.leaves_logo_in_vram() && !rom_header.is_empty() {
+            for (i, val) in hardware_model::generate_logo_tiles(rom_header).iter().enumerate() {
+                self.gpu.write_byte(0x8000 + i, *val);
+            }
+            for (address, tile_id) in hardware_model::get_logo_tilemap() {
+                self.gpu.write_byte(address as usize, tile_id);
+            }
+        }
     }
 
     pub fn set_initial_values(&mut self) {
@@ -234,5 +387,6 @@ impl Memory {
         self.write_byte(0xFF49, 0xFF);
         self.write_byte(0xFF4A, 0);
         self.write_byte(0xFF4B, 0);
+        self.gpu.set_post_boot_state();
     }
 }
\ No newline at end of file
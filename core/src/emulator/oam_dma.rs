@@ -0,0 +1,150 @@
+// Important memory locations:
+// DMA (OAM DMA source) : 0xFF46. Writing starts a transfer of 160 bytes from 0xXX00 - 0xXX9F to OAM
+// The transfer starts after a machine cycle and copies a byte every machine cycle.
+// Sources from 0xE000 read the working RAM, like echo RAM.
+// During the transfer, the CPU can not access OAM. The bus the transfer reads from is busy,
+// so CPU reads from it return the byte being transferred. HRAM and the IO registers stay accessible
+
+use serde::{Serialize, Deserialize};
+
+const TRANSFER_LENGTH: usize = 160;
+
+/// The memory buses of the Gameboy
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Bus {
+    External, // Cartridge and working RAM
+    Video,    // VRAM
+    Oam,
+    Internal, // IO registers and HRAM
+}
+
+impl Bus {
+    pub fn from_address(address: usize) -> Bus {
+        return match address {
+            0x8000 ..= 0x9FFF => Bus::Video,
+            0xFE00 ..= 0xFEFF => Bus::Oam,
+            0xFF00 ..= 0xFFFF => Bus::Internal,
+            _ => Bus::External,
+        }
+    }
+}
+
+/// Represents the OAM DMA. The bytes are copied by Memory,
+/// as it needs access to the entire memory map
+#[derive(Serialize, Deserialize)]
+pub struct OamDma {
+    register: u8,
+    source: usize,
+    index: usize, // The next byte to copy
+    start_delay: usize, // Machine cycles until a requested transfer starts
+    pub active: bool,
+    pub transferred_byte: u8, // The last copied byte, seen by the CPU on the busy bus
+}
+
+impl OamDma {
+    pub fn new() -> OamDma {
+        OamDma { register: 0, source: 0, index: 0, start_delay: 0, active: false, transferred_byte: 0xFF }
+    }
+
+    pub fn read_byte(&self) -> u8 {
+        return self.register;
+    }
+
+    /// Request a transfer. A transfer already in progress continues until the new one starts
+    pub fn write_byte(&mut self, val: u8) {
+        self.register = val;
+        self.start_delay = 1;
+    }
+
+    /// Returns the bus the transfer reads from
+    pub fn get_source_bus(&self) -> Bus {
+        return Bus::from_address(self.source);
+    }
+
+    /// Step the transfer by a machine cycle.
+    /// Returns the source address and the OAM index of the byte to copy during it
+    pub fn cycle(&mut self) -> Option<(usize, usize)> {
+        let mut transfer = None;
+        if self.active {
+            transfer = Some((self.source + self.index, self.index));
+            self.index += 1;
+            self.active = self.index < TRANSFER_LENGTH;
+        }
+        if self.start_delay > 0 {
+            self.start_delay -= 1;
+            if self.start_delay == 0 {
+                let source = (self.register as usize) << 8;
+                self.source = if source >= 0xE000 { source - 0x2000 } else { source };
+                self.index = 0;
+                self.active = true;
+            }
+        }
+        return transfer;
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::super::memory;
+
+    /// Returns whether OAM can be read by the CPU
+    fn oam_accessible(mem: &memory::Memory) -> bool {
+        return mem.read_byte(0xFE00) != 0xFF;
+    }
+
+    #[test]
+    fn oam_dma_timing()
+    {
+        let mut mem = memory::Memory::new();
+        mem.write_byte(0xFF40, 0); // Disable the LCD, so OAM is not locked by the PPU
+        for i in 0..160 {
+            mem.write_byte(0xC000 + i, i as u8 + 1);
+        }
+        mem.write_byte(0xFF46, 0xC0);
+        assert_eq!(mem.read_byte(0xFF46), 0xC0);
+        // A machine cycle of delay
+        mem.cycle_devices(1);
+        assert_eq!(oam_accessible(&mem), false);
+        assert_eq!(mem.gpu.oam_ram[0], 0);
+        mem.cycle_devices(1);
+        assert_eq!(mem.gpu.oam_ram[0], 1);
+        // The CPU sees the transferred byte on the external bus, HRAM is accessible
+        assert_eq!(mem.read_byte(0x0000), 1);
+        assert_eq!(mem.read_byte(0xD000), 1);
+        mem.write_byte(0xFF80, 0x42);
+        assert_eq!(mem.read_byte(0xFF80), 0x42);
+        // Writes to the busy bus are ignored
+        mem.write_byte(0xC050, 0);
+        mem.cycle_devices(158);
+        assert_eq!(mem.gpu.oam_ram[158], 159);
+        assert_eq!(mem.gpu.oam_ram[159], 0);
+        assert_eq!(oam_accessible(&mem), false);
+        mem.cycle_devices(1);
+        assert_eq!(oam_accessible(&mem), true);
+        assert_eq!(mem.gpu.oam_ram[0x50], 0x51);
+        assert_eq!(mem.read_byte(0xFE9F), 160);
+    }
+
+    #[test]
+    fn oam_dma_sources()
+    {
+        let mut mem = memory::Memory::new();
+        mem.write_byte(0xFF40, 0);
+        // VRAM uses its own bus, the external bus stays accessible
+        mem.write_byte(0x8000, 0x12);
+        mem.write_byte(0xC000, 0x34);
+        mem.write_byte(0xFF46, 0x80);
+        mem.cycle_devices(2);
+        assert_eq!(mem.gpu.oam_ram[0], 0x12);
+        assert_eq!(mem.read_byte(0xC000), 0x34);
+        assert_eq!(mem.read_byte(0x9000), 0x12);
+        mem.cycle_devices(160);
+
+        // Sources from 0xE000 read the working RAM
+        mem.write_byte(0xDE00, 0x56);
+        mem.write_byte(0xFF46, 0xFE);
+        mem.cycle_devices(2);
+        assert_eq!(mem.gpu.oam_ram[0], 0x56);
+    }
+}
@@ -0,0 +1,368 @@
+// Game Boy Printer, connected to the serial port. The Gameboy is the master of all transfers.
+// Packet format:
+// 0x88 0x33 | command | compression | length (LSB first) | data | checksum (LSB first) | 0x00 0x00
+// The checksum is the sum of the command, compression, length and data bytes.
+// The printer replies 0x81 to the first trailing 0x00 and its status to the second,
+// all other bytes are replied with 0x00.
+// Commands:
+// 0x01 INIT: Clear the image buffer
+// 0x02 PRINT: Print the image buffer. Data: copies, margins (high nibble before, low nibble after),
+//             palette (like BGP) and exposure (ignored)
+// 0x04 DATA: Append tile data to the image buffer, 40 tiles (160x16 pixels) per packet.
+//            When compression is 1 the data is run length encoded
+// 0x0F STATUS: Only request the status
+// Status bits:
+// Bit 0: Checksum error, Bit 1: Printing, Bit 2: Image data full, Bit 3: Unprocessed data
+
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use super::Emulator;
+use super::serial::SerialDevice;
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_PRINTING: u8 = 0x02;
+const STATUS_IMAGE_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED_DATA: u8 = 0x08;
+
+const PRINTER_WIDTH: usize = 160;
+/// The printer RAM holds 9 DATA packets, 160x144 pixels
+const IMAGE_BUFFER_SIZE: usize = 0x1680;
+/// Pixel rows fed per margin unit
+const MARGIN_HEIGHT: usize = 16;
+/// How many STATUS requests the printer reports as busy after printing
+const PRINTING_STATUS_REQUESTS: usize = 4;
+/// The printed shades, from white to black
+const SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+#[derive(Clone, Copy, PartialEq)]
+enum PacketStage {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+/// A finished printout, a RGB bitmap of 160 pixels wide paper
+pub struct PrintedImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+    pub number: usize, // Counts the printouts of the printer, starting at 1
+}
+
+struct PrinterState {
+    stage: PacketStage,
+    command: u8,
+    compression: u8,
+    length: usize,
+    data: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+    status: u8,
+    printing_status_requests: usize,
+    // Tile data received through DATA packets
+    image_buffer: Vec<u8>,
+    // The paper printed since the last cut, as RGB rows
+    paper: Vec<u8>,
+    printed_images: Vec<PrintedImage>,
+    print_count: usize,
+}
+
+impl PrinterState {
+    fn receive_byte(&mut self, byte: u8) -> u8 {
+        let stage = self.stage;
+        match stage {
+            PacketStage::Magic1 => {
+                if byte == 0x88 {
+                    self.stage = PacketStage::Magic2;
+                }
+            }
+            PacketStage::Magic2 => {
+                self.stage = if byte == 0x33 { PacketStage::Command } else { PacketStage::Magic1 };
+            }
+            PacketStage::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                self.stage = PacketStage::Compression;
+            }
+            PacketStage::Compression => {
+                self.compression = byte;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.stage = PacketStage::LengthLow;
+            }
+            PacketStage::LengthLow => {
+                self.length = byte as usize;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.stage = PacketStage::LengthHigh;
+            }
+            PacketStage::LengthHigh => {
+                self.length |= (byte as usize) << 8;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.data.clear();
+                self.stage = if self.length > 0 { PacketStage::Data } else { PacketStage::ChecksumLow };
+            }
+            PacketStage::Data => {
+                self.data.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                if self.data.len() == self.length {
+                    self.stage = PacketStage::ChecksumLow;
+                }
+            }
+            PacketStage::ChecksumLow => {
+                self.received_checksum = byte as u16;
+                self.stage = PacketStage::ChecksumHigh;
+            }
+            PacketStage::ChecksumHigh => {
+                self.received_checksum |= (byte as u16) << 8;
+                self.stage = PacketStage::Alive;
+            }
+            PacketStage::Alive => {
+                self.process_packet();
+                self.stage = PacketStage::Status;
+                return 0x81;
+            }
+            PacketStage::Status => {
+                self.stage = PacketStage::Magic1;
+                return self.status;
+            }
+        }
+        return 0x00;
+    }
+
+    fn process_packet(&mut self) {
+        if self.checksum != self.received_checksum {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+        match self.command {
+            COMMAND_INIT => {
+                self.image_buffer.clear();
+                self.status = 0;
+            }
+            COMMAND_DATA => {
+                if self.compression & 0x01 != 0 {
+                    let data = decompress(&self.data);
+                    self.append_image_data(&data);
+                }
+                else {
+                    let data = std::mem::take(&mut self.data);
+                    self.append_image_data(&data);
+                }
+                if !self.image_buffer.is_empty() {
+                    self.status |= STATUS_UNPROCESSED_DATA;
+                }
+            }
+            COMMAND_PRINT => {
+                if self.data.len() >= 4 {
+                    self.print(self.data[0], self.data[1], self.data[2]);
+                    self.image_buffer.clear();
+                    self.status &= !(STATUS_UNPROCESSED_DATA | STATUS_IMAGE_FULL);
+                    self.status |= STATUS_PRINTING;
+                    self.printing_status_requests = PRINTING_STATUS_REQUESTS;
+                }
+            }
+            COMMAND_STATUS => {
+                // Printing is done after a few status requests
+                if self.printing_status_requests > 0 {
+                    self.printing_status_requests -= 1;
+                    if self.printing_status_requests == 0 {
+                        self.status &= !STATUS_PRINTING;
+                    }
+                }
+            }
+            _ => { }
+        }
+    }
+
+    fn append_image_data(&mut self, data: &[u8]) {
+        let length = data.len().min(IMAGE_BUFFER_SIZE - self.image_buffer.len());
+        self.image_buffer.extend_from_slice(&data[..length]);
+        if self.image_buffer.len() == IMAGE_BUFFER_SIZE {
+            self.status |= STATUS_IMAGE_FULL;
+        }
+    }
+
+    /// Print the image buffer onto the paper. The paper is cut and finished
+    /// if there is a margin after the image, otherwise the next print continues it
+    fn print(&mut self, copies: u8, margins: u8, palette: u8) {
+        // A palette of 0 is treated as the default palette by the printer
+        let palette = if palette == 0 { 0xE4 } else { palette };
+        self.feed_paper((margins >> 4) as usize * MARGIN_HEIGHT);
+        let rows = decode_tiles(&self.image_buffer, palette);
+        for _ in 0..copies {
+            self.paper.extend_from_slice(&rows);
+        }
+        let margin_after = (margins & 0x0F) as usize;
+        if margin_after > 0 {
+            self.feed_paper(margin_after * MARGIN_HEIGHT);
+            let pixels = std::mem::take(&mut self.paper);
+            let height = pixels.len() / (PRINTER_WIDTH * 3);
+            self.print_count += 1;
+            self.printed_images.push(PrintedImage { width: PRINTER_WIDTH, height: height, pixels: pixels, number: self.print_count });
+        }
+    }
+
+    fn feed_paper(&mut self, rows: usize) {
+        self.paper.resize(self.paper.len() + rows * PRINTER_WIDTH * 3, 0xFF);
+    }
+}
+
+/// Decompress run length encoded DATA. A control byte with bit 7 set repeats the next byte
+/// (control & 0x7F) + 2 times, otherwise the next (control + 1) bytes are copied
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+        if control & 0x80 != 0 {
+            if i < data.len() {
+                let count = (control & 0x7F) as usize + 2;
+                output.resize(output.len() + count, data[i]);
+                i += 1;
+            }
+        }
+        else {
+            let end = (i + control as usize + 1).min(data.len());
+            output.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+    return output;
+}
+
+/// Decode tile data, 20 tiles per row, into RGB pixel rows
+fn decode_tiles(data: &[u8], palette: u8) -> Vec<u8> {
+    let tile_rows = data.len() / (20 * 16);
+    let mut pixels = vec![0xFF; tile_rows * 8 * PRINTER_WIDTH * 3];
+    for tile in 0..tile_rows * 20 {
+        for y in 0..8 {
+            let low = data[tile * 16 + y * 2];
+            let high = data[tile * 16 + y * 2 + 1];
+            for x in 0..8 {
+                let color = ((low >> (7 - x)) & 1) | (((high >> (7 - x)) & 1) << 1);
+                let shade = SHADES[((palette >> (color * 2)) & 0b11) as usize];
+                let px = (tile % 20) * 8 + x;
+                let py = (tile / 20) * 8 + y;
+                let i = (py * PRINTER_WIDTH + px) * 3;
+                pixels[i..i + 3].copy_from_slice(&[shade; 3]);
+            }
+        }
+    }
+    return pixels;
+}
+
+/// The printer end of the link cable, plugged into an emulator
+struct PrinterPort {
+    state: Rc<RefCell<PrinterState>>,
+}
+
+impl SerialDevice for PrinterPort {
+    fn exchange_byte(&mut self, sent: u8) -> u8 {
+        return self.state.borrow_mut().receive_byte(sent);
+    }
+}
+
+/// Game Boy Printer, which the frontend takes the printed images from
+pub struct Printer {
+    state: Rc<RefCell<PrinterState>>,
+}
+
+impl Printer {
+    pub fn new() -> Printer {
+        let state = PrinterState { stage: PacketStage::Magic1, command: 0, compression: 0, length: 0, data: Vec::new(),
+            checksum: 0, received_checksum: 0, status: 0, printing_status_requests: 0, image_buffer: Vec::new(),
+            paper: Vec::new(), printed_images: Vec::new(), print_count: 0 };
+        return Printer { state: Rc::new(RefCell::new(state)) };
+    }
+
+    /// Plug the printer into the emulator serial port
+    pub fn connect(&self, emulator: &mut Emulator) {
+        emulator.connect_serial_device(Box::new(PrinterPort { state: self.state.clone() }));
+    }
+
+    /// Returns the oldest finished printout, if any
+    pub fn take_printed_image(&self) -> Option<PrintedImage> {
+        let mut state = self.state.borrow_mut();
+        if state.printed_images.is_empty() {
+            return None;
+        }
+        return Some(state.printed_images.remove(0));
+    }
+}
+
+impl Default for Printer {
+    fn default() -> Printer {
+        return Printer::new();
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+
+    /// Send a packet to the printer. Returns the alive and status bytes
+    fn send_packet(port: &mut PrinterPort, command: u8, compression: u8, data: &[u8]) -> (u8, u8) {
+        let mut packet = vec![0x88, 0x33, command, compression, data.len() as u8, (data.len() >> 8) as u8];
+        packet.extend_from_slice(data);
+        let checksum = packet[2..].iter().fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+        packet.extend_from_slice(&[checksum as u8, (checksum >> 8) as u8]);
+        for &byte in packet.iter() {
+            assert_eq!(port.exchange_byte(byte), 0x00);
+        }
+        return (port.exchange_byte(0x00), port.exchange_byte(0x00));
+    }
+
+    #[test]
+    fn print_image()
+    {
+        let printer = Printer::new();
+        let mut port = PrinterPort { state: printer.state.clone() };
+        assert_eq!(send_packet(&mut port, COMMAND_INIT, 0, &[]), (0x81, 0x00));
+        // A row of 20 tiles in color 3, compressed to 129 + 129 + 62 bytes
+        assert_eq!(send_packet(&mut port, COMMAND_DATA, 1, &[0xFF, 0xFF, 0xFF, 0xFF, 0xBC, 0xFF]), (0x81, 0x08));
+        assert_eq!(send_packet(&mut port, COMMAND_DATA, 0, &[]), (0x81, 0x08));
+        // A bad checksum is reported
+        assert_eq!(port.exchange_byte(0x88), 0x00);
+        for &byte in [0x33, COMMAND_STATUS, 0, 0, 0, 0x00, 0x00].iter() {
+            port.exchange_byte(byte);
+        }
+        assert_eq!((port.exchange_byte(0x00), port.exchange_byte(0x00)), (0x81, 0x09));
+
+        // One copy without margins continues on the same paper
+        assert_eq!(send_packet(&mut port, COMMAND_PRINT, 0, &[1, 0x00, 0xE4, 0x40]), (0x81, 0x02));
+        assert!(printer.take_printed_image().is_none());
+        for _ in 0..PRINTING_STATUS_REQUESTS - 1 {
+            assert_eq!(send_packet(&mut port, COMMAND_STATUS, 0, &[]), (0x81, 0x02));
+        }
+        assert_eq!(send_packet(&mut port, COMMAND_STATUS, 0, &[]), (0x81, 0x00));
+
+        // Print again with an inverted palette and a margin after, which finishes the paper
+        let tiles = vec![0xFF; 640];
+        send_packet(&mut port, COMMAND_DATA, 0, &tiles);
+        send_packet(&mut port, COMMAND_PRINT, 0, &[1, 0x01, 0x1B, 0x40]);
+        let image = printer.take_printed_image().unwrap();
+        assert_eq!((image.width, image.height), (160, 8 + 16 + MARGIN_HEIGHT));
+        assert_eq!(image.number, 1);
+        // Color 3 is black, then white with the inverted palette, then the white margin
+        assert_eq!(image.pixels[0], 0x00);
+        assert_eq!(image.pixels[8*160*3], 0xFF);
+        assert_eq!(image.pixels[(8+16)*160*3], 0xFF);
+        assert!(printer.take_printed_image().is_none());
+    }
+}
@@ -1,10 +1,14 @@
 #![allow(dead_code)]
-use std::convert::TryInto;
 
 use serde::{Serialize, Deserialize};
 use serde_big_array::BigArray;
 
+use super::LoadError;
+
 mod rtc;
+mod header;
+
+pub use header::CartridgeHeader;
 
 const KB : usize = 1024;
 
@@ -21,17 +25,31 @@ enum MBCType {
 pub struct Rom{
     rom_banks: Vec<Vec<u8>>,
     ram_banks: Vec<Vec<u8>>,
-    current_rom_bank: u8,
+    current_rom_bank: u16,
+    current_rom_bank_0: u16, // Only switchable on MBC1
     current_ram_bank: u8,
+    mbc1_bank_1: u8,
+    mbc1_bank_2: u8,
+    mbc1_multicart: bool,
     external_ram_enabled: bool,
     ram_banking_mode: bool,
     pub filename: String,
     pub romname: String,
+    pub header: CartridgeHeader,
     mbc_type: MBCType,
+    pub has_battery: bool,
+    pub has_rtc: bool,
+    pub has_rumble: bool,
+    pub rumble_active: bool,
     pub using_boot_rom: bool,
     #[serde(with = "BigArray")]
     boot_rom: [u8; 256],
+    boot_rom_loaded: bool,
+    pub free_boot_rom_loaded: bool, // The bundled bootrom, its final registers depend on the model
     rtc: rtc::RealTimeClock,
+    // The battery backed RAM or RTC registers were written since the last export
+    #[serde(skip)]
+    battery_ram_modified: bool,
 }
 
 impl Rom {
@@ -41,80 +59,106 @@ impl Rom {
             rom_banks: Vec::new(), 
             ram_banks: Vec::new(), 
             current_rom_bank: 1, 
+            current_rom_bank_0: 0,
             current_ram_bank: 0,
+            mbc1_bank_1: 0,
+            mbc1_bank_2: 0,
+            mbc1_multicart: false,
             external_ram_enabled: false, 
             ram_banking_mode: false, 
             filename: "".to_owned(), 
             romname: "".to_owned(),
+            header: CartridgeHeader::new(),
             mbc_type: MBCType::RomOnly,
+            has_battery: false,
+            has_rtc: false,
+            has_rumble: false,
+            rumble_active: false,
             using_boot_rom: false,
             boot_rom: [0; 256],
+            boot_rom_loaded: false,
+            free_boot_rom_loaded: false,
+            battery_ram_modified: false,
             rtc: rtc::RealTimeClock::new(),
         }
     }
 
-    pub fn load_from_file(&mut self, filename: &str) {
-        let data = std::fs::read(filename);
-        let data = match data {
-            Ok(file) => file,
-            Err(error) => panic!("Problem opening the rom file: {:?}", error),
-        };
+    pub fn load_from_file(&mut self, filename: &str) -> Result<(), LoadError> {
+        let data = std::fs::read(filename)?;
         self.filename = filename.to_owned();
         self.romname =  self.filename.split("/").last().unwrap().to_owned();
-        self.load_from_data(&data);
+        return self.load_from_data(&data);
     }
 
-    pub fn load_from_data(&mut self, data: &Vec<u8>) {
-        // Iterate over the banks and add them to the bank vector
-        for bank in data.chunks(16*KB) {
-            self.rom_banks.push(bank.try_into().unwrap());
+    pub fn load_from_data(&mut self, data: &Vec<u8>) -> Result<(), LoadError> {
+        if data.len() < 0x150 {
+            return Err(LoadError::RomTooSmall(data.len()));
         }
-        let mbc_type = self.rom_banks[0][0x0147];
-        self.mbc_type = match mbc_type {
+        let header = CartridgeHeader::parse(data);
+        self.mbc_type = match header.cartridge_type {
             0x00 | 0x08 | 0x09 => MBCType::RomOnly,
             0x01 ..= 0x03 => MBCType::Mbc1,
-            //0x05 ..= 0x06 => MBCType::Mbc2,
+            0x05 ..= 0x06 => MBCType::Mbc2,
             0x0F ..= 0x13 => MBCType::Mbc3,
-            //0x19 ..= 0x1E => MBCType::Mbc5,
-            _ => { panic!("ROM error: Unsupported ROM type {}", mbc_type)}
-        };
-
-        let ram_size_byte = self.rom_banks[0][0x0148];
-        let ram_bank_count = match ram_size_byte {
-            0x00 ..= 0x02 => 1, // Everyone gets 1 bank for simplicity
-            0x03 => 4,
-            0x04 => 16,
-            0x05 => 8,
-            _ => 1,
+            0x19 ..= 0x1E => MBCType::Mbc5,
+            _ => { return Err(LoadError::UnsupportedCartridgeType(header.cartridge_type)); }
         };
-
-        for _i in 0..ram_bank_count {
-            self.ram_banks.push(vec![0; 8192]);
+        // Iterate over the banks and add them to the bank vector.
+        // Incomplete banks are padded, and there are always at least 2 banks
+        self.rom_banks.clear();
+        for bank in data.chunks(16*KB) {
+            let mut bank = bank.to_vec();
+            bank.resize(16*KB, 0xFF);
+            self.rom_banks.push(bank);
+        }
+        if self.rom_banks.len() < 2 {
+            self.rom_banks.push(vec![0xFF; 16*KB]);
         }
+        self.mbc1_multicart = self.mbc_type == MBCType::Mbc1 && self.is_mbc1_multicart();
+        self.has_rumble = header.has_rumble();
+        self.has_battery = header.has_battery();
+        self.has_rtc = header.has_rtc();
 
-        if !self.is_header_checksum_valid() {
-            //println!("Warning: ROM header checksum is invalid, continuing anyway");
-            panic!("ROM header checksum invalid!")
+        self.ram_banks.clear();
+        if self.mbc_type == MBCType::Mbc2 {
+            // MBC2 has 512 half-bytes of built-in RAM, the header RAM size is ignored
+            self.ram_banks.push(vec![0; 512]);
+        }
+        else if header.ram_size() == 2*KB {
+            self.ram_banks.push(vec![0; 2*KB]);
+        }
+        else {
+            for _i in 0..header.ram_size() / (8*KB) {
+                self.ram_banks.push(vec![0; 8*KB]);
+            }
         }
+        // Carts without a MBC have no RAM enable register
+        self.external_ram_enabled = self.mbc_type == MBCType::RomOnly;
 
         println!("Loaded ROM");
+        self.header = header;
+        return Ok(());
     }
 
-    pub fn load_bootrom_from_file(&mut self, filename: &str) {
-        let data = std::fs::read(filename);
-        let data = match data {
-            Ok(file) => file,
-            Err(error) => panic!("Problem opening the bootrom file '{}': {:?}", filename, error),
-        };
-        if data.len() != 256 {
-            panic!("Loaded bootrom {} file was {} bytes instead of expected 256 bytes", filename, data.len());
-        }
+    pub fn load_bootrom_from_file(&mut self, filename: &str) -> Result<(), LoadError> {
+        let data = std::fs::read(filename)?;
         self.filename = filename.to_owned();
-        self.load_bootrom_from_data(&data);
+        return self.load_bootrom_from_data(&data);
     }
 
-    pub fn load_bootrom_from_data(&mut self, data: &Vec<u8>) {
+    pub fn load_bootrom_from_data(&mut self, data: &Vec<u8>) -> Result<(), LoadError> {
+        if data.len() != 256 {
+            return Err(LoadError::InvalidBootromSize(data.len()));
+        }
         self.boot_rom.clone_from_slice(&data);
+        self.boot_rom_loaded = true;
+        self.free_boot_rom_loaded = false;
+        return Ok(());
+    }
+
+    /// Returns true if a bootrom has been loaded, so that it can be enabled
+    pub fn is_bootrom_loaded(&self) -> bool {
+        return self.boot_rom_loaded;
     }
 
     pub fn read_byte(&self, addr : usize) -> u8 {
@@ -124,9 +168,9 @@ impl Rom {
         match self.mbc_type {
             MBCType::RomOnly => { self.read_byte_rom_only(addr) } // Read-only memory
             MBCType::Mbc1    => { self.read_byte_mbc1(addr) } 
-            MBCType::Mbc2    => { panic!("MBC2 is not implemented") } 
+            MBCType::Mbc2    => { self.read_byte_mbc2(addr) } 
             MBCType::Mbc3    => { self.read_byte_mbc3(addr) } 
-            MBCType::Mbc5    => { panic!("MBC5 is not implemented")}
+            MBCType::Mbc5    => { self.read_byte_mbc5(addr) }
         }
         //return self.rom_banks[self.current_bank_index][addr]
     }
@@ -135,27 +179,59 @@ impl Rom {
         match self.mbc_type {
             MBCType::RomOnly => { } // Read-only memory
             MBCType::Mbc1    => { self.write_byte_mbc1(addr, val)}
-            MBCType::Mbc2    => { panic!("MBC2 is not implemented") }
+            MBCType::Mbc2    => { self.write_byte_mbc2(addr, val)}
             MBCType::Mbc3    => { self.write_byte_mbc3(addr, val)}
-            MBCType::Mbc5    => { panic!("MBC5 is not implemented") }
+            MBCType::Mbc5    => { self.write_byte_mbc5(addr, val) }
         }
         //self.rom_banks[self.current_bank_index][addr as usize] = val;
     }
 
-    pub fn is_header_checksum_valid(&mut self) -> bool {
-        // x=0:FOR i=0134h TO 014Ch:x=x-MEM[i]-1:NEXT
-        let mut x: u8 = 0;
-        for i in 0x0134..0x014D {
-            x = x.wrapping_sub(self.rom_banks[0][i]).wrapping_sub(1);
+    pub fn is_header_checksum_valid(&self) -> bool {
+        return self.header.header_checksum_valid;
+    }
+
+    /// Returns the raw cartridge header area, 0x0000 - 0x014F.
+    /// Empty if no ROM is loaded
+    pub fn get_header_bytes(&self) -> &[u8] {
+        if self.rom_banks.is_empty() {
+            return &[];
         }
-        return self.rom_banks[0][0x14D] == x;
+        return &self.rom_banks[0][0..0x150];
+    }
+
+    /// Read from a ROM bank. Bank numbers past the end of the ROM wrap around,
+    /// as the upper bank bits are not connected on smaller carts
+    fn read_rom_bank(&self, bank: u16, offset: usize) -> u8 {
+        return self.rom_banks[bank as usize % self.rom_banks.len()][offset];
+    }
+
+    /// Read from the current external RAM bank. Disabled or absent RAM reads 0xFF
+    fn read_external_ram(&self, addr: usize) -> u8 {
+        if !self.external_ram_enabled || self.ram_banks.is_empty() {
+            return 0xFF;
+        }
+        let bank = &self.ram_banks[self.current_ram_bank as usize % self.ram_banks.len()];
+        // RAM smaller than 8 KB is mirrored
+        return bank[(addr - 0xA000) % bank.len()];
+    }
+
+    /// Write to the current external RAM bank. Writes to disabled or absent RAM are ignored
+    fn write_external_ram(&mut self, addr: usize, val: u8) {
+        if !self.external_ram_enabled || self.ram_banks.is_empty() {
+            return;
+        }
+        let bank_count = self.ram_banks.len();
+        let bank = &mut self.ram_banks[self.current_ram_bank as usize % bank_count];
+        let bank_size = bank.len();
+        bank[(addr - 0xA000) % bank_size] = val;
+        self.battery_ram_modified = true;
     }
 
     pub fn read_byte_rom_only(&self, addr : usize) -> u8 {
         match addr {
             0x0000 ..= 0x3FFF => { return self.rom_banks[0][addr]; }
-            0x4000 ..= 0x7FFF => { return self.rom_banks[1][addr-0x4000]; }
-            0xA000 ..= 0xBFFF => { return self.ram_banks[0][addr-0xA000]; }
+            0x4000 ..= 0x7FFF => { return self.read_rom_bank(1, addr - 0x4000); }
+            0xA000 ..= 0xBFFF => { return self.read_external_ram(addr); }
             _ => { return 0; }
         }
     }
@@ -163,28 +239,81 @@ impl Rom {
     // MBC1
     pub fn read_byte_mbc1(&self, addr : usize) -> u8 {
         match addr {
-            0x0000 ..= 0x3FFF => { return self.rom_banks[0][addr]; }
-            0x4000 ..= 0x7FFF => { return self.rom_banks[self.current_rom_bank as usize][addr - 0x4000]}
-            0xA000 ..= 0xBFFF => { return self.ram_banks[self.current_ram_bank as usize][addr - 0xA000]; }
+            0x0000 ..= 0x3FFF => { return self.read_rom_bank(self.current_rom_bank_0, addr); }
+            0x4000 ..= 0x7FFF => { return self.read_rom_bank(self.current_rom_bank, addr - 0x4000); }
+            0xA000 ..= 0xBFFF => { return self.read_external_ram(addr); }
             _ => { return 0; }
         }
     }
 
     pub fn write_byte_mbc1(&mut self, addr : usize, val: u8) {
         match addr {
-            0x0000 ..= 0x1FFF => { self.external_ram_enabled = val & 0x0A == 0x0A } // RAM enable/disable
-            0x2000 ..= 0x3FFF => { self.current_rom_bank = self.current_rom_bank & 0b1100_0000 | (if val == 0 {1} else {val})} // Switch ROM banks, lower 5 bits
-            0x4000 ..= 0x5FFF => { 
-                if !self.ram_banking_mode { // ROM banking mode
-                    self.current_rom_bank = self.current_ram_bank & 0b0011_1111 | ((val & 0x03) << 5);
-                    self.current_ram_bank = 0;
+            0x0000 ..= 0x1FFF => { self.external_ram_enabled = val & 0x0F == 0x0A } // RAM enable/disable
+            0x2000 ..= 0x3FFF => { self.mbc1_bank_1 = val & 0x1F; self.update_mbc1_banks(); } // BANK1, lower 5 bits of the ROM bank
+            0x4000 ..= 0x5FFF => { self.mbc1_bank_2 = val & 0x03; self.update_mbc1_banks(); } // BANK2, upper ROM bits or RAM bank
+            0x6000 ..= 0x7FFF => { self.ram_banking_mode = val & 0x01 == 0x01; self.update_mbc1_banks(); } // Banking mode select
+            0xA000 ..= 0xBFFF => { self.write_external_ram(addr, val); }
+            _ => {  }
+        }
+    }
+
+    /// Map the MBC1 bank registers to the current ROM and RAM banks.
+    /// BANK2 is used as the upper ROM bank bits for 0x4000 - 0x7FFF. In mode 1 it also
+    /// banks 0x0000 - 0x3FFF and the RAM, which lets large ROMs reach banks 0x20/0x40/0x60.
+    /// MBC1M multicarts only wire 4 bits of BANK1, so BANK2 is shifted one bit less
+    fn update_mbc1_banks(&mut self) {
+        // The zero check is done on all 5 bits, even on multicarts
+        let bank_1 = if self.mbc1_bank_1 == 0 { 1 } else { self.mbc1_bank_1 };
+        let (bank_1, bank_2_shift) = if self.mbc1_multicart { (bank_1 & 0x0F, 4) } else { (bank_1, 5) };
+        let upper_bits = (self.mbc1_bank_2 as u16) << bank_2_shift;
+
+        self.current_rom_bank = upper_bits | bank_1 as u16;
+        if self.ram_banking_mode {
+            self.current_rom_bank_0 = upper_bits;
+            self.current_ram_bank = self.mbc1_bank_2;
+        }
+        else {
+            self.current_rom_bank_0 = 0;
+            self.current_ram_bank = 0;
+        }
+    }
+
+    /// MBC1M multicarts are 1 MB and contain a separate game every 256 KB,
+    /// detected by a second Nintendo logo in bank 0x10
+    fn is_mbc1_multicart(&self) -> bool {
+        return self.rom_banks.len() == 64 && self.rom_banks[0x10][0x0104..0x0134] == self.rom_banks[0][0x0104..0x0134];
+    }
+
+    // MBC2
+    pub fn read_byte_mbc2(&self, addr : usize) -> u8 {
+        match addr {
+            0x0000 ..= 0x3FFF => { return self.rom_banks[0][addr]; }
+            0x4000 ..= 0x7FFF => { return self.read_rom_bank(self.current_rom_bank, addr - 0x4000); }
+            0xA000 ..= 0xBFFF => { 
+                if !self.external_ram_enabled {
+                    return 0xFF;
                 }
-                else {
-                    self.current_ram_bank = val & 0x03;
+                // Only the lower 4 bits are stored, the upper bits are open bus and read as 1s.
+                // The 512 byte RAM is mirrored through the entire region
+                return 0xF0 | self.ram_banks[0][(addr - 0xA000) & 0x1FF]; 
+            }
+            _ => { return 0; }
+        }
+    }
+
+    pub fn write_byte_mbc2(&mut self, addr : usize, val: u8) {
+        match addr {
+            // Bit 8 of the address decides if this is the RAM enable or the ROM bank register
+            0x0000 ..= 0x3FFF if addr & 0x100 == 0 => { self.external_ram_enabled = val & 0x0F == 0x0A } // RAM enable/disable
+            0x0000 ..= 0x3FFF => { 
+                self.current_rom_bank = if val & 0x0F == 0 {1} else {(val & 0x0F) as u16};
+            } // Switch ROM banks, lower 4 bits
+            0xA000 ..= 0xBFFF => { 
+                if self.external_ram_enabled {
+                    self.ram_banks[0][(addr - 0xA000) & 0x1FF] = val & 0x0F; 
+                    self.battery_ram_modified = true;
                 }
-            }  // Switch ROM banks, upper  5bits
-            0x6000 ..= 0x7FFF => { self.ram_banking_mode = val != 0} // ROM/RAM mode select
-            0xA000 ..= 0xBFFF => { self.ram_banks[self.current_ram_bank as usize][addr - 0xA000] = val; }
+            }
             _ => {  }
         }
     }
@@ -194,14 +323,15 @@ impl Rom {
     pub fn read_byte_mbc3(&self, addr : usize) -> u8 {
         match addr {
             0x0000 ..= 0x3FFF => { return self.rom_banks[0][addr]; } // Good
-            0x4000 ..= 0x7FFF => { return self.rom_banks[self.current_rom_bank as usize][addr - 0x4000]} // Good
+            0x4000 ..= 0x7FFF => { return self.read_rom_bank(self.current_rom_bank, addr - 0x4000); } // Good
             0xA000 ..= 0xBFFF => { 
                 if self.current_ram_bank < 8 {
-                    return self.ram_banks[self.current_ram_bank as usize][addr - 0xA000]; 
+                    return self.read_external_ram(addr);
                 }
-                else {
-                    return self.rtc.read_reg(self.current_ram_bank as usize); // Temporary RTC fix
+                else if self.external_ram_enabled && self.has_rtc {
+                    return self.rtc.read_reg(self.current_ram_bank as usize);
                 }
+                return 0xFF;
             }
             _ => { return 0; }
         }
@@ -209,9 +339,9 @@ impl Rom {
 
     pub fn write_byte_mbc3(&mut self, addr : usize, val: u8) {
         match addr {
-            0x0000 ..= 0x1FFF => { self.external_ram_enabled = val & 0x0A == 0x0A } // RAM enable/disable
+            0x0000 ..= 0x1FFF => { self.external_ram_enabled = val & 0x0F == 0x0A } // RAM enable/disable
             0x2000 ..= 0x3FFF => { 
-                self.current_rom_bank = 0b0111_1111 & (if val == 0 {1} else {val});
+                self.current_rom_bank = 0b0111_1111 & (if val == 0 {1} else {val as u16});
             } // Switch ROM banks, lower 7 bits
             0x4000 ..= 0x5FFF => { 
                 self.current_ram_bank = val;
@@ -222,20 +352,103 @@ impl Rom {
             }
             0xA000 ..= 0xBFFF => { 
                 if self.current_ram_bank < 8 { 
-                    self.ram_banks[self.current_ram_bank as usize][addr - 0xA000] = val; 
+                    self.write_external_ram(addr, val);
+                }
+                else if self.external_ram_enabled && self.has_rtc {
+                    self.rtc.write_reg(self.current_ram_bank as usize, val);
+                    self.battery_ram_modified = true;
+                }
+            }
+            _ => {  }
+        }
+    }
+
+    // MBC5
+    pub fn read_byte_mbc5(&self, addr : usize) -> u8 {
+        match addr {
+            0x0000 ..= 0x3FFF => { return self.rom_banks[0][addr]; }
+            0x4000 ..= 0x7FFF => { return self.read_rom_bank(self.current_rom_bank, addr - 0x4000); }
+            0xA000 ..= 0xBFFF => { return self.read_external_ram(addr); }
+            _ => { return 0; }
+        }
+    }
+
+    pub fn write_byte_mbc5(&mut self, addr : usize, val: u8) {
+        match addr {
+            0x0000 ..= 0x1FFF => { self.external_ram_enabled = val & 0x0F == 0x0A } // RAM enable/disable
+            // Unlike MBC1/MBC3, bank 0 can be mapped to 0x4000 - 0x7FFF
+            0x2000 ..= 0x2FFF => { self.current_rom_bank = self.current_rom_bank & 0x100 | val as u16 } // Switch ROM banks, lower 8 bits
+            0x3000 ..= 0x3FFF => { self.current_rom_bank = self.current_rom_bank & 0x0FF | ((val as u16 & 0x01) << 8) } // Switch ROM banks, 9th bit
+            0x4000 ..= 0x5FFF => { 
+                if self.has_rumble {
+                    self.rumble_active = val & 0x08 == 0x08;
+                    self.current_ram_bank = val & 0x07;
+                }
+                else {
+                    self.current_ram_bank = val & 0x0F;
                 }
             }
+            0xA000 ..= 0xBFFF => { self.write_external_ram(addr, val); }
             _ => {  }
         }
     }
 
-    /// Return a slice of ROM memory, used for DMA transfers
-    pub fn read_mem_slice(&self, start_addr : usize, end_addr : usize) -> &[u8] {
-        match start_addr {
-            0x0000 ..= 0x3FFF => &self.rom_banks[0][start_addr..end_addr],
-            0x4000 ..= 0x7FFF => &self.rom_banks[self.current_rom_bank as usize][start_addr-0x4000..end_addr-0x4000],
-            0xA000 ..= 0xBFFF => &self.ram_banks[self.current_ram_bank as usize][start_addr-0xA000..end_addr-0xA000],
-            _ => { panic!("Invalid ROM memory address")}
+    /// Cycle the cartridge hardware, currently only the MBC3 RTC
+    pub fn cycle(&mut self, cycles: usize) {
+        if self.has_rtc {
+            self.rtc.cycle(cycles);
+        }
+    }
+
+    /// Store the current wall clock time in the RTC
+    pub fn set_rtc_unix_time(&mut self, unix_time: i64) {
+        self.rtc.set_unix_time(unix_time);
+    }
+
+    /// Advance the RTC by the wall clock time passed since it was last stored
+    pub fn catch_up_rtc_to_unix_time(&mut self, unix_time: i64) {
+        if self.has_rtc {
+            self.rtc.catch_up_to_unix_time(unix_time);
+        }
+    }
+
+    /// Returns the size of the external cartridge RAM in bytes, as specified by the header
+    fn get_external_ram_size(&self) -> usize {
+        if self.mbc_type == MBCType::Mbc2 {
+            return 512;
+        }
+        return self.header.ram_size();
+    }
+
+    /// Export the external RAM in the .sav format used by most emulators.
+    /// For carts with a RTC, the 48 byte VBA/BGB RTC footer is appended
+    pub fn export_battery_ram(&mut self) -> Vec<u8> {
+        self.battery_ram_modified = false;
+        let mut data = self.ram_banks.concat();
+        data.truncate(self.get_external_ram_size());
+        if self.has_rtc {
+            data.extend(self.rtc.export_footer());
+        }
+        return data;
+    }
+
+    /// Returns true if the external RAM or the RTC registers were written since the last export
+    pub fn is_battery_ram_modified(&self) -> bool {
+        return self.battery_ram_modified;
+    }
+
+    /// Import external RAM from a .sav file, including the RTC footer if present
+    pub fn import_battery_ram(&mut self, data: &[u8]) {
+        let ram_size = self.get_external_ram_size();
+        for (i, val) in data.iter().take(ram_size).enumerate() {
+            let bank_size = self.ram_banks[0].len();
+            self.ram_banks[i / bank_size][i % bank_size] = match self.mbc_type {
+                MBCType::Mbc2 => { val & 0x0F } // MBC2 only stores the lower 4 bits
+                _ => { *val }
+            };
+        }
+        if self.has_rtc && data.len() > ram_size {
+            self.rtc.import_footer(&data[ram_size..]);
         }
     }
 }
@@ -245,12 +458,36 @@ mod test
 {
     use super::Rom;
     use super::MBCType;
+    use super::LoadError;
+    use super::super::{Emulator, KeyPress};
+    use super::super::test::run_mooneye_test;
+
+    /// Create a ROM with a valid header of the specified cartridge type.
+    /// The first byte of every bank is set to the bank number, 
+    /// to make bank switching easy to verify
+    pub fn create_test_rom(cartridge_type: u8, rom_size: u8, ram_size: u8) -> Vec<u8> {
+        let bank_count = 2 << rom_size;
+        let mut data = vec![0; bank_count * 0x4000];
+        for bank in 0..bank_count {
+            data[bank * 0x4000] = bank as u8;
+            data[bank * 0x4000 + 1] = (bank >> 8) as u8;
+        }
+        data[0x0147] = cartridge_type;
+        data[0x0148] = rom_size;
+        data[0x0149] = ram_size;
+        let mut x: u8 = 0;
+        for i in 0x0134..0x014D {
+            x = x.wrapping_sub(data[i]).wrapping_sub(1);
+        }
+        data[0x014D] = x;
+        return data;
+    }
 
     #[test]
     fn mbc1()
     {
         let mut rom = Rom::new();
-        rom.load_from_file("../roms/blargg/cpu_instrs.gb");
+        rom.load_from_file("../roms/blargg/cpu_instrs.gb").unwrap();
 
         assert_eq!(rom.mbc_type, MBCType::Mbc1);
         assert_eq!(rom.is_header_checksum_valid(), true);
@@ -260,4 +497,347 @@ mod test
         rom.write_byte(0x2000, 0x3); // Switching to bank 3
         assert_eq!(rom.read_byte(0x4000), 42); // Check if we can find our value
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn mbc1_banking_modes()
+    {
+        let mut rom = Rom::new();
+        // MBC1+RAM+BATTERY, 2 MB ROM (128 banks), 32 KB RAM (4 banks)
+        rom.load_from_data(&create_test_rom(0x03, 0x06, 0x03)).unwrap();
+        assert_eq!(rom.mbc1_multicart, false);
+
+        // BANK1 value 0 maps to 1, and BANK2 selects the upper ROM bits
+        rom.write_byte(0x2000, 0x00);
+        rom.write_byte(0x4000, 0x02);
+        assert_eq!(rom.read_byte(0x4000), 0x41);
+        rom.write_byte(0x2000, 0xE3); // Only the lower 5 bits are used
+        assert_eq!(rom.read_byte(0x4000), 0x43);
+        // Mode 0, bank 0 is always mapped to 0x0000 - 0x3FFF and RAM bank 0 is used
+        assert_eq!(rom.read_byte(0x0000), 0x00);
+        rom.write_byte(0x0000, 0x0A);
+        rom.write_byte(0xA000, 0x12);
+        // Mode 1, BANK2 also switches 0x0000 - 0x3FFF and the RAM bank
+        rom.write_byte(0x6000, 0x01);
+        assert_eq!(rom.read_byte(0x0000), 0x40);
+        assert_eq!(rom.read_byte(0x4000), 0x43);
+        assert_eq!(rom.read_byte(0xA000), 0x00);
+        rom.write_byte(0xA000, 0x34);
+        rom.write_byte(0x6000, 0x00);
+        assert_eq!(rom.read_byte(0xA000), 0x12);
+        rom.write_byte(0x6000, 0x01);
+        assert_eq!(rom.read_byte(0xA000), 0x34);
+
+        // Disabled RAM reads 0xFF and ignores writes
+        rom.write_byte(0x0000, 0x1B);
+        rom.write_byte(0xA000, 0x56);
+        assert_eq!(rom.read_byte(0xA000), 0xFF);
+        rom.write_byte(0x0000, 0x0A);
+        assert_eq!(rom.read_byte(0xA000), 0x34);
+
+        // Banks past the end of smaller ROMs wrap around
+        let mut rom = Rom::new();
+        // MBC1, 256 KB ROM (16 banks)
+        rom.load_from_data(&create_test_rom(0x01, 0x03, 0x00)).unwrap();
+        rom.write_byte(0x2000, 0x13);
+        assert_eq!(rom.read_byte(0x4000), 0x03);
+        rom.write_byte(0x4000, 0x01);
+        rom.write_byte(0x6000, 0x01);
+        assert_eq!(rom.read_byte(0x0000), 0x00);
+    }
+
+    #[test]
+    fn mbc1_multicart()
+    {
+        // MBC1, 1 MB ROM (64 banks), with a Nintendo logo in bank 0x10
+        let mut data = create_test_rom(0x01, 0x05, 0x00);
+        for i in 0x0104..0x0134 {
+            data[i] = i as u8;
+            data[0x10*0x4000 + i] = i as u8;
+        }
+        let mut rom = Rom::new();
+        rom.load_from_data(&data).unwrap();
+        assert_eq!(rom.mbc1_multicart, true);
+
+        // Only 4 bits of BANK1 are wired, BANK2 is bits 4-5 of the ROM bank
+        rom.write_byte(0x2000, 0x12);
+        rom.write_byte(0x4000, 0x01);
+        assert_eq!(rom.read_byte(0x4000), 0x12);
+        rom.write_byte(0x4000, 0x03);
+        assert_eq!(rom.read_byte(0x4000), 0x32);
+        // The zero check uses all 5 bits, so 0x10 maps to bank 0 of the game
+        rom.write_byte(0x2000, 0x10);
+        assert_eq!(rom.read_byte(0x4000), 0x30);
+        rom.write_byte(0x6000, 0x01);
+        assert_eq!(rom.read_byte(0x0000), 0x30);
+
+        // The same ROM without the second logo is a regular MBC1 cart
+        let mut data = create_test_rom(0x01, 0x05, 0x00);
+        for i in 0x0104..0x0134 {
+            data[i] = i as u8;
+        }
+        let mut rom = Rom::new();
+        rom.load_from_data(&data).unwrap();
+        assert_eq!(rom.mbc1_multicart, false);
+    }
+
+    #[test]
+    #[ignore = "needs roms/mooneye"]
+    fn mooneye_mbc1()
+    {
+        const TESTS : [&str; 13] = [
+            "bits_bank1.gb", "bits_bank2.gb", "bits_mode.gb", "bits_ramg.gb",
+            "ram_64kb.gb", "ram_256kb.gb", "rom_512kb.gb", "rom_1Mb.gb",
+            "rom_2Mb.gb", "rom_4Mb.gb", "rom_8Mb.gb", "rom_16Mb.gb", "multicart_rom_8Mb.gb",
+        ];
+        for test in TESTS.iter() {
+            run_mooneye_test(&format!("emulator-only/mbc1/{}", test));
+        }
+    }
+
+    #[test]
+    fn bank_wrapping()
+    {
+        let mut rom = Rom::new();
+        // MBC5, 64 KB ROM (4 banks), no RAM
+        rom.load_from_data(&create_test_rom(0x19, 0x01, 0x00)).unwrap();
+        rom.write_byte(0x3000, 0x01);
+        rom.write_byte(0x2000, 0xFF);
+        assert_eq!(rom.read_byte(0x4000), 0x03);
+        // Absent RAM reads 0xFF even when enabled
+        rom.write_byte(0x0000, 0x0A);
+        rom.write_byte(0xA000, 0x12);
+        assert_eq!(rom.read_byte(0xA000), 0xFF);
+
+        // ROMs which are not a multiple of 16 KB are padded
+        let mut data = create_test_rom(0x01, 0x00, 0x00);
+        data.extend(vec![0x42; 0x2000]);
+        let mut rom = Rom::new();
+        rom.load_from_data(&data).unwrap();
+        rom.write_byte(0x2000, 0x02);
+        assert_eq!(rom.read_byte(0x4000), 0x42);
+        assert_eq!(rom.read_byte(0x6000), 0xFF);
+        rom.write_byte(0x2000, 0x03);
+        assert_eq!(rom.read_byte(0x4000), 0x00);
+
+        // MBC3+RAM, 2 KB RAM is mirrored and disabled RAM reads 0xFF
+        let mut rom = Rom::new();
+        rom.load_from_data(&create_test_rom(0x12, 0x00, 0x01)).unwrap();
+        rom.write_byte(0xA000, 0x12);
+        assert_eq!(rom.read_byte(0xA000), 0xFF);
+        rom.write_byte(0x0000, 0x0A);
+        assert_eq!(rom.read_byte(0xA000), 0x00);
+        rom.write_byte(0xA000, 0x34);
+        assert_eq!(rom.read_byte(0xA800), 0x34);
+        // No RTC on this cart
+        rom.write_byte(0x4000, 0x08);
+        assert_eq!(rom.read_byte(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn mbc2()
+    {
+        let mut rom = Rom::new();
+        // MBC2+BATTERY, 256 KB ROM (16 banks)
+        rom.load_from_data(&create_test_rom(0x06, 0x03, 0x00)).unwrap();
+
+        assert_eq!(rom.mbc_type, MBCType::Mbc2);
+        assert_eq!(rom.has_battery, true);
+
+        // ROM switching requires bit 8 of the address to be set
+        rom.write_byte(0x2100, 0x05);
+        assert_eq!(rom.read_byte(0x4000), 5);
+        rom.write_byte(0x0100, 0x0F);
+        assert_eq!(rom.read_byte(0x4000), 15);
+        rom.write_byte(0x2100, 0x00); // Bank 0 maps to bank 1
+        assert_eq!(rom.read_byte(0x4000), 1);
+        // Bit 8 unset, this is the RAM enable register and should not switch banks
+        rom.write_byte(0x2000, 0x03);
+        assert_eq!(rom.read_byte(0x4000), 1);
+
+        // RAM is disabled by default
+        rom.write_byte(0xA000, 0x05);
+        assert_eq!(rom.read_byte(0xA000), 0xFF);
+        rom.write_byte(0x0000, 0x0A);
+        // Only the lower 4 bits are stored, upper bits read as 1s
+        rom.write_byte(0xA000, 0x35);
+        assert_eq!(rom.read_byte(0xA000), 0xF5);
+        rom.write_byte(0xA1FF, 0x0C);
+        assert_eq!(rom.read_byte(0xA1FF), 0xFC);
+        // RAM is mirrored every 512 bytes
+        assert_eq!(rom.read_byte(0xA200), 0xF5);
+        assert_eq!(rom.read_byte(0xBFFF), 0xFC);
+        // Disabling RAM with a 0x0100 address should not work
+        rom.write_byte(0x0100, 0x00);
+        assert_eq!(rom.read_byte(0xA000), 0xF5);
+        rom.write_byte(0x0000, 0x00);
+        assert_eq!(rom.read_byte(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn mbc5()
+    {
+        let mut rom = Rom::new();
+        // MBC5+RAM+BATTERY, 8 MB ROM (512 banks), 128 KB RAM (16 banks)
+        rom.load_from_data(&create_test_rom(0x1B, 0x08, 0x04)).unwrap();
+
+        assert_eq!(rom.mbc_type, MBCType::Mbc5);
+        assert_eq!(rom.has_rumble, false);
+
+        // ROM switching, lower 8 bits
+        rom.write_byte(0x2000, 0x42);
+        assert_eq!(rom.read_byte(0x4000), 0x42);
+        assert_eq!(rom.read_byte(0x4001), 0x00);
+        // Bit 9
+        rom.write_byte(0x3000, 0x01);
+        assert_eq!(rom.read_byte(0x4000), 0x42);
+        assert_eq!(rom.read_byte(0x4001), 0x01);
+        rom.write_byte(0x2000, 0xFF);
+        assert_eq!(rom.read_byte(0x4000), 0xFF);
+        assert_eq!(rom.read_byte(0x4001), 0x01);
+        // Bank 0 is allowed in the switchable region
+        rom.write_byte(0x3000, 0x00);
+        rom.write_byte(0x2000, 0x00);
+        assert_eq!(rom.read_byte(0x4000), 0x00);
+        assert_eq!(rom.read_byte(0x0000), 0x00);
+
+        // RAM switching
+        rom.write_byte(0x0000, 0x0A);
+        for bank in 0..16 {
+            rom.write_byte(0x4000, bank);
+            rom.write_byte(0xA000, bank + 100);
+        }
+        for bank in 0..16 {
+            rom.write_byte(0x4000, bank);
+            assert_eq!(rom.read_byte(0xA000), bank + 100);
+        }
+
+        // OAM DMA reads follow the selected banks
+        rom.write_byte(0x2000, 0x03);
+        assert_eq!(rom.read_byte(0x4000), 0x03);
+        rom.write_byte(0x4000, 0x05);
+        assert_eq!(rom.read_byte(0xA000), 105);
+    }
+
+    #[test]
+    fn mbc5_rumble()
+    {
+        let mut rom = Rom::new();
+        // MBC5+RUMBLE+RAM+BATTERY, 128 KB RAM (16 banks)
+        rom.load_from_data(&create_test_rom(0x1E, 0x02, 0x04)).unwrap();
+
+        assert_eq!(rom.mbc_type, MBCType::Mbc5);
+        assert_eq!(rom.has_rumble, true);
+
+        // Bit 3 of the RAM bank register controls the motor
+        rom.write_byte(0x0000, 0x0A);
+        rom.write_byte(0x4000, 0x02);
+        rom.write_byte(0xA000, 42);
+        rom.write_byte(0x4000, 0x0A);
+        assert_eq!(rom.rumble_active, true);
+        assert_eq!(rom.read_byte(0xA000), 42);
+        rom.write_byte(0x4000, 0x02);
+        assert_eq!(rom.rumble_active, false);
+    }
+
+    #[test]
+    fn load_errors()
+    {
+        let mut rom = Rom::new();
+        assert!(matches!(rom.load_from_data(&vec![0; 0x100]), Err(LoadError::RomTooSmall(0x100))));
+        // HuC1 is not supported
+        assert!(matches!(rom.load_from_data(&create_test_rom(0xFF, 0x01, 0x00)), Err(LoadError::UnsupportedCartridgeType(0xFF))));
+        assert!(matches!(rom.load_bootrom_from_data(&vec![0; 255]), Err(LoadError::InvalidBootromSize(255))));
+        assert!(matches!(rom.load_from_file("../roms/missing.gb"), Err(LoadError::Io(_))));
+
+        // An invalid header checksum is only a warning
+        let mut data = create_test_rom(0x00, 0x00, 0x00);
+        data[0x014D] ^= 0xFF;
+        let mut rom = Rom::new();
+        assert!(rom.load_from_data(&data).is_ok());
+        assert_eq!(rom.is_header_checksum_valid(), false);
+    }
+
+    #[test]
+    fn battery_save()
+    {
+        let mut rom = Rom::new();
+        // MBC3+TIMER+RAM+BATTERY, 32 KB RAM (4 banks)
+        rom.load_from_data(&create_test_rom(0x10, 0x01, 0x03)).unwrap();
+        rom.write_byte(0x0000, 0x0A);
+        rom.write_byte(0x4000, 0x02);
+        rom.write_byte(0xA123, 42);
+        // Set the RTC minutes register
+        rom.write_byte(0x4000, 0x09);
+        rom.write_byte(0xA000, 15);
+        rom.set_rtc_unix_time(1_000_000);
+
+        assert_eq!(rom.is_battery_ram_modified(), true);
+        let save = rom.export_battery_ram();
+        assert_eq!(rom.is_battery_ram_modified(), false);
+        // 32 KB RAM and the 48 byte RTC footer
+        assert_eq!(save.len(), 32*1024 + 48);
+        assert_eq!(save[2*8192 + 0x123], 42);
+        assert_eq!(save[32*1024 + 4], 15);
+
+        let mut rom = Rom::new();
+        rom.load_from_data(&create_test_rom(0x10, 0x01, 0x03)).unwrap();
+        rom.import_battery_ram(&save);
+        rom.write_byte(0x0000, 0x0A);
+        rom.write_byte(0x4000, 0x02);
+        assert_eq!(rom.read_byte(0xA123), 42);
+        rom.write_byte(0x4000, 0x09);
+        assert_eq!(rom.read_byte(0xA000), 15);
+        // One minute later
+        rom.catch_up_rtc_to_unix_time(1_000_060);
+        rom.write_byte(0x6000, 0x00);
+        rom.write_byte(0x6000, 0x01);
+        assert_eq!(rom.read_byte(0xA000), 16);
+        // The clock ticking and reads do not modify the save, RTC register writes do
+        assert_eq!(rom.is_battery_ram_modified(), false);
+        rom.write_byte(0xA000, 20);
+        assert_eq!(rom.is_battery_ram_modified(), true);
+
+        // Carts without a RTC have no footer
+        let mut rom = Rom::new();
+        rom.load_from_data(&create_test_rom(0x03, 0x01, 0x02)).unwrap();
+        assert_eq!(rom.export_battery_ram().len(), 8192);
+    }
+
+    /// Press and release a key, running the emulator for a few frames for each
+    fn tap_key(em: &mut Emulator, key: KeyPress) {
+        em.press_key(key);
+        for _ in 0..10 {
+            em.run_until_frontend_event();
+        }
+        em.clear_key(key);
+        for _ in 0..10 {
+            em.run_until_frontend_event();
+        }
+    }
+
+    /// Run the rtc3test MBC3 RTC test. The test has three different test pages
+    /// which are selected from a menu. Checksums of the result pages were precalculated
+    /// and manually verified to show only passing tests
+    #[test]
+    fn rtc3test()
+    {
+        const EXPECTED_CHECKSUMS : [usize; 3] = [9335550, 426280, 6167355];
+        for page in 0..3 {
+            let mut em = Emulator::new();
+            em.memory.serial.capture.output_to_stdout = false;
+            em.memory.rom.load_from_file("../roms/rtc3test/rtc3test.gb").unwrap();
+            for _ in 0..120 {
+                em.run_until_frontend_event();
+            }
+            for _ in 0..page {
+                tap_key(&mut em, KeyPress::Down);
+            }
+            tap_key(&mut em, KeyPress::A);
+            // The tests take roughly 15 seconds to complete
+            for _ in 0..2400 {
+                em.run_until_frontend_event();
+            }
+            assert_eq!(em.screen.calculate_simple_checksum(), EXPECTED_CHECKSUMS[page], "rtc3test page {} failed", page);
+        }
+    }
+}
@@ -0,0 +1,234 @@
+/// Represents the cartridge header, located at 0x0100 - 0x014F in the ROM.
+/// The header describes the game title, the cartridge hardware
+/// and which Gameboy models the game supports
+
+use std::fmt;
+use serde::{Serialize, Deserialize};
+
+const KB : usize = 1024;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CartridgeHeader {
+    pub title: String, // 0134h - 0143h
+    pub manufacturer_code: String, // 013Fh - 0142h, only on newer carts
+    pub cgb_flag: u8, // 0143h
+    pub new_licensee_code: String, // 0144h - 0145h
+    pub sgb_flag: u8, // 0146h
+    pub cartridge_type: u8, // 0147h
+    pub rom_size_byte: u8, // 0148h
+    pub ram_size_byte: u8, // 0149h
+    pub destination_code: u8, // 014Ah
+    pub old_licensee_code: u8, // 014Bh
+    pub version: u8, // 014Ch
+    pub header_checksum: u8, // 014Dh
+    pub global_checksum: u16, // 014Eh - 014Fh
+    pub header_checksum_valid: bool,
+}
+
+impl CartridgeHeader {
+    pub fn new() -> CartridgeHeader {
+        CartridgeHeader {
+            title: "".to_owned(),
+            manufacturer_code: "".to_owned(),
+            cgb_flag: 0,
+            new_licensee_code: "".to_owned(),
+            sgb_flag: 0,
+            cartridge_type: 0,
+            rom_size_byte: 0,
+            ram_size_byte: 0,
+            destination_code: 0,
+            old_licensee_code: 0,
+            version: 0,
+            header_checksum: 0,
+            global_checksum: 0,
+            header_checksum_valid: false,
+        }
+    }
+
+    /// Parse the header from ROM data. The data has to be at least 0x150 bytes
+    pub fn parse(data: &[u8]) -> CartridgeHeader {
+        let cgb_flag = data[0x0143];
+        // Newer carts use the end of the title area for the manufacturer code
+        // and the CGB flag. The code is 4 uppercase characters, e.g. POKEMON_SLVAAXE
+        let manufacturer_bytes = &data[0x013F..0x0143];
+        let has_manufacturer_code = cgb_flag & 0x80 == 0x80 &&
+            manufacturer_bytes.iter().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit());
+        let title_end = if has_manufacturer_code { 0x013F }
+            else if cgb_flag & 0x80 == 0x80 { 0x0143 }
+            else { 0x0144 };
+
+        // x=0:FOR i=0134h TO 014Ch:x=x-MEM[i]-1:NEXT
+        let mut x: u8 = 0;
+        for i in 0x0134..0x014D {
+            x = x.wrapping_sub(data[i]).wrapping_sub(1);
+        }
+
+        CartridgeHeader {
+            title: CartridgeHeader::parse_string(&data[0x0134..title_end]),
+            manufacturer_code: if has_manufacturer_code { CartridgeHeader::parse_string(manufacturer_bytes) } else { "".to_owned() },
+            cgb_flag: cgb_flag,
+            new_licensee_code: CartridgeHeader::parse_string(&data[0x0144..0x0146]),
+            sgb_flag: data[0x0146],
+            cartridge_type: data[0x0147],
+            rom_size_byte: data[0x0148],
+            ram_size_byte: data[0x0149],
+            destination_code: data[0x014A],
+            old_licensee_code: data[0x014B],
+            version: data[0x014C],
+            header_checksum: data[0x014D],
+            global_checksum: (data[0x014E] as u16) << 8 | data[0x014F] as u16,
+            header_checksum_valid: data[0x014D] == x,
+        }
+    }
+
+    /// Parse a zero padded ASCII string, replacing non-printable characters
+    fn parse_string(bytes: &[u8]) -> String {
+        return bytes.iter()
+            .take_while(|c| **c != 0)
+            .map(|c| if c.is_ascii_graphic() || *c == b' ' { *c as char } else { '?' })
+            .collect::<String>()
+            .trim_end()
+            .to_owned();
+    }
+
+    /// Returns the ROM size in bytes, or 0 if the size byte is unknown
+    pub fn rom_size(&self) -> usize {
+        return match self.rom_size_byte {
+            0x00 ..= 0x08 => 32*KB << self.rom_size_byte,
+            _ => 0,
+        }
+    }
+
+    /// Returns the external RAM size in bytes. MBC2 built-in RAM is not included
+    pub fn ram_size(&self) -> usize {
+        return match self.ram_size_byte {
+            0x01 => 2*KB,
+            0x02 => 8*KB,
+            0x03 => 32*KB,
+            0x04 => 128*KB,
+            0x05 => 64*KB,
+            _ => 0,
+        }
+    }
+
+    /// Returns the licensee code. Newer carts store it as two ASCII characters
+    pub fn licensee_code(&self) -> String {
+        if self.old_licensee_code == 0x33 {
+            return self.new_licensee_code.clone();
+        }
+        return format!("{:02X}", self.old_licensee_code);
+    }
+
+    /// Returns true if the game has Gameboy Color enhancements
+    pub fn supports_cgb(&self) -> bool {
+        return self.cgb_flag & 0x80 == 0x80;
+    }
+
+    /// Returns true if the game only works on the Gameboy Color
+    pub fn requires_cgb(&self) -> bool {
+        return self.cgb_flag == 0xC0;
+    }
+
+    /// Returns true if the game has Super Gameboy enhancements.
+    /// These are ignored by the SGB unless the old licensee code is 0x33
+    pub fn supports_sgb(&self) -> bool {
+        return self.sgb_flag == 0x03 && self.old_licensee_code == 0x33;
+    }
+
+    pub fn has_battery(&self) -> bool {
+        return matches!(self.cartridge_type, 0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E);
+    }
+
+    pub fn has_rtc(&self) -> bool {
+        return matches!(self.cartridge_type, 0x0F | 0x10);
+    }
+
+    /// MBC5 rumble carts use bit 3 of the RAM bank register for the motor
+    pub fn has_rumble(&self) -> bool {
+        return matches!(self.cartridge_type, 0x1C ..= 0x1E);
+    }
+
+    /// Returns the name of the cartridge hardware
+    pub fn cartridge_type_name(&self) -> &'static str {
+        return match self.cartridge_type {
+            0x00 => "ROM ONLY",
+            0x01 => "MBC1",
+            0x02 => "MBC1+RAM",
+            0x03 => "MBC1+RAM+BATTERY",
+            0x05 => "MBC2",
+            0x06 => "MBC2+BATTERY",
+            0x08 => "ROM+RAM",
+            0x09 => "ROM+RAM+BATTERY",
+            0x0B => "MMM01",
+            0x0C => "MMM01+RAM",
+            0x0D => "MMM01+RAM+BATTERY",
+            0x0F => "MBC3+TIMER+BATTERY",
+            0x10 => "MBC3+TIMER+RAM+BATTERY",
+            0x11 => "MBC3",
+            0x12 => "MBC3+RAM",
+            0x13 => "MBC3+RAM+BATTERY",
+            0x19 => "MBC5",
+            0x1A => "MBC5+RAM",
+            0x1B => "MBC5+RAM+BATTERY",
+            0x1C => "MBC5+RUMBLE",
+            0x1D => "MBC5+RUMBLE+RAM",
+            0x1E => "MBC5+RUMBLE+RAM+BATTERY",
+            0x20 => "MBC6",
+            0x22 => "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
+            0xFC => "POCKET CAMERA",
+            0xFD => "BANDAI TAMA5",
+            0xFE => "HuC3",
+            0xFF => "HuC1+RAM+BATTERY",
+            _ => "UNKNOWN",
+        }
+    }
+}
+
+impl fmt::Display for CartridgeHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Title: {}", self.title)?;
+        if !self.manufacturer_code.is_empty() {
+            writeln!(f, "Manufacturer: {}", self.manufacturer_code)?;
+        }
+        writeln!(f, "Cartridge: {} (0x{:02X})", self.cartridge_type_name(), self.cartridge_type)?;
+        writeln!(f, "ROM: {} KB, RAM: {} KB", self.rom_size() / KB, self.ram_size() / KB)?;
+        writeln!(f, "CGB: {}, SGB: {}",
+            if self.requires_cgb() { "Required" } else if self.supports_cgb() { "Supported" } else { "No" },
+            if self.supports_sgb() { "Supported" } else { "No" })?;
+        writeln!(f, "Licensee: {}, Version: {}", self.licensee_code(), self.version)?;
+        write!(f, "Header checksum: 0x{:02X} ({}), Global checksum: 0x{:04X}",
+            self.header_checksum, if self.header_checksum_valid { "valid" } else { "invalid" }, self.global_checksum)
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::CartridgeHeader;
+
+    #[test]
+    fn header_parsing()
+    {
+        let data = std::fs::read("../roms/blargg/cpu_instrs.gb").unwrap();
+        let header = CartridgeHeader::parse(&data);
+        assert_eq!(header.title, "CPU_INSTRS");
+        assert_eq!(header.manufacturer_code, "");
+        assert_eq!(header.supports_cgb(), true);
+        assert_eq!(header.requires_cgb(), false);
+        assert_eq!(header.cartridge_type_name(), "MBC1");
+        assert_eq!(header.rom_size(), 64*1024);
+        assert_eq!(header.ram_size(), 0);
+        assert_eq!(header.header_checksum_valid, true);
+        assert_eq!(header.global_checksum, 0xF530);
+
+        // Newer carts store a manufacturer code at the end of the title
+        let data = std::fs::read("../roms/rtc3test/rtc3test.gb").unwrap();
+        let header = CartridgeHeader::parse(&data);
+        assert_eq!(header.title, "MBC3RTCTEST");
+        assert_eq!(header.manufacturer_code, "RTC3");
+        assert_eq!(header.licensee_code(), "FF");
+        assert_eq!(header.supports_sgb(), false);
+        assert_eq!(header.has_rtc(), true);
+        assert_eq!(header.has_battery(), true);
+    }
+}
@@ -1,10 +1,18 @@
 /// Represents a Real-Time Clock (RTC) for MBC3 cart
+///
+/// The RTC is driven by a 32768 Hz crystal, which is the CPU clock / 128.
+/// The registers are mapped into 0xA000 - 0xBFFF by selecting RAM bank 0x08 - 0x0C.
+/// Reads return the latched registers, which are updated by writing
+/// first 0 and then 1 to 0x6000 - 0x7FFF. Writes go directly to the live registers.
 
 use modular_bitfield::prelude::*;
 use serde::{Serialize, Deserialize};
+use std::convert::TryInto;
+
+const CYCLES_PER_SECOND : usize = 4194304;
 
 #[bitfield]
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
 struct RealTimeClockMem {
     seconds: u8, // 08h
     minutes: u8, // 09h
@@ -18,42 +26,254 @@ struct RealTimeClockMem {
 #[derive(Serialize, Deserialize)]
 pub struct RealTimeClock {
     mem : RealTimeClockMem,
+    latched_mem : RealTimeClockMem,
     latch_zero: bool,
+    // Clock cycles since the last seconds increment
+    cycle_counter: usize,
+    // Wall clock unix time of the last sync, used to catch up between sessions
+    unix_time: i64,
 }
 
 impl RealTimeClock {
     pub fn new() -> RealTimeClock {
-        RealTimeClock { 
+        RealTimeClock {
             mem: RealTimeClockMem::new(),
-            latch_zero: false
+            latched_mem: RealTimeClockMem::new(),
+            latch_zero: false,
+            cycle_counter: 0,
+            unix_time: 0,
         }
     }
 
     pub fn read_reg(&self, ram_bank: usize) -> u8 {
-        return self.mem.bytes[ram_bank - 8];
+        return match ram_bank {
+            0x08 ..= 0x0C => self.latched_mem.bytes[ram_bank - 8],
+            _ => 0xFF,
+        }
+    }
+
+    /// Write to a RTC register. Unused bits are always 0
+    pub fn write_reg(&mut self, ram_bank: usize, val: u8) {
+        let val = match ram_bank {
+            0x08 => {
+                // Writing the seconds resets the sub-second counter
+                self.cycle_counter = 0;
+                val & 0x3F
+            }
+            0x09 => { val & 0x3F }
+            0x0A => { val & 0x1F }
+            0x0B => { val }
+            0x0C => { val & 0xC1 }
+            _ => { return; }
+        };
+        self.mem.bytes[ram_bank - 8] = val;
+        self.latched_mem.bytes[ram_bank - 8] = val;
     }
 
     pub fn write_latch(&mut self, val: u8) {
-        // Latch the time when first 0 and then 1 is written
-        if val == 0 {
-            self.latch_zero = true;
-        }
+        // Latch the time when 0 and then 1 is written, with no other write in between
         if self.latch_zero && val == 1 {
             self.latch_current_time();
-            self.latch_zero = false;
         }
+        self.latch_zero = val == 0;
+    }
+
+    /// Store the current wall clock time as the time the RTC was last synced
+    pub fn set_unix_time(&mut self, unix_time: i64) {
+        self.unix_time = unix_time;
+    }
+
+    pub fn get_unix_time(&self) -> i64 {
+        return self.unix_time;
+    }
+
+    /// Advance the clock by the wall clock time which has passed since
+    /// the last sync. This is used to keep the clock running between sessions
+    pub fn catch_up_to_unix_time(&mut self, unix_time: i64) {
+        if self.unix_time != 0 && unix_time > self.unix_time && !self.mem.halt() {
+            self.advance_seconds((unix_time - self.unix_time) as u64);
+        }
+        self.unix_time = unix_time;
+    }
+
+    /// Export the clock in the 48 byte VBA/BGB .sav footer format.
+    /// The live and then the latched registers are stored as little-endian u32s,
+    /// followed by the unix time of the last sync as a little-endian u64
+    pub fn export_footer(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(48);
+        for regs in [&self.mem, &self.latched_mem].iter() {
+            for reg in regs.bytes.iter() {
+                data.extend_from_slice(&(*reg as u32).to_le_bytes());
+            }
+        }
+        data.extend_from_slice(&(self.unix_time as u64).to_le_bytes());
+        return data;
     }
 
-    fn set_unix_time(&mut self) {
-        
+    /// Import a VBA/BGB .sav footer. The older 44 byte format, 
+    /// which uses a 32 bit timestamp, is also accepted
+    pub fn import_footer(&mut self, data: &[u8]) {
+        if data.len() < 44 {
+            return;
+        }
+        for i in 0..5 {
+            self.write_reg(0x08 + i, data[i*4]);
+        }
+        for i in 0..5 {
+            self.latched_mem.bytes[i] = data[20 + i*4];
+        }
+        self.unix_time = if data.len() >= 48 {
+            u64::from_le_bytes(data[40..48].try_into().unwrap()) as i64
+        } 
+        else {
+            u32::from_le_bytes(data[40..44].try_into().unwrap()) as i64
+        };
     }
 
     fn latch_current_time(&mut self) {
+        self.latched_mem = self.mem;
+    }
+
+    /// Step the clock by normal speed clock cycles. The seconds tick every
+    /// 4194304 cycles, the 32768 Hz RTC crystal divided down to 1 Hz
+    pub fn cycle(&mut self, cycles: usize) {
+        if self.mem.halt() {
+            return;
+        }
+        self.cycle_counter += cycles;
+        while self.cycle_counter >= CYCLES_PER_SECOND {
+            self.cycle_counter -= CYCLES_PER_SECOND;
+            self.increment_seconds();
+        }
+    }
+
+    /// Increment the clock by one second. Registers containing invalid values
+    /// keep counting until they overflow their bits, without carrying over
+    fn increment_seconds(&mut self) {
+        let seconds = (self.mem.seconds() + 1) & 0x3F;
+        if seconds != 60 {
+            self.mem.set_seconds(seconds);
+            return;
+        }
+        self.mem.set_seconds(0);
+        let minutes = (self.mem.minutes() + 1) & 0x3F;
+        if minutes != 60 {
+            self.mem.set_minutes(minutes);
+            return;
+        }
+        self.mem.set_minutes(0);
+        let hours = (self.mem.hours() + 1) & 0x1F;
+        if hours != 24 {
+            self.mem.set_hours(hours);
+            return;
+        }
+        self.mem.set_hours(0);
+        let days = self.mem.days() + 1;
+        if days > 511 {
+            self.mem.set_days(0);
+            self.mem.set_day_carry(true);
+        }
+        else {
+            self.mem.set_days(days);
+        }
+    }
 
+    fn is_time_valid(&self) -> bool {
+        return self.mem.seconds() < 60 && self.mem.minutes() < 60 && self.mem.hours() < 24;
     }
 
-    /// Every 128 cycles is equivalent to a clock cycle
-    fn cycle(&mut self) {
+    /// Advance the clock by many seconds at once
+    fn advance_seconds(&mut self, mut seconds: u64) {
+        // Step through invalid register values one second at a time
+        while !self.is_time_valid() && seconds > 0 {
+            self.increment_seconds();
+            seconds -= 1;
+        }
+        let total = self.mem.seconds() as u64 + self.mem.minutes() as u64 * 60
+            + self.mem.hours() as u64 * 3600 + self.mem.days() as u64 * 86400 + seconds;
+        let days = total / 86400;
+        if days > 511 {
+            self.mem.set_day_carry(true);
+        }
+        self.mem.set_days((days % 512) as u16);
+        self.mem.set_hours(((total % 86400) / 3600) as u8);
+        self.mem.set_minutes(((total % 3600) / 60) as u8);
+        self.mem.set_seconds((total % 60) as u8);
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::RealTimeClock;
+    use super::CYCLES_PER_SECOND;
 
+    #[test]
+    fn rtc_ticking()
+    {
+        let mut rtc = RealTimeClock::new();
+        rtc.write_reg(0x08, 58);
+        rtc.write_reg(0x09, 59);
+        rtc.write_reg(0x0A, 23);
+        rtc.write_reg(0x0B, 0xFF);
+        rtc.write_reg(0x0C, 0x01);
+        rtc.cycle(CYCLES_PER_SECOND);
+        rtc.write_latch(0);
+        rtc.write_latch(1);
+        assert_eq!(rtc.read_reg(0x08), 59);
+        // Overflow everything, day carry should be set
+        rtc.cycle(CYCLES_PER_SECOND);
+        assert_eq!(rtc.read_reg(0x08), 59); // Not latched yet
+        // The 1 has to follow the 0 directly
+        rtc.write_latch(0);
+        rtc.write_latch(0x05);
+        rtc.write_latch(1);
+        assert_eq!(rtc.read_reg(0x08), 59);
+        rtc.write_latch(0);
+        rtc.write_latch(1);
+        assert_eq!(rtc.read_reg(0x08), 0);
+        assert_eq!(rtc.read_reg(0x09), 0);
+        assert_eq!(rtc.read_reg(0x0A), 0);
+        assert_eq!(rtc.read_reg(0x0B), 0);
+        assert_eq!(rtc.read_reg(0x0C), 0x80);
+
+        // Halted clocks do not tick
+        rtc.write_reg(0x0C, 0x40);
+        rtc.cycle(CYCLES_PER_SECOND * 2);
+        rtc.write_latch(0);
+        rtc.write_latch(1);
+        assert_eq!(rtc.read_reg(0x08), 0);
+
+        // Invalid values wrap around without carry
+        rtc.write_reg(0x08, 63);
+        rtc.write_reg(0x0C, 0x00);
+        rtc.cycle(CYCLES_PER_SECOND);
+        rtc.write_latch(0);
+        rtc.write_latch(1);
+        assert_eq!(rtc.read_reg(0x08), 0);
+        assert_eq!(rtc.read_reg(0x09), 0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn rtc_catch_up()
+    {
+        let mut rtc = RealTimeClock::new();
+        rtc.set_unix_time(1_000_000);
+        rtc.write_reg(0x08, 30);
+        rtc.write_reg(0x0B, 10);
+        // 2 days, 3 hours, 4 minutes and 40 seconds later
+        rtc.catch_up_to_unix_time(1_000_000 + 2*86400 + 3*3600 + 4*60 + 40);
+        rtc.write_latch(0);
+        rtc.write_latch(1);
+        assert_eq!(rtc.read_reg(0x08), 10);
+        assert_eq!(rtc.read_reg(0x09), 5);
+        assert_eq!(rtc.read_reg(0x0A), 3);
+        assert_eq!(rtc.read_reg(0x0B), 12);
+        // Day counter overflow
+        rtc.catch_up_to_unix_time(1_000_000 + 2*86400 + 3*3600 + 4*60 + 40 + 510*86400);
+        rtc.write_latch(0);
+        rtc.write_latch(1);
+        assert_eq!(rtc.read_reg(0x0B), 10);
+        assert_eq!(rtc.read_reg(0x0C), 0x80);
+    }
+}
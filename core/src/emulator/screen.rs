@@ -2,6 +2,7 @@
 
 use super::gpu;
 use super::gpu::draw_helper;
+use super::sgb;
 
 use std::cmp;
 
@@ -10,45 +11,78 @@ const SCREEN_HEIGHT: usize = 144;
 
 use serde::{Serialize, Deserialize};
 
+/// The background color ids and CGB tile priorities of a scanline
+struct BackgroundLine {
+    color_ids: [u8; SCREEN_WIDTH],
+    priority: [bool; SCREEN_WIDTH],
+}
+
+impl BackgroundLine {
+    fn new() -> BackgroundLine {
+        BackgroundLine { color_ids: [0; SCREEN_WIDTH], priority: [false; SCREEN_WIDTH] }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Screen {
     pub bitmap: Vec<u8>, // 160*144 screen, 3 channels
+    pub sgb_bitmap: Vec<u8>, // 256*224 Super Gameboy screen with border, 3 channels
+    #[serde(default="serde_shades_default")]
+    pub shades: Vec<u8>, // 160*144 DMG shades (0-3) of the screen, colored by the Super Gameboy palettes
 }
 
 impl Screen {
     pub fn new() -> Screen {
         Screen { 
             bitmap: vec![255; SCREEN_HEIGHT*SCREEN_WIDTH*3], 
+            sgb_bitmap: vec![255; sgb::SGB_SCREEN_HEIGHT*sgb::SGB_SCREEN_WIDTH*3],
+            shades: serde_shades_default(),
         }
     }
 
     pub fn draw_frame(&mut self, gpu: &gpu::GPU) {
+        let mut bg_line = BackgroundLine::new();
         for ly in 0..SCREEN_HEIGHT {
-            self.draw_bg_line(ly, gpu.scroll_x as usize, gpu.scroll_y as usize, gpu, gpu.get_bg_tile_map());
-            self.draw_bg_line(ly, gpu.window_x as usize, gpu.window_y as usize, gpu, gpu.get_window_tile_map());
+            self.draw_bg_line(ly, gpu.scroll_x as usize, gpu.scroll_y as usize, gpu, gpu.get_bg_tile_map(), &mut bg_line);
+            self.draw_bg_line(ly, gpu.window_x as usize, gpu.window_y as usize, gpu, gpu.get_window_tile_map(), &mut bg_line);
         }
     }
 
     /// Draw a scanline to the bitmap, 
     /// consisting of a background layer, a sprite layer and a window layer
     pub fn draw_line(&mut self, gpu: &gpu::GPU) {
-        if gpu.get_bg_enable() {
+        if gpu.renderer == gpu::PpuRenderer::PixelFifo {
+            // The line was already drawn a dot at a time
+            let start = gpu.ly as usize*SCREEN_WIDTH*3;
+            self.bitmap[start..start + SCREEN_WIDTH*3].copy_from_slice(gpu.pixel_fifo.get_line());
+            let start = gpu.ly as usize*SCREEN_WIDTH;
+            self.shades[start..start + SCREEN_WIDTH].copy_from_slice(gpu.pixel_fifo.get_line_shades());
+            return;
+        }
+        // Background color ids and CGB tile priorities of the line, used for sprite priority
+        let mut bg_line = BackgroundLine::new();
+        // On CGB, LCDC bit 0 only removes the background priority, the background is always drawn
+        if gpu.get_bg_enable() || gpu.cgb_mode {
             // Draw the background layer
-            self.draw_bg_line(gpu.ly as usize, gpu.scroll_x as usize, gpu.scroll_y as usize, gpu, gpu.get_bg_tile_map());
+            self.draw_bg_line(gpu.ly as usize, gpu.scroll_x as usize, gpu.scroll_y as usize, gpu, gpu.get_bg_tile_map(), &mut bg_line);
             if gpu.should_draw_window() {
                 // Draw the window layer
-                self.draw_window_line(gpu.ly as usize, gpu.internal_window_ly as usize, gpu.window_x as usize, gpu.window_y as usize, gpu, gpu.get_window_tile_map());
+                self.draw_window_line(gpu.ly as usize, gpu.internal_window_ly as usize, gpu.window_x as usize, gpu.window_y as usize, gpu, gpu.get_window_tile_map(), &mut bg_line);
             }
         }
+        if gpu.cgb_mode && !gpu.get_bg_enable() {
+            // Sprites are always drawn on top
+            bg_line = BackgroundLine::new();
+        }
         if gpu.should_draw_sprites() {
             // Draw 8x8 sprites
             if !gpu.get_sprite_tile_size() {
                 // Draw 8x8 sprites
-                self.draw_sprite_line(gpu.ly as usize, &gpu.draw_helper)
+                self.draw_sprite_line(gpu.ly as usize, &gpu.draw_helper, gpu.cgb_mode, &bg_line)
             }
             else { 
                 // Draw 8x16 sprites
-                self.draw_double_sprite_line(gpu.ly as usize, &gpu.draw_helper)
+                self.draw_double_sprite_line(gpu.ly as usize, &gpu.draw_helper, gpu.cgb_mode, &bg_line)
             }
         }
     }
@@ -57,29 +91,24 @@ impl Screen {
     /// which wraps around
     /// cx and cy is the background scroll position
     /// tilemap_select selects which tilemap to use
-    fn draw_bg_line(&mut self, line_y: usize, cx: usize, cy: usize, gpu: &gpu::GPU, tilemap_select : bool) {
+    fn draw_bg_line(&mut self, line_y: usize, cx: usize, cy: usize, gpu: &gpu::GPU, tilemap_select : bool, bg_line: &mut BackgroundLine) {
         let y = (line_y + cy) % 256;
         let tile_data_y = y / 8;
         let tile_y = y % 8;
-        let mut color: draw_helper::Color;
         // Improvements: Remove modulo
         // Do entire tile at once
         let mut mx : u8 = cx as u8;
         // Go through every x position in the line, determine which tile to use from the tilemap
         // and pick a pixel from it
         for x in 0..SCREEN_WIDTH {
-            let tile_id = gpu.get_tilemap_id((mx as usize) / 8, tile_data_y, tilemap_select);
-            color = gpu.draw_helper.get_bg_tile_pixel(tile_id, (mx % 8) as usize, tile_y, gpu.get_tile_data());
-            self.bitmap[line_y*SCREEN_WIDTH*3+x*3+0] = color.r;
-            self.bitmap[line_y*SCREEN_WIDTH*3+x*3+1] = color.g;
-            self.bitmap[line_y*SCREEN_WIDTH*3+x*3+2] = color.b;
+            self.draw_bg_pixel(line_y, x, mx as usize, tile_data_y, tile_y, gpu, tilemap_select, bg_line);
             mx = mx.wrapping_add(1);
         }
     }
 
     /// Draw a line of the window layer. This window starts to be drawn at cx, cy
     /// and covers everything underneath.
-    fn draw_window_line(&mut self, line_y: usize, internal_window_ly: usize, cx: usize, cy: usize, gpu: &gpu::GPU, tilemap_select : bool) {
+    fn draw_window_line(&mut self, line_y: usize, internal_window_ly: usize, cx: usize, cy: usize, gpu: &gpu::GPU, tilemap_select : bool, bg_line: &mut BackgroundLine) {
         if line_y < cy { 
             // No need to draw this line if window starts further down
             return;
@@ -87,55 +116,68 @@ impl Screen {
         let y = internal_window_ly;
         let tile_data_y = y / 8;
         let tile_y = y % 8;
-        let mut color: draw_helper::Color;
         // If cx is less than 7, we need to start from a later x in the window
         // as part of the window is outside the view
         let mut mx = cmp::max(-(cx as isize - 7), 0) as usize;
         // Which x should we start drawing on the bitmap, while not underflowing when
         // window is outside the bitmap?
         for x in (cmp::max(cx as isize - 7, 0) as usize)..SCREEN_WIDTH {
-            let tile_id = gpu.get_tilemap_id(mx / 8, tile_data_y, tilemap_select);
-            color = gpu.draw_helper.get_bg_tile_pixel(tile_id, mx % 8, tile_y, gpu.get_tile_data());
-            self.bitmap[line_y*SCREEN_WIDTH*3+x*3+0] = color.r;
-            self.bitmap[line_y*SCREEN_WIDTH*3+x*3+1] = color.g;
-            self.bitmap[line_y*SCREEN_WIDTH*3+x*3+2] = color.b;
+            self.draw_bg_pixel(line_y, x, mx, tile_data_y, tile_y, gpu, tilemap_select, bg_line);
             mx += 1;
         }
     }
 
+    /// Draw a background/window pixel. mx is the x position in the tilemap,
+    /// tile_data_y the tilemap row and tile_y the row within the tile
+    fn draw_bg_pixel(&mut self, line_y: usize, x: usize, mx: usize, tile_data_y: usize, tile_y: usize, gpu: &gpu::GPU, tilemap_select : bool, bg_line: &mut BackgroundLine) {
+        let tile_id = gpu.get_tilemap_id(mx / 8, tile_data_y, tilemap_select);
+        let attributes = gpu.get_tilemap_attributes(mx / 8, tile_data_y, tilemap_select);
+        let color_id = gpu.draw_helper.get_bg_tile_color_id(tile_id, mx % 8, tile_y, gpu.get_tile_data(), &attributes);
+        let color = gpu.draw_helper.get_bg_color(color_id, &attributes, gpu.cgb_mode);
+        self.bitmap[line_y*SCREEN_WIDTH*3+x*3+0] = color.r;
+        self.bitmap[line_y*SCREEN_WIDTH*3+x*3+1] = color.g;
+        self.bitmap[line_y*SCREEN_WIDTH*3+x*3+2] = color.b;
+        self.shades[line_y*SCREEN_WIDTH+x] = gpu.draw_helper.get_bg_shade(color_id);
+        bg_line.color_ids[x] = color_id;
+        bg_line.priority[x] = attributes.bg_priority;
+    }
+
+    /// Select the sprites of a line, in the order they are drawn.
+    /// The OAM scan picks the first 10 sprites on the line by OAM index. On DMG, the sprite with
+    /// the lowest x coordinate has priority, with ties broken by OAM index.
+    /// On CGB, the OAM index alone decides the priority
+    fn get_line_sprites<'a>(&self, line_y: usize, height: usize, draw_helper: &'a draw_helper::DrawHelper, cgb_mode: bool) -> Vec<&'a draw_helper::Sprite> {
+        // The sprite y is offset by 16, the line is offset to match the bottom row of the sprite
+        let mut sprites: Vec<&draw_helper::Sprite> = draw_helper.sprite_data.sprites.iter()
+            .filter(|sprite| self.is_sprite_within_line(line_y + 17 - height, sprite, height))
+            .take(10) // Only 10 sprites can be drawn per line
+            .collect();
+        if !cgb_mode {
+            // The sort is stable, so sprites with the same x stay in OAM order
+            sprites.sort_by_key(|sprite| sprite.x);
+        }
+        return sprites;
+    }
+
     /// Draw a line of 8x8 sprites
     /// Every line can have a max of 10 sprites
-    /// On DMG they should generally be picked based on x-sorting, but this seemed
-    /// annoying to implement so priority only uses sprite id order currently
-    fn draw_sprite_line(&mut self, line_y: usize, draw_helper: &draw_helper::DrawHelper) {
-        let mut sprite_count = 0;
-        // Go through all 40 sprites
-        for sprite in &draw_helper.sprite_data.sprites {
-            if sprite_count >= 10 { // Only 10 sprites can be drawn per line
-                return;
+    fn draw_sprite_line(&mut self, line_y: usize, draw_helper: &draw_helper::DrawHelper, cgb_mode: bool, bg_line: &BackgroundLine) {
+        // Pixels already covered by a sprite with higher priority
+        let mut sprite_drawn = [false; SCREEN_WIDTH];
+        for sprite in self.get_line_sprites(line_y, 8, draw_helper, cgb_mode) {
+            // Draw the tile row to the bitmap
+            let start_x = sprite.x as isize - 8;
+            // Ugly code to prevent out of bounds accesses to bitmap
+            let tile_x = -cmp::min(start_x, 0) as usize;
+            let tile_x_end = cmp::min(cmp::max(160 - start_x, 0), 8) as usize;
+            let tile_y = 7 - ((sprite.
+                y) - (line_y + 9));
+            // Go through every pixel in the tile and add it to the bitmap
+            for x in tile_x..tile_x_end {
+                let color_id = draw_helper.get_sprite_tile_color_id(sprite.tile_id, x, tile_y, sprite, false, cgb_mode);
+                let screen_x = (start_x + x as isize) as usize;
+                self.draw_sprite_pixel(line_y, screen_x, color_id, sprite, draw_helper, cgb_mode, bg_line, &mut sprite_drawn);
             }
-            // Is this sprite within the scanline?
-            if self.is_sprite_within_line(line_y + 9 , &sprite, 8) {
-                // If so, draw the tile row to the bitmap
-                let start_x = sprite.x as isize - 8;
-                // Ugly code to prevent out of bounds accesses to bitmap
-                let tile_x = -cmp::min(start_x, 0) as usize;
-                let tile_x_end = cmp::min(cmp::max(160 - start_x, 0), 8) as usize;
-                let tile_y = 7 - ((sprite.
-                    y) - (line_y + 9));
-                let mut color : draw_helper::Color;
-                // Go through every pixel in the tile and add it to the bitmap
-                for x in tile_x..tile_x_end {
-                    color = draw_helper.get_sprite_tile_pixel(sprite.tile_id, x, tile_y, true, sprite, false);
-                    let bitmap_index = line_y*SCREEN_WIDTH*3 + ((start_x + x as isize) as usize)*3;
-                    if color.a > 0 && (!sprite.below_background || self.bitmap[bitmap_index+0] == draw_helper.background_palette.get_color(0).r) { // Skip transparent pixels
-                        self.bitmap[bitmap_index+0] = color.r;
-                        self.bitmap[bitmap_index+1] = color.g;
-                        self.bitmap[bitmap_index+2] = color.b;
-                    }
-                }
-                sprite_count += 1;
-            }   
         }
     }
 
@@ -143,46 +185,91 @@ impl Screen {
     /// This is similar to the 8x8 method, except we need to keep track of two
     /// tiles and also need to handle flip_y a bit differently.
     /// The second tile used is the tile directly to the right in memory
-    fn draw_double_sprite_line(&mut self, line_y: usize, draw_helper: &draw_helper::DrawHelper) {
+    fn draw_double_sprite_line(&mut self, line_y: usize, draw_helper: &draw_helper::DrawHelper, cgb_mode: bool, bg_line: &BackgroundLine) {
         // Clear line to white
         //self.bitmap[line_y*SCREEN_WIDTH*3..(line_y+1)*SCREEN_WIDTH*3].copy_from_slice(&[255; SCREEN_WIDTH*3]);
-        let mut sprite_count = 0;
-        for sprite in &draw_helper.sprite_data.sprites {
-            if self.is_sprite_within_line(line_y+1, &sprite, 16) {
-                if sprite_count >= 10 { // Only 10 sprites can be drawn per line
-                    return;
-                }
-                let start_x = sprite.x as isize - 8;
-                let tile_x = -cmp::min(start_x, 0) as usize;
-                let tile_x_end = cmp::min(cmp::max(160 - start_x, 0), 8) as usize;
-                let mut tile_y: usize;
-                if !sprite.flip_y {
-                    tile_y = 15 - (sprite.
-                        y - (line_y+1));
-                }
-                else {
-                    tile_y = sprite.y - (line_y+1);
-                }
-                let tile_id : u8;
-                if tile_y > 7 {
-                    tile_id = (sprite.tile_id & 0b1111_1110) + 1;
-                }
-                else {
-                    tile_id = sprite.tile_id & 0b1111_1110;
-                }
-                tile_y = tile_y % 8;
-                let mut color : draw_helper::Color;
-                for x in tile_x..tile_x_end {
-                    color = draw_helper.get_sprite_tile_pixel(tile_id, x, tile_y, true, sprite, true);
-                    let bitmap_index = line_y*SCREEN_WIDTH*3 + ((start_x + x as isize) as usize)*3;
-                    if color.a > 0 { // Skip transparent pixels
-                        self.bitmap[bitmap_index+0] = color.r;
-                        self.bitmap[bitmap_index+1] = color.g;
-                        self.bitmap[bitmap_index+2] = color.b;
+        let mut sprite_drawn = [false; SCREEN_WIDTH];
+        for sprite in self.get_line_sprites(line_y, 16, draw_helper, cgb_mode) {
+            let start_x = sprite.x as isize - 8;
+            let tile_x = -cmp::min(start_x, 0) as usize;
+            let tile_x_end = cmp::min(cmp::max(160 - start_x, 0), 8) as usize;
+            let mut tile_y: usize;
+            if !sprite.flip_y {
+                tile_y = 15 - (sprite.
+                    y - (line_y+1));
+            }
+            else {
+                tile_y = sprite.y - (line_y+1);
+            }
+            let tile_id : u8;
+            if tile_y > 7 {
+                tile_id = (sprite.tile_id & 0b1111_1110) + 1;
+            }
+            else {
+                tile_id = sprite.tile_id & 0b1111_1110;
+            }
+            tile_y = tile_y % 8;
+            for x in tile_x..tile_x_end {
+                let color_id = draw_helper.get_sprite_tile_color_id(tile_id, x, tile_y, sprite, true, cgb_mode);
+                let screen_x = (start_x + x as isize) as usize;
+                self.draw_sprite_pixel(line_y, screen_x, color_id, sprite, draw_helper, cgb_mode, bg_line, &mut sprite_drawn);
+            }
+        }
+    }
+
+    /// Draw a sprite pixel, taking sprite and background priority into account.
+    /// The first opaque sprite pixel covers all later sprites, even if it is hidden by the background.
+    /// The background wins if its color is not 0 and either the sprite or the CGB tile attributes
+    /// prioritize the background
+    fn draw_sprite_pixel(&mut self, line_y: usize, x: usize, color_id: u8, sprite: &draw_helper::Sprite, 
+        draw_helper: &draw_helper::DrawHelper, cgb_mode: bool, bg_line: &BackgroundLine, sprite_drawn: &mut [bool; SCREEN_WIDTH]) {
+        if color_id == 0 || sprite_drawn[x] { // Skip transparent pixels
+            return;
+        }
+        sprite_drawn[x] = true;
+        if bg_line.color_ids[x] != 0 && (sprite.below_background || bg_line.priority[x]) {
+            return;
+        }
+        let color = draw_helper.get_sprite_color(color_id, sprite, cgb_mode);
+        let bitmap_index = line_y*SCREEN_WIDTH*3 + x*3;
+        self.bitmap[bitmap_index+0] = color.r;
+        self.bitmap[bitmap_index+1] = color.g;
+        self.bitmap[bitmap_index+2] = color.b;
+        self.shades[line_y*SCREEN_WIDTH + x] = draw_helper.get_sprite_shade(color_id, sprite);
+    }
+
+    /// Draw the Super Gameboy screen. The Gameboy screen is colored with the SGB palettes
+    /// of every 8x8 cell and placed inside the border
+    pub fn draw_sgb_frame(&mut self, sgb: &sgb::Sgb) {
+        let backdrop = draw_helper::Color::from_rgb555(sgb.palettes[0][0]);
+        for y in 0..sgb::SGB_SCREEN_HEIGHT {
+            for x in 0..sgb::SGB_SCREEN_WIDTH {
+                let index = (y*sgb::SGB_SCREEN_WIDTH + x)*3;
+                let screen_x = x as isize - sgb::GB_SCREEN_X as isize;
+                let screen_y = y as isize - sgb::GB_SCREEN_Y as isize;
+                let within_screen = screen_x >= 0 && screen_x < SCREEN_WIDTH as isize && 
+                    screen_y >= 0 && screen_y < SCREEN_HEIGHT as isize;
+                let color = match sgb.get_border_pixel(x, y) {
+                    Some(color) => draw_helper::Color::from_rgb555(color),
+                    None if within_screen => {
+                        let (screen_x, screen_y) = (screen_x as usize, screen_y as usize);
+                        match sgb.mask {
+                            sgb::ScreenMask::Freeze => { continue; } // Keep the previous frame
+                            sgb::ScreenMask::Black => { draw_helper::Color { r: 0, g: 0, b: 0, a: 255 } }
+                            sgb::ScreenMask::Color0 => { backdrop }
+                            sgb::ScreenMask::None => {
+                                let shade = self.shades[screen_y*SCREEN_WIDTH + screen_x];
+                                let palette = sgb.attributes[(screen_y / 8)*20 + screen_x / 8] as usize;
+                                draw_helper::Color::from_rgb555(sgb.palettes[palette][shade as usize])
+                            }
+                        }
                     }
-                }
-                sprite_count += 1;
-            }   
+                    None => { backdrop }
+                };
+                self.sgb_bitmap[index+0] = color.r;
+                self.sgb_bitmap[index+1] = color.g;
+                self.sgb_bitmap[index+2] = color.b;
+            }
         }
     }
 
@@ -202,19 +289,22 @@ impl Screen {
 
 }
 
+fn serde_shades_default() -> Vec<u8> {
+    return vec![0; SCREEN_HEIGHT*SCREEN_WIDTH];
+}
+
 #[cfg(test)]
 mod test
 {
-    use super::super::Emulator;
+    use super::super::{Emulator, PpuRenderer, PaletteCombo};
     
-    /// Run the Acid2 GPU test. The checksum was precalculated.
-    /// A mole is showing on the figure due to no GPU sprite x-ordering implemented
+    /// Run the Acid2 GPU test. The checksum was precalculated from the reference image
     #[test]
     fn acid2()
     {
         let mut em1 = Emulator::new();
-        em1.memory.output_serial_to_stdout = false;
-        em1.memory.rom.load_from_file("../roms/acid2/dmg-acid2.gb");
+        em1.memory.serial.capture.output_to_stdout = false;
+        em1.memory.rom.load_from_file("../roms/acid2/dmg-acid2.gb").unwrap();
 
         // Run emulator for a few frames
         for _ in 0..30 {
@@ -222,6 +312,49 @@ mod test
         }
 
         // Precalculated checksum for test
-        assert_eq!(em1.screen.calculate_simple_checksum(), 4509295);
+        assert_eq!(em1.screen.calculate_simple_checksum(), 597235);
     }
-}
\ No newline at end of file
+
+    /// Run the Acid2 GPU test with the pixel FIFO renderer, which should match the scanline renderer
+    #[test]
+    fn acid2_pixel_fifo()
+    {
+        let mut em1 = Emulator::new();
+        em1.memory.serial.capture.output_to_stdout = false;
+        em1.memory.rom.load_from_file("../roms/acid2/dmg-acid2.gb").unwrap();
+        em1.set_renderer(PpuRenderer::PixelFifo);
+
+        for _ in 0..30 {
+            em1.run_until_frontend_event();
+        }
+
+        assert_eq!(em1.screen.calculate_simple_checksum(), 597235);
+    }
+
+    /// The DMG shades are recorded independently of the colors, so the Super Gameboy palettes
+    /// can be applied to colorized games
+    #[test]
+    fn acid2_shades()
+    {
+        let mut em = Emulator::new();
+        em.memory.serial.capture.output_to_stdout = false;
+        em.memory.rom.load_from_file("../roms/acid2/dmg-acid2.gb").unwrap();
+        for _ in 0..30 {
+            em.run_until_frontend_event();
+        }
+        let gray_shades: Vec<u8> = em.screen.bitmap.chunks(3).map(|pixel| 3 - pixel[0] / 85).collect();
+        assert_eq!(em.screen.shades, gray_shades);
+
+        for renderer in [PpuRenderer::Scanline, PpuRenderer::PixelFifo] {
+            let mut em = Emulator::new();
+            em.memory.serial.capture.output_to_stdout = false;
+            em.memory.rom.load_from_file("../roms/acid2/dmg-acid2.gb").unwrap();
+            em.set_renderer(renderer);
+            em.set_compatibility_palette(Some(PaletteCombo::RightB));
+            for _ in 0..30 {
+                em.run_until_frontend_event();
+            }
+            assert_eq!(em.screen.shades, gray_shades);
+        }
+    }
+}
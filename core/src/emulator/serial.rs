@@ -0,0 +1,209 @@
+// Important memory locations:
+// SB (serial transfer data) : 0xFF01. Shifted out MSB first, while the received bits are shifted in
+// SC (serial transfer control) : 0xFF02.
+// Bit 7: Transfer start/in progress, cleared when the transfer is done
+// Bit 1: Clock speed (CGB only). 0 = 8192 Hz, 1 = 262144 Hz
+// Bit 0: Clock select. 0 = External clock, 1 = Internal clock
+// When a byte has been transferred, a SERIAL interrupt is sent
+
+use std::io::{self, Write};
+
+use serde::{Serialize, Deserialize};
+
+/// A device connected to the link port, like another Gameboy or a printer.
+/// Frontends implement this trait to connect the emulator to other devices
+pub trait SerialDevice {
+    /// Exchange a byte with the device. Called when this Gameboy has shifted out
+    /// a byte using its internal clock. Returns the byte shifted in from the device
+    fn exchange_byte(&mut self, sent: u8) -> u8;
+
+    /// Called every machine cycle while this Gameboy waits for an external clock,
+    /// `sent` is the byte in SB. Returns the received byte once the device
+    /// has clocked a transfer
+    fn poll_external_clock(&mut self, _sent: u8) -> Option<u8> {
+        return None;
+    }
+}
+
+/// Captures the sent bytes, used as debug output by test roms.
+/// Used when no other device is connected, nothing is received
+#[derive(Serialize, Deserialize)]
+pub struct SerialCapture {
+    pub buffer: Vec<u8>,
+    pub output_to_stdout: bool,
+}
+
+impl SerialDevice for SerialCapture {
+    fn exchange_byte(&mut self, sent: u8) -> u8 {
+        self.buffer.push(sent);
+        if self.output_to_stdout {
+            print!("{}", sent as char);
+            io::stdout().flush().expect("Unable to flush stdout");
+        }
+        // A disconnected link port reads all ones
+        return 0xFF;
+    }
+}
+
+/// Represents the serial port
+#[derive(Serialize, Deserialize)]
+pub struct Serial {
+    sb: u8,
+    sc: u8,
+    sent_byte: u8,
+    remaining_bits: u8,
+    clock_counter: usize,
+    pub cgb_mode: bool,
+    pub request_interrupt: bool,
+    pub capture: SerialCapture,
+    // Not saved, Emulator::load_state keeps the connected device
+    #[serde(skip)]
+    device: Option<Box<dyn SerialDevice>>,
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial { sb: 0, sc: 0, sent_byte: 0, remaining_bits: 0, clock_counter: 0, cgb_mode: false, request_interrupt: false,
+            capture: SerialCapture { buffer: Vec::new(), output_to_stdout: true }, device: None }
+    }
+
+    pub fn read_byte(&self, address : usize) -> u8 {
+        match address {
+            0xFF01 => { return self.sb; }
+            0xFF02 => { return self.sc | if self.cgb_mode { 0b0111_1100 } else { 0b0111_1110 }; }
+            _ => panic!("Invalid memory address encountered")
+        }
+    }
+
+    pub fn write_byte(&mut self, address : usize, val: u8) {
+        match address {
+            0xFF01 => { self.sb = val; }
+            0xFF02 => {
+                self.sc = val & if self.cgb_mode { 0b1000_0011 } else { 0b1000_0001 };
+                if self.sc & 0x80 != 0 {
+                    self.sent_byte = self.sb;
+                    self.remaining_bits = 8;
+                    self.clock_counter = 0;
+                }
+                else {
+                    self.remaining_bits = 0;
+                }
+            }
+            _ => panic!("Invalid memory address encountered")
+        }
+    }
+
+    /// Connect a device to the link port, replacing the previous one
+    pub fn connect_device(&mut self, device: Box<dyn SerialDevice>) {
+        self.device = Some(device);
+    }
+
+    /// Disconnect the link port device. The sent bytes are captured again
+    pub fn disconnect_device(&mut self) -> Option<Box<dyn SerialDevice>> {
+        return self.device.take();
+    }
+
+    /// Step the serial port by CPU clock cycles, the transfer speed doubles in CGB double speed mode
+    pub fn cycle(&mut self, cycles: usize) {
+        if self.remaining_bits == 0 {
+            return;
+        }
+        // External clock, the transfer is clocked by the connected device
+        if self.sc & 0b01 == 0 {
+            let received = match &mut self.device {
+                Some(device) => { device.poll_external_clock(self.sb) }
+                None => { None }
+            };
+            if let Some(byte) = received {
+                self.finish_transfer(byte);
+            }
+            return;
+        }
+
+        let bit_period = if self.cgb_mode && self.sc & 0b10 != 0 { 16 } else { 512 };
+        self.clock_counter += cycles;
+        while self.clock_counter >= bit_period && self.remaining_bits > 0 {
+            self.clock_counter -= bit_period;
+            // The received byte is only known when the transfer is done, shift in ones until then
+            self.sb = self.sb << 1 | 1;
+            self.remaining_bits -= 1;
+            if self.remaining_bits == 0 {
+                let received = match &mut self.device {
+                    Some(device) => { device.exchange_byte(self.sent_byte) }
+                    None => { self.capture.exchange_byte(self.sent_byte) }
+                };
+                self.finish_transfer(received);
+            }
+        }
+    }
+
+    fn finish_transfer(&mut self, received: u8) {
+        self.sb = received;
+        self.sc &= 0x7F;
+        self.remaining_bits = 0;
+        self.request_interrupt = true;
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{Serial, SerialDevice};
+
+    /// Echoes the sent bytes back, inverted
+    struct InvertingDevice {
+        clocked_byte: Option<u8>,
+    }
+
+    impl SerialDevice for InvertingDevice {
+        fn exchange_byte(&mut self, sent: u8) -> u8 {
+            return !sent;
+        }
+
+        fn poll_external_clock(&mut self, sent: u8) -> Option<u8> {
+            return self.clocked_byte.take().map(|_| !sent);
+        }
+    }
+
+    #[test]
+    fn internal_clock()
+    {
+        let mut serial = Serial::new();
+        serial.capture.output_to_stdout = false;
+        serial.write_byte(0xFF01, 0x42);
+        serial.write_byte(0xFF02, 0x81);
+        // 8 bits at 8192 Hz
+        serial.cycle(4096 - 4);
+        assert_eq!(serial.read_byte(0xFF02), 0xFF);
+        assert_eq!(serial.request_interrupt, false);
+        serial.cycle(4);
+        assert_eq!((serial.read_byte(0xFF01), serial.read_byte(0xFF02)), (0xFF, 0x7F));
+        assert_eq!(serial.request_interrupt, true);
+        assert_eq!(serial.capture.buffer, vec![0x42]);
+
+        // CGB fast clock, 262144 Hz
+        serial.cgb_mode = true;
+        serial.connect_device(Box::new(InvertingDevice { clocked_byte: None }));
+        serial.write_byte(0xFF01, 0x0F);
+        serial.write_byte(0xFF02, 0x83);
+        serial.cycle(128);
+        assert_eq!((serial.read_byte(0xFF01), serial.read_byte(0xFF02)), (0xF0, 0x7F));
+    }
+
+    #[test]
+    fn external_clock()
+    {
+        let mut serial = Serial::new();
+        serial.connect_device(Box::new(InvertingDevice { clocked_byte: None }));
+        serial.write_byte(0xFF01, 0x0F);
+        serial.write_byte(0xFF02, 0x80);
+        // Without the external clock, the transfer never finishes
+        serial.cycle(100000);
+        assert_eq!(serial.read_byte(0xFF02), 0xFE);
+
+        serial.connect_device(Box::new(InvertingDevice { clocked_byte: Some(0x00) }));
+        serial.cycle(4);
+        assert_eq!((serial.read_byte(0xFF01), serial.read_byte(0xFF02)), (0xF0, 0x7E));
+        assert_eq!(serial.request_interrupt, true);
+    }
+}
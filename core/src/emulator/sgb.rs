@@ -0,0 +1,494 @@
+/// Represents the Super Gameboy (SGB) features.
+///
+/// SGB games send commands to the SNES through the joypad register (0xFF00).
+/// A packet starts with a reset pulse (P14 and P15 low), followed by 128 bits,
+/// where P14 low is a 0 bit and P15 low is a 1 bit, and a 0 stop bit.
+/// The first byte of a command is the command id (bit 3-7) and the number of
+/// 16 byte packets in the command (bit 0-2).
+///
+/// The SGB colors the Gameboy screen using 4 palettes, selected per 8x8 cell
+/// through attribute commands, and draws a 256x224 border around the screen.
+/// Larger data, like the border, is sent by displaying it on the Gameboy screen
+/// and then sending a transfer command (*_TRN).
+
+use super::gpu;
+use super::joypad;
+
+use serde::{Serialize, Deserialize};
+use serde_big_array::BigArray;
+
+pub const SGB_SCREEN_WIDTH: usize = 256;
+pub const SGB_SCREEN_HEIGHT: usize = 224;
+// Position of the Gameboy screen in the SGB screen
+pub const GB_SCREEN_X: usize = 48;
+pub const GB_SCREEN_Y: usize = 40;
+
+const CELLS_X: usize = 20;
+const CELLS_Y: usize = 18;
+
+/// Command ids, sent in bit 3-7 of the first packet byte
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const ATTR_LIN: u8 = 0x05;
+const ATTR_DIV: u8 = 0x06;
+const ATTR_CHR: u8 = 0x07;
+const PAL_SET: u8 = 0x0A;
+const PAL_TRN: u8 = 0x0B;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const ATTR_TRN: u8 = 0x15;
+const ATTR_SET: u8 = 0x16;
+const MASK_EN: u8 = 0x17;
+
+/// Data sent through VRAM, copied at the end of the frame after the command
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Debug)]
+enum VramTransfer {
+    BorderTiles(usize), // CHR_TRN, first tile
+    BorderMap, // PCT_TRN
+    SystemPalettes, // PAL_TRN
+    AttributeFiles, // ATTR_TRN
+}
+
+/// MASK_EN modes, used to hide the screen while the game sets it up
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Debug)]
+pub enum ScreenMask {
+    None,
+    Freeze,
+    Black,
+    Color0,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Sgb {
+    pub enabled: bool,
+
+    // Packet reception
+    receiving: bool,
+    bit_index: usize,
+    packet: [u8; 16],
+    command_data: Vec<u8>,
+    previous_joyp: u8,
+
+    // The 4 screen palettes, as RGB555 colors. Color 0 is shared
+    pub palettes: [[u16; 4]; 4],
+    // 512 palettes sent through PAL_TRN
+    system_palettes: Vec<u16>,
+    // Palette of every 8x8 cell of the Gameboy screen
+    #[serde(with = "BigArray")]
+    pub attributes: [u8; CELLS_X*CELLS_Y],
+    // 45 attribute files sent through ATTR_TRN, 90 bytes each
+    attribute_files: Vec<u8>,
+    pub mask: ScreenMask,
+
+    // Border, 256 SNES 4bpp tiles, a 32x32 tilemap and palettes 4-7
+    border_tiles: Vec<u8>,
+    border_map: Vec<u16>,
+    border_palettes: Vec<u16>,
+
+    pending_transfer: Option<VramTransfer>,
+}
+
+impl Sgb {
+    pub fn new() -> Sgb {
+        Sgb {
+            enabled: false,
+            receiving: false,
+            bit_index: 0,
+            packet: [0; 16],
+            command_data: Vec::new(),
+            previous_joyp: 0x30,
+            // Default to the DMG grays
+            palettes: [[0x7FFF, 0x56B5, 0x294A, 0x0000]; 4],
+            system_palettes: vec![0; 512*4],
+            attributes: [0; CELLS_X*CELLS_Y],
+            attribute_files: vec![0; 45*90],
+            mask: ScreenMask::None,
+            border_tiles: vec![0; 256*32],
+            border_map: vec![0; 32*32],
+            border_palettes: vec![0; 4*16],
+            pending_transfer: None,
+        }
+    }
+
+    /// Handle a write to the joypad register, which is used to send packets
+    pub fn write_joypad(&mut self, joyp: u8, joypad: &mut joypad::Joypad) {
+        let joyp = joyp & 0x30;
+        let previous_joyp = self.previous_joyp;
+        self.previous_joyp = joyp;
+        if joyp == 0x00 { // Reset pulse, start a new packet
+            self.receiving = true;
+            self.bit_index = 0;
+            self.packet = [0; 16];
+            return;
+        }
+        // Bits are registered when P14 or P15 goes low from high
+        if !self.receiving || previous_joyp != 0x30 || joyp == 0x30 {
+            return;
+        }
+        if self.bit_index == 128 { // Stop bit
+            self.receiving = false;
+            self.receive_packet(joypad);
+            return;
+        }
+        if joyp == 0x10 { // P15 low, 1 bit
+            self.packet[self.bit_index / 8] |= 1 << (self.bit_index % 8);
+        }
+        self.bit_index += 1;
+    }
+
+    fn receive_packet(&mut self, joypad: &mut joypad::Joypad) {
+        self.command_data.extend_from_slice(&self.packet);
+        let packet_count = std::cmp::max(self.command_data[0] & 0x07, 1) as usize;
+        if self.command_data.len() >= packet_count*16 {
+            let data = std::mem::take(&mut self.command_data);
+            self.execute_command(&data, joypad);
+        }
+    }
+
+    fn execute_command(&mut self, data: &[u8], joypad: &mut joypad::Joypad) {
+        match data[0] >> 3 {
+            PAL01 => { self.set_palette_pair(0, 1, data); }
+            PAL23 => { self.set_palette_pair(2, 3, data); }
+            PAL03 => { self.set_palette_pair(0, 3, data); }
+            PAL12 => { self.set_palette_pair(1, 2, data); }
+            ATTR_BLK => { self.attribute_block(data); }
+            ATTR_LIN => { self.attribute_line(data); }
+            ATTR_DIV => { self.attribute_divide(data); }
+            ATTR_CHR => { self.attribute_character(data); }
+            PAL_SET => { self.palette_set(data); }
+            PAL_TRN => { self.pending_transfer = Some(VramTransfer::SystemPalettes); }
+            MLT_REQ => {
+                // 1, 2 or 4 players
+                joypad.set_player_count(match data[1] & 0x03 { 1 => 2, 3 => 4, _ => 1 });
+            }
+            CHR_TRN => { self.pending_transfer = Some(VramTransfer::BorderTiles(((data[1] & 0x01) as usize) * 128)); }
+            PCT_TRN => { self.pending_transfer = Some(VramTransfer::BorderMap); }
+            ATTR_TRN => { self.pending_transfer = Some(VramTransfer::AttributeFiles); }
+            ATTR_SET => {
+                self.apply_attribute_file((data[1] & 0x3F) as usize);
+                if data[1] & 0x40 == 0x40 {
+                    self.mask = ScreenMask::None;
+                }
+            }
+            MASK_EN => {
+                self.mask = match data[1] & 0x03 {
+                    1 => ScreenMask::Freeze,
+                    2 => ScreenMask::Black,
+                    3 => ScreenMask::Color0,
+                    _ => ScreenMask::None,
+                };
+            }
+            _ => { } // Sound, SNES and unknown commands are ignored
+        }
+    }
+
+    /// PAL01, PAL23, PAL03, PAL12. Color 0 is shared between all palettes
+    fn set_palette_pair(&mut self, first: usize, second: usize, data: &[u8]) {
+        let color = |i: usize| -> u16 { data[1 + i*2] as u16 | (data[2 + i*2] as u16) << 8 };
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color(0);
+        }
+        for i in 1..4 {
+            self.palettes[first][i] = color(i);
+            self.palettes[second][i] = color(i + 3);
+        }
+    }
+
+    /// ATTR_BLK. Set the palette inside, on the border of and outside rectangles
+    fn attribute_block(&mut self, data: &[u8]) {
+        let count = data[1] as usize & 0x1F;
+        for set in data[2..].chunks(6).take(count) {
+            if set.len() < 6 {
+                break;
+            }
+            let control = set[0];
+            let inside = control & 0x01 == 0x01;
+            let mut border = control & 0x02 == 0x02;
+            let outside = control & 0x04 == 0x04;
+            let inside_palette = set[1] & 0x03;
+            let mut border_palette = (set[1] >> 2) & 0x03;
+            let outside_palette = (set[1] >> 4) & 0x03;
+            // If only inside or outside is set, the border uses the same palette
+            if control & 0x07 == 0x01 {
+                border = true;
+                border_palette = inside_palette;
+            }
+            else if control & 0x07 == 0x04 {
+                border = true;
+                border_palette = outside_palette;
+            }
+            let (x1, y1, x2, y2) = (set[2] as usize & 0x1F, set[3] as usize & 0x1F, set[4] as usize & 0x1F, set[5] as usize & 0x1F);
+            for y in 0..CELLS_Y {
+                for x in 0..CELLS_X {
+                    let in_x = x >= x1 && x <= x2;
+                    let in_y = y >= y1 && y <= y2;
+                    let on_border = in_x && in_y && (x == x1 || x == x2 || y == y1 || y == y2);
+                    let cell = &mut self.attributes[y*CELLS_X + x];
+                    if on_border {
+                        if border { *cell = border_palette; }
+                    }
+                    else if in_x && in_y {
+                        if inside { *cell = inside_palette; }
+                    }
+                    else if outside {
+                        *cell = outside_palette;
+                    }
+                }
+            }
+        }
+    }
+
+    /// ATTR_LIN. Set the palette of entire rows or columns
+    fn attribute_line(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+        for line in data[2..].iter().take(count) {
+            let index = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0x03;
+            if line & 0x80 == 0x80 { // Horizontal line
+                if index < CELLS_Y {
+                    for x in 0..CELLS_X {
+                        self.attributes[index*CELLS_X + x] = palette;
+                    }
+                }
+            }
+            else if index < CELLS_X { // Vertical line
+                for y in 0..CELLS_Y {
+                    self.attributes[y*CELLS_X + index] = palette;
+                }
+            }
+        }
+    }
+
+    /// ATTR_DIV. Divide the screen in two with a line between
+    fn attribute_divide(&mut self, data: &[u8]) {
+        let after_palette = data[1] & 0x03;
+        let before_palette = (data[1] >> 2) & 0x03;
+        let line_palette = (data[1] >> 4) & 0x03;
+        let horizontal = data[1] & 0x40 == 0x40;
+        let coordinate = data[2] as usize & 0x1F;
+        for y in 0..CELLS_Y {
+            for x in 0..CELLS_X {
+                let position = if horizontal { y } else { x };
+                self.attributes[y*CELLS_X + x] = if position < coordinate { before_palette }
+                    else if position == coordinate { line_palette }
+                    else { after_palette };
+            }
+        }
+    }
+
+    /// ATTR_CHR. Set the palette of individual cells, 2 bits per cell
+    fn attribute_character(&mut self, data: &[u8]) {
+        let mut x = data[1] as usize % CELLS_X;
+        let mut y = data[2] as usize % CELLS_Y;
+        let count = std::cmp::min(data[3] as usize | (data[4] as usize) << 8, CELLS_X*CELLS_Y);
+        let vertical = data[5] & 0x01 == 0x01;
+        for i in 0..count {
+            let byte = match data.get(6 + i / 4) {
+                Some(byte) => *byte,
+                None => break,
+            };
+            self.attributes[y*CELLS_X + x] = (byte >> (6 - (i % 4)*2)) & 0x03;
+            if vertical {
+                y += 1;
+                if y == CELLS_Y { y = 0; x = (x + 1) % CELLS_X; }
+            }
+            else {
+                x += 1;
+                if x == CELLS_X { x = 0; y = (y + 1) % CELLS_Y; }
+            }
+        }
+    }
+
+    /// PAL_SET. Copy system palettes from PAL_TRN into the screen palettes
+    fn palette_set(&mut self, data: &[u8]) {
+        for i in 0..4 {
+            let index = (data[1 + i*2] as usize | (data[2 + i*2] as usize) << 8) & 0x1FF;
+            self.palettes[i].copy_from_slice(&self.system_palettes[index*4..index*4 + 4]);
+        }
+        // Color 0 of palette 0 is used for all palettes
+        for i in 1..4 {
+            self.palettes[i][0] = self.palettes[0][0];
+        }
+        if data[9] & 0x80 == 0x80 {
+            self.apply_attribute_file((data[9] & 0x3F) as usize);
+        }
+        if data[9] & 0x40 == 0x40 {
+            self.mask = ScreenMask::None;
+        }
+    }
+
+    /// Set the cell palettes from an attribute file, 4 cells per byte
+    fn apply_attribute_file(&mut self, file: usize) {
+        if file >= 45 {
+            return;
+        }
+        let data = &self.attribute_files[file*90..(file + 1)*90];
+        for i in 0..CELLS_X*CELLS_Y {
+            self.attributes[i] = (data[i / 4] >> (6 - (i % 4)*2)) & 0x03;
+        }
+    }
+
+    /// Perform a pending VRAM transfer. The SGB reads the 4KB of data from
+    /// the first 256 tiles shown in the background, row by row
+    pub fn perform_vram_transfer(&mut self, gpu: &gpu::GPU) {
+        let transfer = match self.pending_transfer.take() {
+            Some(transfer) => transfer,
+            None => return,
+        };
+        let mut data = vec![0; 4096];
+        for tile in 0..256 {
+            let tile_id = gpu.get_tilemap_id(tile % CELLS_X, tile / CELLS_X, gpu.get_bg_tile_map());
+            let address = if gpu.get_tile_data() { tile_id as usize * 16 }
+                else { (0x1000 + (tile_id as i8 as isize)*16) as usize };
+            data[tile*16..tile*16 + 16].copy_from_slice(&gpu.video_ram[address..address + 16]);
+        }
+        let word = |i: usize| -> u16 { data[i*2] as u16 | (data[i*2 + 1] as u16) << 8 };
+        match transfer {
+            VramTransfer::BorderTiles(first_tile) => {
+                self.border_tiles[first_tile*32..first_tile*32 + 4096].copy_from_slice(&data);
+            }
+            VramTransfer::BorderMap => {
+                for i in 0..32*32 {
+                    self.border_map[i] = word(i);
+                }
+                for i in 0..4*16 {
+                    self.border_palettes[i] = word(0x400 + i);
+                }
+            }
+            VramTransfer::SystemPalettes => {
+                for i in 0..512*4 {
+                    self.system_palettes[i] = word(i);
+                }
+            }
+            VramTransfer::AttributeFiles => {
+                self.attribute_files.copy_from_slice(&data[0..45*90]);
+            }
+        }
+    }
+
+    /// Returns the RGB555 color of a border pixel, or None if it is transparent
+    pub fn get_border_pixel(&self, x: usize, y: usize) -> Option<u16> {
+        let entry = self.border_map[(y / 8)*32 + x / 8];
+        let tile = (entry & 0xFF) as usize;
+        let palette = ((entry >> 10) & 0x07) as usize;
+        let mut tile_x = x % 8;
+        let mut tile_y = y % 8;
+        if entry & 0x4000 == 0x4000 { tile_x = 7 - tile_x; }
+        if entry & 0x8000 == 0x8000 { tile_y = 7 - tile_y; }
+        // SNES 4bpp tile, bitplane 0 and 1 interleaved followed by bitplane 2 and 3
+        let data = &self.border_tiles[tile*32..tile*32 + 32];
+        let bit = 7 - tile_x;
+        let color = ((data[tile_y*2] >> bit) & 1)
+            | ((data[tile_y*2 + 1] >> bit) & 1) << 1
+            | ((data[16 + tile_y*2] >> bit) & 1) << 2
+            | ((data[16 + tile_y*2 + 1] >> bit) & 1) << 3;
+        if color == 0 || palette < 4 {
+            return None;
+        }
+        return Some(self.border_palettes[(palette - 4)*16 + color as usize]);
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+
+    /// Send a command packet through the joypad register
+    fn send_packet(sgb: &mut Sgb, joypad: &mut joypad::Joypad, packet: &[u8; 16]) {
+        sgb.write_joypad(0x00, joypad);
+        sgb.write_joypad(0x30, joypad);
+        for i in 0..128 {
+            let bit = (packet[i / 8] >> (i % 8)) & 1;
+            sgb.write_joypad(if bit == 1 { 0x10 } else { 0x20 }, joypad);
+            sgb.write_joypad(0x30, joypad);
+        }
+        sgb.write_joypad(0x20, joypad);
+        sgb.write_joypad(0x30, joypad);
+    }
+
+    #[test]
+    fn sgb_commands()
+    {
+        let mut sgb = Sgb::new();
+        let mut joypad = joypad::Joypad::new();
+
+        // PAL01, color 0 is red, palette 1 color 3 is blue
+        let mut packet = [0; 16];
+        packet[0] = PAL01 << 3 | 1;
+        packet[1] = 0x1F;
+        packet[13] = 0x00;
+        packet[14] = 0x7C;
+        send_packet(&mut sgb, &mut joypad, &packet);
+        assert_eq!(sgb.palettes[0][0], 0x001F);
+        assert_eq!(sgb.palettes[3][0], 0x001F);
+        assert_eq!(sgb.palettes[1][3], 0x7C00);
+
+        // ATTR_BLK, palette 2 inside and on the border of (1,1)-(3,2), palette 1 outside
+        let mut packet = [0; 16];
+        packet[0] = ATTR_BLK << 3 | 1;
+        packet[1] = 1;
+        packet[2] = 0x07;
+        packet[3] = 0b01_10_10;
+        packet[4..8].copy_from_slice(&[1, 1, 3, 2]);
+        send_packet(&mut sgb, &mut joypad, &packet);
+        assert_eq!(sgb.attributes[0], 1);
+        assert_eq!(sgb.attributes[CELLS_X + 1], 2);
+        assert_eq!(sgb.attributes[2*CELLS_X + 3], 2);
+        assert_eq!(sgb.attributes[2*CELLS_X + 4], 1);
+
+        // ATTR_DIV, horizontal division at row 9
+        let mut packet = [0; 16];
+        packet[0] = ATTR_DIV << 3 | 1;
+        packet[1] = 0x40 | 0b11_10_01;
+        packet[2] = 9;
+        send_packet(&mut sgb, &mut joypad, &packet);
+        assert_eq!(sgb.attributes[8*CELLS_X], 2);
+        assert_eq!(sgb.attributes[9*CELLS_X], 3);
+        assert_eq!(sgb.attributes[10*CELLS_X], 1);
+
+        // MASK_EN
+        let mut packet = [0; 16];
+        packet[0] = MASK_EN << 3 | 1;
+        packet[1] = 2;
+        send_packet(&mut sgb, &mut joypad, &packet);
+        assert_eq!(sgb.mask, ScreenMask::Black);
+
+        // MLT_REQ, games detect the SGB by checking the joypad id
+        let mut packet = [0; 16];
+        packet[0] = MLT_REQ << 3 | 1;
+        packet[1] = 1;
+        send_packet(&mut sgb, &mut joypad, &packet);
+        joypad.write_byte(0x30);
+        let first_id = joypad.read_byte();
+        joypad.write_byte(0x10);
+        joypad.write_byte(0x30);
+        assert_ne!(joypad.read_byte(), first_id);
+    }
+
+    #[test]
+    fn sgb_screen()
+    {
+        let mut sgb = Sgb::new();
+        let mut screen = super::super::screen::Screen::new();
+        // Border tile 0 in the top left corner, with palette 4 color 1 as red
+        sgb.border_map[0] = 4 << 10;
+        sgb.border_tiles[0] = 0xFF;
+        sgb.border_palettes[1] = 0x001F;
+        sgb.palettes[0][0] = 0x7C00;
+        sgb.palettes[1][3] = 0x03E0;
+        sgb.attributes[1] = 1;
+        screen.shades[8] = 3;
+        screen.draw_sgb_frame(&sgb);
+
+        let pixel = |x: usize, y: usize| -> &[u8] { &screen.sgb_bitmap[(y*SGB_SCREEN_WIDTH + x)*3..(y*SGB_SCREEN_WIDTH + x)*3+3] };
+        assert_eq!(pixel(0, 0), &[255, 0, 0]); // Border
+        assert_eq!(pixel(0, 1), &[0, 0, 255]); // Backdrop
+        assert_eq!(pixel(GB_SCREEN_X, GB_SCREEN_Y), &[0, 0, 255]); // White, color 0
+        assert_eq!(pixel(GB_SCREEN_X + 8, GB_SCREEN_Y), &[0, 255, 0]); // Black in palette 1
+    }
+}
@@ -10,80 +10,250 @@
 // 10: CPU Clock / 64   (DMG, CGB:  65536 Hz, SGB:  ~67110 Hz)
 // 11: CPU Clock / 256  (DMG, CGB:  16384 Hz, SGB:  ~16780 Hz)
 // When TIMA overflows, a TIMER interrupt is sent
+//
+// DIV is the upper byte of an internal 16 bit counter, which increments every cycle.
+// TIMA increments on the falling edge of a counter bit selected by TAC, ANDed
+// with the timer enable. Resetting DIV or changing TAC can therefore cause
+// extra increments. The APU frame sequencer is stepped by the falling edge of bit 12
+// (bit 13 in CGB double speed mode).
 
 use serde::{Serialize, Deserialize};
 
 #[derive(Serialize, Deserialize)]
 pub struct Timer {
-    pub div: u8,
+    counter: u16,
     pub tima: u8,
     pub tma: u8,
     pub tac: u8,
     pub request_interrupt: bool,
-    div_increment_counter: u16,
-    tima_increment_counter: u16,
-    enabled: bool,
-    tima_step: u16,
+    pub frame_sequencer_step_requested: bool,
+    pub double_speed: bool,
+    // TIMA overflowed during the last machine cycle, it is reloaded during the next one
+    overflow_pending: bool,
+    // TIMA was reloaded from TMA during this machine cycle
+    reloading: bool,
 }
 
 impl Timer {
     pub fn new() -> Timer {
-        Timer { div: 0, tima: 0, tma: 0, tac: 0, request_interrupt: false, div_increment_counter: 0,
-            tima_increment_counter: 0, enabled: false, tima_step: 256, }
+        Timer { counter: 0, tima: 0, tma: 0, tac: 0, request_interrupt: false, frame_sequencer_step_requested: false,
+            double_speed: false, overflow_pending: false, reloading: false, }
     }
 
     pub fn read_byte(&self, address : usize) -> u8 {
         match address {
-            // Timer 
-            0xFF04 => { 
-                return self.div; }
+            // Timer
+            0xFF04 => { return (self.counter >> 8) as u8; }
             0xFF05 => { return self.tima; }
             0xFF06 => { return self.tma; }
-            0xFF07 => { return self.tac; }
+            0xFF07 => { return self.tac | 0b1111_1000; }
             _ => panic!("Invalid memory address encountered")
         }
     }
 
     pub fn write_byte(&mut self, address : usize, val: u8) {
         match address {
-            0xFF04 => { self.div = 0; }
-            0xFF05 => { self.tima = val; }
-            0xFF06 => { self.tma = val; }
+            0xFF04 => { self.set_divider(0); }
+            0xFF05 => {
+                // Writing during the reload cycle is ignored,
+                // writing in the cycle before cancels the reload
+                if !self.reloading {
+                    self.tima = val;
+                    self.overflow_pending = false;
+                }
+            }
+            0xFF06 => {
+                self.tma = val;
+                if self.reloading {
+                    self.tima = val;
+                }
+            }
             0xFF07 => { self.set_tac(val); }
             _ => panic!("Invalid memory address encountered")
         }
     }
 
+    /// Set the internal 16 bit divider counter, DIV is the upper byte
+    pub fn set_divider(&mut self, counter: u16) {
+        self.update_counter(counter);
+    }
+
     pub fn set_tac(&mut self, tac: u8) {
-        self.tac = tac;
-        self.enabled = tac & 0b100 == 0b100;
-        match tac & 0b11 {
-            0b00 => { self.tima_step = 1024}
-            0b01 => { self.tima_step = 16}
-            0b10 => { self.tima_step = 64}
-            0b11 => { self.tima_step = 256}
-            _ => { panic!("Timer: Incorrect tac");}
+        let old_signal = self.get_timer_signal(self.counter);
+        self.tac = tac & 0b111;
+        if old_signal && !self.get_timer_signal(self.counter) {
+            self.increment_tima();
         }
     }
 
     pub fn increment_by_cycles(&mut self, cycles : u16) {
-        self.div_increment_counter += cycles;
-        while self.div_increment_counter >= 256 {
-            self.div = self.div.wrapping_add(1);
-            self.div_increment_counter -= 256
+        // The counter is stepped a machine cycle at a time,
+        // as the lowest bit used by TIMA changes every 8 cycles
+        for _ in 0..cycles / 4 {
+            self.reloading = false;
+            if self.overflow_pending {
+                self.overflow_pending = false;
+                self.tima = self.tma;
+                self.request_interrupt = true;
+                self.reloading = true;
+            }
+            self.update_counter(self.counter.wrapping_add(4));
+        }
+    }
+
+    /// Set the counter and increment TIMA and the frame sequencer on falling edges
+    fn update_counter(&mut self, counter: u16) {
+        let old_counter = self.counter;
+        self.counter = counter;
+        if self.get_timer_signal(old_counter) && !self.get_timer_signal(counter) {
+            self.increment_tima();
         }
+        let frame_sequencer_bit = if self.double_speed { 1 << 13 } else { 1 << 12 };
+        if old_counter & frame_sequencer_bit != 0 && counter & frame_sequencer_bit == 0 {
+            self.frame_sequencer_step_requested = true;
+        }
+    }
 
-        if self.enabled {
-            self.tima_increment_counter += cycles;
+    /// The input of the TIMA falling edge detector, the selected counter bit ANDed with the enable bit
+    fn get_timer_signal(&self, counter: u16) -> bool {
+        let bit = match self.tac & 0b11 {
+            0b00 => { 9 }
+            0b01 => { 3 }
+            0b10 => { 5 }
+            _ => { 7 }
+        };
+        return self.tac & 0b100 != 0 && counter & (1 << bit) != 0;
+    }
 
-            while self.tima_increment_counter >= self.tima_step {
-                self.tima = self.tima.wrapping_add(1);
-                if self.tima == 0 {
-                    self.tima = self.tma;
-                    self.request_interrupt = true;
-                }
-                self.tima_increment_counter -= self.tima_step;
-            }
+    fn increment_tima(&mut self) {
+        self.tima = self.tima.wrapping_add(1);
+        // TIMA stays 0 for a machine cycle before it is reloaded
+        if self.tima == 0 {
+            self.overflow_pending = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::Timer;
+    use super::super::test::run_mooneye_test;
+
+    #[test]
+    fn timer_edges()
+    {
+        let mut timer = Timer::new();
+        // 262144 Hz, bit 3
+        timer.write_byte(0xFF07, 0b101);
+        timer.increment_by_cycles(16);
+        assert_eq!(timer.tima, 1);
+        // Resetting DIV while bit 3 is set increments TIMA
+        timer.increment_by_cycles(8);
+        timer.write_byte(0xFF04, 0);
+        assert_eq!(timer.tima, 2);
+        // So does disabling the timer
+        timer.increment_by_cycles(8);
+        timer.write_byte(0xFF07, 0b001);
+        assert_eq!(timer.tima, 3);
+    }
+
+    #[test]
+    fn tima_reload()
+    {
+        let mut timer = Timer::new();
+        timer.write_byte(0xFF06, 0x42);
+        timer.write_byte(0xFF05, 0xFF);
+        timer.write_byte(0xFF07, 0b101);
+        timer.increment_by_cycles(16);
+        // TIMA reads 0 for a machine cycle before the reload
+        assert_eq!((timer.tima, timer.request_interrupt), (0x00, false));
+        timer.increment_by_cycles(4);
+        assert_eq!((timer.tima, timer.request_interrupt), (0x42, true));
+        // TMA writes during the reload cycle go through to TIMA, TIMA writes are ignored
+        timer.write_byte(0xFF06, 0x50);
+        timer.write_byte(0xFF05, 0x10);
+        assert_eq!(timer.tima, 0x50);
+
+        // Writing TIMA in the cycle after the overflow cancels the reload
+        timer.request_interrupt = false;
+        timer.write_byte(0xFF05, 0xFF);
+        timer.increment_by_cycles(16);
+        timer.write_byte(0xFF05, 0x20);
+        timer.increment_by_cycles(4);
+        assert_eq!((timer.tima, timer.request_interrupt), (0x20, false));
+    }
+
+    #[test]
+    fn div_write_increments_tima()
+    {
+        // The counter bit selected by each TAC frequency
+        const SELECTED_BITS : [(u8, u16); 4] = [(0b00, 9), (0b01, 3), (0b10, 5), (0b11, 7)];
+        for (frequency, bit) in SELECTED_BITS.iter() {
+            let mut timer = Timer::new();
+            timer.write_byte(0xFF07, 0b100 | frequency);
+            // The selected bit is high, resetting DIV is a falling edge
+            timer.set_divider(1 << bit);
+            timer.write_byte(0xFF04, 0x12);
+            assert_eq!(timer.tima, 1, "TAC frequency {:02b}", frequency);
+            assert_eq!(timer.read_byte(0xFF04), 0);
+            // Only the selected bit matters
+            timer.set_divider(!(1 << bit) & 0xFFFC);
+            timer.write_byte(0xFF04, 0x12);
+            assert_eq!(timer.tima, 1, "TAC frequency {:02b}", frequency);
+        }
+
+        // A disabled timer does not increment
+        let mut timer = Timer::new();
+        timer.write_byte(0xFF07, 0b001);
+        timer.set_divider(1 << 3);
+        timer.write_byte(0xFF04, 0);
+        assert_eq!(timer.tima, 0);
+    }
+
+    #[test]
+    fn tac_change_glitch()
+    {
+        let mut timer = Timer::new();
+        // Bit 3 high, bit 5 low
+        timer.write_byte(0xFF07, 0b101);
+        timer.set_divider(1 << 3);
+        // Switching to a frequency with a low bit is a falling edge
+        timer.write_byte(0xFF07, 0b110);
+        assert_eq!(timer.tima, 1);
+        // Switching from a low bit to a high one is not
+        timer.write_byte(0xFF07, 0b101);
+        assert_eq!(timer.tima, 1);
+        // Disabling the timer while the selected bit is high is a falling edge, enabling it is not
+        timer.write_byte(0xFF07, 0b001);
+        assert_eq!(timer.tima, 2);
+        timer.write_byte(0xFF07, 0b101);
+        assert_eq!(timer.tima, 2);
+        // Switching between two high bits keeps the signal high
+        timer.set_divider((1 << 3) | (1 << 9));
+        timer.write_byte(0xFF07, 0b100);
+        assert_eq!(timer.tima, 2);
+        // The overflow caused by the glitch reloads TIMA like a normal one
+        timer.write_byte(0xFF06, 0x80);
+        timer.write_byte(0xFF05, 0xFF);
+        timer.write_byte(0xFF07, 0b110);
+        assert_eq!(timer.tima, 0);
+        timer.increment_by_cycles(4);
+        assert_eq!((timer.tima, timer.request_interrupt), (0x80, true));
+    }
+
+    #[test]
+    #[ignore = "needs roms/mooneye"]
+    fn mooneye_timer()
+    {
+        const TESTS : [&str; 13] = [
+            "div_write.gb", "rapid_toggle.gb", "tim00.gb", "tim00_div_trigger.gb",
+            "tim01.gb", "tim01_div_trigger.gb", "tim10.gb", "tim10_div_trigger.gb",
+            "tim11.gb", "tim11_div_trigger.gb", "tima_reload.gb", "tima_write_reloading.gb",
+            "tma_write_reloading.gb",
+        ];
+        for test in TESTS.iter() {
+            run_mooneye_test(&format!("acceptance/timer/{}", test));
         }
     }
-}
\ No newline at end of file
+}
@@ -1,6 +1,7 @@
 mod renderer;
 mod sound;
 mod debugger;
+mod network_link;
 
 #[macro_use]
 extern crate bmp;
@@ -8,6 +9,10 @@ use emulator_core::{emulator, emulator::FrontendEvent};
 
 use clap::{Arg};
 use std::fs;
+use std::path::{Path, PathBuf};
+
+/// How often battery backed cartridge RAM is flushed to the .sav file, in frames
+const BATTERY_SAVE_INTERVAL : usize = 300;
 
 /// Run a SDL2 frontend for the Gameboy Emulator
 fn main() {
@@ -32,6 +37,30 @@ fn main() {
          .long("bootrom")
          .takes_value(true)
          .value_name("BOOTROMFILE"))
+    .arg(Arg::new("freebootrom")
+         .help("Use the bundled free bootrom, which shows the boot animation")
+         .long("free-bootrom")
+         .conflicts_with("bootrom"))
+    .arg(Arg::new("model")
+        .help("Select the Gameboy model to emulate. By default it is selected from the cartridge header")
+        .long("model")
+        .takes_value(true)
+        .value_name("MODEL")
+        .possible_values(&["dmg0", "dmg", "mgb", "sgb", "sgb2", "cgb", "agb"]))
+    .arg(Arg::new("renderer")
+        .help("Select the PPU renderer. \"fifo\" draws a dot at a time and shows mid-scanline effects, \"scanline\" is faster")
+        .long("renderer")
+        .takes_value(true)
+        .value_name("RENDERER")
+        .default_value("scanline")
+        .possible_values(&["scanline", "fifo"]))
+    .arg(Arg::new("palette")
+        .help("Colorize DMG games like the Gameboy Color. \"auto\" picks the palette from the title, or select a button combination like \"up+a\"")
+        .long("palette")
+        .takes_value(true)
+        .value_name("PALETTE")
+        .possible_values(&["auto", "up", "up+a", "up+b", "left", "left+a", "left+b", 
+            "down", "down+a", "down+b", "right", "right+a", "right+b"]))
     .arg(Arg::new("audiosync")
         .help("Select audio syncing strategy.")
         .long("audiosync")
@@ -47,25 +76,43 @@ fn main() {
          .help("Use CPU Debugger")
          .short('d')
          .long("debugger"))
+    .arg(Arg::new("link")
+         .help("Connect a second emulator running ROMFILE through the link cable, shown side by side")
+         .short('l')
+         .long("link")
+         .takes_value(true)
+         .value_name("ROMFILE")
+         .conflicts_with("debugger"))
+    .arg(Arg::new("host")
+         .help("Wait for an emulator in another process to connect through the link cable. ADDRESS is a TCP address like 0.0.0.0:5432, or a Unix socket like unix:/tmp/gameboy.sock")
+         .long("host")
+         .takes_value(true)
+         .value_name("ADDRESS")
+         .conflicts_with_all(&["link", "join"]))
+    .arg(Arg::new("join")
+         .help("Connect to an emulator hosting a link cable at ADDRESS")
+         .long("join")
+         .takes_value(true)
+         .value_name("ADDRESS")
+         .conflicts_with("link"))
+    .arg(Arg::new("printer")
+         .help("Connect a Game Boy Printer to the link port. Printed images are saved as .bmp files")
+         .short('p')
+         .long("printer")
+         .conflicts_with_all(&["link", "host", "join"]))
     .get_matches();
 
-    let mut emulator = emulator::Emulator::new();
-
-    // Optionally load bootrom if flag is sent in
-    if let Some(i) = matches.value_of("bootrom") {
-        emulator.enable_bootrom();
-        emulator.memory.rom.load_bootrom_from_file(i);
-    }
-
-    // Load ROM file
-    if let Some(i) = matches.value_of("filename") {
-        emulator.memory.rom.load_from_file(i);
-    }
+    let (mut emulator, battery_save_path) = create_emulator(&matches, matches.value_of("filename").unwrap());
 
     // Load and deserialize emulator from provided file
     if let Some(i) = matches.value_of("savefile") {
-        let bytes = fs::read(i).expect("Unable to read file");
-        emulator = emulator::Emulator::deserialize(&bytes);
+        let result = fs::read(i)
+            .map_err(emulator::LoadError::from)
+            .and_then(|bytes| emulator::Emulator::deserialize(&bytes));
+        match result {
+            Ok(em) => { emulator = em; }
+            Err(error) => { eprintln!("Error loading savefile \"{}\": {}. Starting the ROM instead", i, error); }
+        }
     }
 
     // Start debugger if requested
@@ -73,8 +120,28 @@ fn main() {
         debugger::debug(&mut emulator);
     };
 
+    // Connect the Game Boy Printer
+    let mut printer = None;
+    if matches.is_present("printer") {
+        let device = emulator::Printer::new();
+        device.connect(&mut emulator);
+        printer = Some(device);
+    }
+
+    // Connect the link cable to an emulator in another process
+    let mut network_link = None;
+    if let Some(i) = matches.value_of("host") {
+        println!("Waiting for the other emulator to connect to \"{}\"", i);
+        network_link = Some(connect_network_link(network_link::NetworkLink::host(i), i, &mut emulator));
+    }
+    else if let Some(i) = matches.value_of("join") {
+        network_link = Some(connect_network_link(network_link::NetworkLink::join(i), i, &mut emulator));
+    }
+
     // Create an instance of Renderer, which starts a window
-    let mut renderer = renderer::Renderer::new();
+    let (screen_width, screen_height) = emulator.get_screen_size();
+    let screen_count = if matches.is_present("link") { 2 } else { 1 };
+    let mut renderer = renderer::Renderer::new(screen_width, screen_height, screen_count);
 
     // Set renderer audio syncing strategy
     if let Some(i) = matches.value_of("audiosync") {
@@ -87,22 +154,123 @@ fn main() {
 
     renderer.sound_enabled = !matches.is_present("noaudio");
 
-    run_emulator(&mut emulator, &mut renderer);
+    if let Some(i) = matches.value_of("link") {
+        let (second_emulator, mut second_battery_save_path) = create_emulator(&matches, i);
+        // Linking a ROM to itself, only one of them can own the battery save
+        if second_battery_save_path == battery_save_path {
+            second_battery_save_path = None;
+        }
+        println!("Link cable mode. Left: WASD, A: Space, B: LShift, Start: Tab, Select: Q");
+        println!("Right: Arrow keys, A: K, B: J, Start: Return, Select: Backspace");
+        let mut linked = emulator::LinkedEmulators::new(emulator, second_emulator);
+        run_linked_emulators(&mut linked, &mut renderer, [battery_save_path, second_battery_save_path]);
+    }
+    else {
+        run_emulator(&mut emulator, &mut renderer, battery_save_path, network_link, printer);
+    }
+}
+
+/// Plug a link cable connection into the emulator, exits if the connection failed
+fn connect_network_link(result: std::io::Result<network_link::NetworkLink>, address: &str, emulator: &mut emulator::Emulator) -> network_link::NetworkLink {
+    match result {
+        Ok(link) => {
+            println!("Link cable connected to \"{}\"", address);
+            link.connect(emulator);
+            return link;
+        }
+        Err(error) => {
+            eprintln!("Error connecting link cable to \"{}\": {}", address, error);
+            std::process::exit(1);
+        }
+    }
 }
 
-/// Run the SDL2 emulator frontend
-fn run_emulator(emulator : &mut emulator::Emulator, renderer: &mut renderer::Renderer) {
+/// Create an emulator running the ROM file, with the options from the command line.
+/// Returns the emulator and the path of its battery save
+fn create_emulator(matches: &clap::ArgMatches, filename: &str) -> (emulator::Emulator, Option<PathBuf>) {
+    let mut emulator = emulator::Emulator::new();
+
+    // Optionally select the hardware model
+    if let Some(i) = matches.value_of("model") {
+        emulator.set_hardware_model(emulator::HardwareModel::from_name(i));
+    }
+
+    if let Some(renderer) = matches.value_of("renderer").and_then(emulator::PpuRenderer::from_name) {
+        emulator.set_renderer(renderer);
+    }
+
+    // Optionally load bootrom if flag is sent in
+    if let Some(i) = matches.value_of("bootrom") {
+        let result = emulator.memory.rom.load_bootrom_from_file(i)
+            .and_then(|()| emulator.enable_bootrom());
+        if let Err(error) = result {
+            eprintln!("Error loading bootrom \"{}\": {}. Continuing without bootrom", i, error);
+        }
+    }
+    else if matches.is_present("freebootrom") {
+        emulator.load_free_bootrom();
+        emulator.enable_bootrom().expect("The free bootrom is always loaded");
+    }
+
+    // Load ROM file
+    let mut battery_save_path = None;
+    if let Err(error) = emulator.load_rom_from_file(filename) {
+        eprintln!("Error loading ROM \"{}\": {}", filename, error);
+        std::process::exit(1);
+    }
+    println!("{}", emulator.get_cartridge_header());
+    if !emulator.is_rom_header_checksum_valid() {
+        println!("Warning: ROM header checksum is invalid, continuing anyway");
+    }
+    // Load the battery backed cartridge RAM from <rom>.sav if it exists
+    if emulator.has_battery_save() {
+        let path = Path::new(filename).with_extension("sav");
+        if let Ok(bytes) = fs::read(&path) {
+            emulator.import_battery_save(&bytes);
+            println!("Loaded battery save \"{}\"", path.display());
+        }
+        battery_save_path = Some(path);
+    }
+
+    // Optionally colorize DMG games
+    if let Some(i) = matches.value_of("palette") {
+        emulator.set_compatibility_palette(emulator::PaletteCombo::from_name(i));
+    }
+
+    return (emulator, battery_save_path);
+}
+
+/// Run the SDL2 emulator frontend, optionally linked to an emulator in another process or a printer
+fn run_emulator(emulator : &mut emulator::Emulator, renderer: &mut renderer::Renderer, battery_save_path: Option<PathBuf>, 
+    mut network_link: Option<network_link::NetworkLink>, printer: Option<emulator::Printer>) {
+    let mut frames_since_battery_save = 0;
     loop 
     {  
         // Cycle the emulator until a frontend event is requested
-        match emulator.run_until_frontend_event() {
+        let event = match &mut network_link {
+            Some(link) => { link.run_until_frontend_event(emulator) }
+            None => { emulator.run_until_frontend_event() }
+        };
+        match event {
             // Render the emulator bitmap to the screen
             FrontendEvent::Render => {
-                renderer.set_screen_buffer(&mut emulator.screen.bitmap);
+                renderer.set_screen_buffer(0, emulator.get_screen_bitmap());
                 //renderer.set_screen_buffer(&mut debugger::gpu_state_dump(&mut emulator));
                 renderer.render();
                 // Handle input
                 let exit = renderer.input(emulator);
+                if let Some(printer) = &printer {
+                    while let Some(image) = printer.take_printed_image() {
+                        renderer::Renderer::save_printed_image(emulator, &image);
+                    }
+                }
+                if let Some(path) = &battery_save_path {
+                    frames_since_battery_save += 1;
+                    if exit || frames_since_battery_save >= BATTERY_SAVE_INTERVAL {
+                        flush_battery_save(emulator, path, exit);
+                        frames_since_battery_save = 0;
+                    }
+                }
                 if exit {
                     break;
                 }
@@ -114,4 +282,54 @@ fn run_emulator(emulator : &mut emulator::Emulator, renderer: &mut renderer::Ren
             }
         }
     }
+}
+
+/// Run the SDL2 emulator frontend with two linked emulators side by side
+fn run_linked_emulators(linked : &mut emulator::LinkedEmulators, renderer: &mut renderer::Renderer, battery_save_paths: [Option<PathBuf>; 2]) {
+    let mut frames_since_battery_save = 0;
+    loop 
+    {  
+        // Cycle the emulators until either of them requests a frontend event
+        match linked.run_until_frontend_event() {
+            (i, FrontendEvent::Render) => {
+                renderer.set_screen_buffer(i, linked.emulators[i].get_screen_bitmap());
+                // The first emulator paces the frontend
+                if i != 0 {
+                    continue;
+                }
+                renderer.render();
+                // Handle input
+                let exit = renderer.input_linked(&mut linked.emulators);
+                frames_since_battery_save += 1;
+                if exit || frames_since_battery_save >= BATTERY_SAVE_INTERVAL {
+                    for (j, path) in battery_save_paths.iter().enumerate() {
+                        if let Some(path) = path {
+                            flush_battery_save(&mut linked.emulators[j], path, exit);
+                        }
+                    }
+                    frames_since_battery_save = 0;
+                }
+                if exit {
+                    break;
+                }
+                renderer.sleep_to_sync_video();
+            }
+            // Only the first emulator is heard
+            (0, FrontendEvent::QueueSound) => {
+                renderer.queue_sound(&mut linked.emulators[0]);
+            }
+            _ => { }
+        }
+    }
+}
+
+/// Write the battery backed cartridge RAM to the .sav file if it has been modified.
+/// It is always written on exit, so the RTC footer holds the time the emulator was closed
+fn flush_battery_save(emulator : &mut emulator::Emulator, path: &Path, exit: bool) {
+    if !exit && !emulator.is_battery_save_modified() {
+        return;
+    }
+    if let Err(error) = fs::write(path, emulator.export_battery_save()) {
+        eprintln!("Error writing battery save \"{}\": {}", path.display(), error);
+    }
 }
\ No newline at end of file
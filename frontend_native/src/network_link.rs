@@ -0,0 +1,334 @@
+/// Connects the emulator to an emulator in another process through a TCP or Unix socket,
+/// like a link cable between two Gameboys.
+///
+/// Both sides regularly report how far they have run in clock cycles, and neither
+/// runs more than SYNC_WINDOW cycles ahead of the other. The side using the internal
+/// clock is the master of a transfer. It sends its byte along with the time of the
+/// transfer and waits for the reply. The other side replies once it has reached that time,
+/// with the byte in SB if it was waiting for an external clock, otherwise 0xFF.
+///
+/// Every message is 10 bytes: the message type, the time as a little endian u64 and a data byte.
+
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::convert::TryInto;
+use std::sync::mpsc;
+use std::thread;
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use emulator_core::emulator::{self, FrontendEvent, SerialDevice};
+
+/// How often the elapsed time is sent to the other side, in clock cycles
+const SYNC_INTERVAL: u64 = 8192;
+/// How far an emulator may run ahead of the other side, in clock cycles
+const SYNC_WINDOW: u64 = 4*SYNC_INTERVAL;
+
+const MESSAGE_SYNC: u8 = 0;
+const MESSAGE_TRANSFER: u8 = 1;
+const MESSAGE_REPLY: u8 = 2;
+
+enum Message {
+    Sync(u64),          // The other side has reached this time
+    Transfer(u64, u8),  // The other side clocked out a byte at this time
+    Reply(u8),          // The byte received by the other side in our transfer
+}
+
+/// The state shared by the link and the serial port device
+struct LinkState {
+    writer: Box<dyn Write>,
+    receiver: mpsc::Receiver<Message>,
+    connected: bool,
+    clock_cycles: u64,
+    peer_clock_cycles: u64,
+    last_sync_sent: u64,
+    // A transfer from the other side, replied to when this side reaches its time
+    peer_transfer: Option<(u64, u8)>,
+    // The byte in SB, while this side waits for an external clock
+    waiting_byte: Option<u8>,
+    // The byte clocked in from the other side, received at the next poll
+    incoming_byte: Option<u8>,
+}
+
+impl LinkState {
+    fn new(mut reader: Box<dyn Read + Send>, writer: Box<dyn Write>) -> LinkState {
+        // Messages are read on a separate thread, so the emulator can check for them without blocking
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buffer = [0; 10];
+            while reader.read_exact(&mut buffer).is_ok() {
+                let time = u64::from_le_bytes(buffer[1..9].try_into().unwrap());
+                let message = match buffer[0] {
+                    MESSAGE_SYNC => { Message::Sync(time) }
+                    MESSAGE_TRANSFER => { Message::Transfer(time, buffer[9]) }
+                    MESSAGE_REPLY => { Message::Reply(buffer[9]) }
+                    kind => {
+                        // The stream can not be trusted after an invalid message, drop the connection
+                        println!("Link cable received an invalid message type {}", kind);
+                        break;
+                    }
+                };
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+        return LinkState { writer: writer, receiver: receiver, connected: true, clock_cycles: 0, peer_clock_cycles: 0,
+            last_sync_sent: 0, peer_transfer: None, waiting_byte: None, incoming_byte: None };
+    }
+
+    fn send(&mut self, kind: u8, time: u64, byte: u8) {
+        let mut buffer = [0; 10];
+        buffer[0] = kind;
+        buffer[1..9].copy_from_slice(&time.to_le_bytes());
+        buffer[9] = byte;
+        if self.connected && self.writer.write_all(&buffer).is_err() {
+            self.disconnect();
+        }
+    }
+
+    /// Receive a message from the other side. Returns None if there is no message,
+    /// or if the other side has disconnected
+    fn receive(&mut self, blocking: bool) -> Option<Message> {
+        if !self.connected {
+            return None;
+        }
+        let result = if blocking {
+            self.receiver.recv().map_err(|_| mpsc::TryRecvError::Disconnected)
+        } else {
+            self.receiver.try_recv()
+        };
+        match result {
+            Ok(message) => { return Some(message); }
+            Err(mpsc::TryRecvError::Empty) => { return None; }
+            Err(mpsc::TryRecvError::Disconnected) => {
+                self.disconnect();
+                return None;
+            }
+        }
+    }
+
+    fn disconnect(&mut self) {
+        if self.connected {
+            println!("Link cable disconnected");
+        }
+        self.connected = false;
+    }
+
+    fn handle_message(&mut self, message: Message) {
+        match message {
+            Message::Sync(time) => { self.peer_clock_cycles = time; }
+            Message::Transfer(time, byte) => {
+                self.peer_clock_cycles = time;
+                self.peer_transfer = Some((time, byte));
+            }
+            // Replies are only sent during our own transfers
+            Message::Reply(_) => { }
+        }
+    }
+
+    /// Called between emulator steps. Reports the elapsed time to the other side,
+    /// waits if this side is too far ahead and replies to transfers from the other side
+    fn sync(&mut self) {
+        while let Some(message) = self.receive(false) {
+            self.handle_message(message);
+        }
+        if self.clock_cycles >= self.last_sync_sent + SYNC_INTERVAL {
+            self.send(MESSAGE_SYNC, self.clock_cycles, 0);
+            self.last_sync_sent = self.clock_cycles;
+        }
+        self.reply_to_peer_transfer();
+        while self.connected && self.clock_cycles > self.peer_clock_cycles + SYNC_WINDOW {
+            if let Some(message) = self.receive(true) {
+                self.handle_message(message);
+            }
+            // The other side may be waiting for a reply, which it is allowed to be behind
+            self.reply_to_peer_transfer();
+        }
+        // A waiting side polls every cycle, so it is only marked as waiting during the next step
+        self.waiting_byte = None;
+    }
+
+    fn reply_to_peer_transfer(&mut self) {
+        if let Some((time, byte)) = self.peer_transfer {
+            if self.clock_cycles >= time {
+                self.peer_transfer = None;
+                match self.waiting_byte {
+                    Some(sent) => {
+                        self.send(MESSAGE_REPLY, self.clock_cycles, sent);
+                        self.incoming_byte = Some(byte);
+                    }
+                    // Not waiting for the external clock, nothing is shifted
+                    None => { self.send(MESSAGE_REPLY, self.clock_cycles, 0xFF); }
+                }
+            }
+        }
+    }
+}
+
+/// The link port end of the socket, plugged into the emulator
+struct NetworkPort {
+    state: Rc<RefCell<LinkState>>,
+}
+
+impl SerialDevice for NetworkPort {
+    fn exchange_byte(&mut self, sent: u8) -> u8 {
+        let mut state = self.state.borrow_mut();
+        let time = state.clock_cycles;
+        state.send(MESSAGE_TRANSFER, time, sent);
+        // Block until the other side has reached the time of the transfer and replied
+        while let Some(message) = state.receive(true) {
+            match message {
+                Message::Reply(received) => { return received; }
+                Message::Transfer(time, _) => {
+                    // Both sides use the internal clock, neither receives anything
+                    state.peer_clock_cycles = time;
+                    state.send(MESSAGE_REPLY, time, 0xFF);
+                }
+                message => { state.handle_message(message); }
+            }
+        }
+        return 0xFF;
+    }
+
+    fn poll_external_clock(&mut self, sent: u8) -> Option<u8> {
+        let mut state = self.state.borrow_mut();
+        let received = state.incoming_byte.take();
+        if received.is_none() {
+            state.waiting_byte = Some(sent);
+        }
+        return received;
+    }
+}
+
+/// A link cable connection to an emulator in another process
+pub struct NetworkLink {
+    state: Rc<RefCell<LinkState>>,
+}
+
+impl NetworkLink {
+    /// Wait for another emulator to connect. The address is a TCP address like "0.0.0.0:5432",
+    /// or a Unix socket path prefixed with "unix:"
+    pub fn host(address: &str) -> io::Result<NetworkLink> {
+        #[cfg(unix)]
+        if let Some(path) = address.strip_prefix("unix:") {
+            // Remove the socket file left behind by a previous session
+            let _ = std::fs::remove_file(path);
+            let (stream, _) = UnixListener::bind(path)?.accept()?;
+            return Ok(NetworkLink::new(Box::new(stream.try_clone()?), Box::new(stream)));
+        }
+        let (stream, _) = TcpListener::bind(address)?.accept()?;
+        return NetworkLink::from_tcp_stream(stream);
+    }
+
+    /// Connect to an emulator hosting a link at the address
+    pub fn join(address: &str) -> io::Result<NetworkLink> {
+        #[cfg(unix)]
+        if let Some(path) = address.strip_prefix("unix:") {
+            let stream = UnixStream::connect(path)?;
+            return Ok(NetworkLink::new(Box::new(stream.try_clone()?), Box::new(stream)));
+        }
+        return NetworkLink::from_tcp_stream(TcpStream::connect(address)?);
+    }
+
+    fn from_tcp_stream(stream: TcpStream) -> io::Result<NetworkLink> {
+        // The messages are small and latency sensitive
+        stream.set_nodelay(true)?;
+        return Ok(NetworkLink::new(Box::new(stream.try_clone()?), Box::new(stream)));
+    }
+
+    fn new(reader: Box<dyn Read + Send>, writer: Box<dyn Write>) -> NetworkLink {
+        return NetworkLink { state: Rc::new(RefCell::new(LinkState::new(reader, writer))) };
+    }
+
+    /// Plug the link cable into the emulator serial port
+    pub fn connect(&self, emulator: &mut emulator::Emulator) {
+        emulator.connect_serial_device(Box::new(NetworkPort { state: self.state.clone() }));
+    }
+
+    /// Step the emulator until a frontend event occurs, staying in sync with the other side.
+    /// Returns the frontend event that occured
+    pub fn run_until_frontend_event(&mut self, emulator: &mut emulator::Emulator) -> FrontendEvent {
+        if emulator.paused {
+            return FrontendEvent::Render;
+        }
+        loop {
+            self.state.borrow_mut().sync();
+            let machine_cycles = emulator.cpu.machine_cycles;
+            let event = emulator.step_frontend();
+            let clock_cycles_per_machine_cycle = if emulator.memory.double_speed { 2 } else { 4 };
+            self.state.borrow_mut().clock_cycles += (emulator.cpu.machine_cycles - machine_cycles) * clock_cycles_per_machine_cycle;
+            if let Some(event) = event {
+                return event;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::NetworkLink;
+    use emulator_core::emulator::{Emulator, FrontendEvent};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Run a ROM which sends `byte` through serial using the clock in `sc`
+    /// for a few frames. Returns the received byte
+    fn run_transfer_rom(link: &mut NetworkLink, byte: u8, sc: u8) -> u8 {
+        let mut data = vec![0; 0x8000];
+        let code = [
+            0x3E, byte, // LD A byte
+            0xE0, 0x01, // LD (SB) A
+            0x3E, sc,   // LD A sc
+            0xE0, 0x02, // LD (SC) A
+            0xF0, 0x02, // LD A (SC)
+            0xCB, 0x7F, // BIT 7 A
+            0x20, 0xFA, // JR NZ -6
+            0xF0, 0x01, // LD A (SB)
+            0xE0, 0x80, // LD (0xFF80) A
+            0x18, 0xFE, // JR -2
+        ];
+        data[0x100..0x100 + code.len()].copy_from_slice(&code);
+        let mut emulator = Emulator::new();
+        emulator.load_rom_from_data(&data).unwrap();
+        link.connect(&mut emulator);
+        let mut frames = 0;
+        while frames < 5 {
+            if let FrontendEvent::Render = link.run_until_frontend_event(&mut emulator) {
+                frames += 1;
+            }
+        }
+        return emulator.memory.read_byte(0xFF80);
+    }
+
+    #[test]
+    fn loopback_transfer()
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let slave = thread::spawn(move || {
+            let mut link = NetworkLink::join(&address).unwrap();
+            return run_transfer_rom(&mut link, 0x99, 0x80);
+        });
+        let mut link = NetworkLink::from_tcp_stream(listener.accept().unwrap().0).unwrap();
+        assert_eq!(run_transfer_rom(&mut link, 0x42, 0x81), 0x99);
+        assert_eq!(slave.join().unwrap(), 0x42);
+    }
+
+    #[test]
+    fn invalid_message()
+    {
+        use super::{LinkState, Message, MESSAGE_SYNC};
+        use std::io::{self, Cursor};
+        // A sync message followed by an unknown message type
+        let mut stream = vec![MESSAGE_SYNC, 100, 0, 0, 0, 0, 0, 0, 0, 0];
+        stream.extend_from_slice(&[7, 200, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let mut state = LinkState::new(Box::new(Cursor::new(stream)), Box::new(io::sink()));
+        assert!(matches!(state.receive(true), Some(Message::Sync(100))));
+        assert!(state.receive(true).is_none());
+        assert_eq!(state.connected, false);
+    }
+}
@@ -17,14 +17,8 @@ use bmp::{Image, Pixel};
 
 // Various settings
 
-const GB_SCREEN_WIDTH: usize = 160;
-const GB_SCREEN_HEIGHT: usize = 144;
-
 const SCREEN_UPSCALE_FACTOR: usize = 4;
 
-const SCREEN_WIDTH: usize = GB_SCREEN_WIDTH*SCREEN_UPSCALE_FACTOR;
-const SCREEN_HEIGHT: usize = GB_SCREEN_HEIGHT*SCREEN_UPSCALE_FACTOR;
-
 const PRINT_FRAMERATE : bool = false;
 const PRINT_AUDIO_INFO: bool = false;
 
@@ -42,10 +36,13 @@ pub enum AudioSyncStrategy {
 pub struct Renderer
 {
     // SDL2 related
-    screen_texture: sdl2::render::Texture,
+    screen_textures: Vec<sdl2::render::Texture>,
     canvas: sdl2::render::Canvas<sdl2::video::Window>,
     event_pump: sdl2::EventPump,
     sound_player: sound::SoundPlayer,
+    // Size of an upscaled emulator screen. Linked emulators are shown side by side
+    screen_width: usize,
+    screen_height: usize,
     // FPS counting
     frame_counter: u32,
     audio_counter: usize,
@@ -63,16 +60,19 @@ pub struct Renderer
 
 impl Renderer
 {
-    /// Create a new SDL2 emulator frontend
-    pub fn new() -> Renderer
+    /// Create a new SDL2 emulator frontend, showing `screen_count` emulator screens side by side.
+    /// The emulator screen is 160x144, or 256x224 in Super Gameboy mode
+    pub fn new(gb_screen_width: usize, gb_screen_height: usize, screen_count: usize) -> Renderer
     {
-        
+        let screen_width = gb_screen_width*SCREEN_UPSCALE_FACTOR;
+        let screen_height = gb_screen_height*SCREEN_UPSCALE_FACTOR;
+
         let sdl_context = sdl2::init().unwrap();
 
         // Setup bitmap rendering and window
         let video_subsystem = sdl_context.video().unwrap();
 
-        let window = video_subsystem.window("CorrodedBoy - Gameboy Emulator", SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32)
+        let window = video_subsystem.window("CorrodedBoy - Gameboy Emulator", (screen_width*screen_count) as u32, screen_height as u32)
             .position_centered()
             .opengl()
             .build()
@@ -86,7 +86,9 @@ impl Renderer
         canvas.present();
 
         let texture_creator =  canvas.texture_creator();
-        let texture = texture_creator.create_texture_streaming(sdl2::pixels::PixelFormatEnum::RGB24, GB_SCREEN_WIDTH as u32, GB_SCREEN_HEIGHT as u32).unwrap();
+        let textures = (0..screen_count).map(|_| {
+            texture_creator.create_texture_streaming(sdl2::pixels::PixelFormatEnum::RGB24, gb_screen_width as u32, gb_screen_height as u32).unwrap()
+        }).collect();
     
         let event_pump = sdl_context.event_pump().unwrap();
 
@@ -99,10 +101,12 @@ impl Renderer
             speed_up: false, 
             paused: false,
             sound_enabled: true,
-            screen_texture: texture, 
+            screen_textures: textures, 
             canvas: canvas, 
             event_pump: event_pump, 
             sound_player: sound_player,
+            screen_width: screen_width,
+            screen_height: screen_height,
             frame_counter: 0,
             audio_counter: 0,
             frame_timer : Instant::now(),
@@ -128,7 +132,10 @@ impl Renderer
         }
         
         self.canvas.clear();
-        self.canvas.copy(&self.screen_texture, None, Some(sdl2::rect::Rect::new(0, 0, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32))).unwrap();
+        for (i, texture) in self.screen_textures.iter().enumerate() {
+            let rect = sdl2::rect::Rect::new((i*self.screen_width) as i32, 0, self.screen_width as u32, self.screen_height as u32);
+            self.canvas.copy(texture, None, Some(rect)).unwrap();
+        }
         self.canvas.present();
         self.frame_counter += 1;
     }
@@ -152,10 +159,10 @@ impl Renderer
         }
     }
 
-    /// Set the screen texture to a buffer array of size GB_HEIGHT*GB_WIDTH*3
-    pub fn set_screen_buffer(&mut self, buffer : &mut [u8])
+    /// Set the screen texture at `index` to a buffer array of size GB_HEIGHT*GB_WIDTH*3
+    pub fn set_screen_buffer(&mut self, index: usize, buffer : &[u8])
     {
-        self.screen_texture.with_lock(None, |tbuffer: &mut [u8], _| {
+        self.screen_textures[index].with_lock(None, |tbuffer: &mut [u8], _| {
             tbuffer.copy_from_slice(buffer);
         }).unwrap();
     }
@@ -175,53 +182,26 @@ impl Renderer
                     keycode: Some(Keycode::Escape), .. } => {
                         return true;
                 },
-                Event::KeyDown { keycode, .. } => {
+                Event::KeyDown { keycode: Some(keycode), .. } => {
+                    if let Some(key) = get_key_press(keycode) {
+                        emulator.press_key(key);
+                    }
                     match keycode {
-                        // Gameboy button presses
-                        Some(Keycode::Return) =>    emulator.press_key(emulator::KeyPress::Start),
-                        Some(Keycode::Backspace) => emulator.press_key(emulator::KeyPress::Select),
-                        Some(Keycode::W) =>         emulator.press_key(emulator::KeyPress::Up),
-                        Some(Keycode::S) =>         emulator.press_key(emulator::KeyPress::Down),
-                        Some(Keycode::A) =>         emulator.press_key(emulator::KeyPress::Left),
-                        Some(Keycode::D) =>         emulator.press_key(emulator::KeyPress::Right),
-                        Some(Keycode::Up) =>        emulator.press_key(emulator::KeyPress::Up),        
-                        Some(Keycode::Down) =>      emulator.press_key(emulator::KeyPress::Down),
-                        Some(Keycode::Left) =>      emulator.press_key(emulator::KeyPress::Left),        
-                        Some(Keycode::Right) =>     emulator.press_key(emulator::KeyPress::Right),
-                        Some(Keycode::Z) =>         emulator.press_key(emulator::KeyPress::A),
-                        Some(Keycode::X) =>         emulator.press_key(emulator::KeyPress::B),
-                        Some(Keycode::Space) =>     emulator.press_key(emulator::KeyPress::A),
-                        Some(Keycode::LShift) =>    emulator.press_key(emulator::KeyPress::B),
                         // Various emulator controls
-                        Some(Keycode::P) =>         emulator.paused = !emulator.paused,
-                        Some(Keycode::O) =>         self.sound_enabled = !self.sound_enabled,
-                        Some(Keycode::LCtrl) =>     self.speed_up = !self.speed_up,
-                        Some(Keycode::F1) =>        Renderer::save_emulator(emulator),
-                        Some(Keycode::F2) =>        take_screenshot = true,
+                        Keycode::P =>         emulator.paused = !emulator.paused,
+                        Keycode::O =>         self.sound_enabled = !self.sound_enabled,
+                        Keycode::LCtrl =>     self.speed_up = !self.speed_up,
+                        Keycode::F1 =>        Renderer::save_emulator(emulator),
+                        Keycode::F2 =>        take_screenshot = true,
                         // Debugging controls
-                        Some(Keycode::F3) =>        debug_helper::save_gpu_state_to_file(emulator, "debug.bmp"),
-                        Some(Keycode::F4) =>        debugger::debug(emulator),
+                        Keycode::F3 =>        debug_helper::save_gpu_state_to_file(emulator, "debug.bmp"),
+                        Keycode::F4 =>        debugger::debug(emulator),
                         _ => { }
                     }
                 }
-                Event::KeyUp { keycode, .. } => {
-                    match keycode {
-                        // Gameboy button releases
-                        Some(Keycode::Return) =>    emulator.clear_key(emulator::KeyPress::Start),
-                        Some(Keycode::Backspace) => emulator.clear_key(emulator::KeyPress::Select),
-                        Some(Keycode::W) =>         emulator.clear_key(emulator::KeyPress::Up),
-                        Some(Keycode::S) =>         emulator.clear_key(emulator::KeyPress::Down),
-                        Some(Keycode::A) =>         emulator.clear_key(emulator::KeyPress::Left),
-                        Some(Keycode::D) =>         emulator.clear_key(emulator::KeyPress::Right),
-                        Some(Keycode::Up) =>        emulator.clear_key(emulator::KeyPress::Up),        
-                        Some(Keycode::Down) =>      emulator.clear_key(emulator::KeyPress::Down),
-                        Some(Keycode::Left) =>      emulator.clear_key(emulator::KeyPress::Left),        
-                        Some(Keycode::Right) =>     emulator.clear_key(emulator::KeyPress::Right),
-                        Some(Keycode::Z) =>         emulator.clear_key(emulator::KeyPress::A),
-                        Some(Keycode::X) =>         emulator.clear_key(emulator::KeyPress::B),
-                        Some(Keycode::Space) =>     emulator.clear_key(emulator::KeyPress::A),
-                        Some(Keycode::LShift) =>    emulator.clear_key(emulator::KeyPress::B),
-                        _ => { }
+                Event::KeyUp { keycode: Some(keycode), .. } => {
+                    if let Some(key) = get_key_press(keycode) {
+                        emulator.clear_key(key);
                     }
                 }
                 _ => {}
@@ -233,6 +213,46 @@ impl Renderer
         return false;
     }
 
+    /// Check for user input for two linked emulators.
+    /// The left player uses WASD, the right player the arrow keys
+    /// 
+    /// Returns true if the emulator should exit, otherwise false
+    pub fn input_linked(&mut self, emulators: &mut [emulator::Emulator; 2]) -> bool
+    {
+        for event in self.event_pump.poll_iter() {
+            match event {
+                // Exit program
+                Event::Quit {..} |
+                Event::KeyDown { 
+                    keycode: Some(Keycode::Escape), .. } => {
+                        return true;
+                },
+                Event::KeyDown { keycode: Some(keycode), .. } => {
+                    if let Some((player, key)) = get_linked_key_press(keycode) {
+                        emulators[player].press_key(key);
+                    }
+                    match keycode {
+                        // Various emulator controls, which apply to both emulators
+                        Keycode::P => { 
+                            emulators[0].paused = !emulators[0].paused; 
+                            emulators[1].paused = emulators[0].paused; 
+                        }
+                        Keycode::O =>         self.sound_enabled = !self.sound_enabled,
+                        Keycode::LCtrl =>     self.speed_up = !self.speed_up,
+                        _ => { }
+                    }
+                }
+                Event::KeyUp { keycode: Some(keycode), .. } => {
+                    if let Some((player, key)) = get_linked_key_press(keycode) {
+                        emulators[player].clear_key(key);
+                    }
+                }
+                _ => {}
+            }
+        }
+        return false;
+    }
+
     /// Queue sound from the emulator
     pub fn queue_sound(&mut self, emulator : &mut emulator::Emulator) {
         if self.sound_enabled && !self.speed_up {
@@ -287,13 +307,74 @@ impl Renderer
     /// The screenshot is placed in the current working directory.
     pub fn save_screenshot(&self, emulator : &mut emulator::Emulator) {
         let filename = format!("screenshot-{}-{}.bmp", emulator.get_rom_name(), prelude::Utc::now().format("%Y-%m-%dT%H:%M:%S"));
-        let mut img = Image::new(SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32);
+        let window_width = self.screen_width*self.screen_textures.len();
+        let mut img = Image::new(window_width as u32, self.screen_height as u32);
         let pixels = self.canvas.read_pixels(None, PixelFormatEnum::RGB24).unwrap();
         for (x, y) in img.coordinates() {
-            let i = (y as usize)*SCREEN_WIDTH + x as usize;
+            let i = (y as usize)*window_width + x as usize;
             img.set_pixel(x, y, px!(pixels[i*3+0], pixels[i*3+1], pixels[i*3+2]));
         }
         let _ = img.save(&filename);
         println!("Saved screenshot to file \"{}\"", &filename);
     }
-}
\ No newline at end of file
+
+    /// Save an image printed by the Game Boy Printer.
+    /// The image is placed in the current working directory.
+    /// The printout number keeps several printouts within a second apart
+    pub fn save_printed_image(emulator : &mut emulator::Emulator, image: &emulator::PrintedImage) {
+        let filename = format!("print-{}-{}-{:03}.bmp", emulator.get_rom_name(), prelude::Utc::now().format("%Y-%m-%dT%H:%M:%S"), image.number);
+        let mut img = Image::new(image.width as u32, image.height as u32);
+        for (x, y) in img.coordinates() {
+            let i = (y as usize)*image.width + x as usize;
+            img.set_pixel(x, y, px!(image.pixels[i*3+0], image.pixels[i*3+1], image.pixels[i*3+2]));
+        }
+        let _ = img.save(&filename);
+        println!("Saved printed image to file \"{}\"", &filename);
+    }
+}
+
+/// Map a keyboard key to a Gameboy button
+fn get_key_press(keycode: Keycode) -> Option<emulator::KeyPress> {
+    return match keycode {
+        Keycode::Return =>    Some(emulator::KeyPress::Start),
+        Keycode::Backspace => Some(emulator::KeyPress::Select),
+        Keycode::W =>         Some(emulator::KeyPress::Up),
+        Keycode::S =>         Some(emulator::KeyPress::Down),
+        Keycode::A =>         Some(emulator::KeyPress::Left),
+        Keycode::D =>         Some(emulator::KeyPress::Right),
+        Keycode::Up =>        Some(emulator::KeyPress::Up),
+        Keycode::Down =>      Some(emulator::KeyPress::Down),
+        Keycode::Left =>      Some(emulator::KeyPress::Left),
+        Keycode::Right =>     Some(emulator::KeyPress::Right),
+        Keycode::Z =>         Some(emulator::KeyPress::A),
+        Keycode::X =>         Some(emulator::KeyPress::B),
+        Keycode::Space =>     Some(emulator::KeyPress::A),
+        Keycode::LShift =>    Some(emulator::KeyPress::B),
+        _ => None,
+    }
+}
+
+/// Map a keyboard key to a Gameboy button of the left (0) or right (1) linked emulator
+fn get_linked_key_press(keycode: Keycode) -> Option<(usize, emulator::KeyPress)> {
+    return match keycode {
+        // Left player
+        Keycode::W =>         Some((0, emulator::KeyPress::Up)),
+        Keycode::S =>         Some((0, emulator::KeyPress::Down)),
+        Keycode::A =>         Some((0, emulator::KeyPress::Left)),
+        Keycode::D =>         Some((0, emulator::KeyPress::Right)),
+        Keycode::Space =>     Some((0, emulator::KeyPress::A)),
+        Keycode::LShift =>    Some((0, emulator::KeyPress::B)),
+        Keycode::Tab =>       Some((0, emulator::KeyPress::Start)),
+        Keycode::Q =>         Some((0, emulator::KeyPress::Select)),
+        // Right player
+        Keycode::Up =>        Some((1, emulator::KeyPress::Up)),
+        Keycode::Down =>      Some((1, emulator::KeyPress::Down)),
+        Keycode::Left =>      Some((1, emulator::KeyPress::Left)),
+        Keycode::Right =>     Some((1, emulator::KeyPress::Right)),
+        Keycode::K =>         Some((1, emulator::KeyPress::A)),
+        Keycode::J =>         Some((1, emulator::KeyPress::B)),
+        Keycode::Return =>    Some((1, emulator::KeyPress::Start)),
+        Keycode::Backspace => Some((1, emulator::KeyPress::Select)),
+        _ => None,
+    }
+}
@@ -3,8 +3,15 @@ use wasm_bindgen::prelude::*;
 use emulator_core::emulator;
 use base64;
 
-const SCREEN_WIDTH : usize = 160;
-const SCREEN_HEIGHT : usize = 144;
+/// Returns the current unix time in seconds, used by the cartridge RTC
+fn get_unix_time() -> i64 {
+    return (js_sys::Date::now() / 1000.0) as i64;
+}
+
+/// Convert an emulator load error into a JS error message
+fn to_js_error(error: emulator::LoadError) -> JsValue {
+    return JsValue::from_str(&error.to_string());
+}
 
 #[wasm_bindgen]
 pub struct EmulatorWrapper {
@@ -20,24 +27,89 @@ impl EmulatorWrapper {
         EmulatorWrapper { emulator: emulator::Emulator::new()}
     }
 
-    /// Load ROM data to the emulator
-    pub fn load_rom(&mut self, rom_data : Vec<u8>) {
-        self.emulator.load_rom_from_data(&rom_data);
+    /// Load ROM data to the emulator. Throws an error message on failure
+    pub fn load_rom(&mut self, rom_data : Vec<u8>) -> Result<(), JsValue> {
+        return self.emulator.load_rom_from_data(&rom_data).map_err(to_js_error);
+    }
+
+    /// Returns the title from the cartridge header
+    pub fn get_rom_title(&self) -> String {
+        return self.emulator.get_cartridge_header().title.to_owned();
+    }
+
+    /// Returns a human readable summary of the cartridge header
+    pub fn get_cartridge_header_info(&self) -> String {
+        return self.emulator.get_cartridge_header().to_string();
+    }
+
+    /// Returns false if the loaded ROM has an invalid header checksum
+    pub fn is_rom_header_checksum_valid(&self) -> bool {
+        return self.emulator.is_rom_header_checksum_valid();
     }
 
-    /// Load bootrom data to the emulator
-    pub fn load_bootrom(&mut self, bootrom_data: Vec<u8>) {
-        self.emulator.load_bootrom_from_data(&bootrom_data);
-        self.emulator.enable_bootrom();
+    /// Colorize a DMG game like the Gameboy Color. "auto" selects the palette
+    /// from the cartridge title, "off" restores the gray shades and
+    /// button combinations like "up+a" select a specific palette
+    pub fn set_compatibility_palette(&mut self, name: &str) -> Result<(), JsValue> {
+        match name {
+            "off" => { self.emulator.clear_compatibility_palette(); }
+            "auto" => { self.emulator.set_compatibility_palette(None); }
+            _ => {
+                let combo = emulator::PaletteCombo::from_name(name)
+                    .ok_or_else(|| JsValue::from_str(&format!("Unknown palette \"{}\"", name)))?;
+                self.emulator.set_compatibility_palette(Some(combo));
+            }
+        }
+        return Ok(());
     }
 
-    /// Set the emulator state to match the serialized save state
-    pub fn load_save(&mut self, save_data: Vec<u8>) {
-        self.emulator = emulator::Emulator::deserialize(&save_data);
+    /// Select the Gameboy model to emulate, e.g. "dmg", "mgb" or "cgb".
+    /// "auto" selects the model from the cartridge header
+    pub fn set_hardware_model(&mut self, name: &str) -> Result<(), JsValue> {
+        if name == "auto" {
+            self.emulator.set_hardware_model(None);
+            return Ok(());
+        }
+        let model = emulator::HardwareModel::from_name(name)
+            .ok_or_else(|| JsValue::from_str(&format!("Unknown hardware model \"{}\"", name)))?;
+        self.emulator.set_hardware_model(Some(model));
+        return Ok(());
+    }
+
+    /// Select the PPU renderer, "scanline" or "fifo". The pixel FIFO renderer
+    /// shows mid-scanline raster effects, but is slower
+    pub fn set_renderer(&mut self, name: &str) -> Result<(), JsValue> {
+        let renderer = emulator::PpuRenderer::from_name(name)
+            .ok_or_else(|| JsValue::from_str(&format!("Unknown renderer \"{}\"", name)))?;
+        self.emulator.set_renderer(renderer);
+        return Ok(());
+    }
+
+    /// Load bootrom data to the emulator. Throws an error message on failure
+    pub fn load_bootrom(&mut self, bootrom_data: Vec<u8>) -> Result<(), JsValue> {
+        self.emulator.load_bootrom_from_data(&bootrom_data).map_err(to_js_error)?;
+        self.emulator.enable_bootrom().map_err(to_js_error)?;
+        return Ok(());
+    }
+
+    /// Use the bundled free bootrom, which shows the boot animation
+    pub fn load_free_bootrom(&mut self) -> Result<(), JsValue> {
+        self.emulator.load_free_bootrom();
+        self.emulator.enable_bootrom().map_err(to_js_error)?;
+        return Ok(());
+    }
+
+    /// Set the emulator state to match the serialized save state. 
+    /// Throws an error message on failure
+    pub fn load_save(&mut self, save_data: Vec<u8>) -> Result<(), JsValue> {
+        self.emulator.load_state(&save_data).map_err(to_js_error)?;
+        self.emulator.catch_up_rtc_to_unix_time(get_unix_time());
+        return Ok(());
     }
 
     /// Returns a serialized emulator state for savefiles
     pub fn save(&mut self) -> Vec<u8> {
+        self.emulator.set_rtc_unix_time(get_unix_time());
         return self.emulator.serialize();
     }
 
@@ -54,18 +126,31 @@ impl EmulatorWrapper {
         }
     }
 
-    /// Returns the emulator screen bitmap
+    /// Returns the emulator screen bitmap, 
+    /// with the border in Super Gameboy mode
     pub fn get_screen_bitmap(&mut self) -> Vec<u8>  {
-        let mut bitmap : Vec<u8> = vec![255; SCREEN_WIDTH*SCREEN_HEIGHT*4];
-        for i in 0..SCREEN_WIDTH*SCREEN_HEIGHT {
-            bitmap[i*4 + 0] = self.emulator.screen.bitmap[i*3 + 0];
-            bitmap[i*4 + 1] = self.emulator.screen.bitmap[i*3 + 1];
-            bitmap[i*4 + 2] = self.emulator.screen.bitmap[i*3 + 2];
+        let (width, height) = self.emulator.get_screen_size();
+        let screen_bitmap = self.emulator.get_screen_bitmap();
+        let mut bitmap : Vec<u8> = vec![255; width*height*4];
+        for i in 0..width*height {
+            bitmap[i*4 + 0] = screen_bitmap[i*3 + 0];
+            bitmap[i*4 + 1] = screen_bitmap[i*3 + 1];
+            bitmap[i*4 + 2] = screen_bitmap[i*3 + 2];
             bitmap[i*4 + 3] = 255;
         }
         return bitmap;
     }
 
+    /// Returns the width of the screen bitmap, 256 in Super Gameboy mode, otherwise 160
+    pub fn get_screen_width(&self) -> usize {
+        return self.emulator.get_screen_size().0;
+    }
+
+    /// Returns the height of the screen bitmap, 224 in Super Gameboy mode, otherwise 144
+    pub fn get_screen_height(&self) -> usize {
+        return self.emulator.get_screen_size().1;
+    }
+
     /// Returns the name of currently loaded rom file
     pub fn get_rom_name(&mut self) -> String {
         return self.emulator.get_rom_name().to_owned();
@@ -151,8 +236,8 @@ impl EmulatorWrapper {
     }
 
     /// Turn the compact string representation into save data and deserialize
-    pub fn load_save_str(&mut self, string : String) {
-        let save_data = base64::decode(string).unwrap();
-        self.load_save(save_data);
+    pub fn load_save_str(&mut self, string : String) -> Result<(), JsValue> {
+        let save_data = base64::decode(string).map_err(|_| to_js_error(emulator::LoadError::InvalidSavestate))?;
+        return self.load_save(save_data);
     }
 }